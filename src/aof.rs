@@ -0,0 +1,397 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use serde_derive::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{
+    backend::Backend, command::Command, encoding, map::KvStore, resp::RespValue, value::Value,
+};
+
+/// When to flush the append-only file to disk, as Redis's `appendfsync`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fsync {
+    /// After every write, so that no acknowledged write is lost.
+    Always,
+    /// Once a second, losing at most a second of writes on a crash.
+    Everysec,
+    /// Whenever the OS decides to.
+    No,
+}
+
+impl FromStr for Fsync {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(Fsync::Always),
+            "everysec" => Ok(Fsync::Everysec),
+            "no" => Ok(Fsync::No),
+            _ => Err(format!("invalid fsync policy {}", s)),
+        }
+    }
+}
+
+/// A line of the append-only file.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Record {
+    /// Writes as committed, replayed at the master's clock they were
+    /// stamped with so that they apply like they did the first time.
+    Batch(u64, Vec<RespValue>),
+    /// A key as of the last rewrite, with its deadline and tags.
+    Key(String, Value, Option<u64>, Vec<String>),
+    /// The last fencing token handed out as of the last rewrite.
+    FencingToken(u64),
+}
+
+/// The records of a [dump](Backend::dump) as of when it was taken, put
+/// together when called.
+pub(crate) type Dump = Box<dyn FnOnce() -> io::Result<Vec<Record>> + Send>;
+
+impl<T> Backend<T>
+where
+    T: KvStore,
+{
+    /// The records that rebuild the current dataset from scratch.
+    pub(crate) fn dump(&self) -> io::Result<Vec<Record>> {
+        self.deferred_dump()?()
+    }
+
+    /// The records of [dump] as of now, put together when called, which
+    /// may be on another thread. Only what's in memory is copied right
+    /// away, values being read from the store's [deferred snapshot].
+    ///
+    /// [dump]: Backend::dump
+    /// [deferred snapshot]: KvStore::kv_deferred_snapshot
+    pub(crate) fn deferred_dump(&self) -> io::Result<Dump> {
+        let snapshot = self.store.kv_deferred_snapshot()?;
+        let expires = self.expires.clone();
+        let tags: HashMap<String, Vec<String>> = self
+            .tags
+            .tagged()
+            .map(|key| (key.clone(), self.tags.tags(key).cloned().collect()))
+            .collect();
+        let (now, state) = (self.now, self.state(self.now));
+        Ok(Box::new(move || {
+            let mut records = Vec::new();
+            for (key, value) in snapshot()? {
+                let deadline = expires.get(&key).copied();
+                if deadline.is_none_or(|at| at > now) {
+                    let tags = tags.get(&key).cloned().unwrap_or_default();
+                    records.push(Record::Key(key, value, deadline, tags));
+                }
+            }
+            records.extend(state);
+            Ok(records)
+        }))
+    }
+
+    /// The records that rebuild what isn't about any key in particular,
+    /// to load after the keys, as the indexes index them once created.
+    pub(crate) fn state(&self, now: u64) -> Vec<Record> {
+        let mut records = vec![Record::FencingToken(self.fencing_token)];
+        let thresholds = encoding::threshold_commands();
+        if !thresholds.is_empty() {
+            records.push(Record::Batch(now, thresholds));
+        }
+        for (name, index) in self.indexes.iter() {
+            records.push(Record::Batch(now, vec![index.create_command(name)]));
+        }
+        records
+    }
+
+    pub(crate) fn load(&mut self, record: Record) -> io::Result<()> {
+        match record {
+            Record::Batch(now, cmds) => {
+                self.now = now;
+                for cmd in cmds {
+                    if let Ok(cmd) = Command::try_from(cmd) {
+                        self.process_command(cmd);
+                    }
+                }
+            }
+            Record::Key(key, value, deadline, tags) => {
+                self.store.kv_put(&key, value)?;
+                if let Some(deadline) = deadline {
+                    self.expires.insert(key.clone(), deadline);
+                }
+                self.tags.tag(&key, tags);
+            }
+            Record::FencingToken(token) => self.fencing_token = token,
+        }
+        // Nobody to publish them to
+        self.events.clear();
+        Ok(())
+    }
+}
+
+fn encode(record: &Record) -> Vec<u8> {
+    let mut line = serde_json::to_vec(record).unwrap();
+    line.push(b'\n');
+    line
+}
+
+/// A rewrite writing a snapshot of the dataset to a temporary file in the
+/// background, while the writes committed meanwhile wait to be appended.
+struct Rewrite {
+    handle: JoinHandle<io::Result<File>>,
+    pending: Vec<u8>,
+}
+
+/// The append-only file of a replica, logging every committed batch.
+pub struct Aof {
+    path: PathBuf,
+    file: File,
+    /// A handle on the same file for the everysec fsyncs, so they don't
+    /// hold up appends
+    sync_handle: Arc<Mutex<File>>,
+    fsync: Fsync,
+    rewrite: Option<Rewrite>,
+}
+
+impl Aof {
+    /// Replays the file into the backend, unless told not to as the backend
+    /// has a dataset at least as up to date, then opens it for appending,
+    /// creating it from the backend's dataset if missing.
+    pub fn open<T: KvStore>(
+        path: &Path,
+        fsync: Fsync,
+        replay_file: bool,
+        backend: &mut Backend<T>,
+    ) -> Result<Aof, Box<dyn std::error::Error>> {
+        if replay_file && path.exists() {
+            replay(path, backend)?;
+        }
+        if !path.exists() {
+            // Once the file exists, the dataset is restored from it alone,
+            // so it starts out with what was loaded from elsewhere
+            let temp = path.with_extension("seed");
+            let mut file = File::create(&temp)?;
+            for record in backend.dump()? {
+                file.write_all(&encode(&record))?;
+            }
+            file.sync_all()?;
+            fs::rename(&temp, path)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let sync_handle = Arc::new(Mutex::new(file.try_clone()?));
+
+        if fsync == Fsync::Everysec {
+            let sync_handle = Arc::downgrade(&sync_handle);
+            thread::spawn(move || {
+                while let Some(sync_handle) = sync_handle.upgrade() {
+                    // Only locked to take the handle, a rewrite may swap it
+                    let file = sync_handle.lock().unwrap().try_clone();
+                    drop(sync_handle);
+                    if let Err(e) = file.and_then(|file| file.sync_data()) {
+                        warn!("failed to fsync the append-only file: {}", e);
+                    }
+                    thread::sleep(Duration::from_secs(1));
+                }
+            });
+        }
+
+        Ok(Aof {
+            path: path.to_path_buf(),
+            file,
+            sync_handle,
+            fsync,
+            rewrite: None,
+        })
+    }
+
+    /// Logs a batch the backend committed.
+    pub fn append(&mut self, now: u64, cmds: Vec<RespValue>) -> io::Result<()> {
+        let line = encode(&Record::Batch(now, cmds));
+        self.file.write_all(&line)?;
+        if self.fsync == Fsync::Always {
+            self.file.sync_data()?;
+        }
+
+        if let Some(rewrite) = self.rewrite.as_mut() {
+            rewrite.pending.extend_from_slice(&line);
+        }
+        self.poll_rewrite()
+    }
+
+    /// Starts rewriting the file from the dataset, replying with an error
+    /// if a rewrite is in progress already.
+    pub fn rewrite<T: KvStore>(&mut self, backend: &Backend<T>) -> RespValue {
+        if self.rewrite.is_some() {
+            return RespValue::Error(
+                "ERR Background append only file rewriting already in progress".into(),
+            );
+        }
+
+        let dump = match backend.deferred_dump() {
+            Ok(dump) => dump,
+            Err(e) => return e.into(),
+        };
+        let path = self.path.with_extension("rewrite");
+        let handle = thread::spawn(move || {
+            let mut file = File::create(&path)?;
+            for record in dump()? {
+                file.write_all(&encode(&record))?;
+            }
+            Ok(file)
+        });
+        self.rewrite = Some(Rewrite {
+            handle,
+            pending: Vec::new(),
+        });
+        RespValue::SimpleString("Background append only file rewriting started".into())
+    }
+
+    /// Swaps in the rewritten file once it's written, with the writes
+    /// committed meanwhile appended to it.
+    pub fn poll_rewrite(&mut self) -> io::Result<()> {
+        if !self
+            .rewrite
+            .as_ref()
+            .is_some_and(|rewrite| rewrite.handle.is_finished())
+        {
+            return Ok(());
+        }
+
+        let rewrite = self.rewrite.take().unwrap();
+        let temp = self.path.with_extension("rewrite");
+        let mut new_file = match rewrite.handle.join().unwrap() {
+            Ok(file) => file,
+            Err(e) => {
+                warn!("failed to rewrite the append-only file: {}", e);
+                let _ = fs::remove_file(&temp);
+                return Ok(());
+            }
+        };
+        new_file.write_all(&rewrite.pending)?;
+        new_file.sync_all()?;
+        fs::rename(&temp, &self.path)?;
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        *self.sync_handle.lock().unwrap() = self.file.try_clone()?;
+        info!("rewrote the append-only file");
+        Ok(())
+    }
+}
+
+/// Loads every record of the file, truncating an incomplete last record
+/// left by a crash mid-write.
+fn replay<T: KvStore>(
+    path: &Path,
+    backend: &mut Backend<T>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let contents = fs::read(path)?;
+    let mut offset = 0;
+    let mut loaded = 0;
+    while offset < contents.len() {
+        let Some(len) = contents[offset..].iter().position(|&b| b == b'\n') else {
+            warn!(
+                "truncating the incomplete record at the end of the append-only file, at byte {}",
+                offset
+            );
+            OpenOptions::new()
+                .write(true)
+                .open(path)?
+                .set_len(offset as u64)?;
+            break;
+        };
+        let record = serde_json::from_slice(&contents[offset..offset + len]).map_err(|e| {
+            format!(
+                "bad record in the append-only file at byte {}: {}",
+                offset, e
+            )
+        })?;
+        backend.load(record)?;
+        offset += len + 1;
+        loaded += 1;
+    }
+    backend.now = crate::backend::unix_millis();
+    info!("loaded {} records from the append-only file", loaded);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn run(backend: &mut Backend<HashMap<String, Value>>, aof: &mut Aof, now: u64, cmd: &[&str]) {
+        let cmd = RespValue::array(cmd);
+        backend.now = now;
+        backend.process_command(Command::try_from(cmd.clone()).unwrap());
+        aof.append(now, vec![cmd]).unwrap();
+    }
+
+    #[test]
+    fn test_aof() {
+        let dir = std::env::temp_dir().join(format!("kvkv-aof-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("appendonly.aof");
+        let _ = fs::remove_file(&path);
+
+        let mut backend = Backend::new(HashMap::new());
+        let mut aof = Aof::open(&path, Fsync::Always, true, &mut backend).unwrap();
+        run(
+            &mut backend,
+            &mut aof,
+            1000,
+            &["SET", "a", "1", "PX", "5000"],
+        );
+        run(
+            &mut backend,
+            &mut aof,
+            1000,
+            &["HSET", "user:1", "name", "Ada"],
+        );
+        run(
+            &mut backend,
+            &mut aof,
+            1000,
+            &["FT.CREATE", "users", "SCHEMA", "name", "TEXT"],
+        );
+        run(
+            &mut backend,
+            &mut aof,
+            1000,
+            &["LOCK.ACQUIRE", "job", "me", "100"],
+        );
+
+        // A crash in the middle of appending
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"{\"Batch\":[1000,[").unwrap();
+
+        let mut replayed = Backend::new(HashMap::new());
+        let mut aof = Aof::open(&path, Fsync::No, true, &mut replayed).unwrap();
+        assert_eq!(replayed.store, backend.store);
+        assert_eq!(replayed.expires, backend.expires);
+        assert_eq!(replayed.fencing_token, 1);
+        assert!(replayed.indexes.contains_key("users"));
+
+        // Rewritten into one record per key, which replays alike
+        run(&mut replayed, &mut aof, 2000, &["DEL", "job"]);
+        assert!(matches!(aof.rewrite(&replayed), RespValue::SimpleString(_)));
+        while aof.rewrite.is_some() {
+            aof.poll_rewrite().unwrap();
+        }
+        run(&mut replayed, &mut aof, 3000, &["SET", "b", "2"]);
+
+        let mut rewritten = Backend::new(HashMap::new());
+        Aof::open(&path, Fsync::No, true, &mut rewritten).unwrap();
+        assert_eq!(rewritten.store, replayed.store);
+        assert_eq!(rewritten.fencing_token, 1);
+        assert!(rewritten.indexes.contains_key("users"));
+        let contents = fs::read_to_string(&path).unwrap();
+        // Other tests may have set thresholds, which are global
+        let records = contents.lines().filter(|line| !line.contains("CONFIG"));
+        assert_eq!(records.count(), 5);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
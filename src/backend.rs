@@ -1,4 +1,30 @@
-use crate::{command::Command, map::KvStore, resp::RespValue};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    aof::Record,
+    command::{Command, Expiry},
+    encoding,
+    map::{BatchOp, KvStore},
+    memory::Memory,
+    notify::KeyEvent,
+    queue::QueueStats,
+    resp::RespValue,
+    search::Index,
+    tags::Tags,
+    value::{Value, WRONGTYPE},
+};
+
+/// Milliseconds since the unix epoch.
+pub(crate) fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
 
 pub(crate) struct Backend<T>
 where
@@ -6,40 +32,444 @@ where
 {
     pub id: u32,
     pub store: T,
+    /// Deadlines of the keys that have a TTL, in unix milliseconds.
+    pub expires: HashMap<String, u64>,
+    /// The time commands are processed at, in unix milliseconds.
+    /// Writes are processed at the master's clock, so that every
+    /// replica expires keys at the same point.
+    pub now: u64,
+    /// Keyspace events raised by the writes processed so far.
+    pub events: Vec<KeyEvent>,
+    /// Secondary indexes over hashes, by name.
+    pub indexes: BTreeMap<String, Index>,
+    /// The last fencing token `LOCK.ACQUIRE` handed out.
+    pub fencing_token: u64,
+    pub queue_stats: QueueStats,
+    pub tags: Tags,
+    /// The bytes each key takes.
+    pub memory: Memory,
+    /// What isn't about any key in particular, as last kept with the
+    /// store.
+    pub saved_state: Vec<Record>,
 }
 
 impl<T> Backend<T>
 where
     T: KvStore,
 {
+    pub fn new(store: T) -> Backend<T> {
+        Backend {
+            id: u32::MAX,
+            store,
+            expires: HashMap::new(),
+            now: unix_millis(),
+            events: Vec::new(),
+            indexes: BTreeMap::new(),
+            fencing_token: 0,
+            queue_stats: QueueStats::default(),
+            tags: Tags::default(),
+            memory: Memory::default(),
+            saved_state: Vec::new(),
+        }
+    }
+
     pub fn process_command(&mut self, cmd: Command) -> RespValue {
         use Command::*;
-        match cmd {
-            Get(k) => self.process_get(k),
-            Set(k, v) => self.process_set(k, v),
-            Del(keys) => self.process_del(keys),
+        let first_event = self.events.len();
+        let reply = match cmd {
+            Get(k) => self.process_get(k).unwrap_or_else(|e| e),
+            Set(k, v) => self.process_set(k, v, None, vec![]).unwrap_or_else(|e| e),
+            SetEx(k, v, expiry) => self
+                .process_set(k, v, Some(expiry), vec![])
+                .unwrap_or_else(|e| e),
+            SetTagged(k, v, expiry, tags) => {
+                self.process_set(k, v, expiry, tags).unwrap_or_else(|e| e)
+            }
+            Cas(k, old, new) => self.process_cas(k, old, new).unwrap_or_else(|e| e),
+            Del(keys) => self.process_del(keys).unwrap_or_else(|e| e),
+            FlushAll => self.process_flushall().unwrap_or_else(|e| e),
+            ConfigSet(name, value) => encoding::set_threshold(&name, &value),
+            Expire(k, expiry) => self.process_expire(k, expiry).unwrap_or_else(|e| e),
+            Ttl(k) => self.process_ttl(k, 1000).unwrap_or_else(|e| e),
+            PTtl(k) => self.process_ttl(k, 1).unwrap_or_else(|e| e),
+            Persist(k) => self.process_persist(k).unwrap_or_else(|e| e),
+            Sweep => self.process_sweep().unwrap_or_else(|e| e),
+            Evict(keys) => self
+                .delete_keys(keys, ('e', "evicted"))
+                .map_or_else(RespValue::from, RespValue::Integer),
+            Info(section) => self.process_info(section),
+            Bit(cmd) => self.process_bit(cmd),
+            Hll(cmd) => self.process_hll(cmd),
+            Bloom(cmd) => self.process_bloom(cmd),
+            Cuckoo(cmd) => self.process_cuckoo(cmd),
+            ZSet(cmd) => self.process_zset(cmd),
+            Geo(cmd) => self.process_geo(cmd),
+            Json(cmd) => self.process_json(cmd),
+            Vector(cmd) => self.process_vector(cmd),
+            Hash(cmd) => self.process_hash(cmd),
+            Search(cmd) => self.process_search(cmd),
+            Throttle(cmd) => self.process_throttle(cmd),
+            Lock(cmd) => self.process_lock(cmd),
+            Queue(cmd) => self.process_queue(cmd),
+            Tag(cmd) => self.process_tag(cmd),
+            Range(cmd) => self.process_range(cmd),
+            Memory(cmd) => self.process_memory(cmd).unwrap_or_else(|e| e),
+            Eval(script, keys, args) => self.process_eval(&script, keys, args),
+            // The master should have swapped in the script body
+            EvalSha(..) => RespValue::Error("NOSCRIPT No matching script.".into()),
+        };
+        // What the command did stands, but the reply says the rest failed
+        let outcome = self
+            .reindex(first_event)
+            .and_then(|()| self.untag_deleted(first_event))
+            .and_then(|()| self.account(first_event));
+        // Nothing borrows the values read from the store anymore
+        self.store.kv_trim();
+        match outcome {
+            Ok(()) => reply,
+            Err(e) => e.into(),
+        }
+    }
+
+    pub(crate) fn notify(&mut self, class: char, event: &str, key: &str) {
+        self.events.push(KeyEvent::new(class, event, key));
+    }
+
+    /// Whether the key has a TTL that ran out. Reads treat such keys as
+    /// missing, but leave deleting them to writes, so that replicas serving
+    /// reads don't drift apart from each other.
+    pub(crate) fn is_expired(&self, key: &str) -> bool {
+        self.expires.get(key).is_some_and(|&at| at <= self.now)
+    }
+
+    /// Deletes the key if its TTL ran out. Writes call this before
+    /// touching a key. Returns true if the key expired.
+    pub(crate) fn expire_if_needed(&mut self, key: &str) -> io::Result<bool> {
+        if !self.is_expired(key) {
+            return Ok(false);
+        }
+        self.store.kv_del(key)?;
+        self.expires.remove(key);
+        self.notify('x', "expired", key);
+        Ok(true)
+    }
+
+    /// The value of a key, unless it expired.
+    pub(crate) fn value(&self, key: &str) -> io::Result<Option<&Value>> {
+        if self.is_expired(key) {
+            return Ok(None);
         }
+        self.store.kv_get(key)
     }
 
-    fn process_get(&mut self, k: String) -> RespValue {
-        match self.store.kv_get(k.as_str()) {
-            Some(v) => RespValue::array(&[v]),
+    /// The value of a key for writing to it, deleting it first if it expired.
+    pub(crate) fn value_mut(&mut self, key: &str) -> io::Result<Option<&mut Value>> {
+        self.expire_if_needed(key)?;
+        self.store.kv_get_mut(key)
+    }
+
+    /// Deletes the keys, which must be there, as one batch, raising the
+    /// event for each, and replying with how many there were, which
+    /// doesn't count those that expired.
+    pub(crate) fn delete_keys(
+        &mut self,
+        keys: impl IntoIterator<Item = String>,
+        (class, event): (char, &str),
+    ) -> io::Result<i64> {
+        let mut deleted = Vec::new();
+        for key in keys {
+            if !self.expire_if_needed(&key)? {
+                deleted.push(key);
+            }
+        }
+        let batch = deleted.iter().cloned().map(BatchOp::Del).collect();
+        self.store.kv_write_batch(batch)?;
+        for key in deleted.iter() {
+            self.expires.remove(key);
+            self.notify(class, event, key);
+        }
+        Ok(deleted.len() as i64)
+    }
+
+    fn process_get(&mut self, k: String) -> Result<RespValue, RespValue> {
+        Ok(match self.value(k.as_str())? {
+            Some(Value::Str(v)) => {
+                RespValue::Array(vec![RespValue::BulkBytes(v.as_bytes().into())])
+            }
+            Some(_) => RespValue::Error(WRONGTYPE.into()),
             None => RespValue::array(&["nil"]),
+        })
+    }
+
+    /// Sets a string, replacing the tags of the key.
+    fn process_set(
+        &mut self,
+        k: String,
+        v: Vec<u8>,
+        expiry: Option<Expiry>,
+        tags: Vec<String>,
+    ) -> Result<RespValue, RespValue> {
+        self.expire_if_needed(&k)?;
+        self.store.kv_put(k.as_str(), v.into())?;
+        self.tags.tag(&k, tags);
+        self.notify('$', "set", &k);
+
+        match expiry {
+            Some(expiry) => {
+                self.expires.insert(k.clone(), expiry.deadline(self.now));
+                self.notify('g', "expire", &k);
+            }
+            None => {
+                self.expires.remove(&k);
+            }
+        }
+        Ok(RespValue::SimpleString("OK".into()))
+    }
+
+    /// Sets a string to `new` if it's still `old`, keeping its TTL and
+    /// tags, replying with whether it did.
+    fn process_cas(
+        &mut self,
+        k: String,
+        old: Vec<u8>,
+        new: Vec<u8>,
+    ) -> Result<RespValue, RespValue> {
+        self.expire_if_needed(&k)?;
+        if let Some(Value::Str(_)) | None = self.store.kv_get(&k)? {
+            let swapped =
+                self.store
+                    .kv_compare_and_swap(&k, Some(&old.into()), Some(new.into()))?;
+            if swapped {
+                self.notify('$', "set", &k);
+            }
+            return Ok(RespValue::Integer(swapped as i64));
         }
+        Err(RespValue::Error(WRONGTYPE.into()))
     }
 
-    fn process_set(&mut self, k: String, v: String) -> RespValue {
-        self.store.kv_put(k.as_str(), v.as_str());
-        RespValue::SimpleString("OK".into())
+    /// Deletes every key as one batch, raising the event for each, so that
+    /// whatever tracks keys forgets them too.
+    fn process_flushall(&mut self) -> Result<RespValue, RespValue> {
+        let keys: Vec<String> = self.store.kv_keys().map(String::from).collect();
+        self.store.kv_clear()?;
+        self.expires.clear();
+        for key in keys.iter() {
+            self.notify('g', "del", key);
+        }
+        Ok(RespValue::SimpleString("OK".into()))
     }
 
-    fn process_del(&mut self, keys: Vec<String>) -> RespValue {
-        // FIXME: Report how many keys are succussfully deleted
+    fn process_del(&mut self, keys: Vec<String>) -> Result<RespValue, RespValue> {
         let mut num_deleted = 0;
         for key in keys {
-            num_deleted += self.store.kv_del(key.as_str()) as i64;
+            self.expire_if_needed(&key)?;
+            if self.store.kv_del(key.as_str())? {
+                self.expires.remove(&key);
+                self.notify('g', "del", &key);
+                num_deleted += 1;
+            }
+        }
+
+        Ok(RespValue::Integer(num_deleted))
+    }
+
+    fn process_expire(&mut self, k: String, expiry: Expiry) -> Result<RespValue, RespValue> {
+        self.expire_if_needed(&k)?;
+        if self.store.kv_get(&k)?.is_none() {
+            return Ok(RespValue::Integer(0));
+        }
+
+        let deadline = expiry.deadline(self.now);
+        if deadline <= self.now {
+            self.store.kv_del(&k)?;
+            self.expires.remove(&k);
+            self.notify('g', "del", &k);
+        } else {
+            self.expires.insert(k.clone(), deadline);
+            self.notify('g', "expire", &k);
+        }
+        Ok(RespValue::Integer(1))
+    }
+
+    /// Replies with the remaining TTL in units of `unit_ms` milliseconds,
+    /// -1 if the key has no TTL, or -2 if the key doesn't exist.
+    fn process_ttl(&mut self, k: String, unit_ms: u64) -> Result<RespValue, RespValue> {
+        if self.value(&k)?.is_none() {
+            return Ok(RespValue::Integer(-2));
+        }
+        Ok(match self.expires.get(&k) {
+            // Rounded to the nearest unit, like Redis
+            Some(&at) => RespValue::Integer(((at - self.now + unit_ms / 2) / unit_ms) as i64),
+            None => RespValue::Integer(-1),
+        })
+    }
+
+    fn process_persist(&mut self, k: String) -> Result<RespValue, RespValue> {
+        self.expire_if_needed(&k)?;
+        if self.expires.remove(&k).is_some() {
+            self.notify('g', "persist", &k);
+            Ok(RespValue::Integer(1))
+        } else {
+            Ok(RespValue::Integer(0))
+        }
+    }
+
+    fn process_sweep(&mut self) -> Result<RespValue, RespValue> {
+        // FIXME: Scans every key with a TTL, Redis samples a few instead.
+        let expired: Vec<String> = self
+            .expires
+            .iter()
+            .filter(|(_, &at)| at <= self.now)
+            .map(|(k, _)| k.clone())
+            .collect();
+        for k in expired.iter() {
+            self.expire_if_needed(k)?;
+        }
+
+        Ok(RespValue::Integer(expired.len() as i64))
+    }
+
+    /// Replies with `name:value` lines under a header for each section of
+    /// `INFO`, or only for the section asked for.
+    fn process_info(&self, section: Option<String>) -> RespValue {
+        let sections = [
+            (
+                "Keyspace",
+                vec![
+                    ("keys", self.store.kv_len() as u64),
+                    ("expires", self.expires.len() as u64),
+                ],
+            ),
+            ("Memory", vec![("used_memory", self.memory.used())]),
+            ("Queues", self.queue_stats.info()),
+        ];
+
+        let mut info = String::new();
+        for (name, fields) in sections {
+            if section
+                .as_ref()
+                .is_some_and(|s| s != "all" && *s != name.to_lowercase())
+            {
+                continue;
+            }
+            info += &format!("# {}\r\n", name);
+            for (field, value) in fields {
+                info += &format!("{}:{}\r\n", field, value);
+            }
         }
+        RespValue::BulkString(info)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn run(backend: &mut Backend<HashMap<String, Value>>, cmd: &[&str]) -> RespValue {
+        backend.process_command(Command::try_from(RespValue::array(cmd)).unwrap())
+    }
+
+    #[test]
+    fn test_binary_values() {
+        let mut backend = Backend::new(HashMap::new());
+        let value = b"\xff\r\n\x00$3\r\n".to_vec();
+        let set = RespValue::Array(vec![
+            RespValue::BulkString("SET".into()),
+            RespValue::BulkString("k".into()),
+            RespValue::BulkBytes(value.clone()),
+        ]);
+        // Through the wire and back, as the master forwards it
+        let set = RespValue::from_bytes(&set.into_bytes());
+        backend.process_command(Command::try_from(set).unwrap());
+
+        let get = run(&mut backend, &["GET", "k"]);
+        let reply = RespValue::Array(vec![RespValue::BulkBytes(value)]);
+        assert_eq!(get, reply);
+        assert_eq!(RespValue::from_bytes(&get.into_bytes()), reply);
+    }
+
+    #[test]
+    fn test_cas() {
+        let mut backend = Backend::new(HashMap::new());
+        run(&mut backend, &["SET", "k", "a", "PX", "1000", "TAGS", "t"]);
+        assert_eq!(
+            run(&mut backend, &["CAS", "k", "b", "c"]),
+            RespValue::Integer(0)
+        );
+        assert_eq!(
+            run(&mut backend, &["CAS", "k", "a", "c"]),
+            RespValue::Integer(1)
+        );
+        assert_eq!(backend.store.kv_get("k").unwrap(), Some(&"c".into()));
+        assert!(backend.expires.contains_key("k"));
+        assert_eq!(backend.tags.tags("k").count(), 1);
+        assert_eq!(
+            run(&mut backend, &["CAS", "nope", "a", "c"]),
+            RespValue::Integer(0)
+        );
+
+        run(&mut backend, &["HSET", "h", "f", "v"]);
+        assert_eq!(
+            run(&mut backend, &["CAS", "h", "a", "c"]),
+            RespValue::Error(WRONGTYPE.into())
+        );
+    }
+
+    #[test]
+    fn test_flushall() {
+        let mut backend = Backend::new(HashMap::new());
+        run(&mut backend, &["SET", "a", "1", "PX", "1000", "TAGS", "t"]);
+        run(&mut backend, &["HSET", "user:1", "name", "Ada"]);
+        run(
+            &mut backend,
+            &[
+                "FT.CREATE",
+                "users",
+                "PREFIX",
+                "1",
+                "user:",
+                "SCHEMA",
+                "name",
+                "TEXT",
+            ],
+        );
+        backend.events.clear();
+
+        let reply = run(&mut backend, &["FLUSHALL"]);
+        assert_eq!(reply, RespValue::SimpleString("OK".into()));
+        assert_eq!(backend.store.kv_len(), 0);
+        assert!(backend.expires.is_empty());
+        assert_eq!(backend.tags.tagged().count(), 0);
+        assert_eq!(backend.memory.used(), 0);
+        assert_eq!(backend.events.len(), 2);
+        assert_eq!(
+            run(&mut backend, &["FT.SEARCH", "users", "Ada"]),
+            RespValue::Array(vec![RespValue::Integer(0)])
+        );
+    }
 
-        RespValue::Integer(num_deleted)
+    #[test]
+    fn test_config_set() {
+        let mut backend = Backend::new(HashMap::new());
+        // Thresholds are global, so this sets the one it already has
+        let set = ["CONFIG", "SET", "list-max-listpack-size", "128"];
+        assert_eq!(
+            run(&mut backend, &set),
+            RespValue::SimpleString("OK".into())
+        );
+        assert_eq!(
+            run(
+                &mut backend,
+                &["CONFIG", "SET", "list-max-listpack-size", "x"]
+            ),
+            RespValue::Error("ERR Invalid list-max-listpack-size".into())
+        );
+        assert!(Command::try_from(RespValue::array(&["CONFIG", "SET", "maxmemory", "1"])).is_err());
+        // Kept with the state, so that it's restored along with it
+        assert!(backend.state(0).iter().any(|record| matches!(
+            record,
+            Record::Batch(_, cmds) if cmds.contains(&RespValue::array(&set))
+        )));
     }
 }
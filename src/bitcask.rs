@@ -0,0 +1,907 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    mem,
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+    thread::{self, JoinHandle},
+};
+
+use crc::{Crc, CRC_32_ISCSI};
+use elsa::FrozenMap;
+use tracing::{info, warn};
+
+use crate::{
+    map::{check_key, BatchOp, KvStore, Snapshot, META_PREFIX},
+    value::Value,
+};
+
+const CHECKSUM: Crc<u32> = Crc::<u32>::new(&CRC_32_ISCSI);
+/// The value length of entries recording deletions.
+const TOMBSTONE: u32 = u32::MAX;
+/// The value length of batch headers, whose key length is instead that of
+/// the entries of the batch following them.
+const BATCH: u32 = u32::MAX - 1;
+const HEADER_LEN: usize = 12;
+
+/// When the store starts a new data file and merges the old ones, in
+/// bytes unless said otherwise.
+#[derive(Debug, Clone)]
+pub struct BitcaskOptions {
+    /// The active data file is closed once it gets this big.
+    pub max_file_size: u64,
+    /// The closed data files are merged once at least this many of their
+    /// bytes are dead, and at least half of them are.
+    pub merge_min_dead: u64,
+    /// How many values read from data files are kept around.
+    pub cache_entries: usize,
+}
+
+impl Default for BitcaskOptions {
+    fn default() -> Self {
+        BitcaskOptions {
+            max_file_size: 64 << 20,
+            merge_min_dead: 16 << 20,
+            cache_entries: 1024,
+        }
+    }
+}
+
+fn corrupt(what: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what.to_string())
+}
+
+/// Where the latest value of a key is.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Location {
+    file: u64,
+    /// Of the entry, not the value.
+    offset: u64,
+    /// Of the whole entry.
+    len: u32,
+}
+
+impl Location {
+    fn value_range(&self, key: &str) -> (u64, usize) {
+        let skip = HEADER_LEN + key.len();
+        (self.offset + skip as u64, self.len as usize - skip)
+    }
+}
+
+/// A header of the checksum of the rest and two lengths, followed by
+/// the rest.
+fn checksummed(header: [u32; 2], rest: &[u8]) -> Vec<u8> {
+    let mut buf = vec![0; 4];
+    buf.extend_from_slice(&header[0].to_le_bytes());
+    buf.extend_from_slice(&header[1].to_le_bytes());
+    buf.extend_from_slice(rest);
+    let checksum = CHECKSUM.checksum(&buf[4..]);
+    buf[..4].copy_from_slice(&checksum.to_le_bytes());
+    buf
+}
+
+/// An entry of a data file: a CRC-32 of the rest, the lengths of the key
+/// and of the value, then the key and the value.
+fn encode_entry(key: &str, value: Option<&Value>) -> Vec<u8> {
+    let value = value.map(|v| serde_json::to_vec(v).unwrap());
+    let value_len = value.as_ref().map_or(TOMBSTONE, |v| v.len() as u32);
+    let mut rest = key.as_bytes().to_vec();
+    rest.extend_from_slice(value.as_deref().unwrap_or_default());
+    checksummed([key.len() as u32, value_len], &rest)
+}
+
+enum Scanned {
+    /// The key of an entry, and whether it's a deletion.
+    Entry(String, bool),
+    /// The header of a batch, whose entries follow.
+    Batch,
+}
+
+/// What's at the start of `buf`, and how long it is, a batch header
+/// counting without its entries, or `None` if it's incomplete or corrupt.
+fn scan_entry(buf: &[u8]) -> Option<(Scanned, usize)> {
+    let header = buf.get(..HEADER_LEN)?;
+    let checksum = u32::from_le_bytes(header[..4].try_into().unwrap());
+    let key_len = u32::from_le_bytes(header[4..8].try_into().unwrap()) as usize;
+    let value_len = u32::from_le_bytes(header[8..].try_into().unwrap());
+    let len = HEADER_LEN
+        + key_len
+        + match value_len {
+            TOMBSTONE | BATCH => 0,
+            len => len as usize,
+        };
+    // A batch header checksums its entries too, so that a crash in the
+    // middle of writing them drops them all
+    let entry = buf.get(..len)?;
+    if CHECKSUM.checksum(&entry[4..]) != checksum {
+        return None;
+    }
+    if value_len == BATCH {
+        return Some((Scanned::Batch, HEADER_LEN));
+    }
+    let key = String::from_utf8(entry[HEADER_LEN..HEADER_LEN + key_len].to_vec()).ok()?;
+    Some((Scanned::Entry(key, value_len == TOMBSTONE), len))
+}
+
+/// Which key directory tracks the key, as entries of metadata are kept
+/// apart from the dataset.
+fn keydir_of<'a>(
+    key: &str,
+    keydir: &'a mut HashMap<String, Location>,
+    meta: &'a mut HashMap<String, Location>,
+) -> &'a mut HashMap<String, Location> {
+    match key.starts_with(META_PREFIX) {
+        true => meta,
+        false => keydir,
+    }
+}
+
+fn read(file: &File, key: &str, location: Location) -> io::Result<Value> {
+    let (offset, len) = location.value_range(key);
+    let mut buf = vec![0; len];
+    file.read_exact_at(&mut buf, offset)?;
+    serde_json::from_slice(&buf).map_err(corrupt)
+}
+
+/// A merge of the closed data files, written out in the background while
+/// writes go on to the active one.
+struct Merge {
+    /// The data files merged.
+    old: Vec<u64>,
+    /// The ids set aside for the merged files, between the old ones and
+    /// the active one, so that what's written meanwhile wins on a restart.
+    ids: std::ops::RangeInclusive<u64>,
+    /// Every key merged, with where it was and where it is now.
+    handle: JoinHandle<io::Result<Vec<(String, Location, Location)>>>,
+}
+
+/// A log-structured hash table, as in Bitcask.
+///
+/// Every write is appended to the active data file, and the key directory
+/// in memory tells where the latest value of each key is, so that reading
+/// it takes a single seek. Once the active file is full, another one is
+/// started. Once enough of the closed files is dead, overwritten or
+/// deleted entries, the live entries are merged into new files in the
+/// background, along with hint files listing their keys, so that
+/// restarting doesn't need to read the values.
+pub struct Bitcask {
+    dir: PathBuf,
+    options: BitcaskOptions,
+    /// Data files by id, the last one being the active one.
+    files: BTreeMap<u64, File>,
+    active: File,
+    active_id: u64,
+    active_size: u64,
+    keydir: HashMap<String, Location>,
+    /// Where the entries of metadata are, which aren't part of the dataset.
+    meta: HashMap<String, Location>,
+    /// Bytes of overwritten or deleted entries, by file.
+    dead: HashMap<u64, u64>,
+    /// Values handed out to be changed in place, appended on the next call
+    /// that changes the store, as their final values aren't known until
+    /// then.
+    dirty: HashMap<String, Value>,
+    cache: FrozenMap<String, Box<Value>>,
+    merge: Option<Merge>,
+}
+
+fn data_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:06}.data", id))
+}
+
+fn hint_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:06}.hint", id))
+}
+
+impl Bitcask {
+    /// Opens the store in `dir`, creating it if needed.
+    pub fn open(dir: &Path, options: BitcaskOptions) -> io::Result<Bitcask> {
+        fs::create_dir_all(dir)?;
+        let mut ids = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            // Left by a crash in the middle of a merge
+            if path
+                .extension()
+                .is_some_and(|ext| ext == "merge" || ext == "tmp")
+            {
+                fs::remove_file(&path)?;
+            }
+            if path.extension().is_some_and(|ext| ext == "data") {
+                let id = path.file_stem().and_then(|s| s.to_str()?.parse().ok());
+                ids.push(id.ok_or_else(|| corrupt(format!("bad data file {}", path.display())))?);
+            }
+        }
+        ids.sort();
+
+        let mut keydir = HashMap::new();
+        let mut dead = HashMap::new();
+        let mut files = BTreeMap::new();
+        let mut active_size = 0;
+        for (i, &id) in ids.iter().enumerate() {
+            let size = match load_hints(dir, id, &mut keydir, &mut dead)? {
+                Some(size) => size,
+                None => load_data(dir, id, i == ids.len() - 1, &mut keydir, &mut dead)?,
+            };
+            files.insert(id, File::open(data_path(dir, id))?);
+            active_size = size;
+        }
+
+        // Merged files keep their hint files in sync by never growing
+        let active_id = match ids.last() {
+            Some(&id) if hint_path(dir, id).exists() => {
+                active_size = 0;
+                id + 1
+            }
+            Some(&id) => id,
+            None => 0,
+        };
+        let active = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(data_path(dir, active_id))?;
+        files.insert(active_id, File::open(data_path(dir, active_id))?);
+        let (meta, keydir): (HashMap<_, _>, HashMap<_, _>) = keydir
+            .into_iter()
+            .partition(|(key, _)| key.starts_with(META_PREFIX));
+        info!(
+            "opened the Bitcask store in {} with {} keys",
+            dir.display(),
+            keydir.len()
+        );
+        Ok(Bitcask {
+            dir: dir.to_path_buf(),
+            options,
+            files,
+            active,
+            active_id,
+            active_size,
+            keydir,
+            meta,
+            dead,
+            dirty: HashMap::new(),
+            cache: FrozenMap::new(),
+            merge: None,
+        })
+    }
+
+    fn read(&self, key: &str, location: Location) -> io::Result<Value> {
+        read(&self.files[&location.file], key, location)
+    }
+
+    /// Appends the entries to the active data file, behind a batch header
+    /// if there's more than one.
+    fn append(&mut self, entries: &[(&str, Option<&Value>)]) -> io::Result<()> {
+        if self.active_size >= self.options.max_file_size {
+            self.rotate(self.active_id + 1)?;
+        }
+        let encoded: Vec<Vec<u8>> = entries.iter().map(|(k, v)| encode_entry(k, *v)).collect();
+        let mut buf = Vec::new();
+        if entries.len() > 1 {
+            let entries = encoded.concat();
+            buf = checksummed([entries.len() as u32, BATCH], &entries);
+            buf.truncate(HEADER_LEN);
+            *self.dead.entry(self.active_id).or_default() += HEADER_LEN as u64;
+        }
+        let mut offset = self.active_size + buf.len() as u64;
+        for entry in encoded.iter() {
+            buf.extend_from_slice(entry);
+        }
+        self.active.write_all(&buf)?;
+        self.active_size += buf.len() as u64;
+
+        for ((key, value), entry) in entries.iter().zip(encoded) {
+            let location = Location {
+                file: self.active_id,
+                offset,
+                len: entry.len() as u32,
+            };
+            offset += entry.len() as u64;
+            let keydir = keydir_of(key, &mut self.keydir, &mut self.meta);
+            let old = match value {
+                Some(_) => keydir.insert(key.to_string(), location),
+                None => {
+                    // The deletion itself is dead as soon as it's written
+                    *self.dead.entry(self.active_id).or_default() += entry.len() as u64;
+                    keydir.remove(*key)
+                }
+            };
+            if let Some(old) = old {
+                *self.dead.entry(old.file).or_default() += old.len as u64;
+            }
+        }
+        Ok(())
+    }
+
+    /// Closes the active data file, starting the one with the given id.
+    fn rotate(&mut self, id: u64) -> io::Result<()> {
+        self.active.sync_data()?;
+        self.active_id = id;
+        self.active_size = 0;
+        let path = data_path(&self.dir, self.active_id);
+        self.active = OpenOptions::new().create(true).append(true).open(&path)?;
+        self.files.insert(self.active_id, File::open(path)?);
+        Ok(())
+    }
+
+    fn write_dirty(&mut self) -> io::Result<()> {
+        for (key, value) in mem::take(&mut self.dirty) {
+            self.append(&[(&key, Some(&value))])?;
+        }
+        Ok(())
+    }
+
+    /// Syncs the active data file, including the values changed in place.
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.write_dirty()?;
+        self.active.sync_data()
+    }
+
+    /// Prepares for a change: writes what was changed in place, and
+    /// forgets the cached value.
+    fn before_change(&mut self, key: &str) -> io::Result<()> {
+        self.write_dirty()?;
+        self.cache.as_mut().remove(key);
+        Ok(())
+    }
+
+    /// Swaps in the merge once it's done, then starts another one if
+    /// enough of the closed files is dead.
+    fn after_change(&mut self) -> io::Result<()> {
+        self.finish_merge(false)?;
+        if self.merge.is_some() {
+            return Ok(());
+        }
+        let closed = |id: &&u64| **id != self.active_id;
+        let dead: u64 = self
+            .dead
+            .iter()
+            .filter(|(id, _)| closed(id))
+            .map(|(_, d)| d)
+            .sum();
+        let total: u64 = self
+            .files
+            .iter()
+            .filter(|(id, _)| closed(id))
+            .map(|(_, file)| file.metadata().map_or(0, |m| m.len()))
+            .sum();
+        if dead >= self.options.merge_min_dead && dead * 2 >= total {
+            self.start_merge()?;
+        }
+        Ok(())
+    }
+
+    /// Starts rewriting the live entries of every data file into new ones,
+    /// with hint files, in the background. The new files come after the
+    /// old ones, so that a crash before deleting these leaves the store as
+    /// it was, and before the new active one.
+    fn start_merge(&mut self) -> io::Result<()> {
+        self.write_dirty()?;
+        let old: Vec<u64> = self.files.keys().copied().collect();
+        let size: u64 = self
+            .files
+            .values()
+            .map(|file| file.metadata().map_or(0, |m| m.len()))
+            .sum();
+        // Entries are merged as they were written, into files that are
+        // closed once full, so they can't take more files than this
+        let ids = self.active_id + 1..=self.active_id + 1 + size / self.options.max_file_size;
+        self.rotate(ids.end() + 1)?;
+
+        let files = old
+            .iter()
+            .map(|&id| Ok((id, self.files[&id].try_clone()?)))
+            .collect::<io::Result<HashMap<_, _>>>()?;
+        let mut entries: Vec<(String, Location)> = self
+            .keydir
+            .iter()
+            .chain(self.meta.iter())
+            .map(|(key, &location)| (key.clone(), location))
+            .collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        let (dir, max_file_size) = (self.dir.clone(), self.options.max_file_size);
+        let merged = ids.clone();
+        let handle =
+            thread::spawn(move || write_merged(&dir, &files, entries, merged, max_file_size));
+        self.merge = Some(Merge { old, ids, handle });
+        Ok(())
+    }
+
+    /// Swaps in the merged files once they're written, if `wait`ing then
+    /// as soon as they are, then deletes the old ones. Keys written since
+    /// the merge started stay where they are.
+    fn finish_merge(&mut self, wait: bool) -> io::Result<()> {
+        match self.merge.as_ref() {
+            Some(merge) if wait || merge.handle.is_finished() => (),
+            _ => return Ok(()),
+        }
+        let merge = self.merge.take().unwrap();
+        let moved = match merge.handle.join().unwrap() {
+            Ok(moved) => moved,
+            Err(e) => {
+                warn!("failed to merge the data files: {}", e);
+                for id in merge.ids {
+                    let _ = fs::remove_file(data_path(&self.dir, id));
+                    let _ = fs::remove_file(hint_path(&self.dir, id));
+                }
+                return Ok(());
+            }
+        };
+
+        for id in merge.ids.clone() {
+            let path = data_path(&self.dir, id);
+            if path.exists() {
+                self.files.insert(id, File::open(path)?);
+            }
+        }
+        for (key, old, new) in moved {
+            let keydir = keydir_of(&key, &mut self.keydir, &mut self.meta);
+            match keydir.get_mut(&key) {
+                Some(location) if *location == old => *location = new,
+                _ => *self.dead.entry(new.file).or_default() += new.len as u64,
+            }
+        }
+        for old in merge.old.iter() {
+            self.files.remove(old);
+            self.dead.remove(old);
+            fs::remove_file(data_path(&self.dir, *old))?;
+            let _ = fs::remove_file(hint_path(&self.dir, *old));
+        }
+        info!("merged {} data files", merge.old.len());
+        Ok(())
+    }
+}
+
+/// Writes the values of the entries, sorted by key, into data files with
+/// the given ids, with their hint files. Replies with where each entry was
+/// and where it is now.
+fn write_merged(
+    dir: &Path,
+    files: &HashMap<u64, File>,
+    entries: Vec<(String, Location)>,
+    ids: std::ops::RangeInclusive<u64>,
+    max_file_size: u64,
+) -> io::Result<Vec<(String, Location, Location)>> {
+    let mut id = *ids.start();
+    let mut moved = Vec::with_capacity(entries.len());
+    let mut data = Vec::new();
+    let mut hints = Vec::new();
+    for (key, old) in entries.iter() {
+        let value = read(&files[&old.file], key, *old)?;
+        let entry = encode_entry(key, Some(&value));
+        let location = Location {
+            file: id,
+            offset: data.len() as u64,
+            len: entry.len() as u32,
+        };
+        hints.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        hints.extend_from_slice(key.as_bytes());
+        hints.extend_from_slice(&location.offset.to_le_bytes());
+        hints.extend_from_slice(&location.len.to_le_bytes());
+        data.extend_from_slice(&entry);
+        moved.push((key.clone(), *old, location));
+        if data.len() as u64 >= max_file_size {
+            write_merged_file(dir, id, &mem::take(&mut data), &mem::take(&mut hints))?;
+            id += 1;
+            if !ids.contains(&id) {
+                return Err(corrupt("merged entries outgrew the data files"));
+            }
+        }
+    }
+    write_merged_file(dir, id, &data, &hints)?;
+    Ok(moved)
+}
+
+fn write_merged_file(dir: &Path, id: u64, data: &[u8], hints: &[u8]) -> io::Result<()> {
+    // Renamed once complete, as it isn't the last data file
+    let temp = data_path(dir, id).with_extension("merge");
+    let mut file = File::create(&temp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(temp, data_path(dir, id))?;
+
+    // Checksummed, as a corrupt hint file would point at the wrong values
+    let temp = hint_path(dir, id).with_extension("tmp");
+    let mut file = File::create(&temp)?;
+    file.write_all(hints)?;
+    file.write_all(&CHECKSUM.checksum(hints).to_le_bytes())?;
+    file.sync_all()?;
+    fs::rename(temp, hint_path(dir, id))
+}
+
+/// Loads the keys of a merged data file from its hint file, replying with
+/// the size of the data file, or `None` if there's no usable hint file.
+fn load_hints(
+    dir: &Path,
+    id: u64,
+    keydir: &mut HashMap<String, Location>,
+    dead: &mut HashMap<u64, u64>,
+) -> io::Result<Option<u64>> {
+    let Ok(buf) = fs::read(hint_path(dir, id)) else {
+        return Ok(None);
+    };
+    let Some((hints, checksum)) = buf.split_last_chunk::<4>() else {
+        return Ok(None);
+    };
+    if CHECKSUM.checksum(hints) != u32::from_le_bytes(*checksum) {
+        warn!("ignoring the corrupt hint file of data file {}", id);
+        return Ok(None);
+    }
+    let mut pos = 0;
+    while pos < hints.len() {
+        let key_len = u32::from_le_bytes(hints[pos..pos + 4].try_into().unwrap()) as usize;
+        let key = String::from_utf8(hints[pos + 4..pos + 4 + key_len].to_vec()).map_err(corrupt)?;
+        pos += 4 + key_len;
+        let offset = u64::from_le_bytes(hints[pos..pos + 8].try_into().unwrap());
+        let len = u32::from_le_bytes(hints[pos + 8..pos + 12].try_into().unwrap());
+        pos += 12;
+        let location = Location {
+            file: id,
+            offset,
+            len,
+        };
+        if let Some(old) = keydir.insert(key, location) {
+            *dead.entry(old.file).or_default() += old.len as u64;
+        }
+    }
+    Ok(Some(fs::metadata(data_path(dir, id))?.len()))
+}
+
+/// Loads the keys of a data file by reading it through, truncating an
+/// incomplete last entry of the active one, replying with its size.
+fn load_data(
+    dir: &Path,
+    id: u64,
+    active: bool,
+    keydir: &mut HashMap<String, Location>,
+    dead: &mut HashMap<u64, u64>,
+) -> io::Result<u64> {
+    let path = data_path(dir, id);
+    let buf = fs::read(&path)?;
+    let mut pos = 0;
+    while pos < buf.len() {
+        let Some((scanned, len)) = scan_entry(&buf[pos..]) else {
+            if !active {
+                return Err(corrupt(format!(
+                    "corrupt entry in {} at byte {}",
+                    path.display(),
+                    pos
+                )));
+            }
+            warn!("truncating {} at byte {}", path.display(), pos);
+            OpenOptions::new()
+                .write(true)
+                .open(&path)?
+                .set_len(pos as u64)?;
+            break;
+        };
+        let (key, deleted) = match scanned {
+            Scanned::Entry(key, deleted) => (key, deleted),
+            Scanned::Batch => {
+                *dead.entry(id).or_default() += len as u64;
+                pos += len;
+                continue;
+            }
+        };
+        let location = Location {
+            file: id,
+            offset: pos as u64,
+            len: len as u32,
+        };
+        let old = match deleted {
+            true => {
+                *dead.entry(id).or_default() += len as u64;
+                keydir.remove(&key)
+            }
+            false => keydir.insert(key, location),
+        };
+        if let Some(old) = old {
+            *dead.entry(old.file).or_default() += old.len as u64;
+        }
+        pos += len;
+    }
+    Ok(pos as u64)
+}
+
+impl Drop for Bitcask {
+    fn drop(&mut self) {
+        if let Err(e) = self.sync().and_then(|()| self.finish_merge(true)) {
+            warn!("failed to sync the Bitcask store: {}", e);
+        }
+    }
+}
+
+impl KvStore for Bitcask {
+    fn kv_get(&self, key: &str) -> io::Result<Option<&Value>> {
+        if let Some(value) = self.dirty.get(key) {
+            return Ok(Some(value));
+        }
+        let Some(&location) = self.keydir.get(key) else {
+            return Ok(None);
+        };
+        if let Some(value) = self.cache.get(key) {
+            return Ok(Some(value));
+        }
+        let value = self.read(key, location)?;
+        Ok(Some(self.cache.insert(key.to_string(), Box::new(value))))
+    }
+
+    fn kv_get_mut(&mut self, key: &str) -> io::Result<Option<&mut Value>> {
+        if !self.dirty.contains_key(key) {
+            let Some(&location) = self.keydir.get(key) else {
+                return Ok(None);
+            };
+            self.before_change(key)?;
+            let value = self.read(key, location)?;
+            self.dirty.insert(key.to_string(), value);
+        }
+        Ok(self.dirty.get_mut(key))
+    }
+
+    fn kv_put(&mut self, key: &str, value: Value) -> io::Result<()> {
+        check_key(key)?;
+        self.dirty.remove(key);
+        self.before_change(key)?;
+        self.append(&[(key, Some(&value))])?;
+        self.after_change()
+    }
+
+    fn kv_del(&mut self, key: &str) -> io::Result<bool> {
+        self.dirty.remove(key);
+        if !self.keydir.contains_key(key) {
+            return Ok(false);
+        }
+        self.before_change(key)?;
+        self.append(&[(key, None)])?;
+        self.after_change()?;
+        Ok(true)
+    }
+
+    fn kv_write_batch(&mut self, batch: Vec<BatchOp>) -> io::Result<()> {
+        for op in batch.iter() {
+            if let BatchOp::Put(key, _) = op {
+                check_key(key)?;
+            }
+        }
+        for op in batch.iter() {
+            let (BatchOp::Put(key, _) | BatchOp::Del(key)) = op;
+            self.dirty.remove(key);
+            self.before_change(key)?;
+        }
+        let entries: Vec<(&str, Option<&Value>)> = batch
+            .iter()
+            .map(|op| match op {
+                BatchOp::Put(key, value) => (key.as_str(), Some(value)),
+                BatchOp::Del(key) => (key.as_str(), None),
+            })
+            .collect();
+        self.append(&entries)?;
+        self.after_change()
+    }
+
+    fn kv_sync(&mut self) -> io::Result<()> {
+        self.sync()
+    }
+
+    fn kv_trim(&mut self) {
+        let cache = self.cache.as_mut();
+        if cache.len() > self.options.cache_entries {
+            cache.clear();
+        }
+    }
+
+    /// Reads every value without caching it.
+    fn kv_snapshot(&self) -> io::Result<Vec<(String, Value)>> {
+        self.kv_deferred_snapshot()?()
+    }
+
+    /// Copies the key directory and the values changed in place, and
+    /// duplicates the handles of the data files, which only ever get
+    /// appended to, and merges then only unlink.
+    fn kv_deferred_snapshot(&self) -> io::Result<Snapshot> {
+        let files = self
+            .files
+            .iter()
+            .map(|(&id, file)| Ok((id, file.try_clone()?)))
+            .collect::<io::Result<HashMap<u64, File>>>()?;
+        let keydir = self.keydir.clone();
+        let dirty = self.dirty.clone();
+        Ok(Box::new(move || {
+            keydir
+                .into_iter()
+                .map(|(key, location)| {
+                    let value = match dirty.get(&key) {
+                        Some(value) => value.clone(),
+                        None => read(&files[&location.file], &key, location)?,
+                    };
+                    Ok((key, value))
+                })
+                .collect()
+        }))
+    }
+
+    fn kv_put_meta(&mut self, entries: Vec<(String, Option<Value>)>) -> io::Result<()> {
+        let entries: Vec<(String, Option<Value>)> = entries
+            .into_iter()
+            .map(|(name, value)| (format!("{}{}", META_PREFIX, name), value))
+            .filter(|(key, value)| value.is_some() || self.meta.contains_key(key))
+            .collect();
+        if entries.is_empty() {
+            return Ok(());
+        }
+        self.write_dirty()?;
+        let entries: Vec<(&str, Option<&Value>)> = entries
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_ref()))
+            .collect();
+        self.append(&entries)?;
+        self.after_change()
+    }
+
+    fn kv_meta(&self) -> io::Result<Vec<(String, Value)>> {
+        self.meta
+            .iter()
+            .map(|(key, &location)| {
+                let value = self.read(key, location)?;
+                Ok((key[META_PREFIX.len()..].to_string(), value))
+            })
+            .collect()
+    }
+
+    fn kv_keys(&self) -> impl Iterator<Item = &str> {
+        self.keydir.keys().map(String::as_str)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bitcask() {
+        let dir = std::env::temp_dir().join(format!("kvkv-bitcask-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let options = BitcaskOptions {
+            max_file_size: 1024,
+            merge_min_dead: 4096,
+            cache_entries: 4,
+        };
+
+        let mut bitcask = Bitcask::open(&dir, options.clone()).unwrap();
+        for round in 0..10 {
+            for i in 0..50 {
+                bitcask
+                    .kv_put(&format!("key:{}", i), format!("{} {}", round, i).into())
+                    .unwrap();
+            }
+        }
+        for i in (0..50).step_by(5) {
+            assert!(bitcask.kv_del(&format!("key:{}", i)).unwrap());
+        }
+        if let Some(Value::Str(s)) = bitcask.kv_get_mut("key:1").unwrap() {
+            s.to_mut().extend_from_slice(b"!");
+        }
+        // Merged as they went, out of about 25 files written, once the last
+        // merge lands
+        bitcask.finish_merge(true).unwrap();
+        assert!(bitcask.files.len() < 10);
+
+        let check = |bitcask: &Bitcask| {
+            assert_eq!(bitcask.kv_keys().count(), 40);
+            assert_eq!(bitcask.kv_get("key:0").unwrap(), None);
+            assert_eq!(bitcask.kv_get("key:1").unwrap(), Some(&"9 1!".into()));
+            assert_eq!(bitcask.kv_get("key:49").unwrap(), Some(&"9 49".into()));
+        };
+        check(&bitcask);
+        drop(bitcask);
+
+        // A crash in the middle of appending
+        let last = fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .filter(|p| p.extension().is_some_and(|ext| ext == "data"))
+            .max()
+            .unwrap();
+        let mut file = OpenOptions::new().append(true).open(last).unwrap();
+        file.write_all(&[1, 2, 3, 4, 5]).unwrap();
+
+        let mut bitcask = Bitcask::open(&dir, options.clone()).unwrap();
+        check(&bitcask);
+        bitcask.start_merge().unwrap();
+        bitcask.kv_put("key:2", "merging".into()).unwrap();
+        bitcask.finish_merge(true).unwrap();
+        assert_eq!(bitcask.kv_get("key:2").unwrap(), Some(&"merging".into()));
+        bitcask.kv_put("key:2", "9 2".into()).unwrap();
+        check(&bitcask);
+        let merged = bitcask
+            .files
+            .keys()
+            .filter(|&&id| hint_path(&dir, id).exists());
+        assert_eq!(merged.count(), bitcask.files.len() - 1);
+        drop(bitcask);
+
+        // Restarted from the hint files
+        let mut bitcask = Bitcask::open(&dir, options).unwrap();
+        check(&bitcask);
+        assert!(bitcask.kv_del("key:1").unwrap());
+        assert!(!bitcask.kv_del("key:1").unwrap());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_bitcask_batch() {
+        let dir = std::env::temp_dir().join(format!("kvkv-bitcask-batch-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        crate::map::tests::conformance(&mut Bitcask::open(&dir, Default::default()).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+
+        let mut bitcask = Bitcask::open(&dir, Default::default()).unwrap();
+        bitcask.kv_put("a", "a".into()).unwrap();
+        let batch = vec![
+            BatchOp::Put("b".into(), "b".into()),
+            BatchOp::Del("a".into()),
+            BatchOp::Put("c".into(), "c".into()),
+        ];
+        bitcask.kv_write_batch(batch).unwrap();
+        drop(bitcask);
+        let bitcask = Bitcask::open(&dir, Default::default()).unwrap();
+        assert_eq!(bitcask.kv_get("a").unwrap(), None);
+        assert_eq!(bitcask.kv_len(), 2);
+        drop(bitcask);
+
+        // A crash in the middle of appending the batch
+        let data = OpenOptions::new()
+            .write(true)
+            .open(data_path(&dir, 0))
+            .unwrap();
+        data.set_len(data.metadata().unwrap().len() - 3).unwrap();
+        let bitcask = Bitcask::open(&dir, Default::default()).unwrap();
+        assert_eq!(bitcask.kv_get("a").unwrap(), Some(&"a".into()));
+        assert_eq!(bitcask.kv_get("b").unwrap(), None);
+        assert_eq!(bitcask.kv_get("c").unwrap(), None);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_bitcask_meta() {
+        let dir = std::env::temp_dir().join(format!("kvkv-bitcask-meta-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let options = BitcaskOptions {
+            max_file_size: 1024,
+            merge_min_dead: 1024,
+            cache_entries: 4,
+        };
+
+        let mut bitcask = Bitcask::open(&dir, options.clone()).unwrap();
+        for i in 0..100 {
+            bitcask
+                .kv_put(&format!("key:{}", i), "value".into())
+                .unwrap();
+        }
+        let meta = vec![("a".to_string(), Some("1".into())), ("b".into(), None)];
+        bitcask.kv_put_meta(meta).unwrap();
+        let reserved = format!("{}a", META_PREFIX);
+        assert!(bitcask.kv_put(&reserved, "x".into()).is_err());
+        assert_eq!(bitcask.kv_get(&reserved).unwrap(), None);
+
+        // Reads cache what they hand out until trimmed, snapshots don't
+        assert_eq!(bitcask.kv_snapshot().unwrap().len(), 100);
+        assert!(bitcask.cache.as_mut().is_empty());
+        assert_eq!(bitcask.kv_iter().count(), 100);
+        bitcask.kv_trim();
+        assert!(bitcask.cache.as_mut().len() <= 4);
+
+        // Merges keep the metadata
+        bitcask.start_merge().unwrap();
+        bitcask.finish_merge(true).unwrap();
+        drop(bitcask);
+        let bitcask = Bitcask::open(&dir, options).unwrap();
+        assert_eq!(bitcask.kv_len(), 100);
+        assert_eq!(bitcask.kv_meta().unwrap(), vec![("a".into(), "1".into())]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,546 @@
+use std::{borrow::Cow, vec::IntoIter};
+
+use crate::{
+    backend::Backend,
+    command::{int, strings, CommandError},
+    map::KvStore,
+    resp::RespValue,
+    value::{Value, WRONGTYPE},
+};
+
+/// Bit offsets are limited to 2^32, making bitmaps at most 512MB.
+const MAX_BIT_OFFSET: u64 = u32::MAX as u64;
+
+/// A range of a string, in bytes or in bits,
+/// counting from the end if negative.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct BitRange {
+    pub start: i64,
+    pub end: Option<i64>,
+    pub bit_unit: bool,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum BitOp {
+    And,
+    Or,
+    Xor,
+    Not,
+}
+
+/// Integer type of a `BITFIELD`, like `i8` or `u16`.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct BitType {
+    pub signed: bool,
+    pub bits: u32,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Overflow {
+    Wrap,
+    Sat,
+    Fail,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum BitFieldOp {
+    Get(BitType, u64),
+    Set(BitType, u64, i64, Overflow),
+    IncrBy(BitType, u64, i64, Overflow),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum BitCommand {
+    SetBit(String, u64, bool),
+    GetBit(String, u64),
+    BitCount(String, Option<BitRange>),
+    BitPos(String, bool, BitRange),
+    BitOp(BitOp, String, Vec<String>),
+    BitField(String, Vec<BitFieldOp>),
+}
+
+impl BitCommand {
+    pub fn keys(&self) -> Vec<&str> {
+        use BitCommand::*;
+        match self {
+            SetBit(k, ..) | GetBit(k, _) | BitCount(k, _) | BitPos(k, ..) | BitField(k, _) => {
+                vec![k.as_str()]
+            }
+            BitOp(_, dest, keys) => std::iter::once(dest)
+                .chain(keys.iter())
+                .map(|k| k.as_str())
+                .collect(),
+        }
+    }
+}
+
+fn bit_offset(s: &str) -> Result<u64, CommandError> {
+    check_offset(int(s)?)
+}
+
+fn check_offset(offset: u64) -> Result<u64, CommandError> {
+    if offset <= MAX_BIT_OFFSET {
+        Ok(offset)
+    } else {
+        Err(CommandError::InvalidCommand)
+    }
+}
+
+fn bit_unit(s: Option<&String>) -> Result<bool, CommandError> {
+    match s.map(|s| s.to_uppercase()).as_deref() {
+        None | Some("BYTE") => Ok(false),
+        Some("BIT") => Ok(true),
+        _ => Err(CommandError::InvalidCommand),
+    }
+}
+
+impl BitType {
+    fn parse(s: &str) -> Result<BitType, CommandError> {
+        let (signed, bits) = if let Some(bits) = s.strip_prefix(['i', 'I']) {
+            (true, int(bits)?)
+        } else if let Some(bits) = s.strip_prefix(['u', 'U']) {
+            (false, int(bits)?)
+        } else {
+            return Err(CommandError::InvalidCommand);
+        };
+        // Like Redis, unsigned integers are at most 63 bits so they fit in an i64
+        match (signed, bits) {
+            (true, 1..=64) | (false, 1..=63) => Ok(BitType { signed, bits }),
+            _ => Err(CommandError::InvalidCommand),
+        }
+    }
+
+    /// Parses an offset, which is multiplied by the width of
+    /// the type when prefixed with `#`.
+    fn offset(&self, s: &str) -> Result<u64, CommandError> {
+        match s.strip_prefix('#') {
+            Some(n) => check_offset(int::<u64>(n)?.saturating_mul(self.bits as u64)),
+            None => bit_offset(s),
+        }
+    }
+
+    fn min(&self) -> i128 {
+        if self.signed {
+            -(1i128 << (self.bits - 1))
+        } else {
+            0
+        }
+    }
+
+    fn max(&self) -> i128 {
+        if self.signed {
+            (1i128 << (self.bits - 1)) - 1
+        } else {
+            (1i128 << self.bits) - 1
+        }
+    }
+
+    /// Fits `value` into the type according to the overflow policy,
+    /// `None` if it overflows and the policy is to fail.
+    fn fit(&self, value: i128, overflow: Overflow) -> Option<i64> {
+        if (self.min()..=self.max()).contains(&value) {
+            return Some(value as i64);
+        }
+        match overflow {
+            Overflow::Fail => None,
+            Overflow::Sat => Some(value.clamp(self.min(), self.max()) as i64),
+            Overflow::Wrap => {
+                let wrapped = value.rem_euclid(1i128 << self.bits);
+                Some(self.decode(wrapped as u64))
+            }
+        }
+    }
+
+    /// Interprets the low `bits` bits as an integer of this type.
+    fn decode(&self, raw: u64) -> i64 {
+        if self.signed && self.bits < 64 {
+            let shift = 64 - self.bits;
+            ((raw << shift) as i64) >> shift
+        } else {
+            raw as i64
+        }
+    }
+}
+
+/// Parses the arguments of `SETBIT`, `GETBIT`, `BITCOUNT`, `BITPOS`, `BITOP` and `BITFIELD`.
+pub fn parse(verb: &str, arr: IntoIter<RespValue>) -> Result<BitCommand, CommandError> {
+    let args = strings(arr)?;
+    let cmd = match (verb, args.as_slice()) {
+        ("SETBIT", [k, offset, bit]) => {
+            let bit = match bit.as_str() {
+                "0" => false,
+                "1" => true,
+                _ => return Err(CommandError::InvalidCommand),
+            };
+            BitCommand::SetBit(k.clone(), bit_offset(offset)?, bit)
+        }
+        ("GETBIT", [k, offset]) => BitCommand::GetBit(k.clone(), bit_offset(offset)?),
+        ("BITCOUNT", [k]) => BitCommand::BitCount(k.clone(), None),
+        ("BITCOUNT", [k, start, end, unit @ ..]) if unit.len() <= 1 => {
+            let range = BitRange {
+                start: int(start)?,
+                end: Some(int(end)?),
+                bit_unit: bit_unit(unit.first())?,
+            };
+            BitCommand::BitCount(k.clone(), Some(range))
+        }
+        ("BITPOS", [k, bit, range @ ..]) if range.len() <= 3 => {
+            let bit = match bit.as_str() {
+                "0" => false,
+                "1" => true,
+                _ => return Err(CommandError::InvalidCommand),
+            };
+            let range = BitRange {
+                start: range.first().map(|s| int(s)).transpose()?.unwrap_or(0),
+                end: range.get(1).map(|s| int(s)).transpose()?,
+                bit_unit: bit_unit(range.get(2))?,
+            };
+            BitCommand::BitPos(k.clone(), bit, range)
+        }
+        ("BITOP", [op, dest, keys @ ..]) if !keys.is_empty() => {
+            let op = match op.to_uppercase().as_str() {
+                "AND" => BitOp::And,
+                "OR" => BitOp::Or,
+                "XOR" => BitOp::Xor,
+                "NOT" if keys.len() == 1 => BitOp::Not,
+                _ => return Err(CommandError::InvalidCommand),
+            };
+            BitCommand::BitOp(op, dest.clone(), keys.to_vec())
+        }
+        ("BITFIELD", [k, ops @ ..]) => BitCommand::BitField(k.clone(), bitfield_ops(ops)?),
+        _ => return Err(CommandError::InvalidCommand),
+    };
+    Ok(cmd)
+}
+
+fn bitfield_ops(args: &[String]) -> Result<Vec<BitFieldOp>, CommandError> {
+    let mut ops = Vec::new();
+    let mut overflow = Overflow::Wrap;
+    let mut args = args.iter();
+
+    while let Some(op) = args.next() {
+        let mut next = || args.next().ok_or(CommandError::InvalidCommand);
+        match op.to_uppercase().as_str() {
+            "OVERFLOW" => {
+                overflow = match next()?.to_uppercase().as_str() {
+                    "WRAP" => Overflow::Wrap,
+                    "SAT" => Overflow::Sat,
+                    "FAIL" => Overflow::Fail,
+                    _ => return Err(CommandError::InvalidCommand),
+                }
+            }
+            "GET" => {
+                let ty = BitType::parse(next()?)?;
+                ops.push(BitFieldOp::Get(ty, ty.offset(next()?)?));
+            }
+            "SET" => {
+                let ty = BitType::parse(next()?)?;
+                let offset = ty.offset(next()?)?;
+                ops.push(BitFieldOp::Set(ty, offset, int(next()?)?, overflow));
+            }
+            "INCRBY" => {
+                let ty = BitType::parse(next()?)?;
+                let offset = ty.offset(next()?)?;
+                ops.push(BitFieldOp::IncrBy(ty, offset, int(next()?)?, overflow));
+            }
+            _ => return Err(CommandError::InvalidCommand),
+        }
+    }
+
+    Ok(ops)
+}
+
+fn get_bit(bytes: &[u8], offset: u64) -> bool {
+    let byte = bytes.get((offset / 8) as usize).copied().unwrap_or(0);
+    byte & (0x80 >> (offset % 8)) != 0
+}
+
+/// Sets a bit, growing the string as needed. Returns the previous bit.
+fn set_bit(bytes: &mut Vec<u8>, offset: u64, bit: bool) -> bool {
+    let idx = (offset / 8) as usize;
+    if idx >= bytes.len() {
+        bytes.resize(idx + 1, 0);
+    }
+    let mask = 0x80 >> (offset % 8);
+    let old = bytes[idx] & mask != 0;
+    if bit {
+        bytes[idx] |= mask;
+    } else {
+        bytes[idx] &= !mask;
+    }
+    old
+}
+
+/// Reads `bits` bits starting at `offset`, most significant bit first.
+fn get_bits(bytes: &[u8], offset: u64, bits: u32) -> u64 {
+    (0..bits as u64).fold(0, |acc, i| (acc << 1) | get_bit(bytes, offset + i) as u64)
+}
+
+fn set_bits(bytes: &mut Vec<u8>, offset: u64, bits: u32, value: u64) {
+    for i in 0..bits as u64 {
+        let bit = (value >> (bits as u64 - 1 - i)) & 1 == 1;
+        set_bit(bytes, offset + i, bit);
+    }
+}
+
+/// Resolves a possibly negative `start..=end` range over `len` units,
+/// `None` if the range is empty.
+fn resolve_range(start: i64, end: i64, len: i64) -> Option<(u64, u64)> {
+    let resolve = |i: i64| if i < 0 { (len + i).max(0) } else { i };
+    let (start, end) = (resolve(start), resolve(end).min(len - 1));
+    if len == 0 || start > end {
+        None
+    } else {
+        Some((start as u64, end as u64))
+    }
+}
+
+impl<T> Backend<T>
+where
+    T: KvStore,
+{
+    fn bitmap(&self, key: &str) -> Result<Cow<'_, [u8]>, RespValue> {
+        match self.value(key)? {
+            Some(Value::Str(s)) => Ok(s.as_bytes()),
+            Some(_) => Err(RespValue::Error(WRONGTYPE.into())),
+            None => Ok(Cow::Borrowed(&[])),
+        }
+    }
+
+    /// The string at `key` for modifying its bits, created if missing.
+    fn bitmap_mut(&mut self, key: &str) -> Result<&mut Vec<u8>, RespValue> {
+        if self.value_mut(key)?.is_none() {
+            self.store.kv_put(key, Value::Str(Vec::new().into()))?;
+        }
+        match self.store.kv_get_mut(key)? {
+            Some(Value::Str(s)) => Ok(s.to_mut()),
+            _ => Err(RespValue::Error(WRONGTYPE.into())),
+        }
+    }
+
+    pub(crate) fn process_bit(&mut self, cmd: BitCommand) -> RespValue {
+        use BitCommand::*;
+        match cmd {
+            SetBit(k, offset, bit) => self.process_setbit(&k, offset, bit),
+            GetBit(k, offset) => self
+                .bitmap(&k)
+                .map(|bytes| RespValue::Integer(get_bit(&bytes, offset) as i64)),
+            BitCount(k, range) => self.process_bitcount(&k, range),
+            BitPos(k, bit, range) => self.process_bitpos(&k, bit, range),
+            BitOp(op, dest, keys) => self.process_bitop(op, &dest, &keys),
+            BitField(k, ops) => self.process_bitfield(&k, ops),
+        }
+        .unwrap_or_else(|e| e)
+    }
+
+    fn process_setbit(
+        &mut self,
+        key: &str,
+        offset: u64,
+        bit: bool,
+    ) -> Result<RespValue, RespValue> {
+        let old = set_bit(self.bitmap_mut(key)?, offset, bit);
+        self.notify('$', "setbit", key);
+        Ok(RespValue::Integer(old as i64))
+    }
+
+    fn process_bitcount(&self, key: &str, range: Option<BitRange>) -> Result<RespValue, RespValue> {
+        let bytes = self.bitmap(key)?;
+        let range = range.unwrap_or(BitRange {
+            start: 0,
+            end: Some(-1),
+            bit_unit: false,
+        });
+
+        let count = if range.bit_unit {
+            let len = bytes.len() as i64 * 8;
+            match resolve_range(range.start, range.end.unwrap(), len) {
+                Some((start, end)) => (start..=end).filter(|&i| get_bit(&bytes, i)).count(),
+                None => 0,
+            }
+        } else {
+            match resolve_range(range.start, range.end.unwrap(), bytes.len() as i64) {
+                Some((start, end)) => bytes[start as usize..=end as usize]
+                    .iter()
+                    .map(|b| b.count_ones() as usize)
+                    .sum(),
+                None => 0,
+            }
+        };
+
+        Ok(RespValue::Integer(count as i64))
+    }
+
+    fn process_bitpos(
+        &self,
+        key: &str,
+        bit: bool,
+        range: BitRange,
+    ) -> Result<RespValue, RespValue> {
+        let bytes = self.bitmap(key)?;
+        let (unit, len) = if range.bit_unit {
+            (1, bytes.len() as i64 * 8)
+        } else {
+            (8, bytes.len() as i64)
+        };
+
+        let found =
+            resolve_range(range.start, range.end.unwrap_or(-1), len).and_then(|(start, end)| {
+                (start * unit..(end + 1) * unit).find(|&i| get_bit(&bytes, i) == bit)
+            });
+
+        let pos = match found {
+            Some(pos) => RespValue::Integer(pos as i64),
+            // Looking for a clear bit without an explicit end, the string
+            // is considered padded with zeros to the right.
+            None if !bit && range.end.is_none() => match resolve_range(range.start, -1, len) {
+                Some(_) => RespValue::Integer(len * unit as i64),
+                None if len == 0 => RespValue::Integer(0),
+                None => RespValue::Integer(-1),
+            },
+            None => RespValue::Integer(-1),
+        };
+        Ok(pos)
+    }
+
+    fn process_bitop(
+        &mut self,
+        op: BitOp,
+        dest: &str,
+        keys: &[String],
+    ) -> Result<RespValue, RespValue> {
+        let sources = keys
+            .iter()
+            .map(|k| self.bitmap(k))
+            .collect::<Result<Vec<_>, _>>()?;
+        let len = sources.iter().map(|s| s.len()).max().unwrap_or(0);
+
+        let result: Vec<u8> = (0..len)
+            .map(|i| {
+                let mut bytes = sources.iter().map(|s| s.get(i).copied().unwrap_or(0));
+                let first = bytes.next().unwrap();
+                match op {
+                    BitOp::And => bytes.fold(first, |acc, b| acc & b),
+                    BitOp::Or => bytes.fold(first, |acc, b| acc | b),
+                    BitOp::Xor => bytes.fold(first, |acc, b| acc ^ b),
+                    BitOp::Not => !first,
+                }
+            })
+            .collect();
+
+        self.expire_if_needed(dest)?;
+        self.expires.remove(dest);
+        if result.is_empty() {
+            if self.store.kv_del(dest)? {
+                self.notify('g', "del", dest);
+            }
+        } else {
+            self.store.kv_put(dest, Value::Str(result.into()))?;
+            self.notify('$', "set", dest);
+        }
+
+        Ok(RespValue::Integer(len as i64))
+    }
+
+    fn process_bitfield(
+        &mut self,
+        key: &str,
+        ops: Vec<BitFieldOp>,
+    ) -> Result<RespValue, RespValue> {
+        let writes = ops.iter().any(|op| !matches!(op, BitFieldOp::Get(..)));
+        if !writes {
+            let bytes = self.bitmap(key)?;
+            let results = ops
+                .iter()
+                .map(|op| match *op {
+                    BitFieldOp::Get(ty, offset) => {
+                        RespValue::Integer(ty.decode(get_bits(&bytes, offset, ty.bits)))
+                    }
+                    _ => unreachable!(),
+                })
+                .collect();
+            return Ok(RespValue::Array(results));
+        }
+
+        let bytes = self.bitmap_mut(key)?;
+        let mut results = Vec::with_capacity(ops.len());
+        for op in ops {
+            let result = match op {
+                BitFieldOp::Get(ty, offset) => Some(ty.decode(get_bits(bytes, offset, ty.bits))),
+                BitFieldOp::Set(ty, offset, value, overflow) => {
+                    let old = ty.decode(get_bits(bytes, offset, ty.bits));
+                    ty.fit(value as i128, overflow).map(|new| {
+                        set_bits(bytes, offset, ty.bits, new as u64);
+                        old
+                    })
+                }
+                BitFieldOp::IncrBy(ty, offset, incr, overflow) => {
+                    let old = ty.decode(get_bits(bytes, offset, ty.bits));
+                    ty.fit(old as i128 + incr as i128, overflow)
+                        .inspect(|&new| {
+                            set_bits(bytes, offset, ty.bits, new as u64);
+                        })
+                }
+            };
+            results.push(result.map_or(RespValue::Null, RespValue::Integer));
+        }
+        self.notify('$', "setbit", key);
+
+        Ok(RespValue::Array(results))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bits() {
+        let mut bytes = Vec::new();
+        assert!(!set_bit(&mut bytes, 9, true));
+        assert_eq!(bytes, vec![0x00, 0x40]);
+        assert!(get_bit(&bytes, 9));
+        assert!(!get_bit(&bytes, 100));
+
+        set_bits(&mut bytes, 0, 8, 0xAB);
+        assert_eq!(get_bits(&bytes, 0, 8), 0xAB);
+        assert_eq!(get_bits(&bytes, 4, 4), 0xB);
+    }
+
+    #[test]
+    fn test_bitfield_overflow() {
+        let u8 = BitType::parse("u8").unwrap();
+        assert_eq!(u8.fit(300, Overflow::Wrap), Some(44));
+        assert_eq!(u8.fit(300, Overflow::Sat), Some(255));
+        assert_eq!(u8.fit(-1, Overflow::Sat), Some(0));
+        assert_eq!(u8.fit(300, Overflow::Fail), None);
+
+        let i8 = BitType::parse("i8").unwrap();
+        assert_eq!(i8.fit(128, Overflow::Wrap), Some(-128));
+        assert_eq!(i8.fit(-200, Overflow::Sat), Some(-128));
+        assert_eq!(i8.decode(0xFF), -1);
+
+        assert!(BitType::parse("u64").is_err());
+        assert_eq!(i8.offset("#2"), Ok(16));
+    }
+
+    #[test]
+    fn test_get_bitmap() {
+        let mut backend = Backend::new(std::collections::HashMap::new());
+        let mut run = |cmd: &[&str]| {
+            backend
+                .process_command(crate::command::Command::try_from(RespValue::array(cmd)).unwrap())
+        };
+        run(&["SETBIT", "b", "0", "1"]);
+        run(&["SETBIT", "b", "15", "1"]);
+        // Not UTF-8, and read back byte for byte
+        assert_eq!(
+            run(&["GET", "b"]),
+            RespValue::Array(vec![RespValue::BulkBytes(vec![0x80, 0x01])])
+        );
+        assert_eq!(
+            RespValue::BulkBytes(vec![0x80, 0x01]).into_bytes(),
+            b"$2\r\n\x80\x01\r\n"
+        );
+    }
+}
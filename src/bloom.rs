@@ -0,0 +1,340 @@
+use std::{f64::consts::LN_2, vec::IntoIter};
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{
+    backend::Backend,
+    command::{int, strings, CommandError},
+    hll::murmurhash64a,
+    map::KvStore,
+    resp::RespValue,
+    value::{Value, WRONGTYPE},
+};
+
+/// Filters created by `BF.ADD` and `BF.MADD`, as in RedisBloom.
+const DEFAULT_ERROR_RATE: f64 = 0.01;
+const DEFAULT_CAPACITY: u64 = 100;
+const DEFAULT_EXPANSION: u32 = 2;
+
+/// Each filter added when scaling up gets this times the error rate of the
+/// previous one, so the overall error rate stays within the one asked for.
+const TIGHTENING_RATIO: f64 = 0.5;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct Layer {
+    bits: Vec<u8>,
+    hashes: u32,
+    capacity: u64,
+    items: u64,
+}
+
+impl Layer {
+    fn new(capacity: u64, error_rate: f64) -> Layer {
+        let bits_per_item = -error_rate.ln() / (LN_2 * LN_2);
+        let num_bits = (capacity as f64 * bits_per_item).ceil() as usize;
+        Layer {
+            bits: vec![0; num_bits.div_ceil(8).max(1)],
+            hashes: (LN_2 * bits_per_item).ceil() as u32,
+            capacity,
+            items: 0,
+        }
+    }
+
+    /// Bits of the item, using double hashing.
+    fn positions(&self, (h1, h2): (u64, u64)) -> impl Iterator<Item = usize> {
+        let num_bits = self.bits.len() as u64 * 8;
+        (0..self.hashes as u64)
+            .map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % num_bits) as usize)
+    }
+
+    fn contains(&self, hash: (u64, u64)) -> bool {
+        self.positions(hash)
+            .all(|i| self.bits[i / 8] & (1 << (i % 8)) != 0)
+    }
+
+    fn insert(&mut self, hash: (u64, u64)) {
+        for i in self.positions(hash).collect::<Vec<_>>() {
+            self.bits[i / 8] |= 1 << (i % 8);
+        }
+        self.items += 1;
+    }
+}
+
+fn hash(item: &[u8]) -> (u64, u64) {
+    let h1 = murmurhash64a(item, 0xc6a4a7935bd1e995);
+    (h1, murmurhash64a(item, h1))
+}
+
+/// Tells whether an item was added before, with no false negatives and
+/// false positives at the configured error rate.
+///
+/// Once the items added reach its capacity, a bigger filter is stacked on
+/// top, unless the filter was made non-scaling.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BloomFilter {
+    layers: Vec<Layer>,
+    error_rate: f64,
+    /// How many times bigger each stacked filter is, 0 if non-scaling.
+    expansion: u32,
+}
+
+impl BloomFilter {
+    pub fn new(error_rate: f64, capacity: u64, expansion: u32) -> BloomFilter {
+        BloomFilter {
+            layers: vec![Layer::new(capacity, error_rate)],
+            error_rate,
+            expansion,
+        }
+    }
+
+    pub fn contains(&self, item: &[u8]) -> bool {
+        let hash = hash(item);
+        self.layers.iter().any(|layer| layer.contains(hash))
+    }
+
+    /// Adds an item, returning false if it may have been added before.
+    pub fn add(&mut self, item: &[u8]) -> Result<bool, RespValue> {
+        let hash = hash(item);
+        if self.layers.iter().any(|layer| layer.contains(hash)) {
+            return Ok(false);
+        }
+
+        let last = self.layers.last().unwrap();
+        if last.items >= last.capacity {
+            if self.expansion == 0 {
+                return Err(RespValue::Error("ERR non scaling filter is full".into()));
+            }
+            let capacity = last.capacity * self.expansion as u64;
+            let error_rate = self.error_rate * TIGHTENING_RATIO.powi(self.layers.len() as i32);
+            self.layers.push(Layer::new(capacity, error_rate));
+        }
+        self.layers.last_mut().unwrap().insert(hash);
+        Ok(true)
+    }
+
+    pub fn capacity(&self) -> u64 {
+        self.layers.iter().map(|layer| layer.capacity).sum()
+    }
+
+    /// Bytes taken by the bits of the filters.
+    pub fn size(&self) -> usize {
+        self.layers.iter().map(|layer| layer.bits.len()).sum()
+    }
+
+    pub fn items(&self) -> u64 {
+        self.layers.iter().map(|layer| layer.items).sum()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum BloomCommand {
+    Reserve {
+        key: String,
+        error_rate: f64,
+        capacity: u64,
+        expansion: u32,
+    },
+    Add(String, String),
+    MAdd(String, Vec<String>),
+    Exists(String, String),
+    MExists(String, Vec<String>),
+    Info(String),
+}
+
+impl BloomCommand {
+    pub fn keys(&self) -> Vec<&str> {
+        use BloomCommand::*;
+        match self {
+            Reserve { key, .. } | Add(key, _) | MAdd(key, _) => vec![key.as_str()],
+            Exists(key, _) | MExists(key, _) | Info(key) => vec![key.as_str()],
+        }
+    }
+}
+
+/// Parses the arguments of the `BF.*` commands.
+pub fn parse(verb: &str, arr: IntoIter<RespValue>) -> Result<BloomCommand, CommandError> {
+    let args = strings(arr)?;
+    let cmd = match (verb, args.as_slice()) {
+        ("BF.RESERVE", [key, error_rate, capacity, options @ ..]) => {
+            let error_rate: f64 = int(error_rate)?;
+            let capacity: u64 = int(capacity)?;
+            if !(error_rate > 0.0 && error_rate < 1.0) || capacity == 0 {
+                return Err(CommandError::InvalidCommand);
+            }
+            let expansion = match options {
+                [] => DEFAULT_EXPANSION,
+                [flag] if flag.eq_ignore_ascii_case("NONSCALING") => 0,
+                [flag, n] if flag.eq_ignore_ascii_case("EXPANSION") => match int(n)? {
+                    0 => return Err(CommandError::InvalidCommand),
+                    n => n,
+                },
+                _ => return Err(CommandError::InvalidCommand),
+            };
+            BloomCommand::Reserve {
+                key: key.clone(),
+                error_rate,
+                capacity,
+                expansion,
+            }
+        }
+        ("BF.ADD", [key, item]) => BloomCommand::Add(key.clone(), item.clone()),
+        ("BF.MADD", [key, items @ ..]) if !items.is_empty() => {
+            BloomCommand::MAdd(key.clone(), items.to_vec())
+        }
+        ("BF.EXISTS", [key, item]) => BloomCommand::Exists(key.clone(), item.clone()),
+        ("BF.MEXISTS", [key, items @ ..]) if !items.is_empty() => {
+            BloomCommand::MExists(key.clone(), items.to_vec())
+        }
+        ("BF.INFO", [key]) => BloomCommand::Info(key.clone()),
+        _ => return Err(CommandError::InvalidCommand),
+    };
+    Ok(cmd)
+}
+
+impl<T> Backend<T>
+where
+    T: KvStore,
+{
+    fn bloom(&self, key: &str) -> Result<Option<&BloomFilter>, RespValue> {
+        match self.value(key)? {
+            Some(Value::Bloom(filter)) => Ok(Some(filter)),
+            Some(_) => Err(RespValue::Error(WRONGTYPE.into())),
+            None => Ok(None),
+        }
+    }
+
+    /// The filter at `key` for adding to it, created with the defaults if missing.
+    fn bloom_mut(&mut self, key: &str) -> Result<&mut BloomFilter, RespValue> {
+        if self.value_mut(key)?.is_none() {
+            let filter = BloomFilter::new(DEFAULT_ERROR_RATE, DEFAULT_CAPACITY, DEFAULT_EXPANSION);
+            self.store.kv_put(key, Value::Bloom(filter))?;
+        }
+        match self.store.kv_get_mut(key)? {
+            Some(Value::Bloom(filter)) => Ok(filter),
+            _ => Err(RespValue::Error(WRONGTYPE.into())),
+        }
+    }
+
+    pub(crate) fn process_bloom(&mut self, cmd: BloomCommand) -> RespValue {
+        use BloomCommand::*;
+        match cmd {
+            Reserve {
+                key,
+                error_rate,
+                capacity,
+                expansion,
+            } => self.process_bf_reserve(&key, error_rate, capacity, expansion),
+            Add(key, item) => self
+                .process_bf_add(&key, &[item])
+                .map(|mut added| added.remove(0)),
+            MAdd(key, items) => self.process_bf_add(&key, &items).map(RespValue::Array),
+            Exists(key, item) => self
+                .process_bf_exists(&key, &[item])
+                .map(|mut found| found.remove(0)),
+            MExists(key, items) => self.process_bf_exists(&key, &items).map(RespValue::Array),
+            Info(key) => self.process_bf_info(&key),
+        }
+        .unwrap_or_else(|e| e)
+    }
+
+    fn process_bf_reserve(
+        &mut self,
+        key: &str,
+        error_rate: f64,
+        capacity: u64,
+        expansion: u32,
+    ) -> Result<RespValue, RespValue> {
+        if self.value_mut(key)?.is_some() {
+            return Err(RespValue::Error("ERR item exists".into()));
+        }
+        let filter = BloomFilter::new(error_rate, capacity, expansion);
+        self.store.kv_put(key, Value::Bloom(filter))?;
+        self.notify('g', "bf.reserve", key);
+        Ok(RespValue::SimpleString("OK".into()))
+    }
+
+    fn process_bf_add(&mut self, key: &str, items: &[String]) -> Result<Vec<RespValue>, RespValue> {
+        let filter = self.bloom_mut(key)?;
+        let added: Vec<RespValue> = items
+            .iter()
+            .map(|item| match filter.add(item.as_bytes()) {
+                Ok(added) => RespValue::Integer(added as i64),
+                Err(e) => e,
+            })
+            .collect();
+        if added.contains(&RespValue::Integer(1)) {
+            self.notify('g', "bf.add", key);
+        }
+        Ok(added)
+    }
+
+    fn process_bf_exists(&self, key: &str, items: &[String]) -> Result<Vec<RespValue>, RespValue> {
+        let filter = self.bloom(key)?;
+        Ok(items
+            .iter()
+            .map(|item| {
+                let found = filter.is_some_and(|f| f.contains(item.as_bytes()));
+                RespValue::Integer(found as i64)
+            })
+            .collect())
+    }
+
+    fn process_bf_info(&self, key: &str) -> Result<RespValue, RespValue> {
+        let filter = self
+            .bloom(key)?
+            .ok_or_else(|| RespValue::Error("ERR not found".into()))?;
+        let expansion = match filter.expansion {
+            0 => RespValue::Null,
+            n => RespValue::Integer(n as i64),
+        };
+        Ok(RespValue::Array(vec![
+            RespValue::SimpleString("Capacity".into()),
+            RespValue::Integer(filter.capacity() as i64),
+            RespValue::SimpleString("Size".into()),
+            RespValue::Integer(filter.size() as i64),
+            RespValue::SimpleString("Number of filters".into()),
+            RespValue::Integer(filter.layers.len() as i64),
+            RespValue::SimpleString("Number of items inserted".into()),
+            RespValue::Integer(filter.items() as i64),
+            RespValue::SimpleString("Expansion rate".into()),
+            expansion,
+        ]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bloom_error_rate() {
+        let mut filter = BloomFilter::new(0.01, 10_000, 0);
+        for i in 0..10_000 {
+            filter.add(format!("seen:{}", i).as_bytes()).unwrap();
+        }
+        assert!((0..10_000).all(|i| filter.contains(format!("seen:{}", i).as_bytes())));
+
+        let false_positives = (0..10_000)
+            .filter(|i| filter.contains(format!("unseen:{}", i).as_bytes()))
+            .count();
+        assert!(false_positives < 150, "{} false positives", false_positives);
+
+        // Items that looked added before don't count towards the capacity,
+        // but the filter fills up soon after, and isn't allowed to scale.
+        assert!((0..1000).any(|i| filter.add(format!("more:{}", i).as_bytes()).is_err()));
+        assert_eq!(filter.items(), 10_000);
+    }
+
+    #[test]
+    fn test_bloom_scaling() {
+        let mut filter = BloomFilter::new(0.01, 100, 2);
+        for i in 0..1000 {
+            filter.add(format!("seen:{}", i).as_bytes()).unwrap();
+        }
+        // 100 + 200 + 400 + 800
+        assert_eq!(filter.layers.len(), 4);
+        assert_eq!(filter.capacity(), 1500);
+        assert!((0..1000).all(|i| filter.contains(format!("seen:{}", i).as_bytes())));
+        assert_eq!(filter.add(b"seen:42"), Ok(false));
+    }
+}
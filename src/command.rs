@@ -1,64 +1,384 @@
 use std::vec::IntoIter;
 
-use crate::resp::*;
+use crate::{
+    bitmap::{self, BitCommand},
+    bloom::{self, BloomCommand},
+    cuckoo::{self, CuckooCommand},
+    encoding,
+    geo::{self, GeoCommand},
+    hash::{self, HashCommand},
+    hll::{self, HllCommand},
+    json::{self, JsonCommand},
+    lock::{self, LockCommand},
+    memory::{self, MemoryCommand},
+    queue::{self, QueueCommand},
+    range::{self, RangeCommand},
+    resp::*,
+    search::{self, SearchCommand},
+    tags::{self, TagCommand},
+    throttle::{self, ThrottleCommand},
+    vector::{self, VectorCommand},
+    zset::{self, ZSetCommand},
+};
+
+/// When a key expires.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Expiry {
+    /// Milliseconds after the command is applied
+    In(u64),
+    /// Unix time in milliseconds
+    At(u64),
+}
+
+impl Expiry {
+    /// The deadline in unix milliseconds, if applied at `now`.
+    pub fn deadline(self, now: u64) -> u64 {
+        match self {
+            Expiry::In(ms) => now.saturating_add(ms),
+            Expiry::At(at) => at,
+        }
+    }
+}
 
 #[derive(Debug, PartialEq)]
 pub enum Command {
-    Set(String, String),
+    Set(String, Vec<u8>),
+    /// `SET key value EX|PX|EXAT|PXAT t`, `SETEX` and `PSETEX`
+    SetEx(String, Vec<u8>, Expiry),
+    /// `SET key value [EX seconds|PX ms|EXAT s|PXAT ms] TAGS tag [tag ...]`,
+    /// for invalidating the key along with the others carrying a tag.
+    SetTagged(String, Vec<u8>, Option<Expiry>, Vec<String>),
     Get(String),
+    /// `CAS key expected new`, sets the string only if it's still the
+    /// expected one.
+    Cas(String, Vec<u8>, Vec<u8>),
     Del(Vec<String>), // TODO: Try to use SmallVec
+    /// `FLUSHALL`, deletes every key.
+    FlushAll,
+    /// `CONFIG SET name value` of an encoding threshold.
+    ConfigSet(String, String),
+    /// `EXPIRE`, `PEXPIRE`, `EXPIREAT` and `PEXPIREAT`
+    Expire(String, Expiry),
+    Ttl(String),
+    PTtl(String),
+    Persist(String),
+    /// Deletes every key whose TTL ran out, the master sends it periodically.
+    Sweep,
+    /// `EVICT key [key ...]`, deletes keys to make room, the master sends
+    /// it once `maxmemory` is reached.
+    Evict(Vec<String>),
+    /// `INFO [section]`
+    Info(Option<String>),
+    Bit(BitCommand),
+    Hll(HllCommand),
+    Bloom(BloomCommand),
+    Cuckoo(CuckooCommand),
+    ZSet(ZSetCommand),
+    Geo(GeoCommand),
+    Json(JsonCommand),
+    Vector(VectorCommand),
+    Hash(HashCommand),
+    Search(SearchCommand),
+    Throttle(ThrottleCommand),
+    Lock(LockCommand),
+    Queue(QueueCommand),
+    Tag(TagCommand),
+    Range(RangeCommand),
+    Memory(MemoryCommand),
+    /// `EVAL script numkeys [key ...] [arg ...]`
+    Eval(String, Vec<String>, Vec<String>),
+    /// `EVALSHA sha1 numkeys [key ...] [arg ...]`, the master
+    /// rewrites it into an `Eval` before it reaches any replica.
+    EvalSha(String, Vec<String>, Vec<String>),
+}
+
+impl Command {
+    /// Keys the command reads or writes.
+    pub fn keys(&self) -> Vec<&str> {
+        use Command::*;
+        match self {
+            Get(k)
+            | Cas(k, ..)
+            | Set(k, _)
+            | SetEx(k, ..)
+            | SetTagged(k, ..)
+            | Expire(k, _)
+            | Ttl(k)
+            | PTtl(k)
+            | Persist(k) => {
+                vec![k.as_str()]
+            }
+            // Every key it deletes raises an event
+            FlushAll | Sweep | Info(_) | ConfigSet(..) => vec![],
+            Bit(cmd) => cmd.keys(),
+            Hll(cmd) => cmd.keys(),
+            Bloom(cmd) => cmd.keys(),
+            Cuckoo(cmd) => cmd.keys(),
+            ZSet(cmd) => cmd.keys(),
+            Geo(cmd) => cmd.keys(),
+            Json(cmd) => cmd.keys(),
+            Vector(cmd) => cmd.keys(),
+            Hash(cmd) => cmd.keys(),
+            Search(cmd) => cmd.keys(),
+            Throttle(cmd) => cmd.keys(),
+            Lock(cmd) => cmd.keys(),
+            Queue(cmd) => cmd.keys(),
+            Tag(cmd) => cmd.keys(),
+            Range(cmd) => cmd.keys(),
+            Memory(cmd) => cmd.keys(),
+            Del(keys) | Evict(keys) | Eval(_, keys, _) | EvalSha(_, keys, _) => {
+                keys.iter().map(|k| k.as_str()).collect()
+            }
+        }
+    }
 }
 
 #[derive(Debug, PartialEq)]
 pub enum CommandError {
+    /// Wrong arguments to a known command.
     InvalidCommand,
+    UnknownCommand,
 }
 
-fn get_command(mut arr: IntoIter<RespValue>) -> Result<Command, CommandError> {
-    if let RespValue::BulkString(k) = arr.next().unwrap() {
-        return Ok(Command::Get(k));
+impl CommandError {
+    /// What to reply to the command, named `verb`, that failed to parse.
+    pub fn reply(&self, verb: &str) -> RespValue {
+        RespValue::Error(match self {
+            _ if verb.is_empty() => "ERR empty command".into(),
+            CommandError::InvalidCommand => format!(
+                "ERR wrong number of arguments for '{}' command",
+                verb.to_lowercase()
+            ),
+            CommandError::UnknownCommand => format!("ERR unknown command '{}'", verb),
+        })
     }
+}
 
-    Err(CommandError::InvalidCommand)
+fn get_command(arr: IntoIter<RespValue>) -> Result<Command, CommandError> {
+    args(arr).map(|[k]| Command::Get(k))
 }
 
 fn set_command(mut arr: IntoIter<RespValue>) -> Result<Command, CommandError> {
-    if let RespValue::BulkString(k) = arr.next().unwrap() {
-        if let RespValue::BulkString(v) = arr.next().unwrap() {
-            return Ok(Command::Set(k, v));
+    if let Some(RespValue::BulkString(k)) = arr.next() {
+        if let Some(Ok(v)) = arr.next().map(bytes) {
+            let options = strings(arr)?;
+            let (options, tags) = match options
+                .iter()
+                .position(|option| option.eq_ignore_ascii_case("TAGS"))
+            {
+                Some(i) if i + 1 < options.len() => (&options[..i], Some(&options[i + 1..])),
+                Some(_) => return Err(CommandError::InvalidCommand),
+                None => (options.as_slice(), None),
+            };
+            let expiry = match options {
+                [] => None,
+                [unit, t] => Some(expiry(unit, t)?),
+                _ => return Err(CommandError::InvalidCommand),
+            };
+            return match (expiry, tags) {
+                (None, None) => Ok(Command::Set(k, v)),
+                (Some(expiry), None) => Ok(Command::SetEx(k, v, expiry)),
+                (expiry, Some(tags)) => Ok(Command::SetTagged(k, v, expiry, tags.to_vec())),
+            };
         }
     }
 
     Err(CommandError::InvalidCommand)
 }
 
+/// Parses a TTL given in `unit`, which is one of the `SET` options
+/// `EX`, `PX`, `EXAT` or `PXAT`.
+fn expiry(unit: &str, t: &str) -> Result<Expiry, CommandError> {
+    let t: u64 = t.parse().map_err(|_| CommandError::InvalidCommand)?;
+    match unit.to_uppercase().as_str() {
+        "EX" => Ok(Expiry::In(t.saturating_mul(1000))),
+        "PX" => Ok(Expiry::In(t)),
+        "EXAT" => Ok(Expiry::At(t.saturating_mul(1000))),
+        "PXAT" => Ok(Expiry::At(t)),
+        _ => Err(CommandError::InvalidCommand),
+    }
+}
+
+/// Exactly `N` arguments.
+fn args<const N: usize>(arr: IntoIter<RespValue>) -> Result<[String; N], CommandError> {
+    strings(arr)?
+        .try_into()
+        .map_err(|_| CommandError::InvalidCommand)
+}
+
 fn del_command(arr: IntoIter<RespValue>) -> Result<Command, CommandError> {
-    Ok(Command::Del(
-        arr.map(|v| match v {
-            RespValue::BulkString(k) => k,
-            _ => panic!("Oh no"),
-        })
-        .collect(),
-    ))
+    match strings(arr)? {
+        keys if keys.is_empty() => Err(CommandError::InvalidCommand),
+        keys => Ok(Command::Del(keys)),
+    }
+}
+
+/// A numeric argument.
+pub(crate) fn int<N: std::str::FromStr>(s: &str) -> Result<N, CommandError> {
+    s.parse().map_err(|_| CommandError::InvalidCommand)
+}
+
+/// A value that may not be UTF-8, from a `BulkString` or `BulkBytes`.
+pub(crate) fn bytes(v: RespValue) -> Result<Vec<u8>, CommandError> {
+    match v {
+        RespValue::BulkString(s) => Ok(s.into_bytes()),
+        RespValue::BulkBytes(b) => Ok(b),
+        _ => Err(CommandError::InvalidCommand),
+    }
+}
+
+/// Collects the remaining arguments, which must all be `BulkString`s.
+pub(crate) fn strings(arr: IntoIter<RespValue>) -> Result<Vec<String>, CommandError> {
+    arr.map(|v| match v {
+        RespValue::BulkString(s) => Ok(s),
+        _ => Err(CommandError::InvalidCommand),
+    })
+    .collect()
+}
+
+/// Parses `<script|sha1> numkeys [key ...] [arg ...]`.
+fn script_args(
+    arr: IntoIter<RespValue>,
+) -> Result<(String, Vec<String>, Vec<String>), CommandError> {
+    let mut args = strings(arr)?.into_iter();
+    let script = args.next().ok_or(CommandError::InvalidCommand)?;
+    let numkeys: usize = args
+        .next()
+        .and_then(|n| n.parse().ok())
+        .ok_or(CommandError::InvalidCommand)?;
+
+    let mut args: Vec<String> = args.collect();
+    if numkeys > args.len() {
+        return Err(CommandError::InvalidCommand);
+    }
+    let argv = args.split_off(numkeys);
+
+    Ok((script, args, argv))
 }
 
 impl TryFrom<RespValue> for Command {
     type Error = CommandError;
     fn try_from(value: RespValue) -> Result<Self, Self::Error> {
-        match value {
-            RespValue::Array(arr) => {
-                let mut arr = arr.into_iter();
-                if let RespValue::BulkString(verb) = arr.next().unwrap() {
-                    match verb.as_str() {
-                        "GET" => return get_command(arr),
-                        "SET" => return set_command(arr),
-                        "DEL" => return del_command(arr),
-                        _ => (),
-                    };
-                }
+        if let RespValue::Array(arr) = value {
+            let mut arr = arr.into_iter();
+            if let Some(RespValue::BulkString(verb)) = arr.next() {
+                match verb.as_str() {
+                    "GET" => return get_command(arr),
+                    "SET" => return set_command(arr),
+                    "CAS" => {
+                        return match (arr.next(), arr.next(), arr.next(), arr.next()) {
+                            (Some(RespValue::BulkString(k)), Some(old), Some(new), None) => {
+                                Ok(Command::Cas(k, bytes(old)?, bytes(new)?))
+                            }
+                            _ => Err(CommandError::InvalidCommand),
+                        }
+                    }
+                    "DEL" => return del_command(arr),
+                    "FLUSHALL" => return args(arr).map(|[]| Command::FlushAll),
+                    "CONFIG" => {
+                        return match args(arr)? {
+                            [set, name, value]
+                                if set.eq_ignore_ascii_case("SET")
+                                    && encoding::is_threshold(&name) =>
+                            {
+                                Ok(Command::ConfigSet(name, value))
+                            }
+                            _ => Err(CommandError::InvalidCommand),
+                        }
+                    }
+                    "SETEX" | "PSETEX" => {
+                        let (k, t, v) = match (arr.next(), arr.next(), arr.next(), arr.next()) {
+                            (
+                                Some(RespValue::BulkString(k)),
+                                Some(RespValue::BulkString(t)),
+                                Some(v),
+                                None,
+                            ) => (k, t, bytes(v)?),
+                            _ => return Err(CommandError::InvalidCommand),
+                        };
+                        let unit = if verb == "SETEX" { "EX" } else { "PX" };
+                        return Ok(Command::SetEx(k, v, expiry(unit, &t)?));
+                    }
+                    "EXPIRE" | "PEXPIRE" | "EXPIREAT" | "PEXPIREAT" => {
+                        let [k, t] = args(arr)?;
+                        let unit = match verb.as_str() {
+                            "EXPIRE" => "EX",
+                            "PEXPIRE" => "PX",
+                            "EXPIREAT" => "EXAT",
+                            _ => "PXAT",
+                        };
+                        return Ok(Command::Expire(k, expiry(unit, &t)?));
+                    }
+                    "TTL" => return args(arr).map(|[k]| Command::Ttl(k)),
+                    "PTTL" => return args(arr).map(|[k]| Command::PTtl(k)),
+                    "PERSIST" => return args(arr).map(|[k]| Command::Persist(k)),
+                    "SWEEP" => return args(arr).map(|[]| Command::Sweep),
+                    "EVICT" => {
+                        return match strings(arr)? {
+                            keys if keys.is_empty() => Err(CommandError::InvalidCommand),
+                            keys => Ok(Command::Evict(keys)),
+                        }
+                    }
+                    "INFO" => {
+                        return match strings(arr)?.as_slice() {
+                            [] => Ok(Command::Info(None)),
+                            [section] => Ok(Command::Info(Some(section.to_lowercase()))),
+                            _ => Err(CommandError::InvalidCommand),
+                        }
+                    }
+                    "SETBIT" | "GETBIT" | "BITCOUNT" | "BITPOS" | "BITOP" | "BITFIELD" => {
+                        return bitmap::parse(&verb, arr).map(Command::Bit)
+                    }
+                    "PFADD" | "PFCOUNT" | "PFMERGE" => {
+                        return hll::parse(&verb, arr).map(Command::Hll)
+                    }
+                    "ZREM" | "ZCARD" | "ZSCORE" => {
+                        return zset::parse(&verb, arr).map(Command::ZSet)
+                    }
+                    "GEOADD" | "GEOPOS" | "GEODIST" | "GEOHASH" | "GEOSEARCH"
+                    | "GEOSEARCHSTORE" => return geo::parse(&verb, arr).map(Command::Geo),
+                    "VADD" | "VREM" | "VSIM" | "VCARD" | "VDIM" | "VEMB" | "VGETATTR"
+                    | "VSETATTR" => return vector::parse(&verb, arr).map(Command::Vector),
+                    "HSET" | "HMSET" | "HGET" | "HMGET" | "HDEL" | "HGETALL" | "HKEYS"
+                    | "HVALS" | "HLEN" | "HEXISTS" | "HINCRBY" => {
+                        return hash::parse(&verb, arr).map(Command::Hash)
+                    }
+                    "SCANRANGE" | "DELPREFIX" => {
+                        return range::parse(&verb, arr).map(Command::Range)
+                    }
+                    "MEMORY" | "OBJECT" => return memory::parse(&verb, arr).map(Command::Memory),
+                    "CL.THROTTLE" => return throttle::parse(&verb, arr).map(Command::Throttle),
+                    v if v.starts_with("JSON.") => {
+                        return json::parse(&verb, arr).map(Command::Json)
+                    }
+                    v if v.starts_with("BF.") => {
+                        return bloom::parse(&verb, arr).map(Command::Bloom)
+                    }
+                    v if v.starts_with("CF.") => {
+                        return cuckoo::parse(&verb, arr).map(Command::Cuckoo)
+                    }
+                    v if v.starts_with("FT.") => {
+                        return search::parse(&verb, arr).map(Command::Search)
+                    }
+                    v if v.starts_with("LOCK.") => {
+                        return lock::parse(&verb, arr).map(Command::Lock)
+                    }
+                    v if v.starts_with("TAG.") => return tags::parse(&verb, arr).map(Command::Tag),
+                    v if v.starts_with("Q.") => {
+                        return queue::parse(&verb, arr).map(Command::Queue)
+                    }
+                    "EVAL" => {
+                        let (script, keys, args) = script_args(arr)?;
+                        return Ok(Command::Eval(script, keys, args));
+                    }
+                    "EVALSHA" => {
+                        let (sha, keys, args) = script_args(arr)?;
+                        return Ok(Command::EvalSha(sha, keys, args));
+                    }
+                    _ => return Err(CommandError::UnknownCommand),
+                };
             }
-            _ => (),
-        };
+        }
         Err(CommandError::InvalidCommand)
     }
 }
@@ -91,4 +411,77 @@ mod tests {
             Command::Del(vec!["CS".into(), "Sadness".into(), "Sorrow".into()])
         );
     }
+
+    #[test]
+    fn parse_expire_commands() {
+        let v = RespValue::array(&["SET", "CS", "Cloud", "EX", "10"]);
+        let cmd = Command::try_from(v).unwrap();
+        assert_eq!(
+            cmd,
+            Command::SetEx("CS".into(), "Cloud".into(), Expiry::In(10_000))
+        );
+
+        let v = RespValue::array(&["PEXPIREAT", "CS", "1650000000000"]);
+        let cmd = Command::try_from(v).unwrap();
+        assert_eq!(
+            cmd,
+            Command::Expire("CS".into(), Expiry::At(1_650_000_000_000))
+        );
+
+        let v = RespValue::array(&["SET", "CS", "Cloud", "EX"]);
+        assert_eq!(Command::try_from(v), Err(CommandError::InvalidCommand));
+    }
+
+    #[test]
+    fn parse_malformed_commands() {
+        for cmd in [&["GET"][..], &["GET", "a", "b"], &["SET", "k"], &["DEL"]] {
+            let v = RespValue::array(cmd);
+            assert_eq!(Command::try_from(v), Err(CommandError::InvalidCommand));
+        }
+        let v = RespValue::array(&["NOPE"]);
+        assert_eq!(Command::try_from(v), Err(CommandError::UnknownCommand));
+        assert_eq!(
+            Command::try_from(RespValue::Array(vec![])),
+            Err(CommandError::InvalidCommand)
+        );
+        assert_eq!(
+            CommandError::InvalidCommand.reply("GET"),
+            RespValue::Error("ERR wrong number of arguments for 'get' command".into())
+        );
+    }
+
+    #[test]
+    fn parse_set_tags() {
+        let v = RespValue::array(&["SET", "CS", "Cloud", "PX", "10", "TAGS", "a", "b"]);
+        let cmd = Command::try_from(v).unwrap();
+        assert_eq!(
+            cmd,
+            Command::SetTagged(
+                "CS".into(),
+                "Cloud".into(),
+                Some(Expiry::In(10)),
+                vec!["a".into(), "b".into()]
+            )
+        );
+
+        let v = RespValue::array(&["SET", "CS", "Cloud", "TAGS"]);
+        assert_eq!(Command::try_from(v), Err(CommandError::InvalidCommand));
+    }
+
+    #[test]
+    fn parse_eval_command() {
+        let v = RespValue::array(&["EVAL", "return 1", "2", "k1", "k2", "a1"]);
+        let cmd = Command::try_from(v).unwrap();
+        assert_eq!(
+            cmd,
+            Command::Eval(
+                "return 1".into(),
+                vec!["k1".into(), "k2".into()],
+                vec!["a1".into()]
+            )
+        );
+
+        let v = RespValue::array(&["EVAL", "return 1", "3", "k1"]);
+        assert_eq!(Command::try_from(v), Err(CommandError::InvalidCommand));
+    }
 }
@@ -0,0 +1,468 @@
+use std::vec::IntoIter;
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{
+    backend::Backend,
+    command::{int, strings, CommandError},
+    hll::murmurhash64a,
+    map::KvStore,
+    resp::RespValue,
+    value::{Value, WRONGTYPE},
+};
+
+/// Filters created by `CF.ADD` and `CF.ADDNX`, as in RedisBloom.
+const DEFAULT_CAPACITY: u64 = 1024;
+const DEFAULT_BUCKET_SIZE: usize = 2;
+const DEFAULT_MAX_ITERATIONS: u32 = 20;
+const DEFAULT_EXPANSION: u32 = 1;
+
+/// Fingerprints are a byte, 0 marks an empty slot.
+type Fingerprint = u8;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct Layer {
+    /// `num_buckets` buckets of `bucket_size` slots each.
+    slots: Vec<Fingerprint>,
+    /// A power of two, so that the alternate bucket of the alternate
+    /// bucket is the bucket itself.
+    num_buckets: u64,
+}
+
+impl Layer {
+    fn new(num_buckets: u64, bucket_size: usize) -> Layer {
+        Layer {
+            slots: vec![0; num_buckets as usize * bucket_size],
+            num_buckets,
+        }
+    }
+
+    fn bucket_size(&self) -> usize {
+        self.slots.len() / self.num_buckets as usize
+    }
+
+    fn bucket(&self, i: u64) -> &[Fingerprint] {
+        let size = self.bucket_size();
+        &self.slots[i as usize * size..(i as usize + 1) * size]
+    }
+
+    fn bucket_mut(&mut self, i: u64) -> &mut [Fingerprint] {
+        let size = self.bucket_size();
+        &mut self.slots[i as usize * size..(i as usize + 1) * size]
+    }
+
+    fn index(&self, hash: u64) -> u64 {
+        hash & (self.num_buckets - 1)
+    }
+
+    fn alt_index(&self, i: u64, fp: Fingerprint) -> u64 {
+        (i ^ (fp as u64).wrapping_mul(0x5bd1e995)) & (self.num_buckets - 1)
+    }
+
+    /// The two buckets the item can be in, or the same one twice.
+    fn buckets(&self, hash: u64, fp: Fingerprint) -> [u64; 2] {
+        let i = self.index(hash);
+        [i, self.alt_index(i, fp)]
+    }
+
+    fn count(&self, hash: u64, fp: Fingerprint) -> usize {
+        let [i1, i2] = self.buckets(hash, fp);
+        let in_bucket = |i| self.bucket(i).iter().filter(|&&s| s == fp).count();
+        match i1 == i2 {
+            true => in_bucket(i1),
+            false => in_bucket(i1) + in_bucket(i2),
+        }
+    }
+
+    /// Puts the fingerprint in an empty slot of its buckets, if there's one.
+    fn insert(&mut self, hash: u64, fp: Fingerprint) -> bool {
+        self.buckets(hash, fp).iter().any(|&i| {
+            match self.bucket_mut(i).iter_mut().find(|s| **s == 0) {
+                Some(slot) => {
+                    *slot = fp;
+                    true
+                }
+                None => false,
+            }
+        })
+    }
+
+    /// Makes room for the fingerprint by moving others to their alternate
+    /// bucket, giving up after `max_iterations` moves. Victims are picked
+    /// from the fingerprint rather than at random, so that every replica
+    /// ends up with the same filter.
+    fn insert_kicking(&mut self, hash: u64, fp: Fingerprint, max_iterations: u32) -> bool {
+        let size = self.bucket_size();
+        let mut i = self.index(hash);
+        let mut fp = fp;
+        let mut moves = Vec::new();
+
+        for n in 0..max_iterations {
+            let slot = (fp as usize + n as usize) % size;
+            let victim = std::mem::replace(&mut self.bucket_mut(i)[slot], fp);
+            moves.push((i, slot, victim));
+
+            fp = victim;
+            i = self.alt_index(i, fp);
+            if let Some(slot) = self.bucket_mut(i).iter_mut().find(|s| **s == 0) {
+                *slot = fp;
+                return true;
+            }
+        }
+
+        // Put everything back where it was
+        for (i, slot, victim) in moves.into_iter().rev() {
+            self.bucket_mut(i)[slot] = victim;
+        }
+        false
+    }
+
+    fn delete(&mut self, hash: u64, fp: Fingerprint) -> bool {
+        self.buckets(hash, fp).iter().any(|&i| {
+            match self.bucket_mut(i).iter_mut().find(|s| **s == fp) {
+                Some(slot) => {
+                    *slot = 0;
+                    true
+                }
+                None => false,
+            }
+        })
+    }
+}
+
+fn hash(item: &[u8]) -> (u64, Fingerprint) {
+    let hash = murmurhash64a(item, 0);
+    (hash, (hash % 255 + 1) as Fingerprint)
+}
+
+/// Like a bloom filter, tells whether an item was added before, but also
+/// allows deleting items and counting how many times one was added.
+///
+/// When no room can be made for an item, another filter is stacked on top,
+/// `expansion` times bigger, unless `expansion` is 0.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CuckooFilter {
+    layers: Vec<Layer>,
+    bucket_size: usize,
+    max_iterations: u32,
+    expansion: u32,
+    items: u64,
+    deleted: u64,
+}
+
+impl CuckooFilter {
+    pub fn new(
+        capacity: u64,
+        bucket_size: usize,
+        max_iterations: u32,
+        expansion: u32,
+    ) -> CuckooFilter {
+        let num_buckets = capacity.div_ceil(bucket_size as u64).next_power_of_two();
+        CuckooFilter {
+            layers: vec![Layer::new(num_buckets, bucket_size)],
+            bucket_size,
+            max_iterations,
+            expansion,
+            items: 0,
+            deleted: 0,
+        }
+    }
+
+    pub fn add(&mut self, item: &[u8]) -> Result<(), RespValue> {
+        let (hash, fp) = hash(item);
+        let inserted = self.layers.iter_mut().any(|layer| layer.insert(hash, fp))
+            || (self.layers.last_mut().unwrap()).insert_kicking(hash, fp, self.max_iterations);
+
+        if !inserted {
+            if self.expansion == 0 {
+                return Err(RespValue::Error("ERR Filter is full".into()));
+            }
+            let num_buckets = self.layers.last().unwrap().num_buckets * self.expansion as u64;
+            let mut layer = Layer::new(num_buckets.next_power_of_two(), self.bucket_size);
+            layer.insert(hash, fp);
+            self.layers.push(layer);
+        }
+        self.items += 1;
+        Ok(())
+    }
+
+    pub fn count(&self, item: &[u8]) -> usize {
+        let (hash, fp) = hash(item);
+        self.layers.iter().map(|layer| layer.count(hash, fp)).sum()
+    }
+
+    pub fn contains(&self, item: &[u8]) -> bool {
+        self.count(item) > 0
+    }
+
+    /// Deletes one occurrence of the item, newest filters first.
+    pub fn delete(&mut self, item: &[u8]) -> bool {
+        let (hash, fp) = hash(item);
+        let deleted = self
+            .layers
+            .iter_mut()
+            .rev()
+            .any(|layer| layer.delete(hash, fp));
+        if deleted {
+            self.items -= 1;
+            self.deleted += 1;
+        }
+        deleted
+    }
+
+    pub fn num_buckets(&self) -> u64 {
+        self.layers.iter().map(|layer| layer.num_buckets).sum()
+    }
+
+    /// Bytes taken by the fingerprints of the filters.
+    pub fn size(&self) -> usize {
+        self.layers.iter().map(|layer| layer.slots.len()).sum()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CuckooCommand {
+    Reserve {
+        key: String,
+        capacity: u64,
+        bucket_size: usize,
+        max_iterations: u32,
+        expansion: u32,
+    },
+    Add(String, String),
+    AddNx(String, String),
+    Exists(String, String),
+    MExists(String, Vec<String>),
+    Del(String, String),
+    Count(String, String),
+    Info(String),
+}
+
+impl CuckooCommand {
+    pub fn keys(&self) -> Vec<&str> {
+        use CuckooCommand::*;
+        match self {
+            Reserve { key, .. } | Add(key, _) | AddNx(key, _) | Del(key, _) => vec![key.as_str()],
+            Exists(key, _) | MExists(key, _) | Count(key, _) | Info(key) => vec![key.as_str()],
+        }
+    }
+}
+
+/// Parses the arguments of the `CF.*` commands.
+pub fn parse(verb: &str, arr: IntoIter<RespValue>) -> Result<CuckooCommand, CommandError> {
+    let args = strings(arr)?;
+    let cmd = match (verb, args.as_slice()) {
+        ("CF.RESERVE", [key, capacity, options @ ..]) => {
+            let capacity: u64 = int(capacity)?;
+            let mut bucket_size = DEFAULT_BUCKET_SIZE;
+            let mut max_iterations = DEFAULT_MAX_ITERATIONS;
+            let mut expansion = DEFAULT_EXPANSION;
+            let mut options = options.iter();
+            while let Some(option) = options.next() {
+                let value = options.next().ok_or(CommandError::InvalidCommand)?;
+                match option.to_uppercase().as_str() {
+                    "BUCKETSIZE" => bucket_size = int(value)?,
+                    "MAXITERATIONS" => max_iterations = int(value)?,
+                    "EXPANSION" => expansion = int(value)?,
+                    _ => return Err(CommandError::InvalidCommand),
+                }
+            }
+            if capacity == 0 || !(1..=255).contains(&bucket_size) || max_iterations == 0 {
+                return Err(CommandError::InvalidCommand);
+            }
+            CuckooCommand::Reserve {
+                key: key.clone(),
+                capacity,
+                bucket_size,
+                max_iterations,
+                expansion,
+            }
+        }
+        ("CF.ADD", [key, item]) => CuckooCommand::Add(key.clone(), item.clone()),
+        ("CF.ADDNX", [key, item]) => CuckooCommand::AddNx(key.clone(), item.clone()),
+        ("CF.EXISTS", [key, item]) => CuckooCommand::Exists(key.clone(), item.clone()),
+        ("CF.MEXISTS", [key, items @ ..]) if !items.is_empty() => {
+            CuckooCommand::MExists(key.clone(), items.to_vec())
+        }
+        ("CF.DEL", [key, item]) => CuckooCommand::Del(key.clone(), item.clone()),
+        ("CF.COUNT", [key, item]) => CuckooCommand::Count(key.clone(), item.clone()),
+        ("CF.INFO", [key]) => CuckooCommand::Info(key.clone()),
+        _ => return Err(CommandError::InvalidCommand),
+    };
+    Ok(cmd)
+}
+
+impl<T> Backend<T>
+where
+    T: KvStore,
+{
+    fn cuckoo(&self, key: &str) -> Result<Option<&CuckooFilter>, RespValue> {
+        match self.value(key)? {
+            Some(Value::Cuckoo(filter)) => Ok(Some(filter)),
+            Some(_) => Err(RespValue::Error(WRONGTYPE.into())),
+            None => Ok(None),
+        }
+    }
+
+    /// The filter at `key` for adding to it, created with the defaults if missing.
+    fn cuckoo_mut(&mut self, key: &str) -> Result<&mut CuckooFilter, RespValue> {
+        if self.value_mut(key)?.is_none() {
+            let filter = CuckooFilter::new(
+                DEFAULT_CAPACITY,
+                DEFAULT_BUCKET_SIZE,
+                DEFAULT_MAX_ITERATIONS,
+                DEFAULT_EXPANSION,
+            );
+            self.store.kv_put(key, Value::Cuckoo(filter))?;
+        }
+        match self.store.kv_get_mut(key)? {
+            Some(Value::Cuckoo(filter)) => Ok(filter),
+            _ => Err(RespValue::Error(WRONGTYPE.into())),
+        }
+    }
+
+    pub(crate) fn process_cuckoo(&mut self, cmd: CuckooCommand) -> RespValue {
+        use CuckooCommand::*;
+        match cmd {
+            Reserve {
+                key,
+                capacity,
+                bucket_size,
+                max_iterations,
+                expansion,
+            } => {
+                let filter = CuckooFilter::new(capacity, bucket_size, max_iterations, expansion);
+                self.process_cf_reserve(&key, filter)
+            }
+            Add(key, item) => self.process_cf_add(&key, &item, false),
+            AddNx(key, item) => self.process_cf_add(&key, &item, true),
+            Exists(key, item) => self
+                .process_cf_exists(&key, &[item])
+                .map(|mut found| found.remove(0)),
+            MExists(key, items) => self.process_cf_exists(&key, &items).map(RespValue::Array),
+            Del(key, item) => self.process_cf_del(&key, &item),
+            Count(key, item) => self.cuckoo(&key).map(|filter| {
+                RespValue::Integer(filter.map_or(0, |f| f.count(item.as_bytes())) as i64)
+            }),
+            Info(key) => self.process_cf_info(&key),
+        }
+        .unwrap_or_else(|e| e)
+    }
+
+    fn process_cf_reserve(
+        &mut self,
+        key: &str,
+        filter: CuckooFilter,
+    ) -> Result<RespValue, RespValue> {
+        if self.value_mut(key)?.is_some() {
+            return Err(RespValue::Error("ERR item exists".into()));
+        }
+        self.store.kv_put(key, Value::Cuckoo(filter))?;
+        self.notify('g', "cf.reserve", key);
+        Ok(RespValue::SimpleString("OK".into()))
+    }
+
+    /// Adds the item, or with `nx` only if it doesn't seem to be there already.
+    fn process_cf_add(&mut self, key: &str, item: &str, nx: bool) -> Result<RespValue, RespValue> {
+        let filter = self.cuckoo_mut(key)?;
+        if nx && filter.contains(item.as_bytes()) {
+            return Ok(RespValue::Integer(0));
+        }
+        filter.add(item.as_bytes())?;
+        self.notify('g', "cf.add", key);
+        Ok(RespValue::Integer(1))
+    }
+
+    fn process_cf_exists(&self, key: &str, items: &[String]) -> Result<Vec<RespValue>, RespValue> {
+        let filter = self.cuckoo(key)?;
+        Ok(items
+            .iter()
+            .map(|item| {
+                let found = filter.is_some_and(|f| f.contains(item.as_bytes()));
+                RespValue::Integer(found as i64)
+            })
+            .collect())
+    }
+
+    fn process_cf_del(&mut self, key: &str, item: &str) -> Result<RespValue, RespValue> {
+        self.expire_if_needed(key)?;
+        let filter = match self.store.kv_get_mut(key)? {
+            Some(Value::Cuckoo(filter)) => filter,
+            Some(_) => return Err(RespValue::Error(WRONGTYPE.into())),
+            None => return Err(RespValue::Error("ERR Not found".into())),
+        };
+        if !filter.delete(item.as_bytes()) {
+            return Ok(RespValue::Integer(0));
+        }
+        self.notify('g', "cf.del", key);
+        Ok(RespValue::Integer(1))
+    }
+
+    fn process_cf_info(&self, key: &str) -> Result<RespValue, RespValue> {
+        let filter = self
+            .cuckoo(key)?
+            .ok_or_else(|| RespValue::Error("ERR not found".into()))?;
+        let fields = [
+            ("Size", filter.size() as i64),
+            ("Number of buckets", filter.num_buckets() as i64),
+            ("Number of filters", filter.layers.len() as i64),
+            ("Number of items inserted", filter.items as i64),
+            ("Number of items deleted", filter.deleted as i64),
+            ("Bucket size", filter.bucket_size as i64),
+            ("Expansion rate", filter.expansion as i64),
+            ("Max iterations", filter.max_iterations as i64),
+        ];
+        Ok(RespValue::Array(
+            fields
+                .into_iter()
+                .flat_map(|(name, n)| [RespValue::SimpleString(name.into()), RespValue::Integer(n)])
+                .collect(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cuckoo_add_delete() {
+        let mut filter = CuckooFilter::new(1000, 2, 20, 1);
+        for i in 0..500 {
+            filter.add(format!("seen:{}", i).as_bytes()).unwrap();
+        }
+        filter.add(b"seen:7").unwrap();
+        assert!((0..500).all(|i| filter.contains(format!("seen:{}", i).as_bytes())));
+        assert_eq!(filter.count(b"seen:7"), 2);
+
+        assert!(filter.delete(b"seen:7"));
+        assert!(filter.delete(b"seen:7"));
+        assert!(!filter.contains(b"seen:7"));
+        assert!(!filter.delete(b"seen:7"));
+        assert_eq!((filter.items, filter.deleted), (499, 2));
+    }
+
+    #[test]
+    fn test_cuckoo_full() {
+        // Filling up a non-scaling filter leaves what's in it untouched
+        let mut filter = CuckooFilter::new(64, 4, 50, 0);
+        let mut added = Vec::new();
+        for i in 0..100 {
+            let item = format!("seen:{}", i);
+            if filter.add(item.as_bytes()).is_err() {
+                break;
+            }
+            added.push(item);
+        }
+        assert!(added.len() < 100);
+        assert!(added.iter().all(|item| filter.contains(item.as_bytes())));
+
+        // A scaling one grows instead
+        let mut filter = CuckooFilter::new(64, 4, 50, 2);
+        for i in 0..100 {
+            filter.add(format!("seen:{}", i).as_bytes()).unwrap();
+        }
+        assert!(filter.layers.len() > 1);
+        assert!((0..100).all(|i| filter.contains(format!("seen:{}", i).as_bytes())));
+    }
+}
@@ -0,0 +1,558 @@
+use std::{
+    borrow::Cow,
+    collections::{BTreeSet, VecDeque},
+    str::FromStr,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering::Relaxed},
+};
+
+use serde::{Serialize, Serializer};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::resp::RespValue;
+
+/// Strings at most this long are kept inline, like Redis's embstr.
+pub const INLINE_LEN: usize = 44;
+/// Bytes the allocator takes for each allocation besides what's asked
+/// for, about.
+pub const ALLOC_OVERHEAD: u64 = 16;
+/// Bytes each element of a full structure takes besides its contents,
+/// about: the header of its `String` or `Vec`, and its share of the tree
+/// node or ring buffer holding it.
+pub const ELEMENT_OVERHEAD: u64 = 40;
+
+/// A limit past which small values are converted to full structures,
+/// named after the Redis setting it stands for.
+pub struct Threshold {
+    pub name: &'static str,
+    value: AtomicUsize,
+    /// Whether it was set with `CONFIG SET`, rather than left as it was
+    /// started with.
+    configured: AtomicBool,
+}
+
+impl Threshold {
+    const fn new(name: &'static str, value: usize) -> Threshold {
+        Threshold {
+            name,
+            value: AtomicUsize::new(value),
+            configured: AtomicBool::new(false),
+        }
+    }
+
+    pub fn get(&self) -> usize {
+        self.value.load(Relaxed)
+    }
+}
+
+// Global like Redis's, as values are made, e.g. when deserialized, where
+// there's no backend to ask.
+pub static HASH_MAX_LISTPACK_ENTRIES: Threshold = Threshold::new("hash-max-listpack-entries", 128);
+pub static HASH_MAX_LISTPACK_VALUE: Threshold = Threshold::new("hash-max-listpack-value", 64);
+pub static SET_MAX_INTSET_ENTRIES: Threshold = Threshold::new("set-max-intset-entries", 512);
+pub static SET_MAX_LISTPACK_ENTRIES: Threshold = Threshold::new("set-max-listpack-entries", 128);
+pub static SET_MAX_LISTPACK_VALUE: Threshold = Threshold::new("set-max-listpack-value", 64);
+pub static LIST_MAX_LISTPACK_SIZE: Threshold = Threshold::new("list-max-listpack-size", 128);
+
+static THRESHOLDS: [&Threshold; 6] = [
+    &HASH_MAX_LISTPACK_ENTRIES,
+    &HASH_MAX_LISTPACK_VALUE,
+    &SET_MAX_INTSET_ENTRIES,
+    &SET_MAX_LISTPACK_ENTRIES,
+    &SET_MAX_LISTPACK_VALUE,
+    &LIST_MAX_LISTPACK_SIZE,
+];
+
+fn threshold(name: &str) -> Option<&'static Threshold> {
+    THRESHOLDS
+        .iter()
+        .find(|t| t.name.eq_ignore_ascii_case(name))
+        .copied()
+}
+
+/// Whether `CONFIG` is about a threshold, which replicas answer.
+pub fn is_threshold(name: &str) -> bool {
+    threshold(name).is_some()
+}
+
+/// `--encoding "<name> <value>"`, setting a threshold on start.
+pub struct ThresholdSetting(&'static Threshold, usize);
+
+impl ThresholdSetting {
+    pub fn apply(&self) {
+        self.0.value.store(self.1, Relaxed);
+    }
+}
+
+impl FromStr for ThresholdSetting {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid encoding threshold {}", s);
+        let (name, value) = s.split_once(' ').ok_or_else(invalid)?;
+        let threshold = threshold(name).ok_or_else(invalid)?;
+        let value = value.trim().parse().map_err(|_| invalid())?;
+        Ok(ThresholdSetting(threshold, value))
+    }
+}
+
+/// Answers `CONFIG GET` of thresholds. Setting them is a write, see
+/// [set_threshold].
+pub fn process_config(resp: &RespValue) -> Option<RespValue> {
+    let args = resp.clone().into_args();
+    match args.as_slice() {
+        [get, name] if get.eq_ignore_ascii_case("GET") => {
+            let threshold = threshold(name)?;
+            Some(RespValue::array(&[
+                threshold.name,
+                &threshold.get().to_string(),
+            ]))
+        }
+        _ => None,
+    }
+}
+
+/// `CONFIG SET` of a threshold, which only applies to values converted
+/// from then on, like in Redis. Replicas apply it as a write, so that it's
+/// replicated and logged like one.
+pub fn set_threshold(name: &str, value: &str) -> RespValue {
+    let Some(threshold) = threshold(name) else {
+        return RespValue::Error(format!("ERR Unknown option '{}'", name));
+    };
+    match value.parse() {
+        Ok(value) => {
+            threshold.value.store(value, Relaxed);
+            threshold.configured.store(true, Relaxed);
+            RespValue::SimpleString("OK".into())
+        }
+        Err(_) => RespValue::Error(format!("ERR Invalid {}", threshold.name)),
+    }
+}
+
+/// The `CONFIG SET`s that restore the thresholds set with one. Those left
+/// as they were started with aren't, so that `--encoding` still applies
+/// to them on a restart.
+pub fn threshold_commands() -> Vec<RespValue> {
+    THRESHOLDS
+        .iter()
+        .filter(|t| t.configured.load(Relaxed))
+        .map(|t| RespValue::array(&["CONFIG", "SET", t.name, &t.get().to_string()]))
+        .collect()
+}
+
+/// The integer the bytes spell, if they're exactly how it's written, so
+/// that it's written back the same.
+fn as_int(bytes: &[u8]) -> Option<i64> {
+    if bytes.len() > 20 {
+        return None;
+    }
+    let s = std::str::from_utf8(bytes).ok()?;
+    let n: i64 = s.parse().ok()?;
+    (n.to_string() == s).then_some(n)
+}
+
+/// A binary safe string, kept as an integer if it spells one, or inline
+/// if it's short, and allocated otherwise.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(from = "Vec<u8>")]
+pub enum Str {
+    Int(i64),
+    Inline(u8, [u8; INLINE_LEN]),
+    Raw(Vec<u8>),
+}
+
+impl From<Vec<u8>> for Str {
+    fn from(bytes: Vec<u8>) -> Self {
+        if let Some(n) = as_int(&bytes) {
+            return Str::Int(n);
+        }
+        if bytes.len() > INLINE_LEN {
+            return Str::Raw(bytes);
+        }
+        let mut inline = [0; INLINE_LEN];
+        inline[..bytes.len()].copy_from_slice(&bytes);
+        Str::Inline(bytes.len() as u8, inline)
+    }
+}
+
+impl Str {
+    pub fn as_bytes(&self) -> Cow<'_, [u8]> {
+        match self {
+            Str::Int(n) => Cow::Owned(n.to_string().into_bytes()),
+            Str::Inline(len, inline) => Cow::Borrowed(&inline[..*len as usize]),
+            Str::Raw(bytes) => Cow::Borrowed(bytes),
+        }
+    }
+
+    /// The bytes for changing them in place, which leaves the string raw,
+    /// as Redis does.
+    pub fn to_mut(&mut self) -> &mut Vec<u8> {
+        if !matches!(self, Str::Raw(_)) {
+            *self = Str::Raw(self.as_bytes().into_owned());
+        }
+        match self {
+            Str::Raw(bytes) => bytes,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            Str::Int(_) => "int",
+            Str::Inline(..) => "embstr",
+            Str::Raw(_) => "raw",
+        }
+    }
+
+    /// Bytes allocated besides the value itself.
+    pub fn allocated(&self) -> u64 {
+        match self {
+            Str::Int(_) | Str::Inline(..) => 0,
+            Str::Raw(bytes) => bytes.len() as u64 + ALLOC_OVERHEAD,
+        }
+    }
+}
+
+impl PartialEq for Str {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_bytes() == other.as_bytes()
+    }
+}
+
+// Serialized as the bytes whatever the encoding, as it always was
+impl Serialize for Str {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.as_bytes().serialize(serializer)
+    }
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut n: usize) {
+    while n >= 0x80 {
+        buf.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    buf.push(n as u8);
+}
+
+/// The number at the start of the buffer, and how many bytes it took.
+fn read_varint(buf: &[u8]) -> (usize, usize) {
+    let mut n = 0;
+    for (i, byte) in buf.iter().enumerate() {
+        n |= ((byte & 0x7F) as usize) << (7 * i);
+        if byte & 0x80 == 0 {
+            return (n, i + 1);
+        }
+    }
+    unreachable!("truncated listpack entry")
+}
+
+/// Entries packed one after the other into a single buffer, each after
+/// its length, like in Redis's listpacks. This saves an allocation per
+/// entry of small collections, at the cost of going through the entries
+/// to find one.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Listpack {
+    buf: Vec<u8>,
+    len: usize,
+}
+
+impl Listpack {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn iter(&self) -> ListpackIter<'_> {
+        ListpackIter(&self.buf)
+    }
+
+    /// Where the entry at `index` starts in the buffer.
+    fn offset(&self, index: usize) -> usize {
+        let mut offset = 0;
+        for _ in 0..index {
+            let (len, n) = read_varint(&self.buf[offset..]);
+            offset += n + len;
+        }
+        offset
+    }
+
+    pub fn insert(&mut self, index: usize, entry: &[u8]) {
+        let offset = self.offset(index);
+        let mut encoded = Vec::with_capacity(entry.len() + 2);
+        write_varint(&mut encoded, entry.len());
+        encoded.extend_from_slice(entry);
+        self.buf.splice(offset..offset, encoded);
+        self.len += 1;
+    }
+
+    pub fn push(&mut self, entry: &[u8]) {
+        write_varint(&mut self.buf, entry.len());
+        self.buf.extend_from_slice(entry);
+        self.len += 1;
+    }
+
+    pub fn remove(&mut self, index: usize) {
+        let offset = self.offset(index);
+        let (len, n) = read_varint(&self.buf[offset..]);
+        self.buf.drain(offset..offset + n + len);
+        self.len -= 1;
+    }
+
+    pub fn allocated(&self) -> u64 {
+        self.buf.len() as u64 + ALLOC_OVERHEAD
+    }
+}
+
+pub struct ListpackIter<'a>(&'a [u8]);
+
+impl<'a> Iterator for ListpackIter<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.0.is_empty() {
+            return None;
+        }
+        let (len, n) = read_varint(self.0);
+        let (entry, rest) = self.0[n..].split_at(len);
+        self.0 = rest;
+        Some(entry)
+    }
+}
+
+/// Bytes allocated for the elements of a full structure.
+fn elements_allocated<'a>(elements: impl Iterator<Item = &'a [u8]>) -> u64 {
+    elements
+        .map(|e| e.len() as u64 + ALLOC_OVERHEAD + ELEMENT_OVERHEAD)
+        .sum()
+}
+
+/// A set, kept as integers like Redis's intsets if its members are all
+/// integers, packed into a listpack if it's small, and as a tree otherwise.
+/// Whichever it is, members are kept in byte order, so that walking them
+/// doesn't depend on how the set happens to be kept.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "BTreeSet<Vec<u8>>", into = "BTreeSet<Vec<u8>>")]
+pub enum Set {
+    Ints(Vec<i64>),
+    Packed(Listpack),
+    Table(BTreeSet<Vec<u8>>),
+}
+
+impl From<BTreeSet<Vec<u8>>> for Set {
+    fn from(members: BTreeSet<Vec<u8>>) -> Self {
+        let ints: Option<Vec<i64>> = members.iter().map(|m| as_int(m)).collect();
+        match ints {
+            // Already in byte order, that of the members
+            Some(ints) if ints.len() <= SET_MAX_INTSET_ENTRIES.get() => Set::Ints(ints),
+            _ if members.len() <= SET_MAX_LISTPACK_ENTRIES.get()
+                && members
+                    .iter()
+                    .all(|m| m.len() <= SET_MAX_LISTPACK_VALUE.get()) =>
+            {
+                let mut listpack = Listpack::default();
+                members.iter().for_each(|m| listpack.push(m));
+                Set::Packed(listpack)
+            }
+            _ => Set::Table(members),
+        }
+    }
+}
+
+impl From<Set> for BTreeSet<Vec<u8>> {
+    fn from(set: Set) -> Self {
+        match set {
+            Set::Table(members) => members,
+            set => set.iter().map(Cow::into_owned).collect(),
+        }
+    }
+}
+
+impl FromIterator<Vec<u8>> for Set {
+    fn from_iter<I: IntoIterator<Item = Vec<u8>>>(iter: I) -> Self {
+        iter.into_iter().collect::<BTreeSet<_>>().into()
+    }
+}
+
+impl Set {
+    pub fn len(&self) -> usize {
+        match self {
+            Set::Ints(ints) => ints.len(),
+            Set::Packed(listpack) => listpack.len(),
+            Set::Table(members) => members.len(),
+        }
+    }
+
+    /// The members, in byte order.
+    pub fn iter(&self) -> Box<dyn Iterator<Item = Cow<'_, [u8]>> + '_> {
+        match self {
+            Set::Ints(ints) => {
+                Box::new(ints.iter().map(|n| Cow::Owned(n.to_string().into_bytes())))
+            }
+            Set::Packed(listpack) => Box::new(listpack.iter().map(Cow::Borrowed)),
+            Set::Table(members) => Box::new(members.iter().map(|m| Cow::Borrowed(&m[..]))),
+        }
+    }
+
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            Set::Ints(_) => "intset",
+            Set::Packed(_) => "listpack",
+            Set::Table(_) => "hashtable",
+        }
+    }
+
+    pub fn allocated(&self) -> u64 {
+        match self {
+            Set::Ints(ints) => 8 * ints.len() as u64 + ALLOC_OVERHEAD,
+            Set::Packed(listpack) => listpack.allocated(),
+            Set::Table(members) => elements_allocated(members.iter().map(|m| &m[..])),
+        }
+    }
+}
+
+impl PartialEq for Set {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len()
+            && self.iter().collect::<BTreeSet<_>>() == other.iter().collect::<BTreeSet<_>>()
+    }
+}
+
+/// A list, packed into a listpack if it's small.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "VecDeque<Vec<u8>>", into = "VecDeque<Vec<u8>>")]
+pub enum List {
+    Packed(Listpack),
+    Linked(VecDeque<Vec<u8>>),
+}
+
+impl From<VecDeque<Vec<u8>>> for List {
+    fn from(items: VecDeque<Vec<u8>>) -> Self {
+        if items.len() > LIST_MAX_LISTPACK_SIZE.get() {
+            return List::Linked(items);
+        }
+        let mut listpack = Listpack::default();
+        items.iter().for_each(|item| listpack.push(item));
+        List::Packed(listpack)
+    }
+}
+
+impl From<List> for VecDeque<Vec<u8>> {
+    fn from(list: List) -> Self {
+        match list {
+            List::Packed(listpack) => listpack.iter().map(<[u8]>::to_vec).collect(),
+            List::Linked(items) => items,
+        }
+    }
+}
+
+impl FromIterator<Vec<u8>> for List {
+    fn from_iter<I: IntoIterator<Item = Vec<u8>>>(iter: I) -> Self {
+        iter.into_iter().collect::<VecDeque<_>>().into()
+    }
+}
+
+impl List {
+    pub fn len(&self) -> usize {
+        match self {
+            List::Packed(listpack) => listpack.len(),
+            List::Linked(items) => items.len(),
+        }
+    }
+
+    pub fn iter(&self) -> Box<dyn Iterator<Item = &[u8]> + '_> {
+        match self {
+            List::Packed(listpack) => Box::new(listpack.iter()),
+            List::Linked(items) => Box::new(items.iter().map(|item| &item[..])),
+        }
+    }
+
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            List::Packed(_) => "listpack",
+            List::Linked(_) => "quicklist",
+        }
+    }
+
+    pub fn allocated(&self) -> u64 {
+        match self {
+            List::Packed(listpack) => listpack.allocated(),
+            // And the ring buffer of the items
+            List::Linked(_) => elements_allocated(self.iter()) + ALLOC_OVERHEAD,
+        }
+    }
+}
+
+impl PartialEq for List {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && self.iter().eq(other.iter())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_str() {
+        let encoded = |s: &str| Str::from(s.as_bytes().to_vec());
+        assert_eq!(encoded("-42"), Str::Int(-42));
+        assert_eq!(encoded("042").encoding(), "embstr");
+        assert_eq!(encoded("+1").encoding(), "embstr");
+        assert_eq!(encoded(&"x".repeat(INLINE_LEN)).encoding(), "embstr");
+        assert_eq!(encoded(&"x".repeat(INLINE_LEN + 1)).encoding(), "raw");
+        assert_eq!(encoded("-42").as_bytes(), &b"-42"[..]);
+
+        let mut s = encoded("7");
+        s.to_mut().push(b'0');
+        assert_eq!(s.encoding(), "raw");
+        assert_eq!(s, encoded("70"));
+        assert_eq!(serde_json::to_string(&encoded("1")).unwrap(), "[49]");
+        let s: Str = serde_json::from_str("[49]").unwrap();
+        assert_eq!(s.encoding(), "int");
+    }
+
+    #[test]
+    fn test_listpack() {
+        let mut listpack = Listpack::default();
+        listpack.push(b"b");
+        listpack.insert(0, b"a");
+        listpack.insert(2, &[7; 300]);
+        listpack.insert(2, b"");
+        assert_eq!(listpack.len(), 4);
+        let entries: Vec<&[u8]> = listpack.iter().collect();
+        assert_eq!(entries, [&b"a"[..], b"b", b"", &[7; 300]]);
+        listpack.remove(1);
+        let entries: Vec<&[u8]> = listpack.iter().collect();
+        assert_eq!(entries, [&b"a"[..], b"", &[7; 300]]);
+    }
+
+    #[test]
+    fn test_threshold_commands() {
+        // Set on start rather than with CONFIG SET, so not kept
+        let setting: ThresholdSetting = "hash-max-listpack-value 64".parse().unwrap();
+        setting.apply();
+        assert!(!threshold_commands().iter().any(|cmd| cmd
+            .clone()
+            .into_args()
+            .contains(&"hash-max-listpack-value".into())));
+    }
+
+    #[test]
+    fn test_collections() {
+        let set =
+            |members: &[&str]| -> Set { members.iter().map(|m| m.as_bytes().to_vec()).collect() };
+        assert_eq!(set(&["10", "9", "-1"]).encoding(), "intset");
+        let ints = set(&["10", "9", "-1"]);
+        let members: Vec<Cow<[u8]>> = ints.iter().collect();
+        assert_eq!(members, [&b"-1"[..], b"10", b"9"]);
+        assert_eq!(set(&["10", "x"]).encoding(), "listpack");
+        assert_eq!(set(&["10", "x"]), set(&["x", "10"]));
+        assert_eq!(set(&[&"x".repeat(65)]).encoding(), "hashtable");
+        let many: Set = (0..600).map(|n| n.to_string().into_bytes()).collect();
+        assert_eq!(many.encoding(), "hashtable");
+
+        let list: List = (0..3).map(|n| vec![n]).collect();
+        assert_eq!(list.encoding(), "listpack");
+        let list: List = (0..200).map(|n| vec![n]).collect();
+        assert_eq!(list.encoding(), "quicklist");
+        assert_eq!(list.iter().nth(199), Some(&[199][..]));
+    }
+}
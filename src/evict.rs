@@ -0,0 +1,469 @@
+use std::{fmt, str::FromStr};
+
+use indexmap::{IndexMap, IndexSet};
+
+use crate::{memory::KeyUsage, resp::RespValue};
+
+pub(crate) const OOM: &str = "OOM command not allowed when used memory > 'maxmemory'.";
+
+/// Writes that only ever take data away, allowed over `maxmemory`.
+const FREEING_COMMANDS: &[&str] = &[
+    "DEL",
+    "EXPIRE",
+    "PEXPIRE",
+    "EXPIREAT",
+    "PEXPIREAT",
+    "PERSIST",
+    "SWEEP",
+    "EVICT",
+    "CF.DEL",
+    "ZREM",
+    "JSON.DEL",
+    "VREM",
+    "HDEL",
+    "FT.DROPINDEX",
+    "LOCK.RELEASE",
+    "Q.ACK",
+    "TAG.INVALIDATE",
+    "DELPREFIX",
+    "FLUSHALL",
+];
+
+/// The counter keys start with, so that new keys aren't evicted right
+/// away under LFU.
+const LFU_INIT: u8 = 5;
+/// How much harder it gets to bump the counter the higher it is.
+const LFU_LOG_FACTOR: f64 = 10.0;
+/// The counter goes down by one every that many milliseconds unused.
+const LFU_DECAY_MS: u64 = 60_000;
+/// How many of the best candidates are kept between evictions.
+const POOL_SIZE: usize = 16;
+
+/// Which keys go once `maxmemory` is reached.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Policy {
+    /// None, writes fail instead.
+    NoEviction,
+    AllKeysLru,
+    VolatileLru,
+    AllKeysLfu,
+    VolatileLfu,
+    AllKeysRandom,
+    VolatileRandom,
+    /// Those with the nearest deadline.
+    VolatileTtl,
+}
+
+const POLICIES: [(Policy, &str); 8] = [
+    (Policy::NoEviction, "noeviction"),
+    (Policy::AllKeysLru, "allkeys-lru"),
+    (Policy::VolatileLru, "volatile-lru"),
+    (Policy::AllKeysLfu, "allkeys-lfu"),
+    (Policy::VolatileLfu, "volatile-lfu"),
+    (Policy::AllKeysRandom, "allkeys-random"),
+    (Policy::VolatileRandom, "volatile-random"),
+    (Policy::VolatileTtl, "volatile-ttl"),
+];
+
+impl Policy {
+    /// Whether only keys with a TTL are evicted.
+    fn volatile(self) -> bool {
+        matches!(
+            self,
+            Policy::VolatileLru
+                | Policy::VolatileLfu
+                | Policy::VolatileRandom
+                | Policy::VolatileTtl
+        )
+    }
+}
+
+impl FromStr for Policy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        POLICIES
+            .iter()
+            .find(|(_, name)| name.eq_ignore_ascii_case(s))
+            .map(|(policy, _)| *policy)
+            .ok_or_else(|| format!("invalid maxmemory policy {}", s))
+    }
+}
+
+impl fmt::Display for Policy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (_, name) = POLICIES.iter().find(|(p, _)| p == self).unwrap();
+        f.write_str(name)
+    }
+}
+
+/// Parses a number of bytes the way Redis's config does, e.g. "100mb".
+pub fn parse_bytes(s: &str) -> Result<u64, String> {
+    let lower = s.to_lowercase();
+    let digits = lower.trim_end_matches(char::is_alphabetic);
+    let unit = match &lower[digits.len()..] {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return Err(format!("invalid memory size {}", s)),
+    };
+    let n: u64 = digits
+        .parse()
+        .map_err(|_| format!("invalid memory size {}", s))?;
+    n.checked_mul(unit)
+        .ok_or_else(|| format!("invalid memory size {}", s))
+}
+
+/// What the master knows about a key.
+#[derive(Debug)]
+struct KeyStats {
+    size: u64,
+    deadline: Option<u64>,
+    /// When it was last read or written, in unix milliseconds.
+    access: u64,
+    /// A logarithmic count of accesses, as of `access`.
+    counter: u8,
+}
+
+impl KeyStats {
+    /// The count of accesses, less one for every period it went unused.
+    fn counter(&self, now: u64) -> u8 {
+        let periods = now.saturating_sub(self.access) / LFU_DECAY_MS;
+        self.counter.saturating_sub(periods.min(255) as u8)
+    }
+}
+
+/// Decides which keys to evict once the dataset is over `maxmemory`.
+///
+/// Replicas serve reads on their own, so only the master sees every access
+/// to keys, and evicting on it keeps replicas identical. It learns about
+/// the keys and their sizes from what replicas reply to writes, and, like
+/// Redis, samples a few of them at a time rather than keeping them in
+/// order of eviction.
+pub struct Evictor {
+    /// In bytes, 0 for no limit.
+    pub maxmemory: u64,
+    policy: Policy,
+    /// How many keys are sampled for each eviction.
+    pub samples: usize,
+    keys: IndexMap<String, KeyStats>,
+    /// The keys with a TTL.
+    volatile: IndexSet<String>,
+    used: u64,
+    /// The best candidates sampled so far, best last.
+    pool: Vec<(u64, String)>,
+    rng: u64,
+    evicted: u64,
+}
+
+impl Evictor {
+    pub fn new(maxmemory: u64, policy: Policy, samples: usize) -> Evictor {
+        Evictor {
+            maxmemory,
+            policy,
+            samples,
+            keys: IndexMap::new(),
+            volatile: IndexSet::new(),
+            used: 0,
+            pool: Vec::new(),
+            rng: crate::backend::unix_millis() | 1,
+            evicted: 0,
+        }
+    }
+
+    pub fn policy(&self) -> Policy {
+        self.policy
+    }
+
+    /// Changes the policy, forgetting the candidates sampled so far, as
+    /// they were scored for the old one.
+    pub fn set_policy(&mut self, policy: Policy) {
+        self.policy = policy;
+        self.pool.clear();
+    }
+
+    /// xorshift64*, as what's evicted doesn't need to be unpredictable.
+    fn random(&mut self) -> u64 {
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        self.rng.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// Takes note of what a replica said about keys.
+    pub fn update(&mut self, usage: Vec<KeyUsage>, now: u64) {
+        for KeyUsage {
+            key,
+            size,
+            deadline,
+        } in usage
+        {
+            let Some(size) = size else {
+                if let Some(stats) = self.keys.swap_remove(&key) {
+                    self.used -= stats.size;
+                }
+                self.volatile.swap_remove(&key);
+                continue;
+            };
+            let stats = self.keys.entry(key.clone()).or_insert(KeyStats {
+                size: 0,
+                deadline: None,
+                access: now,
+                counter: LFU_INIT,
+            });
+            self.used = self.used + size - stats.size;
+            stats.size = size;
+            stats.deadline = deadline;
+            match deadline {
+                Some(_) => self.volatile.insert(key),
+                None => self.volatile.swap_remove(&key),
+            };
+        }
+    }
+
+    /// Forgets every key, then takes note of those a replica has.
+    pub fn reset(&mut self, usage: Vec<KeyUsage>, now: u64) {
+        self.keys.clear();
+        self.volatile.clear();
+        self.used = 0;
+        self.pool.clear();
+        self.update(usage, now);
+    }
+
+    /// Records a read or a write of the key.
+    pub fn touch(&mut self, key: &str, now: u64) {
+        let chance = self.random() as f64 / u64::MAX as f64;
+        let Some(stats) = self.keys.get_mut(key) else {
+            return;
+        };
+        let mut counter = stats.counter(now);
+        let base = counter.saturating_sub(LFU_INIT) as f64;
+        if counter < u8::MAX && chance < 1.0 / (base * LFU_LOG_FACTOR + 1.0) {
+            counter += 1;
+        }
+        stats.counter = counter;
+        stats.access = now;
+    }
+
+    /// Seconds since the key was last read or written, for `OBJECT
+    /// IDLETIME`.
+    pub fn idle_time(&self, key: &str, now: u64) -> Option<u64> {
+        let stats = self.keys.get(key)?;
+        Some(now.saturating_sub(stats.access) / 1000)
+    }
+
+    /// The logarithmic count of accesses to the key, for `OBJECT FREQ`.
+    pub fn frequency(&self, key: &str, now: u64) -> Option<u8> {
+        Some(self.keys.get(key)?.counter(now))
+    }
+
+    /// Bytes taken by the dataset, as far as the master knows.
+    pub fn used(&self) -> u64 {
+        self.used
+    }
+
+    /// How many keys were evicted so far.
+    pub fn evicted(&self) -> u64 {
+        self.evicted
+    }
+
+    /// Counts victims once replicas committed their eviction.
+    pub fn count_evicted(&mut self, count: usize) {
+        self.evicted += count as u64;
+    }
+
+    /// Whether the writes would be refused, as they may add data while
+    /// the dataset is over `maxmemory`.
+    pub fn refuses(&self, cmds: &[RespValue]) -> bool {
+        self.maxmemory > 0
+            && self.used > self.maxmemory
+            && cmds.iter().any(|resp| {
+                resp.is_write() && !resp.verb().is_some_and(|v| FREEING_COMMANDS.contains(&v))
+            })
+    }
+
+    /// The keys to evict to get back under `maxmemory`, as many as the
+    /// policy allows if that's not enough.
+    pub fn victims(&mut self, now: u64) -> Vec<String> {
+        let mut victims = Vec::new();
+        let mut used = self.used;
+        while used > self.maxmemory {
+            let Some(key) = self.pick(now, &victims) else {
+                break;
+            };
+            used -= self.keys[&key].size;
+            victims.push(key);
+        }
+        victims
+    }
+
+    /// A random key, among those with a TTL if the policy says so.
+    fn sample(&mut self) -> Option<String> {
+        let len = match self.policy.volatile() {
+            true => self.volatile.len(),
+            false => self.keys.len(),
+        };
+        if len == 0 {
+            return None;
+        }
+        let i = (self.random() % len as u64) as usize;
+        let key = match self.policy.volatile() {
+            true => self.volatile.get_index(i),
+            false => self.keys.get_index(i).map(|(k, _)| k),
+        };
+        key.cloned()
+    }
+
+    /// How much the key deserves to go, the higher the more.
+    fn score(&self, key: &str, now: u64) -> u64 {
+        let stats = &self.keys[key];
+        match self.policy {
+            Policy::AllKeysLru | Policy::VolatileLru => now.saturating_sub(stats.access),
+            Policy::AllKeysLfu | Policy::VolatileLfu => (u8::MAX - stats.counter(now)) as u64,
+            Policy::VolatileTtl => u64::MAX - stats.deadline.unwrap_or(u64::MAX),
+            Policy::NoEviction | Policy::AllKeysRandom | Policy::VolatileRandom => 0,
+        }
+    }
+
+    /// The next key to evict, other than those already picked, sampling
+    /// keys into the pool of candidates, then taking the best of those.
+    fn pick(&mut self, now: u64, picked: &[String]) -> Option<String> {
+        let live = |evictor: &Evictor, key: &String| {
+            !picked.contains(key)
+                && match evictor.policy.volatile() {
+                    true => evictor.volatile.contains(key),
+                    false => evictor.keys.contains_key(key),
+                }
+        };
+        match self.policy {
+            Policy::NoEviction => return None,
+            Policy::AllKeysRandom | Policy::VolatileRandom => {
+                // Unless most keys are picked already, this finds one soon
+                for _ in 0..self.samples.max(1) * 4 {
+                    let key = self.sample()?;
+                    if live(self, &key) {
+                        return Some(key);
+                    }
+                }
+                return None;
+            }
+            _ => (),
+        }
+
+        for _ in 0..self.samples.max(1) {
+            let Some(key) = self.sample() else {
+                break;
+            };
+            if self.pool.iter().any(|(_, k)| *k == key) {
+                continue;
+            }
+            let score = self.score(&key, now);
+            let i = self.pool.partition_point(|(s, _)| *s < score);
+            self.pool.insert(i, (score, key));
+            if self.pool.len() > POOL_SIZE {
+                self.pool.remove(0);
+            }
+        }
+        // Candidates may have gone since they were sampled
+        while let Some((_, key)) = self.pool.pop() {
+            if live(self, &key) {
+                return Some(key);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage(key: &str, size: u64, deadline: Option<u64>) -> KeyUsage {
+        KeyUsage {
+            key: key.into(),
+            size: Some(size),
+            deadline,
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("allkeys-lru".parse(), Ok(Policy::AllKeysLru));
+        assert_eq!(Policy::VolatileTtl.to_string(), "volatile-ttl");
+        assert!("lru".parse::<Policy>().is_err());
+        assert_eq!(parse_bytes("100"), Ok(100));
+        assert_eq!(parse_bytes("1kb"), Ok(1024));
+        assert_eq!(parse_bytes("2MB"), Ok(2 << 20));
+        assert!(parse_bytes("1tb").is_err());
+        assert!(parse_bytes("mb").is_err());
+    }
+
+    #[test]
+    fn test_evict() {
+        let set = RespValue::array(&["SET", "k", "v"]);
+        let del = RespValue::array(&["DEL", "k"]);
+        let mut evictor = Evictor::new(250, Policy::NoEviction, 5);
+        evictor.update(
+            vec![
+                usage("old", 100, Some(9000)),
+                usage("new", 100, None),
+                usage("soon", 100, Some(5000)),
+            ],
+            0,
+        );
+        assert_eq!(evictor.used(), 300);
+        assert!(evictor.refuses(std::slice::from_ref(&set)));
+        assert!(!evictor.refuses(&[del]));
+        assert!(evictor.victims(0).is_empty());
+
+        // Sampled until every key was seen, as there are only three
+        evictor.samples = 64;
+        evictor.set_policy(Policy::AllKeysLru);
+        evictor.touch("new", 1000);
+        evictor.touch("soon", 2000);
+        assert_eq!(evictor.idle_time("new", 3000), Some(2));
+        assert_eq!(evictor.idle_time("nope", 3000), None);
+        assert_eq!(evictor.frequency("new", 3000), Some(LFU_INIT + 1));
+        assert_eq!(
+            evictor.frequency("new", 3000 + 2 * LFU_DECAY_MS),
+            Some(LFU_INIT - 1)
+        );
+        assert_eq!(evictor.victims(3000), ["old"]);
+
+        evictor.set_policy(Policy::VolatileTtl);
+        assert_eq!(evictor.victims(3000), ["soon"]);
+
+        evictor.set_policy(Policy::AllKeysLfu);
+        for _ in 0..100 {
+            evictor.touch("old", 3000);
+            evictor.touch("soon", 3000);
+        }
+        assert_eq!(evictor.victims(3000), ["new"]);
+
+        // Evicted, as a replica says once it's done
+        evictor.update(
+            vec![KeyUsage {
+                key: "new".into(),
+                size: None,
+                deadline: None,
+            }],
+            3000,
+        );
+        evictor.count_evicted(1);
+        assert!(!evictor.refuses(&[set]));
+        assert_eq!(evictor.evicted(), 1);
+
+        evictor.maxmemory = 50;
+        evictor.set_policy(Policy::VolatileRandom);
+        let mut victims = evictor.victims(3000);
+        victims.sort();
+        assert_eq!(victims, ["old", "soon"]);
+
+        evictor.reset(vec![usage("persistent", 100, None)], 3000);
+        assert!(evictor.victims(3000).is_empty());
+    }
+}
@@ -0,0 +1,628 @@
+use std::{collections::HashSet, vec::IntoIter};
+
+use crate::{
+    backend::Backend,
+    command::{int, strings, CommandError},
+    map::KvStore,
+    resp::RespValue,
+    zset::SortedSet,
+};
+
+/// Bits per coordinate in the geohashes used as scores, 52 bits in total
+/// so that they're exact as doubles.
+const STEP: u32 = 26;
+
+/// Limits of EPSG:3785, as in Redis. Points closer to the poles can't be
+/// indexed.
+const LAT_MIN: f64 = -85.05112878;
+const LAT_MAX: f64 = 85.05112878;
+const LON_MIN: f64 = -180.0;
+const LON_MAX: f64 = 180.0;
+
+/// Earth's quadratic mean radius for WGS-84, in meters.
+const EARTH_RADIUS: f64 = 6372797.560856;
+
+const BASE32: &[u8] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+
+/// Spreads the bits of `x` over the even bits and `y` over the odd bits.
+fn interleave(x: u32, y: u32) -> u64 {
+    fn spread(v: u32) -> u64 {
+        let mut v = v as u64;
+        v = (v | (v << 16)) & 0x0000FFFF0000FFFF;
+        v = (v | (v << 8)) & 0x00FF00FF00FF00FF;
+        v = (v | (v << 4)) & 0x0F0F0F0F0F0F0F0F;
+        v = (v | (v << 2)) & 0x3333333333333333;
+        v = (v | (v << 1)) & 0x5555555555555555;
+        v
+    }
+    spread(x) | (spread(y) << 1)
+}
+
+fn deinterleave(bits: u64) -> (u32, u32) {
+    fn squash(mut v: u64) -> u32 {
+        v &= 0x5555555555555555;
+        v = (v | (v >> 1)) & 0x3333333333333333;
+        v = (v | (v >> 2)) & 0x0F0F0F0F0F0F0F0F;
+        v = (v | (v >> 4)) & 0x00FF00FF00FF00FF;
+        v = (v | (v >> 8)) & 0x0000FFFF0000FFFF;
+        v = (v | (v >> 16)) & 0x00000000FFFFFFFF;
+        v as u32
+    }
+    (squash(bits), squash(bits >> 1))
+}
+
+/// The cell containing the point, as latitude and longitude indexes out of
+/// `2^step`.
+fn cell(lon: f64, lat: f64, step: u32, (lat_min, lat_max): (f64, f64)) -> (u32, u32) {
+    let cells = (1u64 << step) as f64;
+    let max = (1u32 << step) - 1;
+    let lat_index = ((lat - lat_min) / (lat_max - lat_min) * cells) as u32;
+    let lon_index = ((lon - LON_MIN) / (LON_MAX - LON_MIN) * cells) as u32;
+    (lat_index.min(max), lon_index.min(max))
+}
+
+/// The geohash of a point, which is the score it's stored with.
+fn encode(lon: f64, lat: f64) -> u64 {
+    let (lat_index, lon_index) = cell(lon, lat, STEP, (LAT_MIN, LAT_MAX));
+    interleave(lat_index, lon_index)
+}
+
+/// The center of the cell of a geohash.
+fn decode(bits: u64) -> (f64, f64) {
+    let (lat_index, lon_index) = deinterleave(bits);
+    let cells = (1u64 << STEP) as f64;
+    let lat = LAT_MIN + (lat_index as f64 + 0.5) * (LAT_MAX - LAT_MIN) / cells;
+    let lon = LON_MIN + (lon_index as f64 + 0.5) * (LON_MAX - LON_MIN) / cells;
+    (lon.clamp(LON_MIN, LON_MAX), lat.clamp(LAT_MIN, LAT_MAX))
+}
+
+/// The standard 11 character geohash, which spans latitudes -90 to 90
+/// unlike the scores.
+fn geohash_string(lon: f64, lat: f64) -> String {
+    let (lat_index, lon_index) = cell(lon, lat, STEP, (-90.0, 90.0));
+    let bits = interleave(lat_index, lon_index);
+    (0..11)
+        .map(|i| {
+            // 52 bits make 10 characters and a bit, the rest is padding
+            let index = if i == 10 {
+                0
+            } else {
+                (bits >> (52 - (i + 1) * 5)) & 0x1f
+            };
+            BASE32[index as usize] as char
+        })
+        .collect()
+}
+
+/// Great-circle distance in meters, using the haversine formula.
+fn distance(lon1: f64, lat1: f64, lon2: f64, lat2: f64) -> f64 {
+    let (lat1, lat2) = (lat1.to_radians(), lat2.to_radians());
+    let u = ((lat2 - lat1) / 2.0).sin();
+    let v = ((lon2 - lon1).to_radians() / 2.0).sin();
+    2.0 * EARTH_RADIUS * (u * u + lat1.cos() * lat2.cos() * v * v).sqrt().asin()
+}
+
+fn unit(s: &str) -> Result<f64, CommandError> {
+    match s.to_lowercase().as_str() {
+        "m" => Ok(1.0),
+        "km" => Ok(1000.0),
+        "ft" => Ok(0.3048),
+        "mi" => Ok(1609.34),
+        _ => Err(CommandError::InvalidCommand),
+    }
+}
+
+fn coord(f: f64) -> RespValue {
+    RespValue::BulkString(f.to_string())
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Origin {
+    Member(String),
+    LonLat(f64, f64),
+}
+
+/// The area searched around the origin, in meters.
+#[derive(Debug, PartialEq)]
+pub enum Shape {
+    Radius(f64),
+    Box(f64, f64),
+}
+
+impl Shape {
+    /// Distance from the origin to the point if it's within the shape.
+    fn distance(&self, (lon, lat): (f64, f64), (plon, plat): (f64, f64)) -> Option<f64> {
+        match *self {
+            Shape::Radius(radius) => Some(distance(lon, lat, plon, plat)).filter(|&d| d <= radius),
+            Shape::Box(width, height) => {
+                let lat_distance = EARTH_RADIUS * (plat - lat).to_radians().abs();
+                let lon_distance = distance(plon, plat, lon, plat);
+                if lat_distance > height / 2.0 || lon_distance > width / 2.0 {
+                    return None;
+                }
+                Some(distance(lon, lat, plon, plat))
+            }
+        }
+    }
+
+    /// Half the height and width of the shape, in degrees of latitude and
+    /// longitude around `lat`.
+    fn deltas(&self, lat: f64) -> (f64, f64) {
+        let (width, height) = match *self {
+            Shape::Radius(radius) => (radius * 2.0, radius * 2.0),
+            Shape::Box(width, height) => (width, height),
+        };
+        let lat_delta = (height / 2.0 / EARTH_RADIUS).to_degrees();
+        // Widest on the side closer to a pole
+        let lon_delta =
+            |lat: f64| (width / 2.0 / EARTH_RADIUS / lat.to_radians().cos()).to_degrees();
+        let lon_delta = lon_delta(lat + lat_delta).max(lon_delta(lat - lat_delta));
+        (lat_delta, lon_delta)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct GeoQuery {
+    pub origin: Origin,
+    pub shape: Shape,
+    /// Meters per unit the shape was given in, to reply distances in.
+    pub unit: f64,
+    /// Ascending, descending, or in no particular order.
+    pub order: Option<bool>,
+    /// At most this many results, or with `any` the first this many found.
+    pub count: Option<(usize, bool)>,
+    pub with_coord: bool,
+    pub with_dist: bool,
+    pub with_hash: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum GeoCommand {
+    Add {
+        key: String,
+        points: Vec<(f64, f64, String)>,
+        nx: bool,
+        xx: bool,
+        ch: bool,
+    },
+    Pos(String, Vec<String>),
+    Dist(String, String, String, f64),
+    Hash(String, Vec<String>),
+    Search(String, GeoQuery),
+    SearchStore {
+        dest: String,
+        src: String,
+        query: GeoQuery,
+        store_dist: bool,
+    },
+}
+
+impl GeoCommand {
+    pub fn keys(&self) -> Vec<&str> {
+        use GeoCommand::*;
+        match self {
+            Add { key, .. } | Pos(key, _) | Dist(key, ..) | Hash(key, _) | Search(key, _) => {
+                vec![key.as_str()]
+            }
+            SearchStore { dest, src, .. } => vec![dest.as_str(), src.as_str()],
+        }
+    }
+}
+
+/// Parses the arguments of `GEOADD`, `GEOPOS`, `GEODIST`, `GEOHASH`,
+/// `GEOSEARCH` and `GEOSEARCHSTORE`.
+pub fn parse(verb: &str, arr: IntoIter<RespValue>) -> Result<GeoCommand, CommandError> {
+    let args = strings(arr)?;
+    let cmd = match (verb, args.as_slice()) {
+        ("GEOADD", [key, rest @ ..]) => {
+            let flags = rest
+                .iter()
+                .take_while(|a| ["NX", "XX", "CH"].contains(&a.to_uppercase().as_str()))
+                .map(|a| a.to_uppercase())
+                .collect::<Vec<_>>();
+            let triples = &rest[flags.len()..];
+            if triples.is_empty() || triples.len() % 3 != 0 {
+                return Err(CommandError::InvalidCommand);
+            }
+            let points = triples
+                .chunks(3)
+                .map(|t| Ok((int(&t[0])?, int(&t[1])?, t[2].clone())))
+                .collect::<Result<_, CommandError>>()?;
+            let has = |flag: &str| flags.iter().any(|f| f == flag);
+            if has("NX") && has("XX") {
+                return Err(CommandError::InvalidCommand);
+            }
+            GeoCommand::Add {
+                key: key.clone(),
+                points,
+                nx: has("NX"),
+                xx: has("XX"),
+                ch: has("CH"),
+            }
+        }
+        ("GEOPOS", [key, members @ ..]) => GeoCommand::Pos(key.clone(), members.to_vec()),
+        ("GEODIST", [key, m1, m2, u @ ..]) if u.len() <= 1 => {
+            let unit = u.first().map_or(Ok(1.0), |u| unit(u))?;
+            GeoCommand::Dist(key.clone(), m1.clone(), m2.clone(), unit)
+        }
+        ("GEOHASH", [key, members @ ..]) => GeoCommand::Hash(key.clone(), members.to_vec()),
+        ("GEOSEARCH", [key, options @ ..]) => {
+            let (query, store_dist) = geo_query(options)?;
+            if store_dist {
+                return Err(CommandError::InvalidCommand);
+            }
+            GeoCommand::Search(key.clone(), query)
+        }
+        ("GEOSEARCHSTORE", [dest, src, options @ ..]) => {
+            let (query, store_dist) = geo_query(options)?;
+            if query.with_coord || query.with_dist || query.with_hash {
+                return Err(CommandError::InvalidCommand);
+            }
+            GeoCommand::SearchStore {
+                dest: dest.clone(),
+                src: src.clone(),
+                query,
+                store_dist,
+            }
+        }
+        _ => return Err(CommandError::InvalidCommand),
+    };
+    Ok(cmd)
+}
+
+/// Parses the options of `GEOSEARCH`, and whether `STOREDIST` was given.
+fn geo_query(options: &[String]) -> Result<(GeoQuery, bool), CommandError> {
+    let mut origin = None;
+    let mut shape = None;
+    let mut query_unit = 1.0;
+    let mut order = None;
+    let mut count = None;
+    let (mut with_coord, mut with_dist, mut with_hash, mut store_dist) =
+        (false, false, false, false);
+
+    let mut options = options.iter().peekable();
+    while let Some(option) = options.next() {
+        let mut next = || options.next().ok_or(CommandError::InvalidCommand);
+        match option.to_uppercase().as_str() {
+            "FROMMEMBER" if origin.is_none() => origin = Some(Origin::Member(next()?.clone())),
+            "FROMLONLAT" if origin.is_none() => {
+                origin = Some(Origin::LonLat(int(next()?)?, int(next()?)?))
+            }
+            "BYRADIUS" if shape.is_none() => {
+                let radius: f64 = int(next()?)?;
+                query_unit = unit(next()?)?;
+                shape = Some(Shape::Radius(radius * query_unit));
+            }
+            "BYBOX" if shape.is_none() => {
+                let (width, height): (f64, f64) = (int(next()?)?, int(next()?)?);
+                query_unit = unit(next()?)?;
+                shape = Some(Shape::Box(width * query_unit, height * query_unit));
+            }
+            "ASC" => order = Some(true),
+            "DESC" => order = Some(false),
+            "COUNT" => {
+                let n: usize = int(next()?)?;
+                if n == 0 {
+                    return Err(CommandError::InvalidCommand);
+                }
+                let any = options.next_if(|o| o.eq_ignore_ascii_case("ANY")).is_some();
+                count = Some((n, any));
+            }
+            "WITHCOORD" => with_coord = true,
+            "WITHDIST" => with_dist = true,
+            "WITHHASH" => with_hash = true,
+            "STOREDIST" => store_dist = true,
+            _ => return Err(CommandError::InvalidCommand),
+        }
+    }
+
+    let (Some(origin), Some(shape)) = (origin, shape) else {
+        return Err(CommandError::InvalidCommand);
+    };
+    // Like Redis, the closest results are picked unless asked for any
+    if order.is_none() && matches!(count, Some((_, false))) {
+        order = Some(true);
+    }
+    let query = GeoQuery {
+        origin,
+        shape,
+        unit: query_unit,
+        order,
+        count,
+        with_coord,
+        with_dist,
+        with_hash,
+    };
+    Ok((query, store_dist))
+}
+
+/// A member found by a search.
+struct Found<'a> {
+    member: &'a str,
+    score: f64,
+    distance: f64,
+}
+
+/// Members of the set within the shape around `(lon, lat)`.
+///
+/// Picks the smallest cells that are at least as big as the shape, so
+/// that it's covered by the cell of its center and the 8 around it, and
+/// only looks at the members in those 9 score ranges.
+fn search<'a>(set: &'a SortedSet, (lon, lat): (f64, f64), query: &GeoQuery) -> Vec<Found<'a>> {
+    let (lat_delta, lon_delta) = query.shape.deltas(lat);
+    let mut step = STEP;
+    while step > 1 {
+        let cells = (1u64 << step) as f64;
+        if (LAT_MAX - LAT_MIN) / cells >= lat_delta && (LON_MAX - LON_MIN) / cells >= lon_delta {
+            break;
+        }
+        step -= 1;
+    }
+
+    let (lat_index, lon_index) = cell(lon, lat, step, (LAT_MIN, LAT_MAX));
+    let cells = 1i64 << step;
+    let mut ranges = HashSet::new();
+    for dlat in -1..=1 {
+        let lat_index = lat_index as i64 + dlat;
+        if !(0..cells).contains(&lat_index) {
+            continue;
+        }
+        for dlon in -1..=1 {
+            // Longitudes wrap around
+            let lon_index = (lon_index as i64 + dlon).rem_euclid(cells);
+            ranges.insert(interleave(lat_index as u32, lon_index as u32));
+        }
+    }
+    let mut ranges: Vec<u64> = ranges.into_iter().collect();
+    ranges.sort();
+
+    let shift = 2 * (STEP - step);
+    let mut found = Vec::new();
+    for bits in ranges {
+        let (min, max) = ((bits << shift) as f64, ((bits + 1) << shift) as f64);
+        for (member, score) in set.range(min, max) {
+            if let Some(distance) = query.shape.distance((lon, lat), decode(score as u64)) {
+                found.push(Found {
+                    member,
+                    score,
+                    distance,
+                });
+                if matches!(query.count, Some((n, true)) if found.len() == n) {
+                    return found;
+                }
+            }
+        }
+    }
+    found
+}
+
+impl<T> Backend<T>
+where
+    T: KvStore,
+{
+    fn position(&self, key: &str, member: &str) -> Result<Option<(f64, f64)>, RespValue> {
+        let score = self.zset(key)?.and_then(|set| set.score(member));
+        Ok(score.map(|score| decode(score as u64)))
+    }
+
+    pub(crate) fn process_geo(&mut self, cmd: GeoCommand) -> RespValue {
+        use GeoCommand::*;
+        match cmd {
+            Add {
+                key,
+                points,
+                nx,
+                xx,
+                ch,
+            } => self.process_geoadd(&key, points, nx, xx, ch),
+            Pos(key, members) => members
+                .iter()
+                .map(|m| {
+                    Ok(match self.position(&key, m)? {
+                        Some((lon, lat)) => RespValue::Array(vec![coord(lon), coord(lat)]),
+                        None => RespValue::Null,
+                    })
+                })
+                .collect::<Result<_, _>>()
+                .map(RespValue::Array),
+            Dist(key, m1, m2, unit) => self.position(&key, &m1).and_then(|p1| {
+                let p2 = self.position(&key, &m2)?;
+                Ok(match (p1, p2) {
+                    (Some((lon1, lat1)), Some((lon2, lat2))) => {
+                        let d = distance(lon1, lat1, lon2, lat2) / unit;
+                        RespValue::BulkString(format!("{:.4}", d))
+                    }
+                    _ => RespValue::Null,
+                })
+            }),
+            Hash(key, members) => members
+                .iter()
+                .map(|m| {
+                    Ok(match self.position(&key, m)? {
+                        Some((lon, lat)) => RespValue::BulkString(geohash_string(lon, lat)),
+                        None => RespValue::Null,
+                    })
+                })
+                .collect::<Result<_, _>>()
+                .map(RespValue::Array),
+            Search(key, query) => self.process_geosearch(&key, &query),
+            SearchStore {
+                dest,
+                src,
+                query,
+                store_dist,
+            } => self.process_geosearchstore(&dest, &src, &query, store_dist),
+        }
+        .unwrap_or_else(|e| e)
+    }
+
+    fn process_geoadd(
+        &mut self,
+        key: &str,
+        points: Vec<(f64, f64, String)>,
+        nx: bool,
+        xx: bool,
+        ch: bool,
+    ) -> Result<RespValue, RespValue> {
+        if let Some(&(lon, lat, _)) = points.iter().find(|&&(lon, lat, _)| {
+            !(LON_MIN..=LON_MAX).contains(&lon) || !(LAT_MIN..=LAT_MAX).contains(&lat)
+        }) {
+            return Err(RespValue::Error(format!(
+                "ERR invalid longitude,latitude pair {:.6},{:.6}",
+                lon, lat
+            )));
+        }
+        if xx && self.zset(key)?.is_none() {
+            return Ok(RespValue::Integer(0));
+        }
+
+        let set = self.zset_mut(key)?;
+        let (mut added, mut changed) = (0, 0);
+        for (lon, lat, member) in points {
+            let score = encode(lon, lat) as f64;
+            match set.score(&member) {
+                Some(_) if nx => continue,
+                None if xx => continue,
+                Some(old) if old == score => continue,
+                Some(_) => changed += 1,
+                None => added += 1,
+            }
+            set.insert(&member, score);
+        }
+
+        if added + changed > 0 {
+            self.notify('z', "zadd", key);
+        }
+        Ok(RespValue::Integer(if ch { added + changed } else { added }))
+    }
+
+    /// Members found by the query, sorted and cut to the count asked for.
+    fn geo_results<'a>(&'a self, key: &str, query: &GeoQuery) -> Result<Vec<Found<'a>>, RespValue> {
+        let Some(set) = self.zset(key)? else {
+            return Ok(Vec::new());
+        };
+        let origin = match &query.origin {
+            Origin::LonLat(lon, lat) => (*lon, *lat),
+            Origin::Member(m) => match set.score(m) {
+                Some(score) => decode(score as u64),
+                None => {
+                    return Err(RespValue::Error(
+                        "ERR could not decode requested zset member".into(),
+                    ))
+                }
+            },
+        };
+
+        let mut found = search(set, origin, query);
+        match query.order {
+            Some(true) => found.sort_by(|a, b| a.distance.total_cmp(&b.distance)),
+            Some(false) => found.sort_by(|a, b| b.distance.total_cmp(&a.distance)),
+            None => (),
+        }
+        if let Some((n, _)) = query.count {
+            found.truncate(n);
+        }
+        Ok(found)
+    }
+
+    fn process_geosearch(&self, key: &str, query: &GeoQuery) -> Result<RespValue, RespValue> {
+        let found = self.geo_results(key, query)?;
+        let plain = !(query.with_coord || query.with_dist || query.with_hash);
+        Ok(RespValue::Array(
+            found
+                .into_iter()
+                .map(|f| {
+                    let member = RespValue::BulkString(f.member.to_string());
+                    if plain {
+                        return member;
+                    }
+                    let mut item = vec![member];
+                    if query.with_dist {
+                        item.push(RespValue::BulkString(format!(
+                            "{:.4}",
+                            f.distance / query.unit
+                        )));
+                    }
+                    if query.with_hash {
+                        item.push(RespValue::Integer(f.score as i64));
+                    }
+                    if query.with_coord {
+                        let (lon, lat) = decode(f.score as u64);
+                        item.push(RespValue::Array(vec![coord(lon), coord(lat)]));
+                    }
+                    RespValue::Array(item)
+                })
+                .collect(),
+        ))
+    }
+
+    fn process_geosearchstore(
+        &mut self,
+        dest: &str,
+        src: &str,
+        query: &GeoQuery,
+        store_dist: bool,
+    ) -> Result<RespValue, RespValue> {
+        let mut set = SortedSet::default();
+        for f in self.geo_results(src, query)? {
+            let score = if store_dist {
+                f.distance / query.unit
+            } else {
+                f.score
+            };
+            set.insert(f.member, score);
+        }
+
+        let len = set.len();
+        self.store_zset(dest, set, "geosearchstore")?;
+        Ok(RespValue::Integer(len as i64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_geohash() {
+        // Palermo, from the Redis docs
+        let (lon, lat) = decode(encode(13.361389, 38.115556));
+        assert!((lon - 13.361389).abs() < 1e-5 && (lat - 38.115556).abs() < 1e-5);
+        assert_eq!(encode(13.361389, 38.115556), 3479099956230698);
+        assert_eq!(geohash_string(lon, lat), "sqc8b49rny0");
+
+        assert_eq!(
+            deinterleave(interleave(0x3ffffff, 12345)),
+            (0x3ffffff, 12345)
+        );
+    }
+
+    #[test]
+    fn test_geo_search() {
+        let mut set = SortedSet::default();
+        set.insert("Palermo", encode(13.361389, 38.115556) as f64);
+        set.insert("Catania", encode(15.087269, 37.502669) as f64);
+        set.insert("Edge", encode(179.9, 0.0) as f64);
+
+        let (lon, lat) = decode(encode(13.361389, 38.115556));
+        let (lon2, lat2) = decode(encode(15.087269, 37.502669));
+        assert!((distance(lon, lat, lon2, lat2) - 166274.1516).abs() < 0.01);
+
+        let options = ["FROMLONLAT", "15", "37", "BYRADIUS", "200", "km", "ASC"];
+        let (query, _) = geo_query(&options.map(String::from)).unwrap();
+        let found: Vec<_> = search(&set, (15.0, 37.0), &query)
+            .iter()
+            .map(|f| f.member)
+            .collect();
+        assert_eq!(found.len(), 2);
+
+        // Cells on the other side of the antimeridian are searched too
+        let query = GeoQuery {
+            shape: Shape::Radius(50_000.0),
+            ..query
+        };
+        let found = search(&set, (-179.9, 0.0), &query);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].member, "Edge");
+    }
+}
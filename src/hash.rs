@@ -0,0 +1,470 @@
+use std::{
+    cmp::Ordering,
+    collections::{btree_map, BTreeMap},
+    vec::IntoIter,
+};
+
+use serde::{Serialize, Serializer};
+use serde_derive::Deserialize;
+
+use crate::{
+    backend::Backend,
+    command::{int, strings, CommandError},
+    encoding::{
+        Listpack, ListpackIter, ALLOC_OVERHEAD, ELEMENT_OVERHEAD, HASH_MAX_LISTPACK_ENTRIES,
+        HASH_MAX_LISTPACK_VALUE,
+    },
+    map::KvStore,
+    resp::RespValue,
+    value::{Value, WRONGTYPE},
+};
+
+/// Fields of a hash, ordered so every replica lists them alike. Small
+/// hashes are packed into a listpack of fields and values, which becomes
+/// a tree once there are too many fields or a field or value too long.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(from = "BTreeMap<String, String>")]
+pub enum Hash {
+    Packed(Listpack),
+    Table(BTreeMap<String, String>),
+}
+
+/// Fields and values only ever come from strings.
+fn utf8(bytes: &[u8]) -> &str {
+    std::str::from_utf8(bytes).expect("hash fields and values are strings")
+}
+
+/// Whether a field and its value can stay in a listpack.
+fn fits(field: &str, value: &str) -> bool {
+    let max = HASH_MAX_LISTPACK_VALUE.get();
+    field.len() <= max && value.len() <= max
+}
+
+/// The index of the pair of the field in the listpack, or of the pair it
+/// would go before.
+fn find(listpack: &Listpack, field: &str) -> Result<usize, usize> {
+    let mut entries = listpack.iter().step_by(2).enumerate();
+    entries
+        .find_map(|(i, f)| match f.cmp(field.as_bytes()) {
+            Ordering::Less => None,
+            Ordering::Equal => Some(Ok(i)),
+            Ordering::Greater => Some(Err(i)),
+        })
+        .unwrap_or(Err(listpack.len() / 2))
+}
+
+impl Hash {
+    pub fn new() -> Hash {
+        Hash::Packed(Listpack::default())
+    }
+
+    pub fn get(&self, field: &str) -> Option<&str> {
+        match self {
+            Hash::Packed(listpack) => {
+                let i = find(listpack, field).ok()?;
+                listpack.iter().nth(2 * i + 1).map(utf8)
+            }
+            Hash::Table(fields) => fields.get(field).map(String::as_str),
+        }
+    }
+
+    pub fn contains_key(&self, field: &str) -> bool {
+        self.get(field).is_some()
+    }
+
+    /// Sets the field, replying with its old value if it had one.
+    pub fn insert(&mut self, field: String, value: String) -> Option<String> {
+        if let Hash::Packed(listpack) = self {
+            match find(listpack, &field) {
+                Ok(i) if fits(&field, &value) => {
+                    let old = listpack.iter().nth(2 * i + 1).map(|v| utf8(v).to_string());
+                    listpack.remove(2 * i + 1);
+                    listpack.insert(2 * i + 1, value.as_bytes());
+                    return old;
+                }
+                Err(i)
+                    if fits(&field, &value)
+                        && listpack.len() / 2 < HASH_MAX_LISTPACK_ENTRIES.get() =>
+                {
+                    listpack.insert(2 * i, field.as_bytes());
+                    listpack.insert(2 * i + 1, value.as_bytes());
+                    return None;
+                }
+                _ => *self = Hash::Table(self.iter().map(|(f, v)| (f.into(), v.into())).collect()),
+            }
+        }
+        match self {
+            Hash::Table(fields) => fields.insert(field, value),
+            Hash::Packed(_) => unreachable!(),
+        }
+    }
+
+    /// Removes the field, replying with its value if it had one.
+    pub fn remove(&mut self, field: &str) -> Option<String> {
+        match self {
+            Hash::Packed(listpack) => {
+                let i = find(listpack, field).ok()?;
+                let old = listpack.iter().nth(2 * i + 1).map(|v| utf8(v).to_string());
+                listpack.remove(2 * i + 1);
+                listpack.remove(2 * i);
+                old
+            }
+            Hash::Table(fields) => fields.remove(field),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Hash::Packed(listpack) => listpack.len() / 2,
+            Hash::Table(fields) => fields.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Fields and their values, by field.
+    pub fn iter(&self) -> Iter<'_> {
+        match self {
+            Hash::Packed(listpack) => Iter::Packed(listpack.iter()),
+            Hash::Table(fields) => Iter::Table(fields.iter()),
+        }
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.iter().map(|(field, _)| field)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &str> {
+        self.iter().map(|(_, value)| value)
+    }
+
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            Hash::Packed(_) => "listpack",
+            Hash::Table(_) => "hashtable",
+        }
+    }
+
+    pub fn allocated(&self) -> u64 {
+        match self {
+            Hash::Packed(listpack) => listpack.allocated(),
+            Hash::Table(fields) => fields
+                .iter()
+                .map(|(f, v)| (f.len() + v.len()) as u64 + 2 * (ALLOC_OVERHEAD + ELEMENT_OVERHEAD))
+                .sum(),
+        }
+    }
+}
+
+impl Default for Hash {
+    fn default() -> Self {
+        Hash::new()
+    }
+}
+
+impl From<BTreeMap<String, String>> for Hash {
+    fn from(fields: BTreeMap<String, String>) -> Self {
+        let small = fields.len() <= HASH_MAX_LISTPACK_ENTRIES.get()
+            && fields.iter().all(|(f, v)| fits(f, v));
+        if !small {
+            return Hash::Table(fields);
+        }
+        // Already in order, so pushed rather than looked up
+        let mut listpack = Listpack::default();
+        for (field, value) in fields.iter() {
+            listpack.push(field.as_bytes());
+            listpack.push(value.as_bytes());
+        }
+        Hash::Packed(listpack)
+    }
+}
+
+impl<const N: usize> From<[(String, String); N]> for Hash {
+    fn from(pairs: [(String, String); N]) -> Self {
+        pairs.into_iter().collect()
+    }
+}
+
+impl FromIterator<(String, String)> for Hash {
+    fn from_iter<I: IntoIterator<Item = (String, String)>>(iter: I) -> Self {
+        let mut hash = Hash::new();
+        for (field, value) in iter {
+            hash.insert(field, value);
+        }
+        hash
+    }
+}
+
+impl PartialEq for Hash {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && self.iter().eq(other.iter())
+    }
+}
+
+// Serialized as a map whatever the encoding, as it always was
+impl Serialize for Hash {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(self.iter())
+    }
+}
+
+pub enum Iter<'a> {
+    Packed(ListpackIter<'a>),
+    Table(btree_map::Iter<'a, String, String>),
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'a str, &'a str);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Iter::Packed(entries) => Some((utf8(entries.next()?), utf8(entries.next()?))),
+            Iter::Table(fields) => fields.next().map(|(f, v)| (f.as_str(), v.as_str())),
+        }
+    }
+}
+
+impl<'a> IntoIterator for &'a Hash {
+    type Item = (&'a str, &'a str);
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum HashCommand {
+    Set(String, Vec<(String, String)>),
+    Get(String, String),
+    MGet(String, Vec<String>),
+    Del(String, Vec<String>),
+    GetAll(String),
+    Keys(String),
+    Vals(String),
+    Len(String),
+    Exists(String, String),
+    IncrBy(String, String, i64),
+}
+
+impl HashCommand {
+    pub fn keys(&self) -> Vec<&str> {
+        use HashCommand::*;
+        match self {
+            Set(k, _)
+            | Get(k, _)
+            | MGet(k, _)
+            | Del(k, _)
+            | GetAll(k)
+            | Keys(k)
+            | Vals(k)
+            | Len(k)
+            | Exists(k, _)
+            | IncrBy(k, ..) => vec![k.as_str()],
+        }
+    }
+}
+
+/// Parses the arguments of `HSET`, `HMSET`, `HGET`, `HMGET`, `HDEL`,
+/// `HGETALL`, `HKEYS`, `HVALS`, `HLEN`, `HEXISTS` and `HINCRBY`.
+pub fn parse(verb: &str, arr: IntoIter<RespValue>) -> Result<HashCommand, CommandError> {
+    let args = strings(arr)?;
+    let cmd = match (verb, args.as_slice()) {
+        ("HSET" | "HMSET", [k, pairs @ ..]) if !pairs.is_empty() && pairs.len() % 2 == 0 => {
+            let pairs = pairs
+                .chunks(2)
+                .map(|pair| (pair[0].clone(), pair[1].clone()))
+                .collect();
+            HashCommand::Set(k.clone(), pairs)
+        }
+        ("HGET", [k, field]) => HashCommand::Get(k.clone(), field.clone()),
+        ("HMGET", [k, fields @ ..]) if !fields.is_empty() => {
+            HashCommand::MGet(k.clone(), fields.to_vec())
+        }
+        ("HDEL", [k, fields @ ..]) if !fields.is_empty() => {
+            HashCommand::Del(k.clone(), fields.to_vec())
+        }
+        ("HGETALL", [k]) => HashCommand::GetAll(k.clone()),
+        ("HKEYS", [k]) => HashCommand::Keys(k.clone()),
+        ("HVALS", [k]) => HashCommand::Vals(k.clone()),
+        ("HLEN", [k]) => HashCommand::Len(k.clone()),
+        ("HEXISTS", [k, field]) => HashCommand::Exists(k.clone(), field.clone()),
+        ("HINCRBY", [k, field, by]) => HashCommand::IncrBy(k.clone(), field.clone(), int(by)?),
+        _ => return Err(CommandError::InvalidCommand),
+    };
+    Ok(cmd)
+}
+
+fn bulk_or_null(value: Option<&str>) -> RespValue {
+    match value {
+        Some(v) => RespValue::BulkString(v.to_string()),
+        None => RespValue::Null,
+    }
+}
+
+impl<T> Backend<T>
+where
+    T: KvStore,
+{
+    pub(crate) fn hash(&self, key: &str) -> Result<Option<&Hash>, RespValue> {
+        match self.value(key)? {
+            Some(Value::Hash(hash)) => Ok(Some(hash)),
+            Some(_) => Err(RespValue::Error(WRONGTYPE.into())),
+            None => Ok(None),
+        }
+    }
+
+    /// The hash at `key` for setting fields in it, created if missing.
+    fn hash_mut(&mut self, key: &str) -> Result<&mut Hash, RespValue> {
+        if self.value_mut(key)?.is_none() {
+            self.store.kv_put(key, Value::Hash(Hash::new()))?;
+        }
+        match self.store.kv_get_mut(key)? {
+            Some(Value::Hash(hash)) => Ok(hash),
+            _ => Err(RespValue::Error(WRONGTYPE.into())),
+        }
+    }
+
+    pub(crate) fn process_hash(&mut self, cmd: HashCommand) -> RespValue {
+        use HashCommand::*;
+        match cmd {
+            Set(k, pairs) => self.process_hset(&k, pairs),
+            Get(k, field) => self
+                .hash(&k)
+                .map(|hash| bulk_or_null(hash.and_then(|h| h.get(&field)))),
+            MGet(k, fields) => self.hash(&k).map(|hash| {
+                let values = fields
+                    .iter()
+                    .map(|field| bulk_or_null(hash.and_then(|h| h.get(field))))
+                    .collect();
+                RespValue::Array(values)
+            }),
+            Del(k, fields) => self.process_hdel(&k, &fields),
+            GetAll(k) => self.hash(&k).map(|hash| {
+                let pairs = hash.into_iter().flatten().flat_map(|(field, value)| {
+                    [
+                        RespValue::BulkString(field.to_string()),
+                        RespValue::BulkString(value.to_string()),
+                    ]
+                });
+                RespValue::Array(pairs.collect())
+            }),
+            Keys(k) => self.hash(&k).map(|hash| {
+                let fields = hash.into_iter().flat_map(|h| h.keys());
+                RespValue::Array(
+                    fields
+                        .map(|f| RespValue::BulkString(f.to_string()))
+                        .collect(),
+                )
+            }),
+            Vals(k) => self.hash(&k).map(|hash| {
+                let values = hash.into_iter().flat_map(|h| h.values());
+                RespValue::Array(
+                    values
+                        .map(|v| RespValue::BulkString(v.to_string()))
+                        .collect(),
+                )
+            }),
+            Len(k) => self
+                .hash(&k)
+                .map(|hash| RespValue::Integer(hash.map_or(0, |h| h.len()) as i64)),
+            Exists(k, field) => self.hash(&k).map(|hash| {
+                RespValue::Integer(hash.is_some_and(|h| h.contains_key(&field)) as i64)
+            }),
+            IncrBy(k, field, by) => self.process_hincrby(&k, field, by),
+        }
+        .unwrap_or_else(|e| e)
+    }
+
+    fn process_hset(
+        &mut self,
+        key: &str,
+        pairs: Vec<(String, String)>,
+    ) -> Result<RespValue, RespValue> {
+        let hash = self.hash_mut(key)?;
+        let added = pairs
+            .into_iter()
+            .filter(|(field, value)| hash.insert(field.clone(), value.clone()).is_none())
+            .count();
+        self.notify('h', "hset", key);
+        Ok(RespValue::Integer(added as i64))
+    }
+
+    fn process_hdel(&mut self, key: &str, fields: &[String]) -> Result<RespValue, RespValue> {
+        let hash = match self.value_mut(key)? {
+            Some(Value::Hash(hash)) => hash,
+            Some(_) => return Err(RespValue::Error(WRONGTYPE.into())),
+            None => return Ok(RespValue::Integer(0)),
+        };
+
+        let removed = fields.iter().filter(|f| hash.remove(f).is_some()).count();
+        let emptied = hash.is_empty();
+        if removed > 0 {
+            self.notify('h', "hdel", key);
+        }
+        if emptied {
+            self.store.kv_del(key)?;
+            self.expires.remove(key);
+            self.notify('g', "del", key);
+        }
+        Ok(RespValue::Integer(removed as i64))
+    }
+
+    fn process_hincrby(
+        &mut self,
+        key: &str,
+        field: String,
+        by: i64,
+    ) -> Result<RespValue, RespValue> {
+        let hash = self.hash_mut(key)?;
+        let old = match hash.get(&field) {
+            Some(v) => v
+                .parse::<i64>()
+                .map_err(|_| RespValue::Error("ERR hash value is not an integer".into()))?,
+            None => 0,
+        };
+        let new = old
+            .checked_add(by)
+            .ok_or_else(|| RespValue::Error("ERR increment or decrement would overflow".into()))?;
+        hash.insert(field, new.to_string());
+        self.notify('h', "hincrby", key);
+        Ok(RespValue::Integer(new))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encoding() {
+        let mut hash = Hash::new();
+        assert_eq!(hash.insert("b".into(), "2".into()), None);
+        assert_eq!(hash.insert("a".into(), "1".into()), None);
+        assert_eq!(hash.insert("b".into(), "3".into()), Some("2".into()));
+        assert_eq!(hash.encoding(), "listpack");
+        assert_eq!(hash.iter().collect::<Vec<_>>(), [("a", "1"), ("b", "3")]);
+        assert_eq!(hash.remove("a"), Some("1".into()));
+        assert_eq!(hash.get("a"), None);
+        assert_eq!(hash.get("b"), Some("3"));
+
+        let packed = hash.clone();
+        hash.insert("c".into(), "x".repeat(65));
+        assert_eq!(hash.encoding(), "hashtable");
+        assert_eq!(hash.keys().collect::<Vec<_>>(), ["b", "c"]);
+        hash.remove("c");
+        assert_eq!(hash, packed);
+
+        let many: Hash = (0..200)
+            .map(|n| (format!("{:03}", n), n.to_string()))
+            .collect();
+        assert_eq!(many.encoding(), "hashtable");
+        let json = serde_json::to_string(&packed).unwrap();
+        assert_eq!(json, r#"{"b":"3"}"#);
+        let hash: Hash = serde_json::from_str(&json).unwrap();
+        assert_eq!(hash.encoding(), "listpack");
+        assert_eq!(hash, packed);
+    }
+}
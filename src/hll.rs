@@ -0,0 +1,354 @@
+use std::vec::IntoIter;
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{
+    backend::Backend,
+    command::{strings, CommandError},
+    map::KvStore,
+    resp::RespValue,
+    value::{Value, WRONGTYPE},
+};
+
+/// Bits of the hash used to pick a register, as in Redis.
+const P: u32 = 14;
+/// Number of registers.
+const M: usize = 1 << P;
+/// Bits of the hash left for counting trailing zeros.
+const Q: u32 = 64 - P;
+
+/// A sparse HyperLogLog is converted to dense past this many registers set.
+const SPARSE_MAX_REGISTERS: usize = 1000;
+
+/// Estimates the number of distinct elements added to it, within 0.81%
+/// of the real number, using at most 16KB.
+///
+/// Like in Redis, it starts out sparse, listing only the registers that
+/// were set, and becomes dense once too many registers are set for the
+/// sparse encoding to save memory.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum HyperLogLog {
+    /// Non-zero registers, as `(index, value)` sorted by index.
+    Sparse(Vec<(u16, u8)>),
+    /// All `M` registers.
+    Dense(Vec<u8>),
+}
+
+impl Default for HyperLogLog {
+    fn default() -> Self {
+        HyperLogLog::Sparse(Vec::new())
+    }
+}
+
+impl HyperLogLog {
+    /// Adds an element, returning true if the estimate may have changed.
+    pub fn add(&mut self, element: &[u8]) -> bool {
+        let hash = murmurhash64a(element, 0xadc83b19);
+        let index = (hash & (M as u64 - 1)) as u16;
+        // Guard bit, so the count is at most Q + 1
+        let rest = (hash >> P) | (1 << Q);
+        let count = rest.trailing_zeros() as u8 + 1;
+
+        self.set_max(index, count)
+    }
+
+    /// Raises a register to `value`, returning true if it changed.
+    fn set_max(&mut self, index: u16, value: u8) -> bool {
+        match self {
+            HyperLogLog::Dense(registers) => {
+                let register = &mut registers[index as usize];
+                if *register >= value {
+                    return false;
+                }
+                *register = value;
+            }
+            HyperLogLog::Sparse(registers) => {
+                match registers.binary_search_by_key(&index, |&(i, _)| i) {
+                    Ok(pos) if registers[pos].1 >= value => return false,
+                    Ok(pos) => registers[pos].1 = value,
+                    Err(pos) => registers.insert(pos, (index, value)),
+                }
+                if registers.len() > SPARSE_MAX_REGISTERS {
+                    self.densify();
+                }
+            }
+        }
+        true
+    }
+
+    fn densify(&mut self) {
+        if let HyperLogLog::Sparse(sparse) = self {
+            let mut registers = vec![0; M];
+            for &(i, v) in sparse.iter() {
+                registers[i as usize] = v;
+            }
+            *self = HyperLogLog::Dense(registers);
+        }
+    }
+
+    /// Non-zero registers as `(index, value)`.
+    fn registers(&self) -> Box<dyn Iterator<Item = (u16, u8)> + '_> {
+        match self {
+            HyperLogLog::Sparse(registers) => Box::new(registers.iter().copied()),
+            HyperLogLog::Dense(registers) => Box::new(
+                registers
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, &v)| v != 0)
+                    .map(|(i, &v)| (i as u16, v)),
+            ),
+        }
+    }
+
+    /// Makes this the union of itself and `other`.
+    pub fn merge(&mut self, other: &HyperLogLog) {
+        for (i, v) in other.registers() {
+            self.set_max(i, v);
+        }
+    }
+
+    /// Estimated cardinality, using the estimator from Otmar Ertl's "New
+    /// cardinality estimation algorithms for HyperLogLog sketches", like
+    /// Redis does.
+    pub fn count(&self) -> u64 {
+        let mut histogram = [0u32; Q as usize + 2];
+        let mut nonzero = 0;
+        for (_, v) in self.registers() {
+            histogram[v as usize] += 1;
+            nonzero += 1;
+        }
+        histogram[0] = (M - nonzero) as u32;
+
+        let m = M as f64;
+        let mut z = m * tau((m - histogram[Q as usize + 1] as f64) / m);
+        for j in (1..=Q as usize).rev() {
+            z += histogram[j] as f64;
+            z *= 0.5;
+        }
+        z += m * sigma(histogram[0] as f64 / m);
+
+        let alpha_inf = 0.5 / std::f64::consts::LN_2;
+        (alpha_inf * m * m / z).round() as u64
+    }
+}
+
+fn sigma(mut x: f64) -> f64 {
+    if x == 1.0 {
+        return f64::INFINITY;
+    }
+    let mut y = 1.0;
+    let mut z = x;
+    loop {
+        x *= x;
+        let z_prev = z;
+        z += x * y;
+        y += y;
+        if z_prev == z {
+            return z;
+        }
+    }
+}
+
+fn tau(mut x: f64) -> f64 {
+    if x == 0.0 || x == 1.0 {
+        return 0.0;
+    }
+    let mut y = 1.0;
+    let mut z = 1.0 - x;
+    loop {
+        x = x.sqrt();
+        let z_prev = z;
+        y *= 0.5;
+        z -= (1.0 - x).powi(2) * y;
+        if z_prev == z {
+            return z / 3.0;
+        }
+    }
+}
+
+/// MurmurHash64A by Austin Appleby, the hash Redis uses for HyperLogLogs.
+pub(crate) fn murmurhash64a(key: &[u8], seed: u64) -> u64 {
+    const M: u64 = 0xc6a4a7935bd1e995;
+    const R: u32 = 47;
+
+    let mut h = seed ^ (key.len() as u64).wrapping_mul(M);
+
+    let chunks = key.chunks_exact(8);
+    let tail = chunks.remainder();
+    for chunk in chunks {
+        let mut k = u64::from_le_bytes(chunk.try_into().unwrap());
+        k = k.wrapping_mul(M);
+        k ^= k >> R;
+        k = k.wrapping_mul(M);
+        h ^= k;
+        h = h.wrapping_mul(M);
+    }
+
+    if !tail.is_empty() {
+        for (i, &b) in tail.iter().enumerate() {
+            h ^= (b as u64) << (8 * i);
+        }
+        h = h.wrapping_mul(M);
+    }
+
+    h ^= h >> R;
+    h = h.wrapping_mul(M);
+    h ^= h >> R;
+    h
+}
+
+#[derive(Debug, PartialEq)]
+pub enum HllCommand {
+    Add(String, Vec<String>),
+    Count(Vec<String>),
+    Merge(String, Vec<String>),
+}
+
+impl HllCommand {
+    pub fn keys(&self) -> Vec<&str> {
+        use HllCommand::*;
+        match self {
+            Add(k, _) => vec![k.as_str()],
+            Count(keys) => keys.iter().map(|k| k.as_str()).collect(),
+            Merge(dest, keys) => std::iter::once(dest)
+                .chain(keys.iter())
+                .map(|k| k.as_str())
+                .collect(),
+        }
+    }
+}
+
+/// Parses the arguments of `PFADD`, `PFCOUNT` and `PFMERGE`.
+pub fn parse(verb: &str, arr: IntoIter<RespValue>) -> Result<HllCommand, CommandError> {
+    let args = strings(arr)?;
+    match (verb, args.as_slice()) {
+        ("PFADD", [k, elements @ ..]) => Ok(HllCommand::Add(k.clone(), elements.to_vec())),
+        ("PFCOUNT", keys) if !keys.is_empty() => Ok(HllCommand::Count(keys.to_vec())),
+        ("PFMERGE", [dest, keys @ ..]) => Ok(HllCommand::Merge(dest.clone(), keys.to_vec())),
+        _ => Err(CommandError::InvalidCommand),
+    }
+}
+
+impl<T> Backend<T>
+where
+    T: KvStore,
+{
+    fn hll(&self, key: &str) -> Result<Option<&HyperLogLog>, RespValue> {
+        match self.value(key)? {
+            Some(Value::Hll(hll)) => Ok(Some(hll)),
+            Some(_) => Err(RespValue::Error(WRONGTYPE.into())),
+            None => Ok(None),
+        }
+    }
+
+    pub(crate) fn process_hll(&mut self, cmd: HllCommand) -> RespValue {
+        use HllCommand::*;
+        match cmd {
+            Add(k, elements) => self.process_pfadd(&k, elements),
+            Count(keys) => self.process_pfcount(&keys),
+            Merge(dest, keys) => self.process_pfmerge(&dest, &keys),
+        }
+        .unwrap_or_else(|e| e)
+    }
+
+    fn process_pfadd(&mut self, key: &str, elements: Vec<String>) -> Result<RespValue, RespValue> {
+        let mut changed = false;
+        let hll = match self.value_mut(key)? {
+            Some(Value::Hll(hll)) => hll,
+            Some(_) => return Err(RespValue::Error(WRONGTYPE.into())),
+            None => {
+                changed = true;
+                self.store.kv_put(key, Value::Hll(HyperLogLog::default()))?;
+                match self.store.kv_get_mut(key)? {
+                    Some(Value::Hll(hll)) => hll,
+                    _ => unreachable!(),
+                }
+            }
+        };
+
+        for element in elements {
+            changed |= hll.add(element.as_bytes());
+        }
+        if changed {
+            self.notify('$', "pfadd", key);
+        }
+
+        Ok(RespValue::Integer(changed as i64))
+    }
+
+    fn process_pfcount(&self, keys: &[String]) -> Result<RespValue, RespValue> {
+        if let [key] = keys {
+            let count = self.hll(key)?.map_or(0, |hll| hll.count());
+            return Ok(RespValue::Integer(count as i64));
+        }
+
+        let mut union = HyperLogLog::default();
+        for key in keys {
+            if let Some(hll) = self.hll(key)? {
+                union.merge(hll);
+            }
+        }
+        Ok(RespValue::Integer(union.count() as i64))
+    }
+
+    fn process_pfmerge(&mut self, dest: &str, keys: &[String]) -> Result<RespValue, RespValue> {
+        self.expire_if_needed(dest)?;
+        let mut union = self.hll(dest)?.cloned().unwrap_or_default();
+        for key in keys {
+            if let Some(hll) = self.hll(key)? {
+                union.merge(hll);
+            }
+        }
+
+        self.store.kv_put(dest, Value::Hll(union))?;
+        self.notify('$', "pfadd", dest);
+        Ok(RespValue::SimpleString("OK".into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hll_of(range: std::ops::Range<u32>) -> HyperLogLog {
+        let mut hll = HyperLogLog::default();
+        for i in range {
+            hll.add(format!("element:{}", i).as_bytes());
+        }
+        hll
+    }
+
+    fn assert_close(estimate: u64, actual: u64) {
+        let error = (estimate as f64 - actual as f64).abs() / actual as f64;
+        assert!(error < 0.02, "estimated {} for {}", estimate, actual);
+    }
+
+    #[test]
+    fn test_hll_count() {
+        assert_eq!(HyperLogLog::default().count(), 0);
+
+        let small = hll_of(0..100);
+        assert!(matches!(small, HyperLogLog::Sparse(_)));
+        assert_close(small.count(), 100);
+
+        let large = hll_of(0..100_000);
+        assert!(matches!(large, HyperLogLog::Dense(_)));
+        assert_close(large.count(), 100_000);
+    }
+
+    #[test]
+    fn test_hll_merge() {
+        let mut a = hll_of(0..600);
+        let b = hll_of(400..1000);
+        a.merge(&b);
+        assert_close(a.count(), 1000);
+
+        // Merging a sparse HyperLogLog into a dense one, or the other way
+        // round, gives the same registers.
+        let mut dense = hll_of(0..5000);
+        dense.merge(&hll_of(5000..5050));
+        let mut sparse = hll_of(5000..5050);
+        sparse.merge(&hll_of(0..5000));
+        assert_eq!(dense, sparse);
+    }
+}
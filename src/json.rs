@@ -0,0 +1,961 @@
+use std::{cmp::Ordering, iter::Peekable, str::Chars, vec::IntoIter};
+
+use serde_json::{Number, Value as Json};
+
+use crate::{
+    backend::Backend,
+    command::{strings, CommandError},
+    map::KvStore,
+    resp::RespValue,
+    value::{Value, WRONGTYPE},
+};
+
+/// One step from a JSON value to one of its children.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Step {
+    Key(String),
+    Index(usize),
+}
+
+/// Where a value is in a document, and the value.
+type Match<'a> = (Vec<Step>, &'a Json);
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Selector {
+    Key(String),
+    /// Counting from the end if negative.
+    Index(i64),
+    Wildcard,
+    Union(Vec<Selector>),
+    Slice(Option<i64>, Option<i64>, i64),
+    Filter(Filter),
+    /// The selector applied to the value and all its descendants, `..`.
+    Descendant(Box<Selector>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Operand {
+    /// A path relative to the element being filtered, `@`.
+    Relative(Vec<Selector>),
+    Literal(Json),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Filter {
+    /// Whether the relative path matches anything.
+    Exists(Vec<Selector>),
+    Compare(Operand, String, Operand),
+    And(Box<Filter>, Box<Filter>),
+    Or(Box<Filter>, Box<Filter>),
+}
+
+/// A path into a JSON document, either JSONPath starting with `$`, which
+/// selects any number of values, or a legacy path like `.a.b` or `a[0]`,
+/// which selects a single value, as in RedisJSON.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Path {
+    text: String,
+    selectors: Vec<Selector>,
+    legacy: bool,
+}
+
+impl Path {
+    pub fn parse(text: &str) -> Option<Path> {
+        let (rest, legacy) = match text.strip_prefix('$') {
+            Some(rest) => (rest.to_string(), false),
+            None if text == "." || text.is_empty() => (String::new(), true),
+            None if text.starts_with(['.', '[']) => (text.to_string(), true),
+            None => (format!(".{}", text), true),
+        };
+        let mut chars = rest.chars().peekable();
+        let selectors = parse_selectors(&mut chars, false)?;
+        if chars.next().is_some() {
+            return None;
+        }
+        Some(Path {
+            text: text.to_string(),
+            selectors,
+            legacy,
+        })
+    }
+
+    fn root() -> Path {
+        Path {
+            text: ".".into(),
+            selectors: Vec::new(),
+            legacy: true,
+        }
+    }
+
+    fn is_root(&self) -> bool {
+        self.selectors.is_empty()
+    }
+
+    /// The path to the parent of what's selected, and the key that would
+    /// be added to it, if the last selector is a plain key.
+    fn split_last_key(&self) -> Option<(Path, &str)> {
+        match self.selectors.split_last() {
+            Some((Selector::Key(key), parent)) => Some((
+                Path {
+                    selectors: parent.to_vec(),
+                    ..self.clone()
+                },
+                key,
+            )),
+            _ => None,
+        }
+    }
+
+    fn select<'a>(&self, root: &'a Json) -> Vec<Match<'a>> {
+        let mut matches = select(root, &self.selectors);
+        if self.legacy {
+            matches.truncate(1);
+        }
+        matches
+    }
+}
+
+fn parse_selectors(chars: &mut Peekable<Chars>, relative: bool) -> Option<Vec<Selector>> {
+    let mut selectors = Vec::new();
+    loop {
+        match chars.peek() {
+            Some('.') => {
+                chars.next();
+                let descendant = chars.next_if_eq(&'.').is_some();
+                let selector = match chars.peek()? {
+                    '*' => {
+                        chars.next();
+                        Selector::Wildcard
+                    }
+                    '[' if descendant => {
+                        chars.next();
+                        parse_bracket(chars)?
+                    }
+                    _ => Selector::Key(parse_name(chars, relative)?),
+                };
+                selectors.push(match descendant {
+                    true => Selector::Descendant(Box::new(selector)),
+                    false => selector,
+                });
+            }
+            Some('[') => {
+                chars.next();
+                selectors.push(parse_bracket(chars)?);
+            }
+            _ => return Some(selectors),
+        }
+    }
+}
+
+/// A member name in dot notation, which in filters ends at operators too.
+fn parse_name(chars: &mut Peekable<Chars>, relative: bool) -> Option<String> {
+    let mut name = String::new();
+    while let Some(&c) = chars.peek() {
+        let end =
+            c == '.' || c == '[' || (relative && (c.is_whitespace() || "=!<>)&|".contains(c)));
+        if end {
+            break;
+        }
+        name.push(c);
+        chars.next();
+    }
+    (!name.is_empty()).then_some(name)
+}
+
+fn skip_spaces(chars: &mut Peekable<Chars>) {
+    while chars.next_if(|c| c.is_whitespace()).is_some() {}
+}
+
+/// Parses what's between `[` and `]`, consuming the `]`.
+fn parse_bracket(chars: &mut Peekable<Chars>) -> Option<Selector> {
+    skip_spaces(chars);
+    let selector = match chars.peek()? {
+        '*' => {
+            chars.next();
+            Selector::Wildcard
+        }
+        '?' => {
+            chars.next();
+            skip_spaces(chars);
+            let parens = chars.next_if_eq(&'(').is_some();
+            let filter = parse_filter(chars)?;
+            skip_spaces(chars);
+            if parens && chars.next() != Some(')') {
+                return None;
+            }
+            Selector::Filter(filter)
+        }
+        _ => {
+            let mut union = Vec::new();
+            loop {
+                skip_spaces(chars);
+                union.push(match chars.peek()? {
+                    '\'' | '"' => Selector::Key(parse_quoted(chars)?),
+                    _ => parse_index_or_slice(chars)?,
+                });
+                skip_spaces(chars);
+                if chars.next_if_eq(&',').is_none() {
+                    break;
+                }
+            }
+            match union.len() {
+                1 => union.pop().unwrap(),
+                _ => Selector::Union(union),
+            }
+        }
+    };
+    skip_spaces(chars);
+    (chars.next()? == ']').then_some(selector)
+}
+
+fn parse_quoted(chars: &mut Peekable<Chars>) -> Option<String> {
+    let quote = chars.next()?;
+    let mut s = String::new();
+    loop {
+        match chars.next()? {
+            '\\' => s.push(chars.next()?),
+            c if c == quote => return Some(s),
+            c => s.push(c),
+        }
+    }
+}
+
+fn parse_int(chars: &mut Peekable<Chars>) -> Option<i64> {
+    let mut s = String::new();
+    while let Some(c) = chars.next_if(|&c| c == '-' || c.is_ascii_digit()) {
+        s.push(c);
+    }
+    s.parse().ok()
+}
+
+fn parse_index_or_slice(chars: &mut Peekable<Chars>) -> Option<Selector> {
+    let start = parse_int(chars);
+    if chars.next_if_eq(&':').is_none() {
+        return start.map(Selector::Index);
+    }
+    let end = parse_int(chars);
+    let step = match chars.next_if_eq(&':') {
+        Some(_) => parse_int(chars).unwrap_or(1),
+        None => 1,
+    };
+    (step != 0).then_some(Selector::Slice(start, end, step))
+}
+
+/// Parses `a || b`, where `a` and `b` are `&&` of comparisons.
+fn parse_filter(chars: &mut Peekable<Chars>) -> Option<Filter> {
+    let mut filter = parse_and(chars)?;
+    loop {
+        skip_spaces(chars);
+        if chars.peek() != Some(&'|') {
+            return Some(filter);
+        }
+        chars.next();
+        (chars.next()? == '|').then_some(())?;
+        filter = Filter::Or(Box::new(filter), Box::new(parse_and(chars)?));
+    }
+}
+
+fn parse_and(chars: &mut Peekable<Chars>) -> Option<Filter> {
+    let mut filter = parse_comparison(chars)?;
+    loop {
+        skip_spaces(chars);
+        if chars.peek() != Some(&'&') {
+            return Some(filter);
+        }
+        chars.next();
+        (chars.next()? == '&').then_some(())?;
+        filter = Filter::And(Box::new(filter), Box::new(parse_comparison(chars)?));
+    }
+}
+
+fn parse_comparison(chars: &mut Peekable<Chars>) -> Option<Filter> {
+    let left = parse_operand(chars)?;
+    skip_spaces(chars);
+    let mut op = String::new();
+    while let Some(c) = chars.next_if(|c| "=!<>".contains(*c)) {
+        op.push(c);
+    }
+    match op.as_str() {
+        "" => match left {
+            Operand::Relative(path) => Some(Filter::Exists(path)),
+            Operand::Literal(_) => None,
+        },
+        "==" | "!=" | "<" | "<=" | ">" | ">=" => {
+            let right = parse_operand(chars)?;
+            Some(Filter::Compare(left, op, right))
+        }
+        _ => None,
+    }
+}
+
+fn parse_operand(chars: &mut Peekable<Chars>) -> Option<Operand> {
+    skip_spaces(chars);
+    match chars.peek()? {
+        '@' => {
+            chars.next();
+            Some(Operand::Relative(parse_selectors(chars, true)?))
+        }
+        // Short for `@.`, as in vector set filters
+        '.' => Some(Operand::Relative(parse_selectors(chars, true)?)),
+        '\'' | '"' => Some(Operand::Literal(Json::String(parse_quoted(chars)?))),
+        _ => {
+            let mut literal = String::new();
+            while let Some(c) = chars.next_if(|c| c.is_alphanumeric() || "+-.".contains(*c)) {
+                literal.push(c);
+            }
+            serde_json::from_str(&literal).ok().map(Operand::Literal)
+        }
+    }
+}
+
+/// Parses a filter expression on its own, as in `$[?(<filter>)]`.
+pub(crate) fn parse_filter_expression(s: &str) -> Option<Filter> {
+    let mut chars = s.chars().peekable();
+    let filter = parse_filter(&mut chars)?;
+    skip_spaces(&mut chars);
+    chars.next().is_none().then_some(filter)
+}
+
+fn children(node: &Json) -> Vec<(Step, &Json)> {
+    match node {
+        Json::Object(map) => map.iter().map(|(k, v)| (Step::Key(k.clone()), v)).collect(),
+        Json::Array(arr) => arr
+            .iter()
+            .enumerate()
+            .map(|(i, v)| (Step::Index(i), v))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn resolve_index(i: i64, len: usize) -> Option<usize> {
+    let i = if i < 0 { len as i64 + i } else { i };
+    (0..len as i64).contains(&i).then_some(i as usize)
+}
+
+/// The values selected by one selector, out of one node.
+fn select_one<'a>(selector: &Selector, node: &'a Json) -> Vec<(Vec<Step>, &'a Json)> {
+    let child = |step: Step, value| (vec![step], value);
+    match (selector, node) {
+        (Selector::Key(key), Json::Object(map)) => map
+            .get(key)
+            .map(|v| child(Step::Key(key.clone()), v))
+            .into_iter()
+            .collect(),
+        (Selector::Index(i), Json::Array(arr)) => resolve_index(*i, arr.len())
+            .map(|i| child(Step::Index(i), &arr[i]))
+            .into_iter()
+            .collect(),
+        (Selector::Wildcard, _) => children(node)
+            .into_iter()
+            .map(|(step, v)| child(step, v))
+            .collect(),
+        (Selector::Union(selectors), _) => {
+            selectors.iter().flat_map(|s| select_one(s, node)).collect()
+        }
+        (Selector::Slice(start, end, step), Json::Array(arr)) => {
+            let len = arr.len() as i64;
+            let clamp = |i: i64| if i < 0 { (len + i).max(0) } else { i.min(len) };
+            let indexes: Vec<i64> = if *step > 0 {
+                let (start, end) = (clamp(start.unwrap_or(0)), clamp(end.unwrap_or(len)));
+                (start..end).step_by(*step as usize).collect()
+            } else {
+                let start = start.map_or(len - 1, |s| clamp(s).min(len - 1));
+                let end = end.map_or(-1, clamp);
+                (end + 1..=start)
+                    .rev()
+                    .step_by(step.unsigned_abs() as usize)
+                    .collect()
+            };
+            indexes
+                .into_iter()
+                .map(|i| child(Step::Index(i as usize), &arr[i as usize]))
+                .collect()
+        }
+        (Selector::Filter(filter), _) => children(node)
+            .into_iter()
+            .filter(|(_, v)| matches_filter(filter, v))
+            .map(|(step, v)| child(step, v))
+            .collect(),
+        (Selector::Descendant(selector), _) => {
+            let mut found = select_one(selector, node);
+            for (step, v) in children(node) {
+                for (mut steps, value) in select_one(&Selector::Descendant(selector.clone()), v) {
+                    steps.insert(0, step.clone());
+                    found.push((steps, value));
+                }
+            }
+            found
+        }
+        _ => Vec::new(),
+    }
+}
+
+fn select<'a>(root: &'a Json, selectors: &[Selector]) -> Vec<Match<'a>> {
+    let mut matches = vec![(Vec::new(), root)];
+    for selector in selectors {
+        matches = matches
+            .into_iter()
+            .flat_map(|(steps, node)| {
+                select_one(selector, node)
+                    .into_iter()
+                    .map(move |(more, v)| {
+                        let mut steps = steps.clone();
+                        steps.extend(more);
+                        (steps, v)
+                    })
+            })
+            .collect();
+    }
+    matches
+}
+
+fn operand<'a>(operand: &'a Operand, node: &'a Json) -> Option<&'a Json> {
+    match operand {
+        Operand::Relative(path) => select(node, path).first().map(|(_, v)| *v),
+        Operand::Literal(value) => Some(value),
+    }
+}
+
+fn compare(a: &Json, b: &Json) -> Option<Ordering> {
+    match (a, b) {
+        (Json::Number(a), Json::Number(b)) => a.as_f64()?.partial_cmp(&b.as_f64()?),
+        (Json::String(a), Json::String(b)) => Some(a.cmp(b)),
+        (a, b) if a == b => Some(Ordering::Equal),
+        _ => None,
+    }
+}
+
+pub(crate) fn matches_filter(filter: &Filter, node: &Json) -> bool {
+    match filter {
+        Filter::Exists(path) => !select(node, path).is_empty(),
+        Filter::Compare(left, op, right) => {
+            let (Some(left), Some(right)) = (operand(left, node), operand(right, node)) else {
+                return false;
+            };
+            let ordering = compare(left, right);
+            match op.as_str() {
+                "==" => ordering == Some(Ordering::Equal),
+                "!=" => ordering != Some(Ordering::Equal),
+                "<" => ordering == Some(Ordering::Less),
+                "<=" => matches!(ordering, Some(Ordering::Less | Ordering::Equal)),
+                ">" => ordering == Some(Ordering::Greater),
+                ">=" => matches!(ordering, Some(Ordering::Greater | Ordering::Equal)),
+                _ => unreachable!(),
+            }
+        }
+        Filter::And(a, b) => matches_filter(a, node) && matches_filter(b, node),
+        Filter::Or(a, b) => matches_filter(a, node) || matches_filter(b, node),
+    }
+}
+
+fn get_mut<'a>(mut node: &'a mut Json, steps: &[Step]) -> Option<&'a mut Json> {
+    for step in steps {
+        node = match (step, node) {
+            (Step::Key(key), Json::Object(map)) => map.get_mut(key)?,
+            (Step::Index(i), Json::Array(arr)) => arr.get_mut(*i)?,
+            _ => return None,
+        };
+    }
+    Some(node)
+}
+
+/// Locations of the selected values, to change them after selecting.
+fn locate(root: &Json, path: &Path) -> Vec<Vec<Step>> {
+    path.select(root)
+        .into_iter()
+        .map(|(steps, _)| steps)
+        .collect()
+}
+
+fn type_name(value: &Json) -> &'static str {
+    match value {
+        Json::Null => "null",
+        Json::Bool(_) => "boolean",
+        Json::Number(n) if n.is_f64() => "number",
+        Json::Number(_) => "integer",
+        Json::String(_) => "string",
+        Json::Array(_) => "array",
+        Json::Object(_) => "object",
+    }
+}
+
+fn add_numbers(a: &Number, b: &Number) -> Option<Number> {
+    match (a.as_i64(), b.as_i64()) {
+        (Some(a), Some(b)) => a.checked_add(b).map(Number::from),
+        _ => Number::from_f64(a.as_f64()? + b.as_f64()?),
+    }
+}
+
+fn json_string(value: &Json) -> RespValue {
+    RespValue::BulkString(value.to_string())
+}
+
+#[derive(Debug, PartialEq)]
+pub enum JsonCommand {
+    Set {
+        key: String,
+        path: Path,
+        value: Json,
+        nx: bool,
+        xx: bool,
+    },
+    Get(String, Vec<Path>),
+    Del(String, Path),
+    NumIncrBy(String, Path, Number),
+    ArrAppend(String, Path, Vec<Json>),
+    ObjKeys(String, Path),
+    Type(String, Path),
+}
+
+impl JsonCommand {
+    pub fn keys(&self) -> Vec<&str> {
+        use JsonCommand::*;
+        match self {
+            Set { key, .. } | Get(key, _) | Del(key, _) | NumIncrBy(key, ..) => vec![key.as_str()],
+            ArrAppend(key, ..) | ObjKeys(key, _) | Type(key, _) => vec![key.as_str()],
+        }
+    }
+}
+
+fn path(s: &str) -> Result<Path, CommandError> {
+    Path::parse(s).ok_or(CommandError::InvalidCommand)
+}
+
+fn optional_path(args: &[String]) -> Result<Path, CommandError> {
+    match args {
+        [] => Ok(Path::root()),
+        [p] => path(p),
+        _ => Err(CommandError::InvalidCommand),
+    }
+}
+
+fn json(s: &str) -> Result<Json, CommandError> {
+    serde_json::from_str(s).map_err(|_| CommandError::InvalidCommand)
+}
+
+/// Parses the arguments of the `JSON.*` commands.
+pub fn parse(verb: &str, arr: IntoIter<RespValue>) -> Result<JsonCommand, CommandError> {
+    let args = strings(arr)?;
+    let cmd = match (verb, args.as_slice()) {
+        ("JSON.SET", [key, p, value, condition @ ..]) if condition.len() <= 1 => {
+            let condition = condition.first().map(|c| c.to_uppercase());
+            let (nx, xx) = match condition.as_deref() {
+                None => (false, false),
+                Some("NX") => (true, false),
+                Some("XX") => (false, true),
+                _ => return Err(CommandError::InvalidCommand),
+            };
+            JsonCommand::Set {
+                key: key.clone(),
+                path: path(p)?,
+                value: json(value)?,
+                nx,
+                xx,
+            }
+        }
+        ("JSON.GET", [key, paths @ ..]) => {
+            let paths = match paths {
+                [] => vec![Path::root()],
+                _ => paths.iter().map(|p| path(p)).collect::<Result<_, _>>()?,
+            };
+            JsonCommand::Get(key.clone(), paths)
+        }
+        ("JSON.DEL", [key, p @ ..]) => JsonCommand::Del(key.clone(), optional_path(p)?),
+        ("JSON.NUMINCRBY", [key, p, n]) => {
+            let n = serde_json::from_str(n).map_err(|_| CommandError::InvalidCommand)?;
+            JsonCommand::NumIncrBy(key.clone(), path(p)?, n)
+        }
+        ("JSON.ARRAPPEND", [key, p, values @ ..]) if !values.is_empty() => {
+            let values = values.iter().map(|v| json(v)).collect::<Result<_, _>>()?;
+            JsonCommand::ArrAppend(key.clone(), path(p)?, values)
+        }
+        ("JSON.OBJKEYS", [key, p @ ..]) => JsonCommand::ObjKeys(key.clone(), optional_path(p)?),
+        ("JSON.TYPE", [key, p @ ..]) => JsonCommand::Type(key.clone(), optional_path(p)?),
+        _ => return Err(CommandError::InvalidCommand),
+    };
+    Ok(cmd)
+}
+
+fn no_such_path(path: &Path) -> RespValue {
+    RespValue::Error(format!("ERR Path '{}' does not exist", path.text))
+}
+
+impl<T> Backend<T>
+where
+    T: KvStore,
+{
+    fn json(&self, key: &str) -> Result<Option<&Json>, RespValue> {
+        match self.value(key)? {
+            Some(Value::Json(doc)) => Ok(Some(doc)),
+            Some(_) => Err(RespValue::Error(WRONGTYPE.into())),
+            None => Ok(None),
+        }
+    }
+
+    fn json_mut(&mut self, key: &str) -> Result<Option<&mut Json>, RespValue> {
+        match self.value_mut(key)? {
+            Some(Value::Json(doc)) => Ok(Some(doc)),
+            Some(_) => Err(RespValue::Error(WRONGTYPE.into())),
+            None => Ok(None),
+        }
+    }
+
+    pub(crate) fn process_json(&mut self, cmd: JsonCommand) -> RespValue {
+        use JsonCommand::*;
+        match cmd {
+            Set {
+                key,
+                path,
+                value,
+                nx,
+                xx,
+            } => self.process_json_set(&key, &path, value, nx, xx),
+            Get(key, paths) => self.process_json_get(&key, &paths),
+            Del(key, path) => self.process_json_del(&key, &path),
+            NumIncrBy(key, path, n) => self.process_json_numincrby(&key, &path, &n),
+            ArrAppend(key, path, values) => self.process_json_arrappend(&key, &path, values),
+            ObjKeys(key, path) => self.process_json_objkeys(&key, &path),
+            Type(key, path) => self.process_json_type(&key, &path),
+        }
+        .unwrap_or_else(|e| e)
+    }
+
+    fn process_json_set(
+        &mut self,
+        key: &str,
+        path: &Path,
+        value: Json,
+        nx: bool,
+        xx: bool,
+    ) -> Result<RespValue, RespValue> {
+        let Some(doc) = self.json_mut(key)? else {
+            if !path.is_root() {
+                return Err(RespValue::Error(
+                    "ERR new objects must be created at the root".into(),
+                ));
+            }
+            if xx {
+                return Ok(RespValue::Null);
+            }
+            self.store.kv_put(key, Value::Json(value))?;
+            self.notify('g', "json.set", key);
+            return Ok(RespValue::SimpleString("OK".into()));
+        };
+
+        let existing = locate(doc, path);
+        let mut changed = false;
+        if !existing.is_empty() {
+            if nx {
+                return Ok(RespValue::Null);
+            }
+            for steps in existing {
+                if let Some(target) = get_mut(doc, &steps) {
+                    *target = value.clone();
+                    changed = true;
+                }
+            }
+        } else if let Some((parent, new_key)) = path.split_last_key() {
+            if xx {
+                return Ok(RespValue::Null);
+            }
+            for steps in locate(doc, &parent) {
+                if let Some(Json::Object(map)) = get_mut(doc, &steps) {
+                    map.insert(new_key.to_string(), value.clone());
+                    changed = true;
+                }
+            }
+        }
+
+        if !changed {
+            return Ok(RespValue::Null);
+        }
+        self.notify('g', "json.set", key);
+        Ok(RespValue::SimpleString("OK".into()))
+    }
+
+    fn process_json_get(&self, key: &str, paths: &[Path]) -> Result<RespValue, RespValue> {
+        let Some(doc) = self.json(key)? else {
+            return Ok(RespValue::Null);
+        };
+
+        let get = |path: &Path| -> Result<Json, RespValue> {
+            let matches = path.select(doc);
+            if !path.legacy {
+                return Ok(Json::Array(
+                    matches.into_iter().map(|(_, v)| v.clone()).collect(),
+                ));
+            }
+            match matches.first() {
+                Some((_, v)) => Ok((*v).clone()),
+                None => Err(no_such_path(path)),
+            }
+        };
+
+        match paths {
+            [path] => Ok(json_string(&get(path)?)),
+            _ => {
+                let mut results = serde_json::Map::new();
+                for path in paths {
+                    results.insert(path.text.clone(), get(path)?);
+                }
+                Ok(json_string(&Json::Object(results)))
+            }
+        }
+    }
+
+    fn process_json_del(&mut self, key: &str, path: &Path) -> Result<RespValue, RespValue> {
+        let Some(doc) = self.json_mut(key)? else {
+            return Ok(RespValue::Integer(0));
+        };
+
+        let mut locations = locate(doc, path);
+        if locations.iter().any(|steps| steps.is_empty()) {
+            self.store.kv_del(key)?;
+            self.expires.remove(key);
+            self.notify('g', "del", key);
+            return Ok(RespValue::Integer(1));
+        }
+
+        // Deleting from the end first keeps the remaining array indexes
+        // valid, and values within deleted ones needn't be deleted again.
+        locations.sort();
+        locations.dedup();
+        let mut deleted: Vec<Vec<Step>> = Vec::new();
+        for steps in locations.into_iter().rev() {
+            if deleted.iter().any(|d| steps.starts_with(d)) {
+                continue;
+            }
+            let (last, parent) = steps.split_last().unwrap();
+            match (get_mut(doc, parent), last) {
+                (Some(Json::Object(map)), Step::Key(k)) => {
+                    // Rebuilt to keep the order of the other members
+                    *map = std::mem::take(map)
+                        .into_iter()
+                        .filter(|(key, _)| key != k)
+                        .collect();
+                }
+                (Some(Json::Array(arr)), Step::Index(i)) => {
+                    arr.remove(*i);
+                }
+                _ => continue,
+            }
+            deleted.push(steps);
+        }
+
+        if !deleted.is_empty() {
+            self.notify('g', "json.del", key);
+        }
+        Ok(RespValue::Integer(deleted.len() as i64))
+    }
+
+    fn process_json_numincrby(
+        &mut self,
+        key: &str,
+        path: &Path,
+        n: &Number,
+    ) -> Result<RespValue, RespValue> {
+        let doc = self.json_mut(key)?.ok_or_else(|| {
+            RespValue::Error(
+                "ERR could not perform this operation on a key that doesn't exist".into(),
+            )
+        })?;
+
+        let mut results = Vec::new();
+        for steps in locate(doc, path) {
+            let result = match get_mut(doc, &steps) {
+                Some(Json::Number(current)) => {
+                    let sum = add_numbers(current, n)
+                        .ok_or_else(|| RespValue::Error("ERR result is not a number".into()))?;
+                    *current = sum.clone();
+                    Json::Number(sum)
+                }
+                _ => Json::Null,
+            };
+            results.push(result);
+        }
+
+        if path.legacy {
+            match results.pop() {
+                Some(Json::Null) => {
+                    return Err(RespValue::Error(format!(
+                        "ERR Path '{}' is not a number",
+                        path.text
+                    )))
+                }
+                Some(result) => {
+                    self.notify('g', "json.numincrby", key);
+                    return Ok(json_string(&result));
+                }
+                None => return Err(no_such_path(path)),
+            }
+        }
+        if results.iter().any(|r| !r.is_null()) {
+            self.notify('g', "json.numincrby", key);
+        }
+        Ok(json_string(&Json::Array(results)))
+    }
+
+    fn process_json_arrappend(
+        &mut self,
+        key: &str,
+        path: &Path,
+        values: Vec<Json>,
+    ) -> Result<RespValue, RespValue> {
+        let doc = self.json_mut(key)?.ok_or_else(|| {
+            RespValue::Error(
+                "ERR could not perform this operation on a key that doesn't exist".into(),
+            )
+        })?;
+
+        let mut results = Vec::new();
+        for steps in locate(doc, path) {
+            let result = match get_mut(doc, &steps) {
+                Some(Json::Array(arr)) => {
+                    arr.extend(values.iter().cloned());
+                    RespValue::Integer(arr.len() as i64)
+                }
+                _ => RespValue::Null,
+            };
+            results.push(result);
+        }
+
+        if results.iter().any(|r| *r != RespValue::Null) {
+            self.notify('g', "json.arrappend", key);
+        }
+        if path.legacy {
+            return match results.pop() {
+                Some(RespValue::Null) => Err(RespValue::Error(format!(
+                    "ERR Path '{}' is not an array",
+                    path.text
+                ))),
+                Some(result) => Ok(result),
+                None => Err(no_such_path(path)),
+            };
+        }
+        Ok(RespValue::Array(results))
+    }
+
+    fn process_json_objkeys(&self, key: &str, path: &Path) -> Result<RespValue, RespValue> {
+        let Some(doc) = self.json(key)? else {
+            return Ok(RespValue::Null);
+        };
+
+        let mut results: Vec<RespValue> = path
+            .select(doc)
+            .into_iter()
+            .map(|(_, v)| match v {
+                Json::Object(map) => RespValue::Array(
+                    map.keys()
+                        .map(|k| RespValue::BulkString(k.clone()))
+                        .collect(),
+                ),
+                _ => RespValue::Null,
+            })
+            .collect();
+
+        if path.legacy {
+            return results.pop().ok_or_else(|| no_such_path(path));
+        }
+        Ok(RespValue::Array(results))
+    }
+
+    fn process_json_type(&self, key: &str, path: &Path) -> Result<RespValue, RespValue> {
+        let Some(doc) = self.json(key)? else {
+            return Ok(RespValue::Null);
+        };
+
+        let mut types: Vec<RespValue> = path
+            .select(doc)
+            .into_iter()
+            .map(|(_, v)| RespValue::BulkString(type_name(v).into()))
+            .collect();
+
+        if path.legacy {
+            return Ok(types.pop().unwrap_or(RespValue::Null));
+        }
+        Ok(RespValue::Array(types))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn selected(doc: &Json, path: &str) -> Vec<Json> {
+        let path = Path::parse(path).unwrap();
+        path.select(doc)
+            .into_iter()
+            .map(|(_, v)| v.clone())
+            .collect()
+    }
+
+    #[test]
+    fn test_json_path() {
+        let doc = json!({
+            "store": {
+                "books": [
+                    {"title": "Sayings", "price": 8.95, "tags": ["old"]},
+                    {"title": "Sword", "price": 12.99},
+                    {"title": "Moby Dick", "price": 8.99, "isbn": "0-553-21311-3"},
+                ],
+                "bicycle": {"color": "red", "price": 19.95},
+            }
+        });
+
+        assert_eq!(selected(&doc, "$.store.bicycle.color"), vec![json!("red")]);
+        assert_eq!(selected(&doc, "store.bicycle['color']"), vec![json!("red")]);
+        assert_eq!(
+            selected(&doc, "$.store.books[-1].title"),
+            vec![json!("Moby Dick")]
+        );
+        assert_eq!(selected(&doc, "$..price").len(), 4);
+        assert_eq!(selected(&doc, "$.store.books[*].title").len(), 3);
+        assert_eq!(
+            selected(&doc, "$.store.books[0,2].price"),
+            vec![json!(8.95), json!(8.99)]
+        );
+        assert_eq!(
+            selected(&doc, "$.store.books[:2].title"),
+            vec![json!("Sayings"), json!("Sword")]
+        );
+        assert_eq!(
+            selected(&doc, "$.store.books[?(@.price < 10 && @.isbn)].title"),
+            vec![json!("Moby Dick")]
+        );
+        assert_eq!(
+            selected(&doc, "$..books[?(@.title == 'Sword' || @.tags)].price"),
+            vec![json!(8.95), json!(12.99)]
+        );
+        assert_eq!(selected(&doc, "$.nothing"), Vec::<Json>::new());
+
+        // Legacy paths select only the first match
+        assert_eq!(selected(&doc, "..price"), vec![json!(8.95)]);
+        assert_eq!(selected(&doc, "."), vec![doc.clone()]);
+
+        assert!(Path::parse("$.store[").is_none());
+        assert!(Path::parse("$.store.books[?(@.price <)]").is_none());
+    }
+
+    #[test]
+    fn test_json_del() {
+        let mut backend = Backend::new(std::collections::HashMap::new());
+        backend
+            .store
+            .kv_put(
+                "doc",
+                Value::Json(json!({"a": [1, 2, 3, 4], "b": {"a": 1}})),
+            )
+            .unwrap();
+
+        let cmd = JsonCommand::Del("doc".into(), Path::parse("$..a[1:3]").unwrap());
+        assert_eq!(backend.process_json(cmd), RespValue::Integer(2));
+        let cmd = JsonCommand::Del("doc".into(), Path::parse("$..a").unwrap());
+        assert_eq!(backend.process_json(cmd), RespValue::Integer(2));
+        assert_eq!(
+            backend.store.kv_get("doc").unwrap(),
+            Some(&Value::Json(json!({"b": {}})))
+        );
+    }
+}
@@ -0,0 +1,191 @@
+use std::vec::IntoIter;
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{
+    backend::Backend,
+    command::{int, strings, CommandError},
+    map::KvStore,
+    resp::RespValue,
+    value::{Value, WRONGTYPE},
+};
+
+/// A lock held by an owner until released or until its lease, the TTL of
+/// its key, runs out.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Lock {
+    owner: String,
+    /// Handed to the owner on acquiring the lock, greater than that of
+    /// any earlier acquisition, so that whatever the lock protects can
+    /// turn away owners whose lease ran out meanwhile.
+    token: u64,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum LockCommand {
+    /// `LOCK.ACQUIRE name owner lease_ms`
+    Acquire(String, String, u64),
+    /// `LOCK.RENEW name owner lease_ms`
+    Renew(String, String, u64),
+    /// `LOCK.RELEASE name owner`
+    Release(String, String),
+    /// `LOCK.INFO name`
+    Info(String),
+}
+
+impl LockCommand {
+    pub fn keys(&self) -> Vec<&str> {
+        use LockCommand::*;
+        match self {
+            Acquire(k, ..) | Renew(k, ..) | Release(k, _) | Info(k) => vec![k.as_str()],
+        }
+    }
+}
+
+/// Parses the arguments of `LOCK.ACQUIRE`, `LOCK.RENEW`, `LOCK.RELEASE`
+/// and `LOCK.INFO`.
+pub fn parse(verb: &str, arr: IntoIter<RespValue>) -> Result<LockCommand, CommandError> {
+    let args = strings(arr)?;
+    let lease = |ms: &str| match int(ms)? {
+        0 => Err(CommandError::InvalidCommand),
+        ms => Ok(ms),
+    };
+    let cmd = match (verb, args.as_slice()) {
+        ("LOCK.ACQUIRE", [k, owner, ms]) => {
+            LockCommand::Acquire(k.clone(), owner.clone(), lease(ms)?)
+        }
+        ("LOCK.RENEW", [k, owner, ms]) => LockCommand::Renew(k.clone(), owner.clone(), lease(ms)?),
+        ("LOCK.RELEASE", [k, owner]) => LockCommand::Release(k.clone(), owner.clone()),
+        ("LOCK.INFO", [k]) => LockCommand::Info(k.clone()),
+        _ => return Err(CommandError::InvalidCommand),
+    };
+    Ok(cmd)
+}
+
+impl<T> Backend<T>
+where
+    T: KvStore,
+{
+    fn lock(&self, key: &str) -> Result<Option<&Lock>, RespValue> {
+        match self.value(key)? {
+            Some(Value::Lock(lock)) => Ok(Some(lock)),
+            Some(_) => Err(RespValue::Error(WRONGTYPE.into())),
+            None => Ok(None),
+        }
+    }
+
+    /// The token of the lock at `key` if `owner` holds it, deleting the
+    /// lock first if its lease ran out.
+    fn owned_token(&mut self, key: &str, owner: &str) -> Result<Option<u64>, RespValue> {
+        self.expire_if_needed(key)?;
+        let lock = self.lock(key)?.filter(|lock| lock.owner == owner);
+        Ok(lock.map(|lock| lock.token))
+    }
+
+    pub(crate) fn process_lock(&mut self, cmd: LockCommand) -> RespValue {
+        use LockCommand::*;
+        match cmd {
+            Acquire(k, owner, lease) => self.process_lock_acquire(&k, owner, lease),
+            Renew(k, owner, lease) => self.process_lock_renew(&k, &owner, lease),
+            Release(k, owner) => self.process_lock_release(&k, &owner),
+            Info(k) => self.lock(&k).map(|lock| match lock {
+                Some(lock) => RespValue::Array(vec![
+                    RespValue::BulkString(lock.owner.clone()),
+                    RespValue::Integer(lock.token as i64),
+                    RespValue::Integer(
+                        self.expires.get(&k).map_or(-1, |at| (at - self.now) as i64),
+                    ),
+                ]),
+                None => RespValue::Null,
+            }),
+        }
+        .unwrap_or_else(|e| e)
+    }
+
+    /// Replies with a new fencing token, the current one if `owner` already
+    /// holds the lock, extending its lease, or nil if someone else holds it.
+    fn process_lock_acquire(
+        &mut self,
+        key: &str,
+        owner: String,
+        lease: u64,
+    ) -> Result<RespValue, RespValue> {
+        let token = match self.owned_token(key, &owner)? {
+            Some(token) => token,
+            None if self.store.kv_get(key)?.is_some() => return Ok(RespValue::Null),
+            None => {
+                self.fencing_token += 1;
+                let lock = Lock {
+                    owner,
+                    token: self.fencing_token,
+                };
+                self.store.kv_put(key, Value::Lock(lock))?;
+                self.fencing_token
+            }
+        };
+        self.expires
+            .insert(key.to_string(), self.now.saturating_add(lease));
+        self.notify('g', "lock.acquire", key);
+        Ok(RespValue::Integer(token as i64))
+    }
+
+    fn process_lock_renew(
+        &mut self,
+        key: &str,
+        owner: &str,
+        lease: u64,
+    ) -> Result<RespValue, RespValue> {
+        if self.owned_token(key, owner)?.is_none() {
+            return Ok(RespValue::Integer(0));
+        }
+        self.expires
+            .insert(key.to_string(), self.now.saturating_add(lease));
+        self.notify('g', "lock.renew", key);
+        Ok(RespValue::Integer(1))
+    }
+
+    fn process_lock_release(&mut self, key: &str, owner: &str) -> Result<RespValue, RespValue> {
+        if self.owned_token(key, owner)?.is_none() {
+            return Ok(RespValue::Integer(0));
+        }
+        self.store.kv_del(key)?;
+        self.expires.remove(key);
+        self.notify('g', "lock.release", key);
+        Ok(RespValue::Integer(1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_lock() {
+        let mut backend = Backend::new(HashMap::new());
+        let mut run = |now, cmd| {
+            backend.now = now;
+            backend.process_lock(cmd)
+        };
+        let acquire = |owner: &str, lease| LockCommand::Acquire("job".into(), owner.into(), lease);
+        let renew = |owner: &str, lease| LockCommand::Renew("job".into(), owner.into(), lease);
+        let release = |owner: &str| LockCommand::Release("job".into(), owner.into());
+
+        assert_eq!(run(1000, acquire("a", 100)), RespValue::Integer(1));
+        assert_eq!(run(1010, acquire("b", 100)), RespValue::Null);
+        assert_eq!(run(1020, acquire("a", 100)), RespValue::Integer(1));
+        assert_eq!(run(1030, renew("b", 100)), RespValue::Integer(0));
+        assert_eq!(run(1040, release("b")), RespValue::Integer(0));
+        assert_eq!(run(1050, renew("a", 100)), RespValue::Integer(1));
+
+        // The lease ran out, so b gets it with a greater token
+        assert_eq!(run(1150, acquire("b", 100)), RespValue::Integer(2));
+        assert_eq!(run(1160, renew("a", 100)), RespValue::Integer(0));
+        assert_eq!(run(1170, release("b")), RespValue::Integer(1));
+        assert_eq!(run(1180, acquire("a", 100)), RespValue::Integer(3));
+
+        // Leases are client input, and don't overflow
+        assert_eq!(run(1190, renew("a", u64::MAX)), RespValue::Integer(1));
+        assert_eq!(run(1200, acquire("a", u64::MAX)), RespValue::Integer(3));
+    }
+}
@@ -0,0 +1,944 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    mem,
+    ops::Bound,
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crc::{Crc, CRC_32_ISCSI};
+use elsa::FrozenMap;
+use serde_derive::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{
+    bloom::BloomFilter,
+    map::{check_key, is_empty_range, BatchOp, KvStore, Snapshot, META_PREFIX},
+    value::Value,
+};
+
+const CHECKSUM: Crc<u32> = Crc::<u32>::new(&CRC_32_ISCSI);
+const TABLE_MAGIC: &[u8; 8] = b"KVKVSST1";
+const BLOOM_ERROR_RATE: f64 = 0.01;
+
+/// A key and its value, or `None` if the key was deleted.
+type Entry = (String, Option<Value>);
+
+/// Sizes at which the store flushes and compacts, in bytes unless said
+/// otherwise.
+#[derive(Debug, Clone)]
+pub struct LsmOptions {
+    /// The memtable is written out as a table once it gets this big.
+    pub memtable_size: usize,
+    pub block_size: usize,
+    /// Compactions split their output into tables of about this size.
+    pub table_size: usize,
+    /// Level 0 is compacted into level 1 once it has this many tables.
+    pub level0_tables: usize,
+    /// Level 1 is compacted into level 2 once it gets this big, and each
+    /// level after may get ten times bigger than the previous one.
+    pub level1_size: u64,
+    /// How many values read from tables are kept around.
+    pub cache_entries: usize,
+}
+
+impl Default for LsmOptions {
+    fn default() -> Self {
+        LsmOptions {
+            memtable_size: 4 << 20,
+            block_size: 4 << 10,
+            table_size: 2 << 20,
+            level0_tables: 4,
+            level1_size: 10 << 20,
+            cache_entries: 1024,
+        }
+    }
+}
+
+fn corrupt(what: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what.to_string())
+}
+
+fn encode_entry(buf: &mut Vec<u8>, (key, value): (&str, Option<&Value>)) {
+    let value = serde_json::to_vec(&value).unwrap();
+    buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    buf.extend_from_slice(key.as_bytes());
+    buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&value);
+}
+
+/// Decodes the entry at the start of `buf`, replying with the bytes it took.
+fn decode_entry(buf: &[u8]) -> io::Result<(Entry, usize)> {
+    let mut pos = 0;
+    let mut take = |n: usize| {
+        let bytes = buf
+            .get(pos..pos + n)
+            .ok_or_else(|| corrupt("truncated entry"))?;
+        pos += n;
+        Ok::<_, io::Error>(bytes)
+    };
+    let key_len = u32::from_le_bytes(take(4)?.try_into().unwrap()) as usize;
+    let key = String::from_utf8(take(key_len)?.to_vec()).map_err(corrupt)?;
+    let value_len = u32::from_le_bytes(take(4)?.try_into().unwrap()) as usize;
+    let value = serde_json::from_slice(take(value_len)?).map_err(corrupt)?;
+    Ok(((key, value), pos))
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct BlockHandle {
+    last_key: String,
+    offset: u64,
+    len: u32,
+}
+
+/// What's at the end of a table file, read once when opening it.
+#[derive(Serialize, Deserialize, Debug)]
+struct TableMeta {
+    index: Vec<BlockHandle>,
+    bloom: BloomFilter,
+    first_key: String,
+    last_key: String,
+}
+
+/// A sorted, immutable table file: blocks of entries each followed by
+/// its checksum, then the block index and the bloom filter of its keys,
+/// then the offset and length of those, and the magic.
+struct Table {
+    id: u64,
+    path: PathBuf,
+    file: File,
+    meta: TableMeta,
+    size: u64,
+}
+
+impl Table {
+    fn path(dir: &Path, id: u64) -> PathBuf {
+        dir.join(format!("{:06}.sst", id))
+    }
+
+    fn open(dir: &Path, id: u64) -> io::Result<Table> {
+        let path = Table::path(dir, id);
+        let file = File::open(&path)?;
+        let size = file.metadata()?.len();
+        let mut footer = [0; 24];
+        file.read_exact_at(
+            &mut footer,
+            size.checked_sub(24)
+                .ok_or_else(|| corrupt("truncated table"))?,
+        )?;
+        if &footer[16..] != TABLE_MAGIC {
+            return Err(corrupt(format!("{} is not a table", path.display())));
+        }
+        let offset = u64::from_le_bytes(footer[..8].try_into().unwrap());
+        let len = u64::from_le_bytes(footer[8..16].try_into().unwrap());
+        let mut meta = vec![0; len as usize];
+        file.read_exact_at(&mut meta, offset)?;
+        let meta = serde_json::from_slice(&meta).map_err(corrupt)?;
+        Ok(Table {
+            id,
+            path,
+            file,
+            meta,
+            size,
+        })
+    }
+
+    /// Writes the entries, in order, as a table.
+    fn write(dir: &Path, id: u64, entries: &[Entry], block_size: usize) -> io::Result<Table> {
+        let mut buf = Vec::new();
+        let mut block = Vec::new();
+        let mut index = Vec::new();
+        let mut bloom = BloomFilter::new(BLOOM_ERROR_RATE, entries.len().max(1) as u64, 0);
+        for (i, (key, value)) in entries.iter().enumerate() {
+            encode_entry(&mut block, (key, value.as_ref()));
+            bloom.add(key.as_bytes()).unwrap();
+            if block.len() >= block_size || i == entries.len() - 1 {
+                index.push(BlockHandle {
+                    last_key: key.clone(),
+                    offset: buf.len() as u64,
+                    len: block.len() as u32,
+                });
+                buf.extend_from_slice(&block);
+                buf.extend_from_slice(&CHECKSUM.checksum(&block).to_le_bytes());
+                block.clear();
+            }
+        }
+
+        let meta = TableMeta {
+            index,
+            bloom,
+            first_key: entries.first().map(|e| e.0.clone()).unwrap_or_default(),
+            last_key: entries.last().map(|e| e.0.clone()).unwrap_or_default(),
+        };
+        let meta = serde_json::to_vec(&meta).unwrap();
+        let offset = buf.len() as u64;
+        buf.extend_from_slice(&meta);
+        buf.extend_from_slice(&offset.to_le_bytes());
+        buf.extend_from_slice(&(meta.len() as u64).to_le_bytes());
+        buf.extend_from_slice(TABLE_MAGIC);
+
+        let mut file = File::create(Table::path(dir, id))?;
+        file.write_all(&buf)?;
+        file.sync_all()?;
+        Table::open(dir, id)
+    }
+
+    fn covers(&self, key: &str) -> bool {
+        self.meta.first_key.as_str() <= key && key <= self.meta.last_key.as_str()
+    }
+
+    fn overlaps(&self, first: &str, last: &str) -> bool {
+        self.meta.first_key.as_str() <= last && first <= self.meta.last_key.as_str()
+    }
+
+    fn block(&self, i: usize) -> io::Result<Vec<Entry>> {
+        let handle = &self.meta.index[i];
+        let mut buf = vec![0; handle.len as usize + 4];
+        self.file.read_exact_at(&mut buf, handle.offset)?;
+        let (block, checksum) = buf.split_at(handle.len as usize);
+        if CHECKSUM.checksum(block).to_le_bytes() != checksum {
+            return Err(corrupt(format!(
+                "checksum mismatch in {}",
+                self.path.display()
+            )));
+        }
+        let mut entries = Vec::new();
+        let mut pos = 0;
+        while pos < block.len() {
+            let (entry, len) = decode_entry(&block[pos..])?;
+            entries.push(entry);
+            pos += len;
+        }
+        Ok(entries)
+    }
+
+    /// The entry of the key, if the table has one.
+    fn get(&self, key: &str) -> io::Result<Option<Option<Value>>> {
+        if !self.covers(key) || !self.meta.bloom.contains(key.as_bytes()) {
+            return Ok(None);
+        }
+        let i = self
+            .meta
+            .index
+            .partition_point(|handle| handle.last_key.as_str() < key);
+        if i == self.meta.index.len() {
+            return Ok(None);
+        }
+        let entry = self.block(i)?.into_iter().find(|(k, _)| k == key);
+        Ok(entry.map(|(_, value)| value))
+    }
+
+    fn iter(&self) -> TableIter<'_> {
+        TableIter {
+            table: self,
+            block: 0,
+            entries: Vec::new().into_iter(),
+        }
+    }
+}
+
+struct TableIter<'a> {
+    table: &'a Table,
+    block: usize,
+    entries: std::vec::IntoIter<Entry>,
+}
+
+impl Iterator for TableIter<'_> {
+    type Item = io::Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.entries.next() {
+                return Some(Ok(entry));
+            }
+            if self.block == self.table.meta.index.len() {
+                return None;
+            }
+            match self.table.block(self.block) {
+                Ok(entries) => self.entries = entries.into_iter(),
+                Err(e) => return Some(Err(e)),
+            }
+            self.block += 1;
+        }
+    }
+}
+
+/// Merges sorted tables into one sorted run, where the entry of the
+/// earliest table wins when several have the same key.
+struct Merge<'a> {
+    sources: Vec<TableIter<'a>>,
+    heads: Vec<Option<Entry>>,
+}
+
+impl<'a> Merge<'a> {
+    fn new(tables: impl Iterator<Item = &'a Arc<Table>>) -> io::Result<Merge<'a>> {
+        let mut sources: Vec<_> = tables.map(|table| table.iter()).collect();
+        let heads = sources
+            .iter_mut()
+            .map(|source| source.next().transpose())
+            .collect::<io::Result<_>>()?;
+        Ok(Merge { sources, heads })
+    }
+}
+
+impl Iterator for Merge<'_> {
+    type Item = io::Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let key = self.heads.iter().flatten().map(|(k, _)| k).min()?.clone();
+        let mut winner = None;
+        for (head, source) in self.heads.iter_mut().zip(self.sources.iter_mut()) {
+            if head.as_ref().is_some_and(|(k, _)| *k == key) {
+                let entry = head.take();
+                winner = winner.or(entry);
+                match source.next().transpose() {
+                    Ok(next) => *head = next,
+                    Err(e) => return Some(Err(e)),
+                }
+            }
+        }
+        winner.map(Ok)
+    }
+}
+
+/// Which tables make up each level, rewritten whenever that changes.
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Manifest {
+    next_table: u64,
+    levels: Vec<Vec<u64>>,
+}
+
+/// A log-structured merge tree keeping values on disk.
+///
+/// Writes go to a write-ahead log and to the memtable, which is written
+/// out as a table in level 0 once full. Level 0 tables may overlap, so
+/// once there are enough of them they're merged into level 1, whose
+/// tables don't overlap. Each level merges a table into the next one
+/// whenever it's over its size, dropping deleted keys at the last level.
+///
+/// Keys are all kept in memory, so that looking up missing keys and
+/// listing them don't touch the disk, but values aren't, apart from those
+/// in the memtable and a bounded cache of the ones last read.
+pub struct Lsm {
+    dir: PathBuf,
+    options: LsmOptions,
+    wal: File,
+    memtable: BTreeMap<String, Option<Value>>,
+    /// Bytes logged since the memtable was last written out.
+    memtable_size: usize,
+    /// Keys whose values were handed out to be changed in place, logged
+    /// on the next call that changes the store, as their final values
+    /// aren't known until then.
+    dirty: BTreeSet<String>,
+    /// Shared with the snapshots still reading them.
+    levels: Vec<Vec<Arc<Table>>>,
+    next_table: u64,
+    keys: BTreeSet<String>,
+    /// Keys of the entries of metadata, which aren't part of the dataset.
+    meta_keys: BTreeSet<String>,
+    cache: FrozenMap<String, Box<Value>>,
+}
+
+impl Lsm {
+    /// Opens the store in `dir`, creating it if needed.
+    pub fn open(dir: &Path, options: LsmOptions) -> io::Result<Lsm> {
+        fs::create_dir_all(dir)?;
+        let manifest: Manifest = match fs::read(dir.join("MANIFEST")) {
+            Ok(buf) => serde_json::from_slice(&buf).map_err(corrupt)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Manifest::default(),
+            Err(e) => return Err(e),
+        };
+        let levels = manifest
+            .levels
+            .iter()
+            .map(|ids| {
+                ids.iter()
+                    .map(|&id| Table::open(dir, id).map(Arc::new))
+                    .collect()
+            })
+            .collect::<io::Result<_>>()?;
+
+        let wal_path = dir.join("wal.log");
+        let mut lsm = Lsm {
+            dir: dir.to_path_buf(),
+            options,
+            wal: OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&wal_path)?,
+            memtable: BTreeMap::new(),
+            memtable_size: 0,
+            dirty: BTreeSet::new(),
+            levels,
+            next_table: manifest.next_table,
+            keys: BTreeSet::new(),
+            meta_keys: BTreeSet::new(),
+            cache: FrozenMap::new(),
+        };
+        lsm.remove_stray_tables()?;
+
+        let mut keys = BTreeSet::new();
+        for entry in Merge::new(lsm.tables())? {
+            if let (key, Some(_)) = entry? {
+                keys.insert(key);
+            }
+        }
+        (lsm.meta_keys, lsm.keys) = keys
+            .into_iter()
+            .partition(|key| key.starts_with(META_PREFIX));
+        lsm.replay_wal(&wal_path)?;
+        info!(
+            "opened the LSM store in {} with {} keys",
+            dir.display(),
+            lsm.keys.len()
+        );
+        Ok(lsm)
+    }
+
+    /// Every table, newest first.
+    fn tables(&self) -> impl Iterator<Item = &Arc<Table>> {
+        let level0 = self.levels.first().into_iter().flat_map(|l| l.iter().rev());
+        level0.chain(self.levels.iter().skip(1).flatten())
+    }
+
+    /// Deletes the tables a crash left behind in the middle of a flush or
+    /// a compaction.
+    fn remove_stray_tables(&self) -> io::Result<()> {
+        let live: BTreeSet<PathBuf> = self.tables().map(|t| t.path.clone()).collect();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "sst") && !live.contains(&path) {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Loads the log into the memtable, dropping the incomplete last
+    /// record of a crash in the middle of writing it.
+    fn replay_wal(&mut self, path: &Path) -> io::Result<()> {
+        let buf = fs::read(path)?;
+        let mut pos = 0;
+        while pos < buf.len() {
+            let record = buf.get(pos..pos + 8).and_then(|header| {
+                let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+                let checksum = u32::from_le_bytes(header[4..].try_into().unwrap());
+                let body = buf.get(pos + 8..pos + 8 + len)?;
+                (CHECKSUM.checksum(body) == checksum).then_some(body)
+            });
+            let Some(body) = record else {
+                warn!("truncating the write-ahead log at byte {}", pos);
+                OpenOptions::new()
+                    .write(true)
+                    .open(path)?
+                    .set_len(pos as u64)?;
+                break;
+            };
+            // Batches are logged as one record of several entries
+            let mut rest = body;
+            while !rest.is_empty() {
+                let ((key, value), len) = decode_entry(rest)?;
+                self.insert(key, value);
+                rest = &rest[len..];
+            }
+            pos += 8 + body.len();
+        }
+        self.memtable_size = pos;
+        Ok(())
+    }
+
+    /// Logs the entries as a single record, so that they're replayed
+    /// either all or none.
+    fn log<'a>(
+        &mut self,
+        entries: impl IntoIterator<Item = (&'a str, Option<&'a Value>)>,
+    ) -> io::Result<()> {
+        let mut body = Vec::new();
+        for entry in entries {
+            encode_entry(&mut body, entry);
+        }
+        let mut record = Vec::with_capacity(body.len() + 8);
+        record.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        record.extend_from_slice(&CHECKSUM.checksum(&body).to_le_bytes());
+        record.extend_from_slice(&body);
+        self.wal.write_all(&record)?;
+        self.memtable_size += record.len();
+        Ok(())
+    }
+
+    fn log_dirty(&mut self) -> io::Result<()> {
+        for key in mem::take(&mut self.dirty) {
+            if let Some(Some(value)) = self.memtable.get(&key) {
+                let value = value.clone();
+                self.log([(key.as_str(), Some(&value))])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Puts the entry in the memtable, keeping track of its key.
+    fn insert(&mut self, key: String, value: Option<Value>) {
+        let keys = match key.starts_with(META_PREFIX) {
+            true => &mut self.meta_keys,
+            false => &mut self.keys,
+        };
+        match value {
+            Some(_) => keys.insert(key.clone()),
+            None => keys.remove(&key),
+        };
+        self.memtable.insert(key, value);
+    }
+
+    /// Syncs the write-ahead log, including the values changed in place.
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.log_dirty()?;
+        self.wal.sync_data()
+    }
+
+    /// Prepares for a change: logs what was changed in place, and forgets
+    /// the cached value.
+    fn before_change(&mut self, key: &str) -> io::Result<()> {
+        self.log_dirty()?;
+        self.cache.as_mut().remove(key);
+        Ok(())
+    }
+
+    /// The value of the key in the tables.
+    fn lookup(&self, key: &str) -> io::Result<Option<Value>> {
+        for table in self.levels.first().into_iter().flat_map(|l| l.iter().rev()) {
+            if let Some(entry) = table.get(key)? {
+                return Ok(entry);
+            }
+        }
+        for level in self.levels.iter().skip(1) {
+            let i = level.partition_point(|t| t.meta.last_key.as_str() < key);
+            if let Some(entry) = level.get(i).map(|t| t.get(key)).transpose()?.flatten() {
+                return Ok(entry);
+            }
+        }
+        Ok(None)
+    }
+
+    /// Writes the entries, logged as one.
+    fn put(&mut self, entries: Vec<Entry>) -> io::Result<()> {
+        for (key, _) in entries.iter() {
+            self.before_change(key)?;
+        }
+        self.log(entries.iter().map(|(k, v)| (k.as_str(), v.as_ref())))?;
+        for (key, value) in entries {
+            self.insert(key, value);
+        }
+        if self.memtable_size >= self.options.memtable_size {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn write_manifest(&self) -> io::Result<()> {
+        let manifest = Manifest {
+            next_table: self.next_table,
+            levels: self
+                .levels
+                .iter()
+                .map(|level| level.iter().map(|t| t.id).collect())
+                .collect(),
+        };
+        let temp = self.dir.join("MANIFEST.tmp");
+        let mut file = File::create(&temp)?;
+        file.write_all(&serde_json::to_vec(&manifest).unwrap())?;
+        file.sync_all()?;
+        fs::rename(temp, self.dir.join("MANIFEST"))
+    }
+
+    /// Writes the memtable out as a level 0 table, then compacts.
+    fn flush(&mut self) -> io::Result<()> {
+        self.log_dirty()?;
+        let entries: Vec<Entry> = mem::take(&mut self.memtable).into_iter().collect();
+        if !entries.is_empty() {
+            let table = Table::write(
+                &self.dir,
+                self.next_table,
+                &entries,
+                self.options.block_size,
+            )?;
+            self.next_table += 1;
+            if self.levels.is_empty() {
+                self.levels.push(Vec::new());
+            }
+            self.levels[0].push(Arc::new(table));
+            self.write_manifest()?;
+        }
+        self.wal.set_len(0)?;
+        self.memtable_size = 0;
+        self.compact()
+    }
+
+    fn level_size(&self, level: usize) -> u64 {
+        self.levels[level].iter().map(|t| t.size).sum()
+    }
+
+    fn compact(&mut self) -> io::Result<()> {
+        loop {
+            let level = (0..self.levels.len()).find(|&level| match level {
+                0 => self.levels[0].len() >= self.options.level0_tables,
+                _ => {
+                    let max = self.options.level1_size * 10u64.pow(level as u32 - 1);
+                    self.level_size(level) > max
+                }
+            });
+            match level {
+                Some(level) => self.compact_level(level)?,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /// Merges level 0, or the first table of a later level, into the
+    /// tables of the next level it overlaps.
+    fn compact_level(&mut self, level: usize) -> io::Result<()> {
+        let mut inputs: Vec<Arc<Table>> = match level {
+            0 => mem::take(&mut self.levels[0]).into_iter().rev().collect(),
+            _ => vec![self.levels[level].remove(0)],
+        };
+        let first = inputs
+            .iter()
+            .map(|t| t.meta.first_key.clone())
+            .min()
+            .unwrap();
+        let last = inputs
+            .iter()
+            .map(|t| t.meta.last_key.clone())
+            .max()
+            .unwrap();
+        if self.levels.len() == level + 1 {
+            self.levels.push(Vec::new());
+        }
+        let (overlapping, rest): (Vec<_>, Vec<_>) = mem::take(&mut self.levels[level + 1])
+            .into_iter()
+            .partition(|t| t.overlaps(&first, &last));
+        self.levels[level + 1] = rest;
+        inputs.extend(overlapping);
+
+        // Deleted keys can go once there's nothing older they could hide
+        let bottom = self.levels[level + 2..].iter().all(|l| l.is_empty());
+        let mut outputs = Vec::new();
+        let mut entries = Vec::new();
+        let mut size = 0;
+        for entry in Merge::new(inputs.iter())? {
+            let entry = entry?;
+            if bottom && entry.1.is_none() {
+                continue;
+            }
+            size += entry.0.len() + 16;
+            entries.push(entry);
+            if size >= self.options.table_size {
+                outputs.push(self.write_table(&mem::take(&mut entries))?);
+                size = 0;
+            }
+        }
+        if !entries.is_empty() {
+            outputs.push(self.write_table(&entries)?);
+        }
+
+        let next = &mut self.levels[level + 1];
+        next.extend(outputs);
+        next.sort_by(|a, b| a.meta.first_key.cmp(&b.meta.first_key));
+        self.write_manifest()?;
+        for table in inputs {
+            fs::remove_file(&table.path)?;
+        }
+        Ok(())
+    }
+
+    fn write_table(&mut self, entries: &[Entry]) -> io::Result<Arc<Table>> {
+        let table = Table::write(&self.dir, self.next_table, entries, self.options.block_size)?;
+        self.next_table += 1;
+        Ok(Arc::new(table))
+    }
+}
+
+impl Drop for Lsm {
+    fn drop(&mut self) {
+        if let Err(e) = self.sync() {
+            warn!("failed to sync the LSM store: {}", e);
+        }
+    }
+}
+
+impl KvStore for Lsm {
+    fn kv_get(&self, key: &str) -> io::Result<Option<&Value>> {
+        if !self.keys.contains(key) {
+            return Ok(None);
+        }
+        if let Some(entry) = self.memtable.get(key) {
+            return Ok(entry.as_ref());
+        }
+        if let Some(value) = self.cache.get(key) {
+            return Ok(Some(value));
+        }
+        let Some(value) = self.lookup(key)? else {
+            return Ok(None);
+        };
+        Ok(Some(self.cache.insert(key.to_string(), Box::new(value))))
+    }
+
+    fn kv_get_mut(&mut self, key: &str) -> io::Result<Option<&mut Value>> {
+        if !self.keys.contains(key) {
+            return Ok(None);
+        }
+        self.before_change(key)?;
+        if !self.memtable.contains_key(key) {
+            let value = self.lookup(key)?;
+            self.memtable.insert(key.to_string(), value);
+        }
+        self.dirty.insert(key.to_string());
+        Ok(self.memtable.get_mut(key).and_then(Option::as_mut))
+    }
+
+    fn kv_put(&mut self, key: &str, value: Value) -> io::Result<()> {
+        check_key(key)?;
+        self.put(vec![(key.to_string(), Some(value))])
+    }
+
+    fn kv_del(&mut self, key: &str) -> io::Result<bool> {
+        if !self.keys.contains(key) {
+            return Ok(false);
+        }
+        self.put(vec![(key.to_string(), None)])?;
+        Ok(true)
+    }
+
+    fn kv_write_batch(&mut self, batch: Vec<BatchOp>) -> io::Result<()> {
+        let entries = batch
+            .into_iter()
+            .map(|op| match op {
+                BatchOp::Put(key, value) => check_key(&key).map(|_| (key, Some(value))),
+                BatchOp::Del(key) => Ok((key, None)),
+            })
+            .collect::<io::Result<_>>()?;
+        self.put(entries)
+    }
+
+    fn kv_sync(&mut self) -> io::Result<()> {
+        self.sync()
+    }
+
+    fn kv_trim(&mut self) {
+        let cache = self.cache.as_mut();
+        if cache.len() > self.options.cache_entries {
+            cache.clear();
+        }
+    }
+
+    /// Walks the tables in order rather than looking up every key, and
+    /// leaves the cache alone.
+    fn kv_snapshot(&self) -> io::Result<Vec<(String, Value)>> {
+        self.kv_deferred_snapshot()?()
+    }
+
+    /// Holds on to the tables, which compactions then only unlink, and
+    /// copies the memtable, walking the tables once it's read.
+    fn kv_deferred_snapshot(&self) -> io::Result<Snapshot> {
+        let tables: Vec<Arc<Table>> = self.tables().cloned().collect();
+        let memtable = self.memtable.clone();
+        let len = self.keys.len();
+        Ok(Box::new(move || {
+            let mut snapshot = Vec::with_capacity(len);
+            for entry in Merge::new(tables.iter())? {
+                if let (key, Some(value)) = entry? {
+                    if !key.starts_with(META_PREFIX) && !memtable.contains_key(&key) {
+                        snapshot.push((key, value));
+                    }
+                }
+            }
+            for (key, value) in memtable {
+                if let (false, Some(value)) = (key.starts_with(META_PREFIX), value) {
+                    snapshot.push((key, value));
+                }
+            }
+            Ok(snapshot)
+        }))
+    }
+
+    fn kv_put_meta(&mut self, entries: Vec<(String, Option<Value>)>) -> io::Result<()> {
+        let entries: Vec<Entry> = entries
+            .into_iter()
+            .map(|(name, value)| (format!("{}{}", META_PREFIX, name), value))
+            .filter(|(key, value)| value.is_some() || self.meta_keys.contains(key))
+            .collect();
+        match entries.is_empty() {
+            true => Ok(()),
+            false => self.put(entries),
+        }
+    }
+
+    fn kv_meta(&self) -> io::Result<Vec<(String, Value)>> {
+        let mut meta = Vec::with_capacity(self.meta_keys.len());
+        for key in self.meta_keys.iter() {
+            let value = match self.memtable.get(key) {
+                Some(entry) => entry.clone(),
+                None => self.lookup(key)?,
+            };
+            let value = value.ok_or_else(|| corrupt(format!("missing {:?}", key)))?;
+            meta.push((key[META_PREFIX.len()..].to_string(), value));
+        }
+        Ok(meta)
+    }
+
+    fn kv_keys(&self) -> impl Iterator<Item = &str> {
+        self.keys.iter().map(String::as_str)
+    }
+
+    fn kv_range<'a>(
+        &'a self,
+        start: Bound<&str>,
+        end: Bound<&str>,
+    ) -> impl Iterator<Item = &'a str> {
+        let range = match is_empty_range(start, end) {
+            true => None,
+            false => Some(self.keys.range::<str, _>((start, end))),
+        };
+        range.into_iter().flatten().map(String::as_str)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn small() -> LsmOptions {
+        LsmOptions {
+            memtable_size: 512,
+            block_size: 64,
+            table_size: 256,
+            level0_tables: 2,
+            level1_size: 1024,
+            cache_entries: 4,
+        }
+    }
+
+    #[test]
+    fn test_lsm() {
+        let dir = std::env::temp_dir().join(format!("kvkv-lsm-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let mut lsm = Lsm::open(&dir, small()).unwrap();
+        for i in 0..500 {
+            lsm.kv_put(&format!("key:{:03}", i), format!("value {}", i).into())
+                .unwrap();
+        }
+        for i in (0..500).step_by(3) {
+            assert!(lsm.kv_del(&format!("key:{:03}", i)).unwrap());
+        }
+        if let Some(Value::Str(s)) = lsm.kv_get_mut("key:001").unwrap() {
+            s.to_mut().extend_from_slice(b"!");
+        }
+        assert!(
+            lsm.levels.len() > 2,
+            "should have compacted into deeper levels"
+        );
+        assert!(lsm.levels[1..].iter().all(|level| level
+            .windows(2)
+            .all(|w| w[0].meta.last_key < w[1].meta.first_key)));
+
+        let check = |lsm: &Lsm| {
+            assert_eq!(lsm.kv_keys().count(), 333);
+            assert_eq!(lsm.kv_get("key:000").unwrap(), None);
+            assert_eq!(lsm.kv_get("key:001").unwrap(), Some(&"value 1!".into()));
+            assert_eq!(lsm.kv_get("key:499").unwrap(), Some(&"value 499".into()));
+            assert_eq!(lsm.kv_get("nope").unwrap(), None);
+        };
+        check(&lsm);
+        drop(lsm);
+
+        // A crash in the middle of logging a write
+        let mut wal = OpenOptions::new()
+            .append(true)
+            .open(dir.join("wal.log"))
+            .unwrap();
+        wal.write_all(&[42, 0, 0, 0, 1, 2]).unwrap();
+        let mut lsm = Lsm::open(&dir, small()).unwrap();
+        check(&lsm);
+        assert!(!lsm.kv_del("key:000").unwrap());
+        assert!(lsm.kv_del("key:001").unwrap());
+        assert_eq!(lsm.kv_get("key:001").unwrap(), None);
+
+        // Compactions unlinking the tables a snapshot reads leave it be
+        let snapshot = lsm.kv_deferred_snapshot().unwrap();
+        for i in 0..500 {
+            lsm.kv_put(&format!("key:{:03}", i), "overwritten".into())
+                .unwrap();
+        }
+        let snapshot = snapshot().unwrap();
+        assert_eq!(snapshot.len(), 332);
+        assert!(snapshot.contains(&("key:499".into(), "value 499".into())));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_lsm_batch() {
+        let dir = std::env::temp_dir().join(format!("kvkv-lsm-batch-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        crate::map::tests::conformance(&mut Lsm::open(&dir, small()).unwrap());
+
+        let mut lsm = Lsm::open(&dir, LsmOptions::default()).unwrap();
+        lsm.kv_put("a", "a".into()).unwrap();
+        let batch = vec![
+            BatchOp::Put("b".into(), "b".into()),
+            BatchOp::Put("c".into(), "c".into()),
+        ];
+        lsm.kv_write_batch(batch).unwrap();
+        drop(lsm);
+        let lsm = Lsm::open(&dir, LsmOptions::default()).unwrap();
+        assert_eq!(lsm.kv_len(), 3);
+        drop(lsm);
+
+        // A crash in the middle of logging the batch
+        let wal = OpenOptions::new()
+            .write(true)
+            .open(dir.join("wal.log"))
+            .unwrap();
+        wal.set_len(wal.metadata().unwrap().len() - 3).unwrap();
+        let lsm = Lsm::open(&dir, LsmOptions::default()).unwrap();
+        assert_eq!(lsm.kv_get("a").unwrap(), Some(&"a".into()));
+        assert_eq!(lsm.kv_get("b").unwrap(), None);
+        assert_eq!(lsm.kv_get("c").unwrap(), None);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_lsm_meta() {
+        let dir = std::env::temp_dir().join(format!("kvkv-lsm-meta-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let mut lsm = Lsm::open(&dir, small()).unwrap();
+        for i in 0..100 {
+            lsm.kv_put(&format!("key:{:03}", i), "value".into())
+                .unwrap();
+        }
+        let meta = vec![("a".to_string(), Some("1".into())), ("b".into(), None)];
+        lsm.kv_put_meta(meta).unwrap();
+        let reserved = format!("{}a", META_PREFIX);
+        assert!(lsm.kv_put(&reserved, "x".into()).is_err());
+        assert_eq!(lsm.kv_get(&reserved).unwrap(), None);
+
+        // Reads cache what they hand out until trimmed, snapshots don't
+        assert_eq!(lsm.kv_snapshot().unwrap().len(), 100);
+        assert!(lsm.cache.as_mut().is_empty());
+        assert_eq!(lsm.kv_iter().count(), 100);
+        lsm.kv_trim();
+        assert!(lsm.cache.as_mut().len() <= 4);
+        drop(lsm);
+
+        let lsm = Lsm::open(&dir, small()).unwrap();
+        assert_eq!(lsm.kv_len(), 100);
+        assert_eq!(lsm.kv_meta().unwrap(), vec![("a".into(), "1".into())]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,27 +1,168 @@
-use clap::Parser;
-use std::error::Error;
+use clap::{Parser, Subcommand};
+use encoding::ThresholdSetting;
+use evict::Evictor;
+use notify::NotifyConfig;
+use replica::AofConfig;
+use snapshot::{Format, SaveRule, Snapshots};
+use std::{
+    collections::{BTreeMap, HashMap},
+    error::Error,
+    path::PathBuf,
+    str::FromStr,
+};
 use tracing::info;
 
+mod aof;
 mod backend;
+mod bitcask;
+mod bitmap;
+mod bloom;
 mod command;
+mod cuckoo;
+mod encoding;
+mod evict;
+mod geo;
+mod hash;
+mod hll;
+mod json;
+mod lock;
+mod lsm;
 mod map;
 mod master;
+mod memory;
+mod meta;
+mod notify;
 mod proto;
+mod pubsub;
+mod queue;
+mod range;
+mod rdb;
 mod replica;
 mod resp;
+mod script;
+mod search;
+mod snapshot;
+mod tags;
+mod throttle;
 mod trace;
+mod value;
+mod vector;
+mod zset;
 
 #[derive(Parser)]
+#[clap(subcommand_negates_reqs = true)]
 struct Cli {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
     /// Listen on this port
-    #[clap(short, long)]
-    port: u16,
+    #[clap(short, long, required = true)]
+    port: Option<u16>,
 
     /// Replica addresses.
     /// If specified, the program will run as master and try to connect to the
     /// replicas.
     #[clap(short, long)]
     replica_addresses: Vec<String>,
+
+    /// Keyspace events to publish, with the same flags as Redis's
+    /// notify-keyspace-events, e.g. "Ex" for key expirations.
+    /// Only meaningful to the master.
+    #[clap(long, default_value = "")]
+    notify_keyspace_events: String,
+
+    /// Evict keys once the dataset takes more than this many bytes, e.g.
+    /// "100mb", 0 for no limit. Only meaningful to the master.
+    #[clap(long, default_value = "0", parse(try_from_str = evict::parse_bytes))]
+    maxmemory: u64,
+
+    /// Which keys to evict: "noeviction" to refuse writes instead,
+    /// "allkeys-lru", "volatile-lru", "allkeys-lfu", "volatile-lfu",
+    /// "allkeys-random", "volatile-random" or "volatile-ttl".
+    #[clap(long, default_value = "noeviction")]
+    maxmemory_policy: evict::Policy,
+
+    /// How many keys to sample for each eviction, more is more accurate
+    /// but slower.
+    #[clap(long, default_value = "5")]
+    maxmemory_samples: usize,
+
+    /// Log every write to this append-only file, and load it on start.
+    /// Only meaningful to replicas.
+    #[clap(long)]
+    aof: Option<PathBuf>,
+
+    /// When to fsync the append-only file: "always", "everysec" or "no".
+    #[clap(long, default_value = "everysec")]
+    appendfsync: aof::Fsync,
+
+    /// Take snapshots to this file, and load it on start unless there's
+    /// an append-only file. Only meaningful to replicas.
+    #[clap(long)]
+    dbfilename: Option<PathBuf>,
+
+    /// Snapshot after "<seconds> <changes>", at least that many changes
+    /// over at least that many seconds. Can be given multiple times.
+    #[clap(long)]
+    save: Vec<SaveRule>,
+
+    /// Write snapshots as "kvkv" snapshots or as Redis "rdb" files.
+    #[clap(long, default_value = "kvkv")]
+    dbformat: Format,
+
+    /// Where replicas keep their data: "memory", "btree" for memory kept in
+    /// key order, for fast SCANRANGE and DELPREFIX, "lsm" for an on-disk
+    /// log-structured merge tree in --dir, for data larger than memory, or
+    /// "bitcask" for an on-disk log-structured hash table in --dir, for
+    /// write-heavy data with small keys.
+    #[clap(long, default_value = "memory")]
+    store: StoreKind,
+
+    /// The directory of on-disk stores.
+    #[clap(long, default_value = "data")]
+    dir: PathBuf,
+
+    /// Convert small values to full structures past "<name> <value>",
+    /// named like Redis's settings, e.g. "hash-max-listpack-entries 256".
+    /// Can be given multiple times. Only meaningful to replicas. Thresholds
+    /// later set with CONFIG SET are kept with the dataset, and win over
+    /// these on a restart.
+    #[clap(long)]
+    encoding: Vec<ThresholdSetting>,
+}
+
+enum StoreKind {
+    Memory,
+    BTree,
+    Lsm,
+    Bitcask,
+}
+
+impl FromStr for StoreKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "memory" => Ok(StoreKind::Memory),
+            "btree" => Ok(StoreKind::BTree),
+            "lsm" => Ok(StoreKind::Lsm),
+            "bitcask" => Ok(StoreKind::Bitcask),
+            _ => Err(format!("invalid store {}", s)),
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Convert a snapshot offline, between kvkv snapshots and RDB files
+    Convert {
+        input: PathBuf,
+        output: PathBuf,
+
+        /// The format to write, the other one than the input's by default
+        #[clap(long)]
+        to: Option<Format>,
+    },
 }
 
 #[tokio::main]
@@ -29,12 +170,41 @@ async fn main() -> Result<(), Box<dyn Error>> {
     trace::init()?;
 
     let cli = Cli::parse();
+    if let Some(Command::Convert { input, output, to }) = cli.command {
+        return snapshot::convert(&input, &output, to);
+    }
+    let port = cli.port.unwrap();
 
     info!("hello from kvkv []~（￣▽￣）~*");
-    if cli.replica_addresses.len() != 0 {
-        master::run(cli.port, cli.replica_addresses).await.unwrap();
+    if !cli.replica_addresses.is_empty() {
+        let notify = NotifyConfig::parse(&cli.notify_keyspace_events)
+            .ok_or("invalid --notify-keyspace-events")?;
+        let evictor = Evictor::new(cli.maxmemory, cli.maxmemory_policy, cli.maxmemory_samples);
+        master::run(port, cli.replica_addresses, notify, evictor)
+            .await
+            .unwrap();
     } else {
-        replica::run(cli.port).await.unwrap();
+        cli.encoding.iter().for_each(ThresholdSetting::apply);
+        let aof = cli.aof.map(|path| AofConfig {
+            path,
+            fsync: cli.appendfsync,
+        });
+        let snapshots = cli
+            .dbfilename
+            .map(|path| Snapshots::new(path, cli.dbformat, cli.save));
+        match cli.store {
+            StoreKind::Memory => replica::run(port, HashMap::new(), aof, snapshots).await,
+            StoreKind::BTree => replica::run(port, BTreeMap::new(), aof, snapshots).await,
+            StoreKind::Lsm => {
+                let store = lsm::Lsm::open(&cli.dir, Default::default())?;
+                replica::run(port, store, aof, snapshots).await
+            }
+            StoreKind::Bitcask => {
+                let store = bitcask::Bitcask::open(&cli.dir, Default::default())?;
+                replica::run(port, store, aof, snapshots).await
+            }
+        }
+        .unwrap();
     }
 
     Ok(())
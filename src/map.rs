@@ -1,41 +1,385 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    ops::{Bound, RangeBounds},
+};
+
+use crate::{resp::RespValue, value::Value};
+
+/// Keys of metadata entries start with this in stores that keep them
+/// along with the keys of the dataset, which then can't.
+pub const META_PREFIX: &str = "\0meta:";
+
+/// Refuses keys of the dataset that would pass for metadata entries.
+pub fn check_key(key: &str) -> io::Result<()> {
+    match key.starts_with(META_PREFIX) {
+        true => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{:?} is reserved", key),
+        )),
+        false => Ok(()),
+    }
+}
+
+/// Every key with its value as of when it was taken, read when called,
+/// which may be on another thread.
+pub type Snapshot = Box<dyn FnOnce() -> io::Result<Vec<(String, Value)>> + Send>;
+
+/// A write of a batch.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BatchOp {
+    Put(String, Value),
+    Del(String),
+}
+
+/// Where keys and their values live. Stores may keep values anywhere, so
+/// whatever touches one can fail, but they keep every key in memory, so
+/// listing them can't.
 pub trait KvStore {
-    fn kv_get(&self, key: &str) -> Option<&str>;
-    fn kv_put(&mut self, key: &str, value: &str);
+    fn kv_get(&self, key: &str) -> io::Result<Option<&Value>>;
+    fn kv_get_mut(&mut self, key: &str) -> io::Result<Option<&mut Value>>;
+    fn kv_put(&mut self, key: &str, value: Value) -> io::Result<()>;
     /// Returns true if the key was in the map.
-    fn kv_del(&mut self, key: &str) -> bool;
+    fn kv_del(&mut self, key: &str) -> io::Result<bool>;
+    /// Every key in the map, in no particular order.
+    fn kv_keys(&self) -> impl Iterator<Item = &str>;
+
+    fn kv_len(&self) -> usize {
+        self.kv_keys().count()
+    }
+
+    /// Keys within the bounds, in order. Ordered maps only walk those, in
+    /// O(log n + k), where others sort every key.
+    fn kv_range<'a>(
+        &'a self,
+        start: Bound<&str>,
+        end: Bound<&str>,
+    ) -> impl Iterator<Item = &'a str> {
+        let mut keys: Vec<&str> = self
+            .kv_keys()
+            .filter(|k| RangeBounds::<str>::contains(&(start, end), *k))
+            .collect();
+        keys.sort_unstable();
+        keys.into_iter()
+    }
+
+    /// Every key with its value, in no particular order.
+    fn kv_iter(&self) -> impl Iterator<Item = io::Result<(&str, &Value)>> {
+        self.kv_keys()
+            .filter_map(|k| self.kv_get(k).map(|v| v.map(|v| (k, v))).transpose())
+    }
+
+    /// A copy of every key with its value, which outlives later changes.
+    fn kv_snapshot(&self) -> io::Result<Vec<(String, Value)>> {
+        self.kv_iter()
+            .map(|entry| entry.map(|(k, v)| (k.to_string(), v.clone())))
+            .collect()
+    }
+
+    /// A copy of every key with its value to read later on, for
+    /// snapshotting in the background. Stores that keep values on disk
+    /// only take note of where they are, reading them when it's called.
+    fn kv_deferred_snapshot(&self) -> io::Result<Snapshot> {
+        let snapshot = self.kv_snapshot()?;
+        Ok(Box::new(move || Ok(snapshot)))
+    }
+
+    /// Deletes every key, as one batch.
+    fn kv_clear(&mut self) -> io::Result<()> {
+        let keys = self
+            .kv_keys()
+            .map(|k| BatchOp::Del(k.to_string()))
+            .collect();
+        self.kv_write_batch(keys)
+    }
+
+    /// Applies every write of the batch in order, or none of them, even
+    /// across a crash. Stores whose writes can fail override this.
+    fn kv_write_batch(&mut self, batch: Vec<BatchOp>) -> io::Result<()> {
+        for op in batch {
+            match op {
+                BatchOp::Put(key, value) => self.kv_put(&key, value)?,
+                BatchOp::Del(key) => {
+                    self.kv_del(&key)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces the value of the key with `new`, deleting it if that's
+    /// `None`, provided its value is `expected`, `None` meaning missing.
+    /// Returns true if it was.
+    fn kv_compare_and_swap(
+        &mut self,
+        key: &str,
+        expected: Option<&Value>,
+        new: Option<Value>,
+    ) -> io::Result<bool> {
+        if self.kv_get(key)? != expected {
+            return Ok(false);
+        }
+        let op = match new {
+            Some(value) => BatchOp::Put(key.to_string(), value),
+            None => BatchOp::Del(key.to_string()),
+        };
+        self.kv_write_batch(vec![op])?;
+        Ok(true)
+    }
+
+    /// Makes the writes so far durable, for stores that buffer them.
+    fn kv_sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Forgets values kept around since reads handed them out, for stores
+    /// that cache them. Called between commands, as nothing borrows them
+    /// then.
+    fn kv_trim(&mut self) {}
+
+    /// Writes entries of metadata as one, `None` deleting one. Stores
+    /// whose data outlives a restart keep them apart from the keys, for
+    /// what the backend knows about keys besides their values to outlive
+    /// it too. Others have no use for them.
+    fn kv_put_meta(&mut self, _entries: Vec<(String, Option<Value>)>) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Every entry of metadata, by name.
+    fn kv_meta(&self) -> io::Result<Vec<(String, Value)>> {
+        Ok(Vec::new())
+    }
+}
+
+/// Store failures, replied to the command that ran into them.
+impl From<io::Error> for RespValue {
+    fn from(e: io::Error) -> Self {
+        RespValue::Error(format!("ERR store failed: {}", e))
+    }
+}
+
+/// Whether the bounds hold any key at all, as ordered maps panic when
+/// asked for a range that's backwards.
+pub fn is_empty_range(start: Bound<&str>, end: Bound<&str>) -> bool {
+    match (start, end) {
+        (Bound::Included(s), Bound::Included(e)) => s > e,
+        (Bound::Included(s) | Bound::Excluded(s), Bound::Included(e) | Bound::Excluded(e)) => {
+            s >= e
+        }
+        _ => false,
+    }
+}
+
+impl KvStore for HashMap<String, Value> {
+    #[inline]
+    fn kv_get(&self, key: &str) -> io::Result<Option<&Value>> {
+        Ok(self.get(key))
+    }
+
+    #[inline]
+    fn kv_get_mut(&mut self, key: &str) -> io::Result<Option<&mut Value>> {
+        Ok(self.get_mut(key))
+    }
+
+    #[inline]
+    fn kv_put(&mut self, key: &str, value: Value) -> io::Result<()> {
+        self.insert(key.into(), value);
+        Ok(())
+    }
+
+    #[inline]
+    fn kv_del(&mut self, key: &str) -> io::Result<bool> {
+        Ok(self.remove(key).is_some())
+    }
+
+    #[inline]
+    fn kv_keys(&self) -> impl Iterator<Item = &str> {
+        self.keys().map(String::as_str)
+    }
+
+    #[inline]
+    fn kv_len(&self) -> usize {
+        self.len()
+    }
+
+    fn kv_iter(&self) -> impl Iterator<Item = io::Result<(&str, &Value)>> {
+        self.iter().map(|(k, v)| Ok((k.as_str(), v)))
+    }
+
+    fn kv_clear(&mut self) -> io::Result<()> {
+        self.clear();
+        Ok(())
+    }
 }
 
-impl KvStore for std::collections::HashMap<String, String> {
+impl KvStore for BTreeMap<String, Value> {
+    #[inline]
+    fn kv_get(&self, key: &str) -> io::Result<Option<&Value>> {
+        Ok(self.get(key))
+    }
+
+    #[inline]
+    fn kv_get_mut(&mut self, key: &str) -> io::Result<Option<&mut Value>> {
+        Ok(self.get_mut(key))
+    }
+
+    #[inline]
+    fn kv_put(&mut self, key: &str, value: Value) -> io::Result<()> {
+        self.insert(key.into(), value);
+        Ok(())
+    }
+
     #[inline]
-    fn kv_get(&self, key: &str) -> Option<&str> {
-        self.get(key).and_then(|s| Some(s.as_str()))
+    fn kv_del(&mut self, key: &str) -> io::Result<bool> {
+        Ok(self.remove(key).is_some())
     }
 
     #[inline]
-    fn kv_put(&mut self, key: &str, value: &str) {
-        self.insert(key.into(), value.into());
+    fn kv_keys(&self) -> impl Iterator<Item = &str> {
+        self.keys().map(String::as_str)
     }
 
     #[inline]
-    fn kv_del(&mut self, key: &str) -> bool {
-        self.remove(key).is_some()
+    fn kv_len(&self) -> usize {
+        self.len()
+    }
+
+    fn kv_iter(&self) -> impl Iterator<Item = io::Result<(&str, &Value)>> {
+        self.iter().map(|(k, v)| Ok((k.as_str(), v)))
+    }
+
+    fn kv_clear(&mut self) -> io::Result<()> {
+        self.clear();
+        Ok(())
+    }
+
+    fn kv_range<'a>(
+        &'a self,
+        start: Bound<&str>,
+        end: Bound<&str>,
+    ) -> impl Iterator<Item = &'a str> {
+        let range = match is_empty_range(start, end) {
+            true => None,
+            false => Some(self.range::<str, _>((start, end))),
+        };
+        range.into_iter().flatten().map(|(k, _)| k.as_str())
     }
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
-    use std::collections::HashMap;
+
+    /// What every store must do, given an empty one.
+    pub(crate) fn conformance<T: KvStore>(store: &mut T) {
+        assert_eq!(store.kv_get("Changsha").unwrap(), None);
+        store.kv_put("Changsha", "Rainy".into()).unwrap();
+        assert_eq!(store.kv_get("Changsha").unwrap(), Some(&"Rainy".into()));
+        store.kv_put("Changsha", "Sunny".into()).unwrap();
+        assert_eq!(store.kv_get("Changsha").unwrap(), Some(&"Sunny".into()));
+        assert!(store.kv_del("Changsha").unwrap());
+        assert!(!store.kv_del("Changsha").unwrap());
+        assert_eq!(store.kv_get("Changsha").unwrap(), None);
+        assert_eq!(store.kv_get_mut("Changsha").unwrap(), None);
+
+        for k in ["c", "a", "b"] {
+            store.kv_put(k, k.into()).unwrap();
+        }
+        if let Some(Value::Str(s)) = store.kv_get_mut("b").unwrap() {
+            s.to_mut().push(b'!');
+        }
+        assert_eq!(store.kv_get("b").unwrap(), Some(&"b!".into()));
+        assert_eq!(store.kv_len(), 3);
+        let mut snapshot = store.kv_snapshot().unwrap();
+        snapshot.sort_unstable_by(|x, y| x.0.cmp(&y.0));
+        assert_eq!(
+            snapshot,
+            [
+                ("a".to_string(), "a".into()),
+                ("b".to_string(), "b!".into()),
+                ("c".to_string(), "c".into())
+            ]
+        );
+        let deferred = store.kv_deferred_snapshot().unwrap();
+        store.kv_put("a", "a!".into()).unwrap();
+        let mut deferred = deferred().unwrap();
+        deferred.sort_unstable_by(|x, y| x.0.cmp(&y.0));
+        assert_eq!(deferred, snapshot);
+        assert_eq!(store.kv_iter().count(), 3);
+        let range: Vec<&str> = store
+            .kv_range(Bound::Excluded("a"), Bound::Unbounded)
+            .collect();
+        assert_eq!(range, ["b", "c"]);
+
+        store
+            .kv_write_batch(vec![
+                BatchOp::Put("d".into(), "d".into()),
+                BatchOp::Del("a".into()),
+                BatchOp::Put("d".into(), "d2".into()),
+                BatchOp::Del("nope".into()),
+            ])
+            .unwrap();
+        assert_eq!(store.kv_get("a").unwrap(), None);
+        assert_eq!(store.kv_get("d").unwrap(), Some(&"d2".into()));
+        assert_eq!(store.kv_len(), 3);
+
+        let cas = |store: &mut T, expected: Option<&str>, new: Option<&str>| {
+            let expected = expected.map(Value::from);
+            store
+                .kv_compare_and_swap("e", expected.as_ref(), new.map(Value::from))
+                .unwrap()
+        };
+        assert!(!cas(store, Some("x"), Some("y")));
+        assert_eq!(store.kv_get("e").unwrap(), None);
+        assert!(cas(store, None, Some("x")));
+        assert!(!cas(store, None, Some("y")));
+        assert!(cas(store, Some("x"), Some("y")));
+        assert_eq!(store.kv_get("e").unwrap(), Some(&"y".into()));
+        assert!(cas(store, Some("y"), None));
+        assert_eq!(store.kv_get("e").unwrap(), None);
+
+        store.kv_sync().unwrap();
+        store.kv_clear().unwrap();
+        assert_eq!(store.kv_len(), 0);
+        assert_eq!(store.kv_get("b").unwrap(), None);
+        assert_eq!(store.kv_iter().count(), 0);
+    }
 
     #[test]
     fn test_kv_trait() {
-        let mut hm: HashMap<String, String> = HashMap::new();
-        assert_eq!(hm.kv_get("Changsha"), None);
-        hm.kv_put("Changsha", "Rainy");
-        assert_eq!(hm.kv_get("Changsha"), Some("Rainy"));
-        hm.kv_put("Changsha", "Sunny");
-        assert_eq!(hm.kv_get("Changsha"), Some("Sunny"));
-        hm.kv_del("Changsha");
-        assert_eq!(hm.kv_get("Changsha"), None);
+        conformance(&mut HashMap::new());
+        conformance(&mut BTreeMap::new());
+    }
+
+    fn range<T: KvStore>(store: &T, start: Bound<&str>, end: Bound<&str>) -> Vec<String> {
+        store.kv_range(start, end).map(String::from).collect()
+    }
+
+    #[test]
+    fn test_kv_range() {
+        let mut hm: HashMap<String, Value> = HashMap::new();
+        let mut bm: BTreeMap<String, Value> = BTreeMap::new();
+        for k in ["user:2", "user:1", "user:10", "item:1", "v"] {
+            hm.kv_put(k, "".into()).unwrap();
+            bm.kv_put(k, "".into()).unwrap();
+        }
+
+        use Bound::*;
+        let users = (Included("user:"), Excluded("user;"));
+        assert_eq!(
+            range(&bm, users.0, users.1),
+            ["user:1", "user:10", "user:2"]
+        );
+        assert_eq!(range(&hm, users.0, users.1), range(&bm, users.0, users.1));
+        assert_eq!(
+            range(&bm, Excluded("user:1"), Unbounded),
+            ["user:10", "user:2", "v"]
+        );
+        assert_eq!(
+            range(&hm, Excluded("user:1"), Unbounded),
+            ["user:10", "user:2", "v"]
+        );
+        assert!(range(&bm, Included("v"), Excluded("a")).is_empty());
+        assert!(range(&hm, Excluded("v"), Excluded("v")).is_empty());
     }
 }
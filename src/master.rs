@@ -1,8 +1,10 @@
 #![allow(dead_code)]
 
 use std::{
+    collections::HashMap,
     error::Error,
     net::{SocketAddr, SocketAddrV4},
+    sync::{Arc, Mutex},
     time::Duration,
 };
 
@@ -10,7 +12,7 @@ use futures::{SinkExt, StreamExt};
 use tokio::{
     join,
     net::{TcpListener, TcpStream},
-    spawn,
+    select, spawn,
     sync::{
         mpsc,
         oneshot::{self, error::RecvError, Sender},
@@ -20,8 +22,15 @@ use tokio_util::codec::{Decoder, Framed};
 use tracing::{error, info, instrument, trace, warn};
 
 use crate::{
+    backend::unix_millis,
+    command::Command,
+    encoding,
+    evict::{self, Evictor, Policy},
+    notify::{KeyEvent, NotifyConfig},
     proto::{read_frame, write_frame, ProtoCodec, ProtoValue},
+    pubsub::{PubSub, Subscriber},
     resp::{RespCodec, RespValue},
+    script::sha1_hex,
 };
 
 /// A [ProtoValue] message that can be sent to a [Master],
@@ -36,20 +45,29 @@ use crate::{
 /// [ProtoValue]: ../proto/enum.ProtoValue.html
 type MasterMessage = (ProtoValue, oneshot::Sender<ProtoValue>);
 
-pub async fn run(port: u16, replica_addrs: Vec<String>) -> Result<(), Box<dyn Error>> {
+pub async fn run(
+    port: u16,
+    replica_addrs: Vec<String>,
+    notify: NotifyConfig,
+    evictor: Evictor,
+) -> Result<(), Box<dyn Error>> {
     let (tx_resp, master_chan) = mpsc::channel::<MasterMessage>(16);
+    let pubsub = Arc::new(Mutex::new(PubSub::default()));
 
+    let master = Master::new(master_chan, replica_addrs, pubsub.clone(), notify, evictor);
     let values = join!(
-        spawn(async move { Master::new(master_chan, replica_addrs).run().await.unwrap() }),
-        spawn(async move { listen_for_clients(tx_resp, port).await.unwrap() })
+        spawn(async move { master.run().await.unwrap() }),
+        spawn(async move { listen_for_clients(tx_resp, pubsub, port).await.unwrap() })
     );
 
-    (values.0?, values.1?);
+    values.0?;
+    values.1?;
     Ok(())
 }
 
 async fn listen_for_clients(
     tx_resp: mpsc::Sender<MasterMessage>,
+    pubsub: Arc<Mutex<PubSub>>,
     port: u16,
 ) -> Result<(), Box<dyn Error>> {
     let address = SocketAddrV4::new(std::net::Ipv4Addr::LOCALHOST, port);
@@ -59,19 +77,102 @@ async fn listen_for_clients(
     loop {
         let tx_resp = tx_resp.clone();
         let (socket, _) = listener.accept().await?;
-        handle_client(socket, tx_resp).await;
+        spawn(handle_client(socket, tx_resp, pubsub.clone()));
     }
 }
 
-async fn handle_client(socket: TcpStream, master: mpsc::Sender<MasterMessage>) {
+async fn handle_client(
+    socket: TcpStream,
+    master: mpsc::Sender<MasterMessage>,
+    pubsub: Arc<Mutex<PubSub>>,
+) {
     let codec = RespCodec {};
     let mut conn = codec.framed(socket);
-    while let Some(resp) = read_frame(&mut conn).await {
-        match talk_to_master(&master, resp.into()).await {
-            Ok(ProtoValue::Resp(resp)) => write_frame(&mut conn, resp).await.unwrap(), // FIXME: handle connection error
-            Ok(_) => panic!("replica returns non-resp value"),
-            Err(_) => panic!("sender dropped"),
-        }
+    let mut tx = Transaction::default();
+    let mut subscriber = Subscriber::new(pubsub.clone());
+    loop {
+        // Besides replying to requests, push out messages published
+        // to the channels the client subscribed to.
+        let resp = select! {
+            resp = read_frame(&mut conn) => match resp {
+                Some(resp) => resp,
+                None => break,
+            },
+            Some(message) = subscriber.messages.recv() => {
+                if subscriber.overflowed() {
+                    warn!("disconnecting subscriber that fell too far behind");
+                    break;
+                }
+                write_frame(&mut conn, message).await.unwrap(); // FIXME: handle connection error
+                continue;
+            }
+        };
+
+        let subscribing = matches!(
+            resp.verb(),
+            Some("SUBSCRIBE" | "UNSUBSCRIBE" | "PSUBSCRIBE" | "PUNSUBSCRIBE")
+        );
+        let response = match resp.verb() {
+            _ if subscriber.is_subscribed() && !subscribing => RespValue::Error(
+                "ERR only (P)SUBSCRIBE / (P)UNSUBSCRIBE are allowed in this context".into(),
+            ),
+            Some("MULTI") => tx.multi(),
+            Some("EXEC") => tx.exec(&master).await,
+            Some("DISCARD") => tx.discard(),
+            Some("WATCH") => tx.watch(resp, &master).await,
+            Some("UNWATCH") => tx.unwatch(),
+            // Only the master sends these, to the replicas
+            Some("SWEEP" | "EVICT") => {
+                tx.dirty |= tx.queued.is_some();
+                RespValue::Error("ERR unknown command".into())
+            }
+            // Pub/sub isn't a command that can be queued, so inside a
+            // transaction it's refused rather than done right away
+            _ if tx.queued.is_some() => tx.queue(resp),
+            // Replies to (un)subscribing are pushed like messages
+            Some("SUBSCRIBE") => {
+                write_pushes(&mut conn, subscriber.subscribe(resp.into_args())).await;
+                continue;
+            }
+            Some("UNSUBSCRIBE") => {
+                write_pushes(&mut conn, subscriber.unsubscribe(resp.into_args())).await;
+                continue;
+            }
+            Some("PSUBSCRIBE") => {
+                write_pushes(&mut conn, subscriber.psubscribe(resp.into_args())).await;
+                continue;
+            }
+            Some("PUNSUBSCRIBE") => {
+                write_pushes(&mut conn, subscriber.punsubscribe(resp.into_args())).await;
+                continue;
+            }
+            Some("PUBLISH") => match resp.into_args().as_slice() {
+                [channel, message] => {
+                    RespValue::Integer(pubsub.lock().unwrap().publish(channel, message))
+                }
+                _ => RespValue::Error("ERR wrong number of arguments for 'publish'".into()),
+            },
+            Some("PUBSUB") => pubsub.lock().unwrap().process_pubsub(resp.into_args()),
+            _ => resp_from_master(&master, resp.into()).await,
+        };
+        write_frame(&mut conn, response).await.unwrap(); // FIXME: handle connection error
+    }
+}
+
+async fn write_pushes(conn: &mut Framed<TcpStream, RespCodec>, pushes: Vec<RespValue>) {
+    for push in pushes {
+        write_frame(conn, push).await.unwrap(); // FIXME: handle connection error
+    }
+}
+
+async fn resp_from_master(
+    master: &mpsc::Sender<MasterMessage>,
+    proto_value: ProtoValue,
+) -> RespValue {
+    match talk_to_master(master, proto_value).await {
+        Ok(ProtoValue::Resp(resp)) => resp,
+        Ok(_) => panic!("replica returns non-resp value"),
+        Err(_) => panic!("sender dropped"),
     }
 }
 
@@ -89,6 +190,83 @@ async fn talk_to_master(
     res.await
 }
 
+/// `MULTI`/`EXEC` state of a client connection.
+#[derive(Debug, Default)]
+struct Transaction {
+    /// Commands queued since `MULTI`, `None` outside of a transaction.
+    queued: Option<Vec<RespValue>>,
+    /// Set when a command couldn't be queued, `EXEC` will then abort.
+    dirty: bool,
+    /// Watched keys, and their versions at the time they were watched.
+    watched: Vec<(String, u64)>,
+}
+
+impl Transaction {
+    fn multi(&mut self) -> RespValue {
+        if self.queued.is_some() {
+            return RespValue::Error("ERR MULTI calls can not be nested".into());
+        }
+        self.queued = Some(Vec::new());
+        RespValue::SimpleString("OK".into())
+    }
+
+    fn queue(&mut self, resp: RespValue) -> RespValue {
+        // Catch malformed commands now rather than having
+        // replicas vote no at EXEC time.
+        if let Err(e) = Command::try_from(resp.clone()) {
+            self.dirty = true;
+            return e.reply(resp.verb().unwrap_or_default());
+        }
+        self.queued.as_mut().unwrap().push(resp);
+        RespValue::SimpleString("QUEUED".into())
+    }
+
+    async fn exec(&mut self, master: &mpsc::Sender<MasterMessage>) -> RespValue {
+        let queued = match self.queued.take() {
+            Some(queued) => queued,
+            None => return RespValue::Error("ERR EXEC without MULTI".into()),
+        };
+        let watched = std::mem::take(&mut self.watched);
+        if std::mem::take(&mut self.dirty) {
+            return RespValue::Error(
+                "EXECABORT Transaction discarded because of previous errors.".into(),
+            );
+        }
+
+        resp_from_master(master, ProtoValue::Exec(queued, watched)).await
+    }
+
+    fn discard(&mut self) -> RespValue {
+        if self.queued.take().is_none() {
+            return RespValue::Error("ERR DISCARD without MULTI".into());
+        }
+        self.dirty = false;
+        self.watched.clear();
+        RespValue::SimpleString("OK".into())
+    }
+
+    async fn watch(&mut self, resp: RespValue, master: &mpsc::Sender<MasterMessage>) -> RespValue {
+        if self.queued.is_some() {
+            return RespValue::Error("ERR WATCH inside MULTI is not allowed".into());
+        }
+
+        let keys = resp.into_args();
+        match talk_to_master(master, ProtoValue::Watch(keys.clone())).await {
+            Ok(ProtoValue::Versions(versions)) => {
+                self.watched.extend(keys.into_iter().zip(versions));
+                RespValue::SimpleString("OK".into())
+            }
+            Ok(_) => panic!("master replied to WATCH with non-versions value"),
+            Err(_) => panic!("sender dropped"),
+        }
+    }
+
+    fn unwatch(&mut self) -> RespValue {
+        self.watched.clear();
+        RespValue::SimpleString("OK".into())
+    }
+}
+
 #[derive(Debug, PartialEq)]
 enum Status {
     Offline,
@@ -109,10 +287,31 @@ struct Master {
     master_chan: mpsc::Receiver<MasterMessage>,
     next_sched: u32,
     written: bool,
+
+    /// Version of each key, the sequence number of the last write to it.
+    /// Keys that were never written are at version 0.
+    // FIXME: This map never shrinks.
+    versions: HashMap<String, u64>,
+    write_seq: u64,
+
+    /// Scripts by their SHA1 digest, for `EVALSHA`.
+    scripts: HashMap<String, String>,
+
+    pubsub: Arc<Mutex<PubSub>>,
+    /// Which keyspace events to publish.
+    notify: NotifyConfig,
+    /// What to evict once `maxmemory` is reached.
+    evictor: Evictor,
 }
 
 impl Master {
-    fn new(master_chan: mpsc::Receiver<MasterMessage>, replica_addrs: Vec<String>) -> Master {
+    fn new(
+        master_chan: mpsc::Receiver<MasterMessage>,
+        replica_addrs: Vec<String>,
+        pubsub: Arc<Mutex<PubSub>>,
+        notify: NotifyConfig,
+        evictor: Evictor,
+    ) -> Master {
         let replicas = replica_addrs
             .into_iter()
             .enumerate()
@@ -129,6 +328,12 @@ impl Master {
             master_chan,
             next_sched: 0,
             written: false,
+            versions: HashMap::new(),
+            write_seq: 0,
+            scripts: HashMap::new(),
+            pubsub,
+            notify,
+            evictor,
         }
     }
 
@@ -139,8 +344,15 @@ impl Master {
 
         info!("ready");
 
-        while let Some((proto, res_chan)) = self.master_chan.recv().await {
-            self.handle_proto(proto, res_chan).await;
+        let mut sweep = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            select! {
+                message = self.master_chan.recv() => match message {
+                    Some((proto, res_chan)) => self.handle_proto(proto, res_chan).await,
+                    None => break,
+                },
+                _ = sweep.tick() => self.sweep().await,
+            }
         }
 
         info!("shutting down master");
@@ -150,6 +362,367 @@ impl Master {
 
     #[instrument(skip(self, res_chan))]
     async fn handle_proto(&mut self, proto: ProtoValue, res_chan: Sender<ProtoValue>) {
+        match proto {
+            ProtoValue::Watch(keys) => {
+                let versions = keys.iter().map(|k| self.version(k)).collect();
+                res_chan.send(ProtoValue::Versions(versions)).unwrap();
+            }
+            ProtoValue::Exec(cmds, watched) => {
+                let response = self.exec(cmds, watched).await;
+                res_chan.send(response).unwrap();
+            }
+            // Replicas are the ones encoding values, so they're the ones
+            // to set thresholds, as a write that's logged along the others
+            ProtoValue::Resp(resp)
+                if Command::try_from(resp.clone())
+                    .is_ok_and(|cmd| matches!(cmd, Command::ConfigSet(..))) =>
+            {
+                // FIXME: handle connection error
+                self.written = true;
+                let response = match self.do_write(vec![resp]).await.unwrap() {
+                    Some(mut results) => results.pop().unwrap(),
+                    None => RespValue::Error("ERROR".into()),
+                };
+                res_chan.send(response.into()).unwrap();
+            }
+            ProtoValue::Resp(resp)
+                if resp.verb() == Some("CONFIG")
+                    && resp
+                        .clone()
+                        .into_args()
+                        .get(1)
+                        .is_some_and(|name| encoding::is_threshold(name)) =>
+            {
+                self.forward(resp.into(), res_chan).await;
+            }
+            ProtoValue::Resp(resp) if resp.verb() == Some("CONFIG") => {
+                res_chan.send(self.process_config(resp).into()).unwrap();
+            }
+            ProtoValue::Resp(resp) if resp.verb() == Some("OBJECT") => {
+                match self.process_object(&resp) {
+                    Some(response) => res_chan.send(response.into()).unwrap(),
+                    None => self.forward(resp.into(), res_chan).await,
+                }
+            }
+            ProtoValue::Resp(resp) if resp.verb() == Some("SCRIPT") => {
+                res_chan.send(self.process_script(resp).into()).unwrap();
+            }
+            ProtoValue::Resp(resp)
+                if matches!(resp.verb(), Some("BGREWRITEAOF" | "SAVE" | "BGSAVE")) =>
+            {
+                res_chan.send(self.broadcast(resp).await.into()).unwrap();
+            }
+            ProtoValue::Resp(resp) if resp.verb() == Some("LASTSAVE") => {
+                res_chan.send(self.last_save(resp).await.into()).unwrap();
+            }
+            ProtoValue::Resp(resp) => match self.resolve_script(resp) {
+                Ok(resp) => self.forward(resp.into(), res_chan).await,
+                Err(e) => res_chan.send(e.into()).unwrap(),
+            },
+            proto => self.forward(proto, res_chan).await,
+        }
+    }
+
+    #[instrument(skip(self))]
+    async fn exec(&mut self, cmds: Vec<RespValue>, watched: Vec<(String, u64)>) -> ProtoValue {
+        if watched.iter().any(|(k, v)| self.version(k) != *v) {
+            trace!("watched keys were modified, aborting");
+            return RespValue::Null.into();
+        }
+
+        let cmds: Vec<RespValue> = match cmds
+            .into_iter()
+            .map(|resp| self.resolve_script(resp))
+            .collect()
+        {
+            Ok(cmds) => cmds,
+            Err(e) => return e.into(),
+        };
+
+        if let Err(e) = self.make_room(&cmds).await {
+            return e.into();
+        }
+        // FIXME: handle connection error
+        self.written = true;
+        match self.do_write(cmds).await.unwrap() {
+            Some(results) => RespValue::Array(results).into(),
+            None => RespValue::Error(
+                "EXECABORT Transaction discarded because a replica voted no.".into(),
+            )
+            .into(),
+        }
+    }
+
+    fn process_script(&mut self, resp: RespValue) -> RespValue {
+        let args = resp.into_args();
+        match args.first().map(|s| s.to_uppercase()).as_deref() {
+            Some("LOAD") if args.len() == 2 => {
+                let sha = sha1_hex(&args[1]);
+                self.scripts.insert(sha.clone(), args[1].clone());
+                RespValue::BulkString(sha)
+            }
+            Some("EXISTS") if args.len() > 1 => RespValue::Array(
+                args[1..]
+                    .iter()
+                    .map(|sha| {
+                        RespValue::Integer(self.scripts.contains_key(&sha.to_lowercase()) as i64)
+                    })
+                    .collect(),
+            ),
+            Some("FLUSH") => {
+                self.scripts.clear();
+                RespValue::SimpleString("OK".into())
+            }
+            _ => RespValue::Error(
+                "ERR unknown SCRIPT subcommand or wrong number of arguments".into(),
+            ),
+        }
+    }
+
+    /// Remembers the bodies of `EVAL`ed scripts, and swaps the digest of an
+    /// `EVALSHA` for the script body, so replicas only ever run `EVAL`s.
+    fn resolve_script(&mut self, resp: RespValue) -> Result<RespValue, RespValue> {
+        match resp.verb() {
+            Some("EVAL") => {
+                if let Ok(Command::Eval(script, ..)) = Command::try_from(resp.clone()) {
+                    self.scripts.insert(sha1_hex(&script), script);
+                }
+                Ok(resp)
+            }
+            Some("EVALSHA") => {
+                let mut arr = match resp {
+                    RespValue::Array(arr) => arr,
+                    _ => unreachable!(),
+                };
+                let script = match arr.get(1) {
+                    Some(RespValue::BulkString(sha)) => self.scripts.get(&sha.to_lowercase()),
+                    // Malformed, leave it to the replicas to reject
+                    _ => return Ok(RespValue::Array(arr)),
+                };
+
+                match script {
+                    Some(script) => {
+                        arr[0] = RespValue::BulkString("EVAL".into());
+                        arr[1] = RespValue::BulkString(script.clone());
+                        Ok(RespValue::Array(arr))
+                    }
+                    None => Err(RespValue::Error(
+                        "NOSCRIPT No matching script. Please use EVAL.".into(),
+                    )),
+                }
+            }
+            _ => Ok(resp),
+        }
+    }
+
+    fn version(&self, key: &str) -> u64 {
+        self.versions.get(key).copied().unwrap_or(0)
+    }
+
+    /// Bumps the versions of the keys the committed write touched,
+    /// failing `EXEC`s that are watching them.
+    fn touch(&mut self, cmds: &[RespValue], events: &[KeyEvent]) {
+        for resp in cmds.iter().filter(|resp| resp.is_write()) {
+            if let Ok(cmd) = Command::try_from(resp.clone()) {
+                self.write_seq += 1;
+                for key in cmd.keys() {
+                    self.versions.insert(key.into(), self.write_seq);
+                }
+            }
+        }
+
+        // Keys can also change without being named by the command,
+        // e.g. when expiring
+        for event in events {
+            self.write_seq += 1;
+            self.versions.insert(event.key.clone(), self.write_seq);
+        }
+    }
+
+    /// Records reads and writes of the keys the commands name, for
+    /// evicting the least recently or frequently used keys.
+    fn accessed(&mut self, cmds: &[RespValue], now: u64) {
+        for cmd in cmds
+            .iter()
+            .filter_map(|resp| Command::try_from(resp.clone()).ok())
+            // Looking into a key isn't using it
+            .filter(|cmd| !matches!(cmd, Command::Memory(_)))
+        {
+            for key in cmd.keys() {
+                self.evictor.touch(key, now);
+            }
+        }
+    }
+
+    /// Answers `OBJECT IDLETIME` and `OBJECT FREQ` from what the master
+    /// knows about accesses, leaving the rest to replicas.
+    fn process_object(&self, resp: &RespValue) -> Option<RespValue> {
+        let args = resp.clone().into_args();
+        let (sub, key) = match args.as_slice() {
+            [sub, key] => (sub.to_uppercase(), key),
+            _ => return None,
+        };
+        let now = unix_millis();
+        let reply = match sub.as_str() {
+            "IDLETIME" => self.evictor.idle_time(key, now),
+            "FREQ" => self.evictor.frequency(key, now).map(u64::from),
+            _ => return None,
+        };
+        Some(reply.map_or(RespValue::Null, |n| RespValue::Integer(n as i64)))
+    }
+
+    fn process_config(&mut self, resp: RespValue) -> RespValue {
+        let args = resp.into_args();
+        let args: Vec<&str> = args.iter().map(|s| s.as_str()).collect();
+        match args.as_slice() {
+            [get, param] if get.eq_ignore_ascii_case("GET") => {
+                match param.to_lowercase().as_str() {
+                    "notify-keyspace-events" => {
+                        RespValue::array(&[param, &self.notify.to_string()])
+                    }
+                    "maxmemory" => RespValue::array(&[param, &self.evictor.maxmemory.to_string()]),
+                    "maxmemory-policy" => {
+                        RespValue::array(&[param, &self.evictor.policy().to_string()])
+                    }
+                    "maxmemory-samples" => {
+                        RespValue::array(&[param, &self.evictor.samples.to_string()])
+                    }
+                    _ => RespValue::Array(vec![]),
+                }
+            }
+            [set, param, value] if set.eq_ignore_ascii_case("SET") => {
+                match param.to_lowercase().as_str() {
+                    "notify-keyspace-events" => match NotifyConfig::parse(value) {
+                        Some(notify) => {
+                            self.notify = notify;
+                            RespValue::SimpleString("OK".into())
+                        }
+                        None => RespValue::Error("ERR Invalid event class character".into()),
+                    },
+                    "maxmemory" => match evict::parse_bytes(value) {
+                        Ok(maxmemory) => {
+                            self.evictor.maxmemory = maxmemory;
+                            RespValue::SimpleString("OK".into())
+                        }
+                        Err(e) => RespValue::Error(format!("ERR {}", e)),
+                    },
+                    "maxmemory-policy" => match value.parse::<Policy>() {
+                        Ok(policy) => {
+                            self.evictor.set_policy(policy);
+                            RespValue::SimpleString("OK".into())
+                        }
+                        Err(e) => RespValue::Error(format!("ERR {}", e)),
+                    },
+                    "maxmemory-samples" => match value.parse::<usize>() {
+                        Ok(samples) if samples > 0 => {
+                            self.evictor.samples = samples;
+                            RespValue::SimpleString("OK".into())
+                        }
+                        _ => RespValue::Error("ERR Invalid maxmemory-samples".into()),
+                    },
+                    _ => RespValue::Error(format!("ERR Unknown option '{}'", param)),
+                }
+            }
+            _ => RespValue::Error(
+                "ERR unknown CONFIG subcommand or wrong number of arguments".into(),
+            ),
+        }
+    }
+
+    /// Has every replica run the command, such as persisting its dataset,
+    /// replying with the first error if any of them can't.
+    async fn broadcast(&mut self, resp: RespValue) -> RespValue {
+        let mut response = RespValue::Error("ERROR".into());
+        for r in self.replicas.iter_mut() {
+            if r.status != Status::Online {
+                continue;
+            }
+            // FIXME: handle connection error
+            match r.talk(resp.clone().into()).await.unwrap() {
+                ProtoValue::Resp(RespValue::Error(e)) => return RespValue::Error(e),
+                ProtoValue::Resp(resp) => response = resp,
+                proto => panic!("replica replied with non-resp response: {:?}", proto),
+            }
+        }
+        response
+    }
+
+    /// `LASTSAVE`, as of the replica that saved the longest ago, as every
+    /// replica saves on its own, but `SAVE` and `BGSAVE` have them all
+    /// save.
+    async fn last_save(&mut self, resp: RespValue) -> RespValue {
+        let mut last_save = None;
+        for r in self.replicas.iter_mut() {
+            if r.status != Status::Online {
+                continue;
+            }
+            // FIXME: handle connection error
+            match r.talk(resp.clone().into()).await.unwrap() {
+                ProtoValue::Resp(RespValue::Integer(at)) => {
+                    last_save = Some(last_save.map_or(at, |min: i64| min.min(at)))
+                }
+                ProtoValue::Resp(resp) => return resp,
+                proto => panic!("replica replied with non-resp response: {:?}", proto),
+            }
+        }
+        last_save.map_or(RespValue::Error("ERROR".into()), RespValue::Integer)
+    }
+
+    /// Expires the keys whose TTL ran out, as reads don't delete them.
+    /// Replicas may have loaded keys from disk, so this goes on whether
+    /// or not anything was written since, as long as they're all there to
+    /// take the write.
+    async fn sweep(&mut self) {
+        if !self.replicas.iter().all(|r| r.status == Status::Online) {
+            return;
+        }
+        // FIXME: handle connection error
+        self.do_write(vec![RespValue::array(&["SWEEP"])])
+            .await
+            .unwrap();
+    }
+
+    /// Evicts keys if the writes may need more memory than `maxmemory`
+    /// allows, refusing them if that's not enough.
+    async fn make_room(&mut self, cmds: &[RespValue]) -> Result<(), RespValue> {
+        if !self.evictor.refuses(cmds) {
+            return Ok(());
+        }
+        let victims = self.evictor.victims(unix_millis());
+        if !victims.is_empty() {
+            trace!(
+                "{} bytes used, evicting {:?}, {} evicted so far",
+                self.evictor.used(),
+                victims,
+                self.evictor.evicted()
+            );
+            let count = victims.len();
+            let evict = std::iter::once("EVICT".to_string())
+                .chain(victims)
+                .map(RespValue::BulkString)
+                .collect();
+            // FIXME: handle connection error
+            if self
+                .do_write(vec![RespValue::Array(evict)])
+                .await
+                .unwrap()
+                .is_some()
+            {
+                self.evictor.count_evicted(count);
+            }
+        }
+        match self.evictor.refuses(cmds) {
+            true => Err(RespValue::Error(evict::OOM.into())),
+            false => Ok(()),
+        }
+    }
+
+    async fn forward(&mut self, proto: ProtoValue, res_chan: Sender<ProtoValue>) {
+        if let ProtoValue::Resp(resp) = &proto {
+            if !resp.is_write() {
+                self.accessed(std::slice::from_ref(resp), unix_millis());
+            }
+        }
         // FIXME: shouldn't pick a replica if it's write operation
         let replica = self.schedule_next();
 
@@ -159,10 +732,22 @@ impl Master {
 
                 let response: ProtoValue = match proto {
                     ProtoValue::Resp(resp) => {
-                        if resp.is_write() {
-                            // FIXME: handle connection error
-                            self.written = true;
-                            self.do_write(resp.into()).await.unwrap()
+                        if let (true, Err(e)) = (resp.is_write(), Command::try_from(resp.clone())) {
+                            // Refused here rather than by replicas voting no
+                            e.reply(resp.verb().unwrap_or_default()).into()
+                        } else if resp.is_write() {
+                            let cmds = vec![resp];
+                            match self.make_room(&cmds).await {
+                                // FIXME: handle connection error
+                                Ok(()) => {
+                                    self.written = true;
+                                    match self.do_write(cmds).await.unwrap() {
+                                        Some(mut results) => results.pop().unwrap().into(),
+                                        None => RespValue::Error("ERROR".into()).into(),
+                                    }
+                                }
+                                Err(e) => e.into(),
+                            }
                         } else {
                             replica.talk(resp.into()).await.unwrap()
                         }
@@ -189,13 +774,19 @@ impl Master {
         }
     }
 
-    // Implements two-phase commit
+    // Implements two-phase commit, returning the results of the
+    // commands, or `None` if the write was aborted.
     // FIXME: Only send messages to replicas that are ONLINE
-    #[instrument(skip(self, value))]
-    async fn do_write(&mut self, value: ProtoValue) -> Result<ProtoValue, std::io::Error> {
+    #[instrument(skip(self, cmds))]
+    async fn do_write(
+        &mut self,
+        cmds: Vec<RespValue>,
+    ) -> Result<Option<Vec<RespValue>>, std::io::Error> {
         // Step 1: send write to all replicas
         // FIXME: We're spending N*RTT here.
         trace!("asking replicas to write");
+        let now = unix_millis();
+        let value = ProtoValue::Batch(now, cmds);
         let mut all_yes = true;
         for r in self.replicas.iter_mut() {
             // Step 2: wait for all replicas to reply
@@ -210,16 +801,36 @@ impl Master {
         // Step 3: if all replicas agree, write to all replicas
         let decision = ProtoValue::Decision(all_yes);
         trace!("decision: {:?}", decision);
-        let mut res = ProtoValue::Handshake(444);
+        let mut res = None;
+        let mut events = Vec::new();
+        let mut usage = Vec::new();
         for r in self.replicas.iter_mut() {
             let response = r.talk(decision.clone()).await.unwrap();
             match response {
-                ProtoValue::Resp(resp) => res = resp.into(),
+                ProtoValue::Committed(results, evs, keys) => {
+                    res = Some(results);
+                    events = evs;
+                    usage = keys;
+                }
+                ProtoValue::Decision(false) => res = None,
                 _ => unreachable!(),
             }
         }
         trace!("response: {:?}", res);
 
+        if let ProtoValue::Batch(_, cmds) = &value {
+            if all_yes {
+                self.touch(cmds, &events);
+                self.evictor.update(usage, now);
+                self.accessed(cmds, now);
+            }
+        }
+
+        let pubsub = self.pubsub.lock().unwrap();
+        for event in events.iter() {
+            self.notify.publish(&pubsub, event);
+        }
+
         Ok(res)
     }
 
@@ -230,6 +841,19 @@ impl Master {
             r.try_connect(self.written).await.unwrap();
         }
 
+        // Replicas may have loaded their dataset from disk
+        if let Some(r) = self
+            .replicas
+            .iter_mut()
+            .find(|r| r.status == Status::Online)
+        {
+            // FIXME: handle connection error
+            match r.talk(ProtoValue::Keyspace).await.unwrap() {
+                ProtoValue::Usage(usage) => self.evictor.reset(usage, unix_millis()),
+                proto => panic!("replica replied to Keyspace with {:?}", proto),
+            }
+        }
+
         trace!("connected to all replicas");
     }
 
@@ -238,8 +862,7 @@ impl Master {
         let r = self
             .replicas
             .iter_mut()
-            .filter(|r| r.id >= self.next_sched && r.status == Status::Online)
-            .next();
+            .find(|r| r.id >= self.next_sched && r.status == Status::Online);
 
         // Oh no
         if let Some(r) = r {
@@ -315,6 +938,6 @@ impl Replica {
     async fn talk(&mut self, value: ProtoValue) -> Result<ProtoValue, std::io::Error> {
         self.write_frame(value).await?;
         // FIXME: return connection error
-        Ok(self.read_frame().await.unwrap()?)
+        self.read_frame().await.unwrap()
     }
 }
@@ -0,0 +1,303 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    io::{self, Write},
+    vec::IntoIter,
+};
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{
+    backend::Backend,
+    command::{int, strings, CommandError},
+    map::KvStore,
+    resp::RespValue,
+    value::Value,
+};
+
+/// Bytes a key takes besides its name and its value, about what Redis
+/// spends on the entry of its dictionary and the object header.
+const KEY_OVERHEAD: u64 = 48;
+
+/// Counts the bytes written to it.
+struct Counter(u64);
+
+impl Write for Counter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// About how many bytes the key takes. Strings and collections take what
+/// their encoding allocates, other values the length of their serialized
+/// form, which is the same on every replica whatever order their elements
+/// are kept in, but takes going through all of them.
+pub(crate) fn memory_usage(key: &str, value: &Value) -> u64 {
+    let value = match value {
+        Value::Str(s) => s.allocated(),
+        Value::Hash(hash) => hash.allocated(),
+        Value::Set(set) => set.allocated(),
+        Value::List(list) => list.allocated(),
+        value => {
+            let mut counter = Counter(0);
+            serde_json::to_writer(&mut counter, value).unwrap();
+            counter.0
+        }
+    };
+    KEY_OVERHEAD + key.len() as u64 + value
+}
+
+#[derive(Debug, PartialEq)]
+pub enum MemoryCommand {
+    /// `MEMORY USAGE key [SAMPLES count]`, where every element is counted
+    /// whatever the count.
+    Usage(String),
+    /// `MEMORY STATS`
+    Stats,
+    /// `OBJECT ENCODING key`, the master answers the other subcommands,
+    /// as it's what sees every access to keys.
+    Encoding(String),
+}
+
+impl MemoryCommand {
+    pub fn keys(&self) -> Vec<&str> {
+        match self {
+            MemoryCommand::Usage(k) | MemoryCommand::Encoding(k) => vec![k.as_str()],
+            MemoryCommand::Stats => vec![],
+        }
+    }
+}
+
+/// Parses the arguments of `MEMORY` and `OBJECT`.
+pub fn parse(verb: &str, arr: IntoIter<RespValue>) -> Result<MemoryCommand, CommandError> {
+    let args = strings(arr)?;
+    let is = |arg: &String, name: &str| arg.eq_ignore_ascii_case(name);
+    match (verb, args.as_slice()) {
+        ("MEMORY", [sub, key]) if is(sub, "USAGE") => Ok(MemoryCommand::Usage(key.clone())),
+        ("MEMORY", [sub, key, option, count]) if is(sub, "USAGE") && is(option, "SAMPLES") => {
+            int::<u64>(count)?;
+            Ok(MemoryCommand::Usage(key.clone()))
+        }
+        ("MEMORY", [sub]) if is(sub, "STATS") => Ok(MemoryCommand::Stats),
+        ("OBJECT", [sub, key]) if is(sub, "ENCODING") => Ok(MemoryCommand::Encoding(key.clone())),
+        _ => Err(CommandError::InvalidCommand),
+    }
+}
+
+/// What a replica tells the master about a key: how many bytes it takes,
+/// `None` if it's gone, and its TTL deadline.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct KeyUsage {
+    pub key: String,
+    pub size: Option<u64>,
+    pub deadline: Option<u64>,
+}
+
+/// The bytes each key takes, kept up to date as keys change.
+#[derive(Debug, Default)]
+pub struct Memory {
+    sizes: HashMap<String, u64>,
+    used: u64,
+    /// The most `used` ever was.
+    peak: u64,
+}
+
+impl Memory {
+    fn set(&mut self, key: &str, size: Option<u64>) {
+        let old = match size {
+            Some(size) => self.sizes.insert(key.to_string(), size),
+            None => self.sizes.remove(key),
+        };
+        self.used = self.used + size.unwrap_or(0) - old.unwrap_or(0);
+        self.peak = self.peak.max(self.used);
+    }
+
+    pub fn size(&self, key: &str) -> Option<u64> {
+        self.sizes.get(key).copied()
+    }
+
+    /// Bytes taken by every key.
+    pub fn used(&self) -> u64 {
+        self.used
+    }
+}
+
+impl<T> Backend<T>
+where
+    T: KvStore,
+{
+    /// Measures the keys that changed since the event at `from`, which
+    /// every change to a key raises one of.
+    pub(crate) fn account(&mut self, from: usize) -> io::Result<()> {
+        let keys: BTreeSet<String> = self.events[from..].iter().map(|e| e.key.clone()).collect();
+        for key in keys {
+            let size = self.store.kv_get(&key)?.map(|v| memory_usage(&key, v));
+            self.memory.set(&key, size);
+        }
+        Ok(())
+    }
+
+    /// Measures every key, for when they were loaded without raising
+    /// events.
+    pub(crate) fn account_all(&mut self) -> io::Result<()> {
+        let mut memory = Memory {
+            peak: self.memory.peak,
+            ..Memory::default()
+        };
+        // One at a time, for stores that cache what's read
+        let keys: Vec<String> = self.store.kv_keys().map(String::from).collect();
+        for key in keys {
+            let size = self.store.kv_get(&key)?.map(|v| memory_usage(&key, v));
+            memory.set(&key, size);
+            self.store.kv_trim();
+        }
+        self.memory = memory;
+        Ok(())
+    }
+
+    /// What the master needs to know about the keys named by the events,
+    /// or about every key.
+    pub(crate) fn usage<'a>(&self, keys: impl IntoIterator<Item = &'a str>) -> Vec<KeyUsage> {
+        let keys: BTreeSet<&str> = keys.into_iter().collect();
+        keys.into_iter()
+            .map(|key| KeyUsage {
+                key: key.to_string(),
+                size: self.memory.size(key),
+                deadline: self.expires.get(key).copied(),
+            })
+            .collect()
+    }
+
+    pub(crate) fn process_memory(&mut self, cmd: MemoryCommand) -> Result<RespValue, RespValue> {
+        match cmd {
+            MemoryCommand::Usage(key) => Ok(match self.value(&key)? {
+                Some(_) => RespValue::Integer(self.memory.size(&key).unwrap_or(0) as i64),
+                None => RespValue::Null,
+            }),
+            MemoryCommand::Stats => {
+                let keys = self.memory.sizes.len() as u64;
+                let overhead = keys * KEY_OVERHEAD;
+                let stats = [
+                    ("peak.allocated", self.memory.peak),
+                    ("total.allocated", self.memory.used),
+                    ("keys.count", keys),
+                    (
+                        "keys.bytes-per-key",
+                        self.memory.used.checked_div(keys).unwrap_or(0),
+                    ),
+                    ("dataset.bytes", self.memory.used - overhead),
+                    ("overhead.total", overhead),
+                    ("expires.count", self.expires.len() as u64),
+                ];
+                Ok(RespValue::Array(
+                    stats
+                        .into_iter()
+                        .flat_map(|(name, n)| {
+                            [
+                                RespValue::BulkString(name.into()),
+                                RespValue::Integer(n as i64),
+                            ]
+                        })
+                        .collect(),
+                ))
+            }
+            MemoryCommand::Encoding(key) => Ok(match self.value(&key)? {
+                Some(value) => RespValue::BulkString(value.encoding().into()),
+                None => RespValue::Null,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{command::Command, encoding::ALLOC_OVERHEAD, resp::RespValue};
+
+    fn run<T: KvStore>(backend: &mut Backend<T>, cmd: &[&str]) -> RespValue {
+        backend.process_command(Command::try_from(RespValue::array(cmd)).unwrap())
+    }
+
+    #[test]
+    fn test_account() {
+        let mut backend = Backend::new(std::collections::HashMap::new());
+        run(&mut backend, &["SET", "a", "12345"]);
+        run(&mut backend, &["HSET", "h", "f", "v"]);
+        // An integer, which takes no allocation
+        assert_eq!(backend.memory.size("a"), Some(KEY_OVERHEAD + 1));
+        let h = backend.memory.size("h").unwrap();
+        assert_eq!(backend.memory.used(), KEY_OVERHEAD + 1 + h);
+
+        run(&mut backend, &["HSET", "h", "g", "w"]);
+        assert!(backend.memory.size("h").unwrap() > h);
+        run(&mut backend, &["DEL", "a"]);
+        assert_eq!(backend.memory.size("a"), None);
+        let used = backend.memory.used();
+
+        backend.account_all().unwrap();
+        assert_eq!(backend.memory.used(), used);
+        assert_eq!(
+            backend.usage(["h", "nope"]),
+            [
+                KeyUsage {
+                    key: "h".into(),
+                    size: backend.memory.size("h"),
+                    deadline: None
+                },
+                KeyUsage {
+                    key: "nope".into(),
+                    size: None,
+                    deadline: None
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn test_memory_command() {
+        let mut backend = Backend::new(std::collections::HashMap::new());
+        run(&mut backend, &["SET", "a", &"x".repeat(50)]);
+        run(&mut backend, &["HSET", "h", "f", "v"]);
+        run(&mut backend, &["DEL", "h"]);
+        let size = (KEY_OVERHEAD + 1 + 50 + ALLOC_OVERHEAD) as i64;
+        assert_eq!(
+            run(&mut backend, &["MEMORY", "USAGE", "a", "SAMPLES", "0"]),
+            RespValue::Integer(size)
+        );
+        assert_eq!(
+            run(&mut backend, &["MEMORY", "USAGE", "h"]),
+            RespValue::Null
+        );
+        assert_eq!(
+            run(&mut backend, &["OBJECT", "encoding", "a"]),
+            RespValue::BulkString("raw".into())
+        );
+        assert!(Command::try_from(RespValue::array(&["OBJECT", "FREQ", "a"])).is_err());
+
+        let stats = match run(&mut backend, &["MEMORY", "STATS"]) {
+            RespValue::Array(stats) => stats,
+            reply => panic!("{:?}", reply),
+        };
+        let stat = |name: &str| {
+            let i = stats
+                .iter()
+                .position(|s| *s == RespValue::BulkString(name.into()))
+                .unwrap();
+            stats[i + 1].clone()
+        };
+        assert_eq!(stat("keys.count"), RespValue::Integer(1));
+        assert_eq!(stat("total.allocated"), RespValue::Integer(size));
+        assert_eq!(
+            stat("dataset.bytes"),
+            RespValue::Integer(size - KEY_OVERHEAD as i64)
+        );
+        match stat("peak.allocated") {
+            RespValue::Integer(peak) => assert!(peak > size),
+            reply => panic!("{:?}", reply),
+        }
+    }
+}
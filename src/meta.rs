@@ -0,0 +1,110 @@
+use std::io;
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{
+    backend::{unix_millis, Backend},
+    map::KvStore,
+    value::Value,
+};
+
+/// The entry of metadata holding the records of [Backend::state].
+const STATE: &str = "state";
+
+/// What the backend knows about a key besides its value.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+struct KeyMeta {
+    deadline: Option<u64>,
+    tags: Vec<String>,
+}
+
+fn key_entry(key: &str) -> String {
+    format!("key:{}", key)
+}
+
+fn corrupt(what: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what.to_string())
+}
+
+fn encode(meta: &impl serde::Serialize) -> Value {
+    Value::from(serde_json::to_string(meta).unwrap())
+}
+
+fn decode<M: serde::de::DeserializeOwned>(name: &str, value: &Value) -> io::Result<M> {
+    match value {
+        Value::Str(s) => serde_json::from_slice(&s.as_bytes()).map_err(corrupt),
+        _ => Err(corrupt(format!("bad metadata {:?}", name))),
+    }
+}
+
+impl<T> Backend<T>
+where
+    T: KvStore,
+{
+    /// Keeps the deadlines and tags of the keys with the store, along with
+    /// the state if it changed since it last was. Replies with whether
+    /// there was anything to write, which then wants syncing.
+    pub(crate) fn save_meta(&mut self, keys: Vec<String>) -> io::Result<bool> {
+        let mut entries: Vec<(String, Option<Value>)> = keys
+            .into_iter()
+            .map(|key| {
+                let meta = KeyMeta {
+                    deadline: self.expires.get(&key).copied(),
+                    tags: self.tags.tags(&key).cloned().collect(),
+                };
+                let value = (meta != KeyMeta::default()).then(|| encode(&meta));
+                (key_entry(&key), value)
+            })
+            .collect();
+        // Stamped with no time, so that it only changes with the state
+        let state = self.state(0);
+        if state != self.saved_state {
+            entries.push((STATE.into(), Some(encode(&state))));
+            self.saved_state = state;
+        }
+        if entries.is_empty() {
+            return Ok(false);
+        }
+        self.store.kv_put_meta(entries)?;
+        Ok(true)
+    }
+
+    /// Keeps the metadata of every key with the store, for when the keys
+    /// were loaded from elsewhere.
+    pub(crate) fn save_all_meta(&mut self) -> io::Result<bool> {
+        let mut keys: Vec<String> = self.expires.keys().cloned().collect();
+        keys.extend(self.tags.tagged().cloned());
+        keys.sort_unstable();
+        keys.dedup();
+        self.save_meta(keys)
+    }
+
+    /// Restores the metadata kept with the store. Replies with whether the
+    /// store had any data, in which case it's as up to date as anything
+    /// else the replica could load.
+    pub(crate) fn load_meta(&mut self) -> io::Result<bool> {
+        let entries = self.store.kv_meta()?;
+        let restored = !entries.is_empty() || self.store.kv_len() > 0;
+        let mut state = Vec::new();
+        for (name, value) in entries {
+            if name == STATE {
+                state = decode(&name, &value)?;
+                continue;
+            }
+            let key = name
+                .strip_prefix("key:")
+                .ok_or_else(|| corrupt(format!("bad metadata {:?}", name)))?;
+            let meta: KeyMeta = decode(&name, &value)?;
+            if let Some(deadline) = meta.deadline {
+                self.expires.insert(key.to_string(), deadline);
+            }
+            self.tags.tag(key, meta.tags);
+        }
+        for record in state.iter().cloned() {
+            self.load(record)?;
+        }
+        self.saved_state = state;
+        self.now = unix_millis();
+        Ok(restored)
+    }
+}
@@ -0,0 +1,108 @@
+use std::fmt;
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::pubsub::PubSub;
+
+/// A change made to a key, published as keyspace notifications.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct KeyEvent {
+    /// Class of the event, as its `notify-keyspace-events` flag,
+    /// e.g. `g` for generic commands or `$` for string commands.
+    pub class: char,
+    pub event: String,
+    pub key: String,
+}
+
+impl KeyEvent {
+    pub fn new(class: char, event: &str, key: &str) -> KeyEvent {
+        KeyEvent {
+            class,
+            event: event.into(),
+            key: key.into(),
+        }
+    }
+}
+
+/// The classes `A` stands for.
+const ALL_CLASSES: &str = "g$lshzxe";
+
+/// Which notifications to publish, configured with the same flags as
+/// Redis's `notify-keyspace-events`: `K` and `E` choose between keyspace
+/// and keyevent channels, the other flags choose the classes of events.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct NotifyConfig {
+    keyspace: bool,
+    keyevent: bool,
+    classes: String,
+}
+
+impl NotifyConfig {
+    pub fn parse(flags: &str) -> Option<NotifyConfig> {
+        let mut config = NotifyConfig::default();
+        for flag in flags.chars() {
+            match flag {
+                'K' => config.keyspace = true,
+                'E' => config.keyevent = true,
+                'A' => config.classes.push_str(ALL_CLASSES),
+                c if ALL_CLASSES.contains(c) => config.classes.push(c),
+                _ => return None,
+            }
+        }
+        Some(config)
+    }
+
+    fn enabled(&self, class: char) -> bool {
+        self.classes.contains(class)
+    }
+
+    /// Publishes the event to `__keyspace@0__:<key>` and
+    /// `__keyevent@0__:<event>`, if configured to.
+    pub fn publish(&self, pubsub: &PubSub, event: &KeyEvent) {
+        if !self.enabled(event.class) {
+            return;
+        }
+        if self.keyspace {
+            pubsub.publish(&format!("__keyspace@0__:{}", event.key), &event.event);
+        }
+        if self.keyevent {
+            pubsub.publish(&format!("__keyevent@0__:{}", event.event), &event.key);
+        }
+    }
+}
+
+impl fmt::Display for NotifyConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if ALL_CLASSES.chars().all(|c| self.enabled(c)) {
+            write!(f, "A")?;
+        } else {
+            for c in ALL_CLASSES.chars().filter(|&c| self.enabled(c)) {
+                write!(f, "{}", c)?;
+            }
+        }
+        if self.keyspace {
+            write!(f, "K")?;
+        }
+        if self.keyevent {
+            write!(f, "E")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_notify_config() {
+        let config = NotifyConfig::parse("Ex$").unwrap();
+        assert!(config.enabled('x') && config.enabled('$'));
+        assert!(!config.enabled('g'));
+        assert_eq!(config.to_string(), "$xE");
+
+        assert_eq!(NotifyConfig::parse("KA").unwrap().to_string(), "AK");
+        assert_eq!(NotifyConfig::parse("").unwrap(), NotifyConfig::default());
+        assert_eq!(NotifyConfig::parse("KQ"), None);
+    }
+}
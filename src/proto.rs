@@ -5,7 +5,7 @@ use futures::{stream::StreamExt, SinkExt};
 use serde_derive::{Deserialize, Serialize};
 use tokio_util::codec::{Decoder, Encoder};
 
-use crate::resp::RespValue;
+use crate::{memory::KeyUsage, notify::KeyEvent, resp::RespValue, value::Value};
 
 /// Coordination packet format
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -14,7 +14,30 @@ pub enum ProtoValue {
     Resp(RespValue),
     Vote(bool),
     Decision(bool),
-    Replicate(HashMap<String, String>),
+    Replicate(HashMap<String, Value>),
+
+    /// Writes committed to every replica as a single two-phase commit,
+    /// either a lone write command or a `MULTI`/`EXEC` transaction.
+    /// Stamped with the master's clock, in unix milliseconds.
+    Batch(u64, Vec<RespValue>),
+    /// A replica's reply to `Decision(true)`, the results of the
+    /// commands, the keyspace events they raised, and the usage of the
+    /// keys they changed.
+    Committed(Vec<RespValue>, Vec<KeyEvent>, Vec<KeyUsage>),
+    /// Asks a replica for the usage of every key, for the master to
+    /// know what it can evict.
+    Keyspace,
+    /// A replica's reply to `Keyspace`.
+    Usage(Vec<KeyUsage>),
+
+    // Between client connections and the master only.
+    /// Asks the master for the current versions of the keys.
+    Watch(Vec<String>),
+    /// The master's reply to `Watch`, one version per key.
+    Versions(Vec<u64>),
+    /// Queued commands of a transaction, along with the watched keys
+    /// and the versions they had when they were watched.
+    Exec(Vec<RespValue>, Vec<(String, u64)>),
 }
 
 #[derive(Debug)]
@@ -49,9 +72,9 @@ impl Encoder<ProtoValue> for ProtoCodec {
     }
 }
 
-impl Into<ProtoValue> for RespValue {
-    fn into(self) -> ProtoValue {
-        ProtoValue::Resp(self)
+impl From<RespValue> for ProtoValue {
+    fn from(resp: RespValue) -> Self {
+        ProtoValue::Resp(resp)
     }
 }
 
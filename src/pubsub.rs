@@ -0,0 +1,417 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+use crate::resp::RespValue;
+
+/// How many messages may wait for a subscriber to read them. A subscriber
+/// that falls further behind is disconnected, rather than have messages
+/// pile up for it without bound.
+const MAX_PENDING_MESSAGES: usize = 1024;
+
+type Subscribers = HashMap<u64, Sink>;
+
+/// Where the messages for a subscriber go.
+#[derive(Debug, Clone)]
+struct Sink {
+    tx: mpsc::Sender<RespValue>,
+    /// Set once a message didn't fit, for the connection to be closed.
+    overflowed: Arc<AtomicBool>,
+}
+
+impl Sink {
+    /// Queues the message, replying with whether it was.
+    fn send(&self, msg: RespValue) -> bool {
+        match self.tx.try_send(msg) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                self.overflowed.store(true, Ordering::Relaxed);
+                false
+            }
+            Err(TrySendError::Closed(_)) => false,
+        }
+    }
+}
+
+/// Channels and patterns every client connection is subscribed to.
+#[derive(Debug, Default)]
+pub(crate) struct PubSub {
+    channels: HashMap<String, Subscribers>,
+    patterns: HashMap<String, Subscribers>,
+    next_id: u64,
+}
+
+impl PubSub {
+    /// Delivers a message to the subscribers of the channel, and to those
+    /// of matching patterns. Returns how many clients received it.
+    pub fn publish(&self, channel: &str, message: &str) -> i64 {
+        let mut receivers = 0;
+
+        if let Some(subs) = self.channels.get(channel) {
+            let msg = RespValue::array(&["message", channel, message]);
+            for sink in subs.values() {
+                receivers += sink.send(msg.clone()) as i64;
+            }
+        }
+
+        for (pattern, subs) in self.patterns.iter() {
+            if !glob_match(pattern.as_bytes(), channel.as_bytes()) {
+                continue;
+            }
+            let msg = RespValue::array(&["pmessage", pattern, channel, message]);
+            for sink in subs.values() {
+                receivers += sink.send(msg.clone()) as i64;
+            }
+        }
+
+        receivers
+    }
+
+    /// Active channels, those with at least one subscriber,
+    /// optionally filtered by a glob-style pattern.
+    pub fn channels(&self, pattern: Option<&str>) -> Vec<&str> {
+        self.channels
+            .keys()
+            .filter(|ch| pattern.is_none_or(|p| glob_match(p.as_bytes(), ch.as_bytes())))
+            .map(|ch| ch.as_str())
+            .collect()
+    }
+
+    pub fn numsub(&self, channel: &str) -> usize {
+        self.channels.get(channel).map_or(0, |subs| subs.len())
+    }
+
+    pub fn numpat(&self) -> usize {
+        self.patterns.len()
+    }
+
+    /// Answers `PUBSUB CHANNELS [pattern]`, `PUBSUB NUMSUB [channel ...]`
+    /// and `PUBSUB NUMPAT`.
+    pub fn process_pubsub(&self, args: Vec<String>) -> RespValue {
+        match args.first().map(|s| s.to_uppercase()).as_deref() {
+            Some("CHANNELS") if args.len() <= 2 => {
+                let channels = self.channels(args.get(1).map(|p| p.as_str()));
+                RespValue::array(&channels)
+            }
+            Some("NUMSUB") => RespValue::Array(
+                args[1..]
+                    .iter()
+                    .flat_map(|ch| {
+                        [
+                            RespValue::BulkString(ch.clone()),
+                            RespValue::Integer(self.numsub(ch) as i64),
+                        ]
+                    })
+                    .collect(),
+            ),
+            Some("NUMPAT") if args.len() == 1 => RespValue::Integer(self.numpat() as i64),
+            _ => RespValue::Error(
+                "ERR unknown PUBSUB subcommand or wrong number of arguments".into(),
+            ),
+        }
+    }
+}
+
+/// Subscriptions of a single client connection.
+///
+/// Published messages are queued to `messages`, for the connection to push
+/// out to the client, and (un)subscribing replies with what to push right
+/// away. Once [overflowed], the connection should be closed. Dropping the
+/// `Subscriber` unsubscribes from everything.
+///
+/// [overflowed]: Subscriber::overflowed
+#[derive(Debug)]
+pub(crate) struct Subscriber {
+    id: u64,
+    pubsub: Arc<Mutex<PubSub>>,
+    sink: Sink,
+    pub messages: mpsc::Receiver<RespValue>,
+    channels: HashSet<String>,
+    patterns: HashSet<String>,
+}
+
+impl Subscriber {
+    pub fn new(pubsub: Arc<Mutex<PubSub>>) -> Subscriber {
+        let id = {
+            let mut pubsub = pubsub.lock().unwrap();
+            pubsub.next_id += 1;
+            pubsub.next_id
+        };
+        let (tx, messages) = mpsc::channel(MAX_PENDING_MESSAGES);
+        let sink = Sink {
+            tx,
+            overflowed: Arc::new(AtomicBool::new(false)),
+        };
+
+        Subscriber {
+            id,
+            pubsub,
+            sink,
+            messages,
+            channels: HashSet::new(),
+            patterns: HashSet::new(),
+        }
+    }
+
+    /// Whether the connection is in the subscribed state, where
+    /// only (un)subscribing is allowed.
+    pub fn is_subscribed(&self) -> bool {
+        !self.channels.is_empty() || !self.patterns.is_empty()
+    }
+
+    /// Whether a message was dropped because too many were pending.
+    pub fn overflowed(&self) -> bool {
+        self.sink.overflowed.load(Ordering::Relaxed)
+    }
+
+    fn count(&self) -> i64 {
+        (self.channels.len() + self.patterns.len()) as i64
+    }
+
+    fn reply(&self, kind: &str, name: Option<&str>) -> RespValue {
+        let name = match name {
+            Some(name) => RespValue::BulkString(name.into()),
+            None => RespValue::Null,
+        };
+        RespValue::Array(vec![
+            RespValue::BulkString(kind.into()),
+            name,
+            RespValue::Integer(self.count()),
+        ])
+    }
+
+    pub fn subscribe(&mut self, channels: Vec<String>) -> Vec<RespValue> {
+        let mut replies = Vec::new();
+        for ch in channels {
+            self.pubsub
+                .lock()
+                .unwrap()
+                .channels
+                .entry(ch.clone())
+                .or_default()
+                .insert(self.id, self.sink.clone());
+            self.channels.insert(ch.clone());
+            replies.push(self.reply("subscribe", Some(&ch)));
+        }
+        replies
+    }
+
+    pub fn psubscribe(&mut self, patterns: Vec<String>) -> Vec<RespValue> {
+        let mut replies = Vec::new();
+        for pat in patterns {
+            self.pubsub
+                .lock()
+                .unwrap()
+                .patterns
+                .entry(pat.clone())
+                .or_default()
+                .insert(self.id, self.sink.clone());
+            self.patterns.insert(pat.clone());
+            replies.push(self.reply("psubscribe", Some(&pat)));
+        }
+        replies
+    }
+
+    /// Unsubscribes from the channels, or from all of them if none is given.
+    pub fn unsubscribe(&mut self, channels: Vec<String>) -> Vec<RespValue> {
+        let channels = if channels.is_empty() {
+            self.channels.iter().cloned().collect()
+        } else {
+            channels
+        };
+        if channels.is_empty() {
+            return vec![self.reply("unsubscribe", None)];
+        }
+
+        let mut replies = Vec::new();
+        for ch in channels {
+            remove_subscriber(&mut self.pubsub.lock().unwrap().channels, &ch, self.id);
+            self.channels.remove(&ch);
+            replies.push(self.reply("unsubscribe", Some(&ch)));
+        }
+        replies
+    }
+
+    /// Unsubscribes from the patterns, or from all of them if none is given.
+    pub fn punsubscribe(&mut self, patterns: Vec<String>) -> Vec<RespValue> {
+        let patterns = if patterns.is_empty() {
+            self.patterns.iter().cloned().collect()
+        } else {
+            patterns
+        };
+        if patterns.is_empty() {
+            return vec![self.reply("punsubscribe", None)];
+        }
+
+        let mut replies = Vec::new();
+        for pat in patterns {
+            remove_subscriber(&mut self.pubsub.lock().unwrap().patterns, &pat, self.id);
+            self.patterns.remove(&pat);
+            replies.push(self.reply("punsubscribe", Some(&pat)));
+        }
+        replies
+    }
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        let mut pubsub = self.pubsub.lock().unwrap();
+        for ch in self.channels.iter() {
+            remove_subscriber(&mut pubsub.channels, ch, self.id);
+        }
+        for pat in self.patterns.iter() {
+            remove_subscriber(&mut pubsub.patterns, pat, self.id);
+        }
+    }
+}
+
+fn remove_subscriber(map: &mut HashMap<String, Subscribers>, name: &str, id: u64) {
+    if let Some(subs) = map.get_mut(name) {
+        subs.remove(&id);
+        if subs.is_empty() {
+            map.remove(name);
+        }
+    }
+}
+
+/// Glob-style matching, as in Redis: `*`, `?`, `[abc]`, `[^a-z]`
+/// and `\` to escape special characters.
+///
+/// Everything but `*` matches a single byte, so on a mismatch it's enough
+/// to let the last star take one more byte and go on from there, which
+/// keeps matching linear in the pattern times the string.
+pub(crate) fn glob_match(pattern: &[u8], string: &[u8]) -> bool {
+    let (mut p, mut s) = (0, 0);
+    // Where to go on from after the last star: the pattern after it,
+    // and how much of the string it took
+    let mut star = None;
+    while s < string.len() {
+        if pattern.get(p) == Some(&b'*') {
+            p += 1;
+            star = Some((p, s));
+            continue;
+        }
+        if let Some(len) = match_one(&pattern[p..], string[s]) {
+            p += len;
+            s += 1;
+            continue;
+        }
+        match star {
+            Some((after, took)) => {
+                p = after;
+                s = took + 1;
+                star = Some((after, took + 1));
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Matches a byte against the start of the pattern, which isn't a star,
+/// replying with how much of the pattern it took if it matched.
+fn match_one(pattern: &[u8], c: u8) -> Option<usize> {
+    match pattern {
+        [] => None,
+        [b'?', ..] => Some(1),
+        [b'[', rest @ ..] => {
+            let negate = rest.first() == Some(&b'^');
+            let mut p = if negate { &rest[1..] } else { rest };
+            let mut matched = false;
+            loop {
+                match p {
+                    [] => break,
+                    [b']', ..] => {
+                        p = &p[1..];
+                        break;
+                    }
+                    [b'\\', e, ..] => {
+                        matched |= *e == c;
+                        p = &p[2..];
+                    }
+                    [lo, b'-', hi, ..] if *hi != b']' => {
+                        let (lo, hi) = if lo <= hi { (*lo, *hi) } else { (*hi, *lo) };
+                        matched |= (lo..=hi).contains(&c);
+                        p = &p[3..];
+                    }
+                    [x, ..] => {
+                        matched |= *x == c;
+                        p = &p[1..];
+                    }
+                }
+            }
+            (matched != negate).then(|| pattern.len() - p.len())
+        }
+        [b'\\', e, ..] => (*e == c).then_some(2),
+        [x, ..] => (*x == c).then_some(1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn glob(pattern: &str, string: &str) -> bool {
+        glob_match(pattern.as_bytes(), string.as_bytes())
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob("news.*", "news.tech"));
+        assert!(!glob("news.*", "weather.today"));
+        assert!(glob("h?llo", "hello"));
+        assert!(glob("h[ae]llo", "hallo"));
+        assert!(!glob("h[^e]llo", "hello"));
+        assert!(glob("h[a-b]llo", "hbllo"));
+        assert!(glob("h\\*llo", "h*llo"));
+        assert!(!glob("h\\*llo", "hello"));
+        assert!(glob("**a**", "banana"));
+        assert!(glob("a*b*c", "aXbYbZc"));
+        assert!(!glob("a*b*c", "aXbYbZ"));
+        assert!(glob("*", ""));
+        assert!(!glob("?", ""));
+        // Would take exponential time with naive backtracking
+        let long = "a".repeat(1000);
+        assert!(!glob("a*a*a*a*a*a*a*a*a*a*b", &long));
+    }
+
+    #[test]
+    fn test_publish() {
+        let pubsub = Arc::new(Mutex::new(PubSub::default()));
+        let mut a = Subscriber::new(pubsub.clone());
+        let mut b = Subscriber::new(pubsub.clone());
+        assert_eq!(a.subscribe(vec!["news.tech".into()]).len(), 1);
+        b.psubscribe(vec!["news.*".into()]);
+
+        assert_eq!(pubsub.lock().unwrap().publish("news.tech", "rust"), 2);
+        assert_eq!(pubsub.lock().unwrap().publish("news.art", "paint"), 1);
+        assert_eq!(pubsub.lock().unwrap().numsub("news.tech"), 1);
+
+        drop(a);
+        assert_eq!(pubsub.lock().unwrap().publish("news.tech", "rust"), 1);
+        assert_eq!(pubsub.lock().unwrap().channels(None).len(), 0);
+
+        b.punsubscribe(vec![]);
+        assert!(!b.is_subscribed());
+        assert_eq!(pubsub.lock().unwrap().publish("news.tech", "rust"), 0);
+    }
+
+    #[test]
+    fn test_publish_overflow() {
+        let pubsub = Arc::new(Mutex::new(PubSub::default()));
+        let mut a = Subscriber::new(pubsub.clone());
+        a.subscribe(vec!["ch".into()]);
+        for _ in 0..MAX_PENDING_MESSAGES {
+            assert_eq!(pubsub.lock().unwrap().publish("ch", "m"), 1);
+        }
+        assert!(!a.overflowed());
+        assert_eq!(pubsub.lock().unwrap().publish("ch", "m"), 0);
+        assert!(a.overflowed());
+    }
+}
@@ -0,0 +1,460 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    vec::IntoIter,
+};
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{
+    backend::Backend,
+    command::{int, strings, CommandError},
+    map::KvStore,
+    resp::RespValue,
+    value::{Value, WRONGTYPE},
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct Message {
+    payload: String,
+    /// How many times the message was reserved.
+    deliveries: u64,
+    /// When the message becomes visible again, if reserved, which is
+    /// where to find it in `Queue::reserved`.
+    #[serde(default)]
+    visible_at: Option<u64>,
+}
+
+/// Messages delivered at least once: a reserved message is hidden from
+/// other consumers until acknowledged, or until its visibility timeout
+/// runs out and it's delivered again.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct Queue {
+    next_id: u64,
+    /// Messages are dead-lettered instead of delivered this many times
+    /// over, unless 0.
+    max_deliveries: u64,
+    /// The queue dead letters go to, or nowhere.
+    dead_letter: Option<String>,
+    messages: BTreeMap<u64, Message>,
+    /// Messages waiting to be reserved, oldest first.
+    ready: BTreeSet<u64>,
+    /// Reserved messages, by when they become visible again.
+    reserved: BTreeSet<(u64, u64)>,
+}
+
+/// Queue counters since startup, shown by `INFO`.
+#[derive(Debug, Default)]
+pub struct QueueStats {
+    pub enqueued: u64,
+    pub delivered: u64,
+    pub redelivered: u64,
+    pub acked: u64,
+    pub nacked: u64,
+    pub dead_lettered: u64,
+}
+
+impl QueueStats {
+    pub fn info(&self) -> Vec<(&str, u64)> {
+        vec![
+            ("queue_enqueued", self.enqueued),
+            ("queue_delivered", self.delivered),
+            ("queue_redelivered", self.redelivered),
+            ("queue_acked", self.acked),
+            ("queue_nacked", self.nacked),
+            ("queue_dead_lettered", self.dead_lettered),
+        ]
+    }
+}
+
+impl Queue {
+    fn push(&mut self, payload: String) -> u64 {
+        self.next_id += 1;
+        let message = Message {
+            payload,
+            deliveries: 0,
+            visible_at: None,
+        };
+        self.messages.insert(self.next_id, message);
+        self.ready.insert(self.next_id);
+        self.next_id
+    }
+
+    /// Makes the reserved message visible again, unless it was delivered
+    /// too many times, in which case it's taken out and returned.
+    fn release(&mut self, id: u64) -> Option<Message> {
+        let message = self.messages.get_mut(&id)?;
+        message.visible_at = None;
+        if self.max_deliveries > 0 && message.deliveries >= self.max_deliveries {
+            return self.messages.remove(&id);
+        }
+        self.ready.insert(id);
+        None
+    }
+
+    /// Makes the messages whose visibility timeout ran out visible again,
+    /// returning how many did and the dead letters.
+    fn reclaim(&mut self, now: u64) -> (usize, Vec<Message>) {
+        let expired: Vec<_> = self
+            .reserved
+            .iter()
+            .take_while(|(deadline, _)| *deadline <= now)
+            .copied()
+            .collect();
+        let mut dead = Vec::new();
+        for entry in expired.iter() {
+            self.reserved.remove(entry);
+            dead.extend(self.release(entry.1));
+        }
+        (expired.len() - dead.len(), dead)
+    }
+
+    /// Reserves up to `count` of the oldest visible messages until `deadline`.
+    fn reserve(&mut self, count: usize, deadline: u64) -> Vec<(u64, &Message)> {
+        let ids: Vec<u64> = self.ready.iter().take(count).copied().collect();
+        for id in ids.iter() {
+            self.ready.remove(id);
+            self.reserved.insert((deadline, *id));
+            if let Some(message) = self.messages.get_mut(id) {
+                message.deliveries += 1;
+                message.visible_at = Some(deadline);
+            }
+        }
+        ids.into_iter()
+            .filter_map(|id| Some((id, self.messages.get(&id)?)))
+            .collect()
+    }
+
+    /// Takes the message out of the reserved ones, if it was reserved.
+    fn unreserve(&mut self, id: u64) -> bool {
+        let visible_at = self.messages.get_mut(&id).and_then(|m| m.visible_at.take());
+        match visible_at {
+            Some(deadline) => self.reserved.remove(&(deadline, id)),
+            None => false,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum QueueCommand {
+    /// `Q.CREATE queue [MAXDELIVERIES n] [DEADLETTER queue]`
+    Create {
+        key: String,
+        max_deliveries: u64,
+        dead_letter: Option<String>,
+    },
+    /// `Q.PUSH queue payload`
+    Push(String, String),
+    /// `Q.RESERVE queue timeout_ms [COUNT n]`
+    Reserve(String, u64, usize),
+    /// `Q.ACK queue id [id ...]`
+    Ack(String, Vec<u64>),
+    /// `Q.NACK queue id [id ...]`
+    Nack(String, Vec<u64>),
+    /// `Q.STATS queue`
+    Stats(String),
+}
+
+impl QueueCommand {
+    pub fn keys(&self) -> Vec<&str> {
+        use QueueCommand::*;
+        match self {
+            Create { key, .. }
+            | Push(key, _)
+            | Reserve(key, ..)
+            | Ack(key, _)
+            | Nack(key, _)
+            | Stats(key) => vec![key.as_str()],
+        }
+    }
+}
+
+/// Parses the arguments of `Q.CREATE`, `Q.PUSH`, `Q.RESERVE`, `Q.ACK`,
+/// `Q.NACK` and `Q.STATS`.
+pub fn parse(verb: &str, arr: IntoIter<RespValue>) -> Result<QueueCommand, CommandError> {
+    let args = strings(arr)?;
+    let ids = |ids: &[String]| match ids {
+        [] => Err(CommandError::InvalidCommand),
+        ids => ids.iter().map(|id| int(id)).collect(),
+    };
+    let cmd = match (verb, args.as_slice()) {
+        ("Q.CREATE", [key, options @ ..]) => {
+            let (mut max_deliveries, mut dead_letter) = (0, None);
+            for option in options.chunks(2) {
+                match option {
+                    [name, n] if name.eq_ignore_ascii_case("MAXDELIVERIES") => {
+                        max_deliveries = int(n)?
+                    }
+                    [name, queue] if name.eq_ignore_ascii_case("DEADLETTER") => {
+                        dead_letter = Some(queue.clone())
+                    }
+                    _ => return Err(CommandError::InvalidCommand),
+                }
+            }
+            QueueCommand::Create {
+                key: key.clone(),
+                max_deliveries,
+                dead_letter,
+            }
+        }
+        ("Q.PUSH", [key, payload]) => QueueCommand::Push(key.clone(), payload.clone()),
+        ("Q.RESERVE", [key, timeout]) => QueueCommand::Reserve(key.clone(), int(timeout)?, 1),
+        ("Q.RESERVE", [key, timeout, option, count]) if option.eq_ignore_ascii_case("COUNT") => {
+            QueueCommand::Reserve(key.clone(), int(timeout)?, int(count)?)
+        }
+        ("Q.ACK", [key, rest @ ..]) => QueueCommand::Ack(key.clone(), ids(rest)?),
+        ("Q.NACK", [key, rest @ ..]) => QueueCommand::Nack(key.clone(), ids(rest)?),
+        ("Q.STATS", [key]) => QueueCommand::Stats(key.clone()),
+        _ => return Err(CommandError::InvalidCommand),
+    };
+    Ok(cmd)
+}
+
+impl<T> Backend<T>
+where
+    T: KvStore,
+{
+    fn queue(&self, key: &str) -> Result<Option<&Queue>, RespValue> {
+        match self.value(key)? {
+            Some(Value::Queue(queue)) => Ok(Some(queue)),
+            Some(_) => Err(RespValue::Error(WRONGTYPE.into())),
+            None => Ok(None),
+        }
+    }
+
+    /// The queue at `key`, after making the messages whose visibility
+    /// timeout ran out visible again.
+    fn queue_mut(&mut self, key: &str) -> Result<Option<&mut Queue>, RespValue> {
+        let now = self.now;
+        let queue = match self.value_mut(key)? {
+            Some(Value::Queue(queue)) => queue,
+            Some(_) => return Err(RespValue::Error(WRONGTYPE.into())),
+            None => return Ok(None),
+        };
+
+        let (reclaimed, dead) = queue.reclaim(now);
+        if reclaimed > 0 || !dead.is_empty() {
+            let dead_letter = queue.dead_letter.clone();
+            self.queue_stats.redelivered += reclaimed as u64;
+            self.notify('g', "q.reclaim", key);
+            self.dead_letter(dead_letter, dead);
+        }
+        match self.store.kv_get_mut(key)? {
+            Some(Value::Queue(queue)) => Ok(Some(queue)),
+            _ => unreachable!(),
+        }
+    }
+
+    /// The queue at `key` for adding to it, created if missing.
+    fn queue_or_default(&mut self, key: &str) -> Result<&mut Queue, RespValue> {
+        if self.queue_mut(key)?.is_none() {
+            self.store.kv_put(key, Value::Queue(Queue::default()))?;
+        }
+        match self.store.kv_get_mut(key)? {
+            Some(Value::Queue(queue)) => Ok(queue),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Moves the messages to the dead letter queue, or drops them if there's
+    /// none, or if the key holds something else.
+    fn dead_letter(&mut self, dead_letter: Option<String>, messages: Vec<Message>) {
+        if messages.is_empty() {
+            return;
+        }
+        self.queue_stats.dead_lettered += messages.len() as u64;
+        let Some(key) = dead_letter else {
+            return;
+        };
+        if let Ok(queue) = self.queue_or_default(&key) {
+            for message in messages {
+                queue.push(message.payload);
+            }
+            self.notify('g', "q.push", &key);
+        }
+    }
+
+    pub(crate) fn process_queue(&mut self, cmd: QueueCommand) -> RespValue {
+        use QueueCommand::*;
+        match cmd {
+            Create {
+                key,
+                max_deliveries,
+                dead_letter,
+            } => self.process_q_create(&key, max_deliveries, dead_letter),
+            Push(key, payload) => self.process_q_push(&key, payload),
+            Reserve(key, timeout, count) => self.process_q_reserve(&key, timeout, count),
+            Ack(key, ids) => self.process_q_ack(&key, &ids),
+            Nack(key, ids) => self.process_q_nack(&key, &ids),
+            Stats(key) => self.queue(&key).map(|queue| {
+                let queue = queue.cloned().unwrap_or_default();
+                // Counted as if the reserved messages that timed out were
+                // visible already, like the next write will make them
+                let visible = queue.reserved.range(..(self.now + 1, 0)).count();
+                RespValue::Array(vec![
+                    RespValue::SimpleString("ready".into()),
+                    RespValue::Integer((queue.ready.len() + visible) as i64),
+                    RespValue::SimpleString("reserved".into()),
+                    RespValue::Integer((queue.reserved.len() - visible) as i64),
+                    RespValue::SimpleString("max_deliveries".into()),
+                    RespValue::Integer(queue.max_deliveries as i64),
+                ])
+            }),
+        }
+        .unwrap_or_else(|e| e)
+    }
+
+    fn process_q_create(
+        &mut self,
+        key: &str,
+        max_deliveries: u64,
+        dead_letter: Option<String>,
+    ) -> Result<RespValue, RespValue> {
+        let queue = self.queue_or_default(key)?;
+        queue.max_deliveries = max_deliveries;
+        queue.dead_letter = dead_letter;
+        self.notify('g', "q.create", key);
+        Ok(RespValue::SimpleString("OK".into()))
+    }
+
+    /// Replies with the id of the new message.
+    fn process_q_push(&mut self, key: &str, payload: String) -> Result<RespValue, RespValue> {
+        let id = self.queue_or_default(key)?.push(payload);
+        self.queue_stats.enqueued += 1;
+        self.notify('g', "q.push", key);
+        Ok(RespValue::Integer(id as i64))
+    }
+
+    /// Replies with the id, payload and number of deliveries so far of
+    /// each message reserved.
+    fn process_q_reserve(
+        &mut self,
+        key: &str,
+        timeout: u64,
+        count: usize,
+    ) -> Result<RespValue, RespValue> {
+        let deadline = self.now.saturating_add(timeout);
+        let Some(queue) = self.queue_mut(key)? else {
+            return Ok(RespValue::Array(vec![]));
+        };
+        let messages: Vec<_> = queue
+            .reserve(count, deadline)
+            .into_iter()
+            .map(|(id, message)| {
+                RespValue::Array(vec![
+                    RespValue::Integer(id as i64),
+                    RespValue::BulkString(message.payload.clone()),
+                    RespValue::Integer(message.deliveries as i64),
+                ])
+            })
+            .collect();
+        if !messages.is_empty() {
+            self.queue_stats.delivered += messages.len() as u64;
+            self.notify('g', "q.reserve", key);
+        }
+        Ok(RespValue::Array(messages))
+    }
+
+    fn process_q_ack(&mut self, key: &str, ids: &[u64]) -> Result<RespValue, RespValue> {
+        let Some(queue) = self.queue_mut(key)? else {
+            return Ok(RespValue::Integer(0));
+        };
+        let mut acked = 0;
+        for &id in ids {
+            if queue.unreserve(id) {
+                queue.messages.remove(&id);
+                acked += 1;
+            }
+        }
+        if acked > 0 {
+            self.queue_stats.acked += acked;
+            self.notify('g', "q.ack", key);
+        }
+        Ok(RespValue::Integer(acked as i64))
+    }
+
+    /// Makes reserved messages visible again right away, or dead-letters
+    /// them if they were delivered too many times.
+    fn process_q_nack(&mut self, key: &str, ids: &[u64]) -> Result<RespValue, RespValue> {
+        let Some(queue) = self.queue_mut(key)? else {
+            return Ok(RespValue::Integer(0));
+        };
+        let mut nacked = 0;
+        let mut dead = Vec::new();
+        for &id in ids {
+            if queue.unreserve(id) {
+                dead.extend(queue.release(id));
+                nacked += 1;
+            }
+        }
+        let dead_letter = queue.dead_letter.clone();
+        if nacked > 0 {
+            self.queue_stats.nacked += nacked;
+            self.notify('g', "q.nack", key);
+        }
+        self.dead_letter(dead_letter, dead);
+        Ok(RespValue::Integer(nacked as i64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_queue() {
+        let mut queue = Queue {
+            max_deliveries: 2,
+            ..Queue::default()
+        };
+        assert_eq!(queue.push("a".into()), 1);
+        assert_eq!(queue.push("b".into()), 2);
+
+        let reserved: Vec<_> = queue
+            .reserve(1, 100)
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        assert_eq!(reserved, [1]);
+        assert_eq!(queue.reclaim(99), (0, vec![]));
+
+        // Timed out, so delivered again before the newer message
+        assert_eq!(queue.reclaim(100), (1, vec![]));
+        let reserved: Vec<_> = queue
+            .reserve(1, 200)
+            .into_iter()
+            .map(|(id, m)| (id, m.deliveries))
+            .collect();
+        assert_eq!(reserved, [(1, 2)]);
+
+        // Delivered too many times
+        let (reclaimed, dead) = queue.reclaim(200);
+        assert_eq!((reclaimed, dead[0].payload.as_str()), (0, "a"));
+        assert_eq!(queue.ready.iter().collect::<Vec<_>>(), [&2]);
+
+        queue.reserve(1, u64::MAX);
+        assert!(queue.unreserve(2));
+        assert!(!queue.unreserve(2));
+        assert!(queue.reserved.is_empty());
+    }
+
+    #[test]
+    fn test_huge_timeout() {
+        let mut backend = Backend::new(std::collections::HashMap::new());
+        let mut run = |cmd: &[&str]| {
+            backend
+                .process_command(crate::command::Command::try_from(RespValue::array(cmd)).unwrap())
+        };
+        run(&["Q.CREATE", "q"]);
+        run(&["Q.PUSH", "q", "a"]);
+        let timeout = u64::MAX.to_string();
+        assert_eq!(
+            run(&["Q.RESERVE", "q", &timeout]),
+            RespValue::Array(vec![RespValue::Array(vec![
+                RespValue::Integer(1),
+                RespValue::BulkString("a".into()),
+                RespValue::Integer(1),
+            ])])
+        );
+        assert_eq!(run(&["Q.ACK", "q", "1"]), RespValue::Integer(1));
+    }
+}
@@ -0,0 +1,88 @@
+use std::{ops::Bound, vec::IntoIter};
+
+use crate::{
+    backend::Backend,
+    command::{int, strings, CommandError},
+    map::KvStore,
+    resp::RespValue,
+};
+
+#[derive(Debug, PartialEq)]
+pub enum RangeCommand {
+    /// `SCANRANGE start end [LIMIT n]`, the keys from `start` up to but
+    /// not including `end`, in order, where an empty `end` has no bound.
+    ScanRange {
+        start: String,
+        end: String,
+        limit: Option<usize>,
+    },
+    /// `DELPREFIX prefix`
+    DelPrefix(String),
+}
+
+impl RangeCommand {
+    /// The keys in the range aren't known until the command runs.
+    pub fn keys(&self) -> Vec<&str> {
+        vec![]
+    }
+}
+
+/// Parses the arguments of `SCANRANGE` and `DELPREFIX`.
+pub fn parse(verb: &str, arr: IntoIter<RespValue>) -> Result<RangeCommand, CommandError> {
+    let args = strings(arr)?;
+    match (verb, args.as_slice()) {
+        ("SCANRANGE", [start, end, rest @ ..]) => {
+            let limit = match rest {
+                [] => None,
+                [option, n] if option.eq_ignore_ascii_case("LIMIT") => match int::<usize>(n)? {
+                    0 => return Err(CommandError::InvalidCommand),
+                    n => Some(n),
+                },
+                _ => return Err(CommandError::InvalidCommand),
+            };
+            Ok(RangeCommand::ScanRange {
+                start: start.clone(),
+                end: end.clone(),
+                limit,
+            })
+        }
+        ("DELPREFIX", [prefix]) => Ok(RangeCommand::DelPrefix(prefix.clone())),
+        _ => Err(CommandError::InvalidCommand),
+    }
+}
+
+impl<T> Backend<T>
+where
+    T: KvStore,
+{
+    /// Keys starting with the prefix, in order.
+    fn keys_with_prefix<'a>(&'a self, prefix: &'a str) -> impl Iterator<Item = &'a str> {
+        self.store
+            .kv_range(Bound::Included(prefix), Bound::Unbounded)
+            .take_while(move |k| k.starts_with(prefix))
+    }
+
+    pub(crate) fn process_range(&mut self, cmd: RangeCommand) -> RespValue {
+        match cmd {
+            RangeCommand::ScanRange { start, end, limit } => {
+                let end = match end.as_str() {
+                    "" => Bound::Unbounded,
+                    end => Bound::Excluded(end),
+                };
+                let keys = self
+                    .store
+                    .kv_range(Bound::Included(&start), end)
+                    .filter(|k| !self.is_expired(k))
+                    .take(limit.unwrap_or(usize::MAX))
+                    .map(|k| RespValue::BulkString(k.to_string()))
+                    .collect();
+                RespValue::Array(keys)
+            }
+            RangeCommand::DelPrefix(prefix) => {
+                let keys: Vec<String> = self.keys_with_prefix(&prefix).map(String::from).collect();
+                self.delete_keys(keys, ('g', "del"))
+                    .map_or_else(RespValue::from, RespValue::Integer)
+            }
+        }
+    }
+}
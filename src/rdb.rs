@@ -0,0 +1,729 @@
+use std::{
+    collections::{BTreeSet, HashMap, VecDeque},
+    ops::Bound::Unbounded,
+};
+
+use crc::{Crc, CRC_64_REDIS};
+use tracing::warn;
+
+use crate::{aof::Record, hash::Hash, value::Value, zset::SortedSet};
+
+const MAGIC: &[u8] = b"REDIS";
+/// The version written, the last one before Redis only wrote small
+/// collections as listpacks. Newer files are read as long as they don't
+/// hold streams or module data.
+const VERSION: u32 = 9;
+const MAX_VERSION: u32 = 12;
+const CHECKSUM: Crc<u64> = Crc::<u64>::new(&CRC_64_REDIS);
+
+const OP_FUNCTION2: u8 = 0xF5;
+const OP_IDLE: u8 = 0xF8;
+const OP_FREQ: u8 = 0xF9;
+const OP_AUX: u8 = 0xFA;
+const OP_RESIZEDB: u8 = 0xFB;
+const OP_EXPIRETIME_MS: u8 = 0xFC;
+const OP_EXPIRETIME: u8 = 0xFD;
+const OP_SELECTDB: u8 = 0xFE;
+const OP_EOF: u8 = 0xFF;
+
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_SET: u8 = 2;
+const TYPE_ZSET: u8 = 3;
+const TYPE_HASH: u8 = 4;
+const TYPE_ZSET_2: u8 = 5;
+const TYPE_LIST_ZIPLIST: u8 = 10;
+const TYPE_SET_INTSET: u8 = 11;
+const TYPE_ZSET_ZIPLIST: u8 = 12;
+const TYPE_HASH_ZIPLIST: u8 = 13;
+const TYPE_LIST_QUICKLIST: u8 = 14;
+const TYPE_HASH_LISTPACK: u8 = 16;
+const TYPE_ZSET_LISTPACK: u8 = 17;
+const TYPE_LIST_QUICKLIST_2: u8 = 18;
+const TYPE_SET_LISTPACK: u8 = 20;
+
+const ENC_INT8: u8 = 0;
+const ENC_INT16: u8 = 1;
+const ENC_INT32: u8 = 2;
+const ENC_LZF: u8 = 3;
+
+/// Whether the file is an RDB file rather than a kvkv snapshot.
+pub fn is_rdb(buf: &[u8]) -> bool {
+    buf.starts_with(MAGIC)
+}
+
+fn write_len(buf: &mut Vec<u8>, len: usize) {
+    match len {
+        0..=0x3F => buf.push(len as u8),
+        0x40..=0x3FFF => buf.extend_from_slice(&(0x4000 | len as u16).to_be_bytes()),
+        _ if len <= u32::MAX as usize => {
+            buf.push(0x80);
+            buf.extend_from_slice(&(len as u32).to_be_bytes());
+        }
+        _ => {
+            buf.push(0x81);
+            buf.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+}
+
+fn write_string(buf: &mut Vec<u8>, s: &[u8]) {
+    write_len(buf, s.len());
+    buf.extend_from_slice(s);
+}
+
+/// Writes the value, replying with its type, unless RDB has no
+/// equivalent for it.
+fn write_value(buf: &mut Vec<u8>, value: &Value) -> Option<u8> {
+    match value {
+        Value::Str(s) => {
+            write_string(buf, &s.as_bytes());
+            Some(TYPE_STRING)
+        }
+        Value::List(list) => {
+            write_len(buf, list.len());
+            list.iter().for_each(|item| write_string(buf, item));
+            Some(TYPE_LIST)
+        }
+        Value::Set(set) => {
+            write_len(buf, set.len());
+            set.iter().for_each(|member| write_string(buf, &member));
+            Some(TYPE_SET)
+        }
+        Value::ZSet(zset) => {
+            write_len(buf, zset.len());
+            for (member, score) in zset.range_by_score(Unbounded, Unbounded) {
+                write_string(buf, member.as_bytes());
+                buf.extend_from_slice(&score.to_le_bytes());
+            }
+            Some(TYPE_ZSET_2)
+        }
+        Value::Hash(hash) => {
+            write_len(buf, hash.len());
+            for (field, value) in hash.iter() {
+                write_string(buf, field.as_bytes());
+                write_string(buf, value.as_bytes());
+            }
+            Some(TYPE_HASH)
+        }
+        _ => None,
+    }
+}
+
+/// Writes the keys among the records as an RDB file, leaving out those
+/// of types Redis doesn't have, along with indexes and fencing tokens.
+pub fn encode(records: &[Record]) -> Vec<u8> {
+    let mut keys = Vec::new();
+    let mut left_out = 0;
+    for record in records {
+        if let Record::Key(key, value, deadline, _) = record {
+            let mut value_buf = Vec::new();
+            match write_value(&mut value_buf, value) {
+                Some(kind) => keys.push((key, kind, value_buf, deadline)),
+                None => left_out += 1,
+            }
+        }
+    }
+    if left_out > 0 {
+        warn!("left {} keys of types RDB doesn't have out", left_out);
+    }
+
+    let mut buf = format!("REDIS{:04}", VERSION).into_bytes();
+    buf.push(OP_AUX);
+    write_string(&mut buf, b"redis-bits");
+    write_string(&mut buf, b"64");
+    buf.push(OP_SELECTDB);
+    write_len(&mut buf, 0);
+    buf.push(OP_RESIZEDB);
+    write_len(&mut buf, keys.len());
+    write_len(&mut buf, keys.iter().filter(|k| k.3.is_some()).count());
+    for (key, kind, value, deadline) in keys {
+        if let Some(deadline) = deadline {
+            buf.push(OP_EXPIRETIME_MS);
+            buf.extend_from_slice(&deadline.to_le_bytes());
+        }
+        buf.push(kind);
+        write_string(&mut buf, key.as_bytes());
+        buf.extend_from_slice(&value);
+    }
+    buf.push(OP_EOF);
+    let checksum = CHECKSUM.checksum(&buf);
+    buf.extend_from_slice(&checksum.to_le_bytes());
+    buf
+}
+
+enum Length {
+    Len(usize),
+    /// A string encoded in a special way, e.g. as an integer.
+    Encoded(u8),
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], String> {
+        let bytes = self
+            .buf
+            .get(self.pos..self.pos.saturating_add(n))
+            .ok_or("RDB file is truncated")?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        Ok(self.bytes(N)?.try_into().unwrap())
+    }
+
+    fn length(&mut self) -> Result<Length, String> {
+        let first = self.byte()?;
+        let len = match first >> 6 {
+            0 => (first & 0x3F) as usize,
+            1 => ((first as usize & 0x3F) << 8) | self.byte()? as usize,
+            2 if first == 0x80 => u32::from_be_bytes(self.array()?) as usize,
+            2 if first == 0x81 => u64::from_be_bytes(self.array()?) as usize,
+            3 => return Ok(Length::Encoded(first & 0x3F)),
+            _ => return Err(format!("bad RDB length {:#x}", first)),
+        };
+        Ok(Length::Len(len))
+    }
+
+    fn len(&mut self) -> Result<usize, String> {
+        match self.length()? {
+            Length::Len(len) => Ok(len),
+            Length::Encoded(_) => Err("expected a length in the RDB file".into()),
+        }
+    }
+
+    fn string(&mut self) -> Result<Vec<u8>, String> {
+        let int = match self.length()? {
+            Length::Len(len) => return Ok(self.bytes(len)?.to_vec()),
+            Length::Encoded(ENC_INT8) => self.byte()? as i8 as i64,
+            Length::Encoded(ENC_INT16) => i16::from_le_bytes(self.array()?) as i64,
+            Length::Encoded(ENC_INT32) => i32::from_le_bytes(self.array()?) as i64,
+            Length::Encoded(ENC_LZF) => {
+                let compressed_len = self.len()?;
+                let len = self.len()?;
+                return lzf_decompress(self.bytes(compressed_len)?, len);
+            }
+            Length::Encoded(enc) => return Err(format!("unknown RDB string encoding {}", enc)),
+        };
+        Ok(int.to_string().into_bytes())
+    }
+
+    fn utf8(&mut self) -> Result<String, String> {
+        utf8(self.string()?)
+    }
+
+    /// A score as written before version 8, as a string.
+    fn string_double(&mut self) -> Result<f64, String> {
+        match self.byte()? {
+            253 => Ok(f64::NAN),
+            254 => Ok(f64::INFINITY),
+            255 => Ok(f64::NEG_INFINITY),
+            len => parse_double(self.bytes(len as usize)?),
+        }
+    }
+
+    fn value(&mut self, kind: u8) -> Result<Value, String> {
+        let value = match kind {
+            TYPE_STRING => Value::Str(self.string()?.into()),
+            TYPE_LIST => Value::List(
+                (0..self.len()?)
+                    .map(|_| self.string())
+                    .collect::<Result<_, _>>()?,
+            ),
+            TYPE_SET => Value::Set(
+                (0..self.len()?)
+                    .map(|_| self.string())
+                    .collect::<Result<_, _>>()?,
+            ),
+            TYPE_ZSET | TYPE_ZSET_2 => {
+                let mut scores = HashMap::new();
+                for _ in 0..self.len()? {
+                    let member = self.utf8()?;
+                    let score = match kind {
+                        TYPE_ZSET => self.string_double()?,
+                        _ => f64::from_le_bytes(self.array()?),
+                    };
+                    scores.insert(member, score);
+                }
+                Value::ZSet(SortedSet::from(scores))
+            }
+            TYPE_HASH => {
+                let mut hash = Hash::new();
+                for _ in 0..self.len()? {
+                    hash.insert(self.utf8()?, self.utf8()?);
+                }
+                Value::Hash(hash)
+            }
+            TYPE_LIST_ZIPLIST => Value::List(ziplist(&self.string()?)?.into_iter().collect()),
+            TYPE_LIST_QUICKLIST => {
+                let mut list = VecDeque::new();
+                for _ in 0..self.len()? {
+                    list.extend(ziplist(&self.string()?)?);
+                }
+                Value::List(list.into())
+            }
+            TYPE_LIST_QUICKLIST_2 => {
+                let mut list = VecDeque::new();
+                for _ in 0..self.len()? {
+                    match self.len()? {
+                        // A single large element
+                        1 => list.push_back(self.string()?),
+                        2 => list.extend(listpack(&self.string()?)?),
+                        container => {
+                            return Err(format!("unknown quicklist container {}", container))
+                        }
+                    }
+                }
+                Value::List(list.into())
+            }
+            TYPE_SET_INTSET => Value::Set(intset(&self.string()?)?.into()),
+            TYPE_SET_LISTPACK => Value::Set(listpack(&self.string()?)?.into_iter().collect()),
+            TYPE_ZSET_ZIPLIST | TYPE_ZSET_LISTPACK => {
+                let entries = match kind {
+                    TYPE_ZSET_ZIPLIST => ziplist(&self.string()?)?,
+                    _ => listpack(&self.string()?)?,
+                };
+                let mut scores = HashMap::new();
+                for pair in entries.chunks(2) {
+                    let [member, score] = pair else {
+                        return Err("odd number of sorted set entries".into());
+                    };
+                    scores.insert(utf8(member.clone())?, parse_double(score)?);
+                }
+                Value::ZSet(SortedSet::from(scores))
+            }
+            TYPE_HASH_ZIPLIST | TYPE_HASH_LISTPACK => {
+                let entries = match kind {
+                    TYPE_HASH_ZIPLIST => ziplist(&self.string()?)?,
+                    _ => listpack(&self.string()?)?,
+                };
+                let mut hash = Hash::new();
+                for pair in entries.chunks(2) {
+                    let [field, value] = pair else {
+                        return Err("odd number of hash entries".into());
+                    };
+                    hash.insert(utf8(field.clone())?, utf8(value.clone())?);
+                }
+                Value::Hash(hash)
+            }
+            _ => return Err(format!("unsupported RDB value type {}", kind)),
+        };
+        Ok(value)
+    }
+}
+
+/// Keys, hash fields and values, and sorted set members are held as
+/// strings, so anything else is refused rather than mangled.
+fn utf8(s: Vec<u8>) -> Result<String, String> {
+    String::from_utf8(s).map_err(|_| "non-UTF-8 string in the RDB file".into())
+}
+
+fn parse_double(s: &[u8]) -> Result<f64, String> {
+    std::str::from_utf8(s)
+        .ok()
+        .and_then(|s| match s {
+            "inf" | "+inf" => Some(f64::INFINITY),
+            "-inf" => Some(f64::NEG_INFINITY),
+            s => s.parse().ok(),
+        })
+        .ok_or_else(|| "bad score in the RDB file".into())
+}
+
+fn lzf_decompress(input: &[u8], len: usize) -> Result<Vec<u8>, String> {
+    let bad = || "bad LZF data in the RDB file".to_string();
+    // A 3-byte back reference makes the most, 264 bytes, so the length
+    // can't be trusted past that
+    if len > input.len().saturating_mul(88) {
+        return Err(bad());
+    }
+    let mut out = Vec::with_capacity(len);
+    let mut i = 0;
+    while i < input.len() {
+        let ctrl = input[i] as usize;
+        i += 1;
+        if ctrl < 32 {
+            // A run of ctrl + 1 literal bytes
+            let literal = input.get(i..i + ctrl + 1).ok_or_else(bad)?;
+            out.extend_from_slice(literal);
+            i += ctrl + 1;
+        } else {
+            // A back reference into what was decompressed so far
+            let mut run = ctrl >> 5;
+            if run == 7 {
+                run += *input.get(i).ok_or_else(bad)? as usize;
+                i += 1;
+            }
+            let offset = ((ctrl & 0x1F) << 8) + *input.get(i).ok_or_else(bad)? as usize + 1;
+            i += 1;
+            let start = out.len().checked_sub(offset).ok_or_else(bad)?;
+            for j in start..start + run + 2 {
+                out.push(out[j]);
+            }
+        }
+        if out.len() > len {
+            return Err(bad());
+        }
+    }
+    if out.len() != len {
+        return Err(bad());
+    }
+    Ok(out)
+}
+
+/// The entries of a ziplist, with integers as their decimal strings.
+fn ziplist(buf: &[u8]) -> Result<Vec<Vec<u8>>, String> {
+    let mut r = Reader { buf, pos: 10 };
+    let mut entries = Vec::new();
+    loop {
+        let prevlen = r.byte()?;
+        if prevlen == 0xFF {
+            return Ok(entries);
+        }
+        if prevlen == 0xFE {
+            r.bytes(4)?;
+        }
+        let enc = r.byte()?;
+        let int = match enc >> 6 {
+            0 => {
+                entries.push(r.bytes((enc & 0x3F) as usize)?.to_vec());
+                continue;
+            }
+            1 => {
+                let len = ((enc as usize & 0x3F) << 8) | r.byte()? as usize;
+                entries.push(r.bytes(len)?.to_vec());
+                continue;
+            }
+            2 => {
+                let len = u32::from_be_bytes(r.array()?) as usize;
+                entries.push(r.bytes(len)?.to_vec());
+                continue;
+            }
+            _ => match enc {
+                0xC0 => i16::from_le_bytes(r.array()?) as i64,
+                0xD0 => i32::from_le_bytes(r.array()?) as i64,
+                0xE0 => i64::from_le_bytes(r.array()?),
+                0xF0 => {
+                    let [a, b, c] = r.array()?;
+                    i32::from_le_bytes([0, a, b, c]) as i64 >> 8
+                }
+                0xFE => r.byte()? as i8 as i64,
+                0xF1..=0xFD => (enc & 0x0F) as i64 - 1,
+                _ => return Err(format!("bad ziplist encoding {:#x}", enc)),
+            },
+        };
+        entries.push(int.to_string().into_bytes());
+    }
+}
+
+/// The entries of a listpack, with integers as their decimal strings.
+fn listpack(buf: &[u8]) -> Result<Vec<Vec<u8>>, String> {
+    let mut r = Reader { buf, pos: 6 };
+    let mut entries = Vec::new();
+    loop {
+        let start = r.pos;
+        let enc = r.byte()?;
+        let entry = match enc {
+            0xFF => return Ok(entries),
+            0x00..=0x7F => (enc as i64).to_string().into_bytes(),
+            0x80..=0xBF => r.bytes((enc & 0x3F) as usize)?.to_vec(),
+            0xC0..=0xDF => {
+                let int = ((enc as i64 & 0x1F) << 8) | r.byte()? as i64;
+                (if int >= 1 << 12 { int - (1 << 13) } else { int })
+                    .to_string()
+                    .into_bytes()
+            }
+            0xE0..=0xEF => {
+                let len = ((enc as usize & 0x0F) << 8) | r.byte()? as usize;
+                r.bytes(len)?.to_vec()
+            }
+            0xF0 => {
+                let len = u32::from_le_bytes(r.array()?) as usize;
+                r.bytes(len)?.to_vec()
+            }
+            0xF1 => i16::from_le_bytes(r.array()?).to_string().into_bytes(),
+            0xF2 => {
+                let [a, b, c] = r.array()?;
+                (i32::from_le_bytes([0, a, b, c]) >> 8)
+                    .to_string()
+                    .into_bytes()
+            }
+            0xF3 => i32::from_le_bytes(r.array()?).to_string().into_bytes(),
+            0xF4 => i64::from_le_bytes(r.array()?).to_string().into_bytes(),
+            _ => return Err(format!("bad listpack encoding {:#x}", enc)),
+        };
+        entries.push(entry);
+
+        // Skip the entry's length, written backwards for reverse traversal
+        let len = r.pos - start;
+        r.bytes(match len {
+            0..128 => 1,
+            128..16384 => 2,
+            16384..2097152 => 3,
+            2097152..268435456 => 4,
+            _ => 5,
+        })?;
+    }
+}
+
+fn intset(buf: &[u8]) -> Result<BTreeSet<Vec<u8>>, String> {
+    let mut r = Reader { buf, pos: 0 };
+    let width = u32::from_le_bytes(r.array()?) as usize;
+    let len = u32::from_le_bytes(r.array()?) as usize;
+    (0..len)
+        .map(|_| {
+            let int = match width {
+                2 => i16::from_le_bytes(r.array()?) as i64,
+                4 => i32::from_le_bytes(r.array()?) as i64,
+                8 => i64::from_le_bytes(r.array()?),
+                _ => return Err(format!("bad intset encoding {}", width)),
+            };
+            Ok(int.to_string().into_bytes())
+        })
+        .collect()
+}
+
+/// Reads the keys of an RDB file, leaving out those that expired before
+/// `now`, in unix milliseconds.
+pub fn decode(buf: &[u8], now: u64) -> Result<Vec<Record>, String> {
+    let version = buf
+        .get(..9)
+        .filter(|header| header.starts_with(MAGIC))
+        .and_then(|header| std::str::from_utf8(&header[5..]).ok()?.parse::<u32>().ok())
+        .ok_or("not an RDB file")?;
+    if version == 0 || version > MAX_VERSION {
+        return Err(format!("unsupported RDB version {}", version));
+    }
+
+    let mut r = Reader { buf, pos: 9 };
+    let mut records = Vec::new();
+    let mut deadline = None;
+    loop {
+        match r.byte()? {
+            OP_EOF => break,
+            OP_AUX => {
+                r.string()?;
+                r.string()?;
+            }
+            OP_SELECTDB => {
+                let db = r.len()?;
+                if db != 0 {
+                    warn!("loading the keys of database {} along with the others", db);
+                }
+            }
+            OP_RESIZEDB => {
+                r.len()?;
+                r.len()?;
+            }
+            OP_EXPIRETIME_MS => deadline = Some(u64::from_le_bytes(r.array()?)),
+            OP_EXPIRETIME => deadline = Some(u32::from_le_bytes(r.array()?) as u64 * 1000),
+            OP_IDLE => {
+                r.len()?;
+            }
+            OP_FREQ => {
+                r.byte()?;
+            }
+            OP_FUNCTION2 => {
+                warn!("leaving out a function library, as functions aren't supported");
+                r.string()?;
+            }
+            kind => {
+                let key = r.utf8()?;
+                let value = r.value(kind)?;
+                match deadline.take() {
+                    Some(deadline) if deadline <= now => (),
+                    deadline => records.push(Record::Key(key, value, deadline, vec![])),
+                }
+            }
+        }
+    }
+
+    // Files without a checksum have zeroes in its place
+    if version >= 5 {
+        let checksum = u64::from_le_bytes(r.array()?);
+        if checksum != 0 && checksum != CHECKSUM.checksum(&buf[..r.pos - 8]) {
+            return Err("RDB checksum mismatch".into());
+        }
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(key: &str, value: Value, deadline: Option<u64>) -> Record {
+        Record::Key(key.into(), value, deadline, vec![])
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let zset = SortedSet::from(HashMap::from([
+            ("a".to_string(), 1.5),
+            ("b".to_string(), -2.0),
+        ]));
+        let records = vec![
+            key("s", "hello".into(), Some(5000)),
+            key(
+                "l",
+                Value::List(VecDeque::from([b"x".to_vec(), b"y".to_vec()]).into()),
+                None,
+            ),
+            key(
+                "set",
+                Value::Set(BTreeSet::from([b"m".to_vec()]).into()),
+                None,
+            ),
+            key("z", Value::ZSet(zset), None),
+            key(
+                "h",
+                Value::Hash(Hash::from([("f".into(), "v".repeat(100))])),
+                None,
+            ),
+            key("big", Value::Str(vec![7; 20000].into()), None),
+            Record::FencingToken(3),
+        ];
+        let buf = encode(&records);
+        assert!(is_rdb(&buf));
+        assert_eq!(decode(&buf, 0).unwrap(), records[..6]);
+
+        // Expired keys are left out
+        assert_eq!(decode(&buf, 5000).unwrap(), records[1..6]);
+
+        let mut corrupt = buf.clone();
+        corrupt[30] ^= 1;
+        assert!(decode(&corrupt, 0).is_err());
+    }
+
+    #[test]
+    fn test_compact_encodings() {
+        // Written by Redis 7: a listpack hash, an intset, a listpack
+        // sorted set, a quicklist of one listpack, and an LZF string
+        let mut buf = b"REDIS0011".to_vec();
+        buf.extend_from_slice(&[OP_SELECTDB, 0]);
+
+        let hash_lp = [
+            &[17, 0, 0, 0, 2, 0][..],
+            &[0x81, b'f', 2],
+            &[0xC1, 0x2C, 3], // 300
+            &[0xFF],
+        ]
+        .concat();
+        buf.extend_from_slice(&[TYPE_HASH_LISTPACK, 1, b'h', hash_lp.len() as u8]);
+        buf.extend_from_slice(&hash_lp);
+
+        let intset = [
+            &[2, 0, 0, 0, 2, 0, 0, 0][..],
+            &(-5i16).to_le_bytes(),
+            &7i16.to_le_bytes(),
+        ]
+        .concat();
+        buf.extend_from_slice(&[TYPE_SET_INTSET, 1, b's', intset.len() as u8]);
+        buf.extend_from_slice(&intset);
+
+        let zset_lp = [
+            &[0, 0, 0, 0, 4, 0][..],
+            &[0x81, b'm', 2],
+            &[0x83, b'1', b'.', b'5', 4],
+            &[0x81, b'n', 2],
+            &[0x05, 1],
+            &[0xFF],
+        ]
+        .concat();
+        buf.extend_from_slice(&[TYPE_ZSET_LISTPACK, 1, b'z', zset_lp.len() as u8]);
+        buf.extend_from_slice(&zset_lp);
+
+        let list_lp = [
+            &[0, 0, 0, 0, 2, 0][..],
+            &[0x81, b'a', 2],
+            &[0xF1, 0xE8, 0x03, 3],
+            &[0xFF],
+        ]
+        .concat();
+        buf.extend_from_slice(&[TYPE_LIST_QUICKLIST_2, 1, b'l', 1, 2, list_lp.len() as u8]);
+        buf.extend_from_slice(&list_lp);
+
+        // "aaaaaaaaaa": a literal "a", then 9 more from one byte back
+        buf.extend_from_slice(&[OP_EXPIRETIME, 100, 0, 0, 0]);
+        buf.extend_from_slice(&[TYPE_STRING, 1, b'c', 0xC3, 5, 10, 0, b'a', 0xE0, 0, 0]);
+        buf.push(OP_EOF);
+        buf.extend_from_slice(&[0; 8]);
+
+        let records = decode(&buf, 0).unwrap();
+        assert_eq!(
+            records,
+            vec![
+                key(
+                    "h",
+                    Value::Hash(Hash::from([("f".into(), "300".into())])),
+                    None
+                ),
+                key(
+                    "s",
+                    Value::Set(BTreeSet::from([b"-5".to_vec(), b"7".to_vec()]).into()),
+                    None
+                ),
+                key(
+                    "z",
+                    Value::ZSet(SortedSet::from(HashMap::from([
+                        ("m".into(), 1.5),
+                        ("n".into(), 5.0)
+                    ]))),
+                    None
+                ),
+                key(
+                    "l",
+                    Value::List(VecDeque::from([b"a".to_vec(), b"1000".to_vec()]).into()),
+                    None
+                ),
+                key(
+                    "c",
+                    Value::Str(b"aaaaaaaaaa".to_vec().into()),
+                    Some(100_000)
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_bad_data() {
+        // Claims far more than 3 bytes can decompress to
+        assert!(lzf_decompress(&[0, b'a', 0xE0], usize::MAX).is_err());
+        assert!(lzf_decompress(&[0, b'a', 0xE0, 0], 5).is_err());
+        assert_eq!(
+            lzf_decompress(&[0, b'a', 0xE0, 0, 0], 10).unwrap(),
+            b"a".repeat(10)
+        );
+
+        let mut buf = b"REDIS0011".to_vec();
+        let hash_lp = [
+            &[0, 0, 0, 0, 2, 0][..],
+            &[0x81, b'f', 2],
+            &[0x81, 0xFF, 2],
+            &[0xFF],
+        ]
+        .concat();
+        buf.extend_from_slice(&[TYPE_HASH_LISTPACK, 1, b'h', hash_lp.len() as u8]);
+        buf.extend_from_slice(&hash_lp);
+        buf.push(OP_EOF);
+        buf.extend_from_slice(&[0; 8]);
+        assert_eq!(
+            decode(&buf, 0).unwrap_err(),
+            "non-UTF-8 string in the RDB file"
+        );
+    }
+
+    #[test]
+    fn test_checksum() {
+        // As in Redis's crc64 test
+        assert_eq!(CHECKSUM.checksum(b"123456789"), 0xe9c6d914c4b8d9ca);
+    }
+}
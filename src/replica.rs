@@ -1,34 +1,149 @@
-use crate::backend::Backend;
+use crate::aof::{Aof, Fsync};
+use crate::backend::{unix_millis, Backend};
 use crate::command::Command;
+use crate::encoding;
 use crate::map::KvStore;
 use crate::proto::{read_frame, write_frame, ProtoCodec, ProtoValue};
 use crate::resp::RespValue;
+use crate::snapshot::{self, Snapshots};
 
 use futures::{stream::StreamExt, SinkExt};
+use std::error::Error;
 use std::net::SocketAddrV4;
-use std::{collections::HashMap, error::Error};
+use std::path::PathBuf;
 use tokio::net::{TcpListener, TcpStream};
 use tokio_util::codec::Decoder;
-use tracing::{info, trace, warn, instrument};
+use tracing::{info, instrument, trace, warn};
 
-pub async fn run(port: u16) -> Result<(), Box<dyn Error>> {
+/// Where a replica keeps its append-only file, and how often it syncs it.
+pub struct AofConfig {
+    pub path: PathBuf,
+    pub fsync: Fsync,
+}
+
+/// How a replica keeps its dataset across restarts, if at all.
+#[derive(Default)]
+struct Persistence {
+    aof: Option<Aof>,
+    snapshots: Option<Snapshots>,
+}
+
+impl Persistence {
+    /// Handles the commands about persistence, which aren't about the
+    /// dataset, so the backend doesn't know them.
+    fn process<T: KvStore>(&mut self, resp: &RespValue, backend: &Backend<T>) -> Option<RespValue> {
+        let disabled = |what: &str| RespValue::Error(format!("ERR {} is disabled", what));
+        let response = match (resp.verb()?, self.aof.as_mut(), self.snapshots.as_mut()) {
+            ("BGREWRITEAOF", Some(aof), _) => aof.rewrite(backend),
+            ("BGREWRITEAOF", None, _) => disabled("append only file"),
+            ("SAVE", _, Some(snapshots)) => snapshots.save(backend),
+            ("BGSAVE", _, Some(snapshots)) => snapshots.bgsave(backend),
+            ("LASTSAVE", _, Some(snapshots)) => snapshots.last_save(),
+            ("SAVE" | "BGSAVE" | "LASTSAVE", _, None) => disabled("snapshotting"),
+            _ => return None,
+        };
+        Some(response)
+    }
+
+    /// Logs a committed write, and counts its changes towards the save
+    /// rules.
+    fn committed<T: KvStore>(
+        &mut self,
+        backend: &mut Backend<T>,
+        now: u64,
+        cmds: Vec<RespValue>,
+    ) -> std::io::Result<()> {
+        let changes = backend.events.len();
+        if let Some(snapshots) = self.snapshots.as_mut() {
+            snapshots.changed(changes, backend);
+        }
+        // Stores that survive a restart are synced first, so that they're
+        // never behind the append-only file
+        let keys = backend.events.iter().map(|e| e.key.clone()).collect();
+        if backend.save_meta(keys)? {
+            backend.store.kv_sync()?;
+        }
+        // Sweeps that expired nothing are left out, as they'd grow the
+        // file every second.
+        let idle_sweep = changes == 0 && cmds.iter().all(|resp| resp.verb() == Some("SWEEP"));
+        match self.aof.as_mut() {
+            Some(aof) if !idle_sweep => aof.append(now, cmds),
+            _ => Ok(()),
+        }
+    }
+
+    /// Takes note of background rewrites and saves that are done.
+    fn poll(&mut self) -> std::io::Result<()> {
+        if let Some(snapshots) = self.snapshots.as_mut() {
+            snapshots.poll();
+        }
+        match self.aof.as_mut() {
+            Some(aof) => aof.poll_rewrite(),
+            None => Ok(()),
+        }
+    }
+}
+
+pub async fn run<T>(
+    port: u16,
+    store: T,
+    aof: Option<AofConfig>,
+    snapshots: Option<Snapshots>,
+) -> Result<(), Box<dyn Error>>
+where
+    T: KvStore,
+{
     let address = SocketAddrV4::new(std::net::Ipv4Addr::LOCALHOST, port);
     info!("starting replica on {}", address);
+    let mut backend = Backend::new(store);
+    let mut persistence = restore(&mut backend, aof, snapshots)?;
     let listener = TcpListener::bind(address).await?;
-    let mut backend = Backend {
-        id: u32::MAX,
-        store: HashMap::new(),
-    };
 
     loop {
         let (socket, _) = listener.accept().await?;
-        handle_socket(socket, &mut backend).await;
+        handle_socket(socket, &mut backend, &mut persistence).await;
     }
 }
 
-#[instrument(skip(socket, backend))]
-async fn handle_socket<T>(socket: TcpStream, backend: &mut Backend<T>)
-where
+/// Loads the dataset from whichever of the store, the append-only file and
+/// the snapshot is the most up to date, and opens those for writing.
+fn restore<T: KvStore>(
+    backend: &mut Backend<T>,
+    aof: Option<AofConfig>,
+    snapshots: Option<Snapshots>,
+) -> Result<Persistence, Box<dyn Error>> {
+    // A store with data was synced before the last write was logged
+    let restored = backend.load_meta()?;
+    if restored {
+        info!("restored the dataset from the store");
+    }
+
+    // The append-only file is the more up to date of the two
+    if let Some(snapshots) = snapshots.as_ref() {
+        if !restored && !aof.as_ref().is_some_and(|aof| aof.path.exists()) {
+            snapshot::load(snapshots.path(), backend)?;
+        }
+    }
+    let persistence = Persistence {
+        aof: match aof {
+            Some(config) => Some(Aof::open(&config.path, config.fsync, !restored, backend)?),
+            None => None,
+        },
+        snapshots,
+    };
+    if !restored && backend.save_all_meta()? {
+        backend.store.kv_sync()?;
+    }
+    backend.account_all()?;
+    Ok(persistence)
+}
+
+#[instrument(skip(socket, backend, persistence))]
+async fn handle_socket<T>(
+    socket: TcpStream,
+    backend: &mut Backend<T>,
+    persistence: &mut Persistence,
+) where
     T: KvStore,
 {
     let codec = ProtoCodec {};
@@ -42,12 +157,27 @@ where
                 write_frame(&mut conn, response).await.unwrap();
             }
             ProtoValue::Resp(resp) => {
-                if resp.is_write() {
-                    handle_write(&mut conn, backend, resp).await.unwrap();
-                } else {
-                    let response = process_resp(resp, backend);
-                    write_frame(&mut conn, response.into()).await.unwrap();
-                }
+                // Writes come in batches, so this must be a read
+                backend.now = unix_millis();
+                let response = match persistence
+                    .process(&resp, backend)
+                    .or_else(|| encoding::process_config(&resp))
+                {
+                    Some(response) => response,
+                    None => process_resp(resp, backend),
+                };
+                write_frame(&mut conn, response.into()).await.unwrap();
+            }
+            ProtoValue::Keyspace => {
+                let usage = backend.usage(backend.store.kv_keys());
+                write_frame(&mut conn, ProtoValue::Usage(usage))
+                    .await
+                    .unwrap();
+            }
+            ProtoValue::Batch(now, cmds) => {
+                handle_write(&mut conn, backend, persistence, now, cmds)
+                    .await
+                    .unwrap();
             }
             _ => {
                 warn!("Unknown proto value: {:?}", proto_value);
@@ -55,16 +185,20 @@ where
                 conn.send(response.into()).await.unwrap(); // FIXME: Handle failure
             }
         }
+
+        persistence.poll().unwrap();
     }
     warn!("master disconnceted");
 }
 
 // cleanup
-#[instrument(skip(conn, backend))]
+#[instrument(skip(conn, backend, persistence))]
 async fn handle_write<T, F, E>(
     conn: &mut F,
     backend: &mut Backend<T>,
-    resp: RespValue,
+    persistence: &mut Persistence,
+    now: u64,
+    cmds: Vec<RespValue>,
 ) -> Result<(), Box<dyn Error>>
 where
     T: KvStore,
@@ -74,10 +208,12 @@ where
         + futures::Sink<ProtoValue, Error = E>,
     E: Error,
 {
-    // Vote yes
-    let vote = ProtoValue::Vote(true);
-    write_frame(conn, vote).await.unwrap();
-    trace!("voted yes");
+    // Vote yes, unless we know we can't apply the batch as a whole
+    let yes = cmds
+        .iter()
+        .all(|resp| Command::try_from(resp.clone()).is_ok());
+    write_frame(conn, ProtoValue::Vote(yes)).await.unwrap();
+    trace!("voted {}", if yes { "yes" } else { "no" });
 
     // Wait for final decision
     let decision = read_frame(conn).await.unwrap();
@@ -86,7 +222,16 @@ where
     let response = match decision {
         ProtoValue::Decision(true) => {
             trace!("master says commit");
-            process_resp(resp, backend)
+            backend.now = now;
+            let results = cmds
+                .iter()
+                .map(|resp| process_resp(resp.clone(), backend))
+                .collect();
+            // Logged before acknowledging, so that what the master
+            // considers committed survives a restart
+            persistence.committed(backend, now, cmds)?;
+            let usage = backend.usage(backend.events.iter().map(|e| e.key.as_str()));
+            ProtoValue::Committed(results, std::mem::take(&mut backend.events), usage)
         }
         ProtoValue::Decision(false) => {
             trace!("master says abort");
@@ -99,20 +244,160 @@ where
     Ok(())
 }
 
-fn process_resp<T>(resp_value: RespValue, backend: &mut Backend<T>) -> ProtoValue
+fn process_resp<T>(resp_value: RespValue, backend: &mut Backend<T>) -> RespValue
 where
     T: KvStore,
 {
+    let verb = resp_value.verb().unwrap_or_default().to_string();
     let response = match Command::try_from(resp_value) {
         Ok(cmd) => {
             trace!("command: {:?}", &cmd);
             backend.process_command(cmd)
         }
-        Err(_) => RespValue::Error("ERROR".into()),
-    }
-    .into();
+        Err(e) => e.reply(&verb),
+    };
 
     trace!("respond: {:?}", &response);
 
     response
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{bitcask::Bitcask, lsm::Lsm, snapshot::Format};
+    use std::{collections::HashMap, fs, path::Path};
+
+    /// Commits the command as the master would have it.
+    fn commit<T: KvStore>(
+        backend: &mut Backend<T>,
+        persistence: &mut Persistence,
+        now: u64,
+        cmd: &[&str],
+    ) {
+        let cmd = RespValue::array(cmd);
+        backend.now = now;
+        process_resp(cmd.clone(), backend);
+        persistence.committed(backend, now, vec![cmd]).unwrap();
+        backend.events.clear();
+    }
+
+    fn read<T: KvStore>(backend: &mut Backend<T>, now: u64, cmd: &[&str]) -> RespValue {
+        backend.now = now;
+        process_resp(RespValue::array(cmd), backend)
+    }
+
+    fn open<T: KvStore>(
+        dir: &Path,
+        aof: bool,
+        store: fn(&Path) -> std::io::Result<T>,
+    ) -> (Backend<T>, Persistence) {
+        let mut backend = Backend::new(store(&dir.join("store")).unwrap());
+        let aof = aof.then(|| AofConfig {
+            path: dir.join("appendonly.aof"),
+            fsync: Fsync::Always,
+        });
+        let persistence = restore(&mut backend, aof, None).unwrap();
+        (backend, persistence)
+    }
+
+    fn restart<T: KvStore>(name: &str, store: fn(&Path) -> std::io::Result<T>) {
+        let dir =
+            std::env::temp_dir().join(format!("kvkv-restart-{}-{}", name, std::process::id()));
+        for aof in [false, true] {
+            let _ = fs::remove_dir_all(&dir);
+            let (mut backend, mut persistence) = open(&dir, aof, store);
+            commit(
+                &mut backend,
+                &mut persistence,
+                1000,
+                &["SET", "a", "1", "PX", "5000"],
+            );
+            commit(
+                &mut backend,
+                &mut persistence,
+                1000,
+                &["HINCRBY", "h", "n", "1"],
+            );
+            commit(
+                &mut backend,
+                &mut persistence,
+                1000,
+                &["LOCK.ACQUIRE", "job", "me", "100"],
+            );
+            drop((backend, persistence));
+
+            // Restored from the store alone, without the file replaying
+            // the writes on top of it
+            for _ in 0..2 {
+                let (mut backend, _) = open(&dir, aof, store);
+                assert_eq!(
+                    read(&mut backend, 2000, &["PTTL", "a"]),
+                    RespValue::Integer(4000)
+                );
+                assert_eq!(
+                    read(&mut backend, 2000, &["HGET", "h", "n"]),
+                    RespValue::BulkString("1".into())
+                );
+                assert_eq!(backend.fencing_token, 1);
+            }
+            let (mut backend, mut persistence) = open(&dir, aof, store);
+            commit(
+                &mut backend,
+                &mut persistence,
+                2000,
+                &["LOCK.ACQUIRE", "job", "you", "100"],
+            );
+            drop((backend, persistence));
+            assert_eq!(open(&dir, aof, store).0.fencing_token, 2);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_restart_after_snapshot() {
+        let dir = std::env::temp_dir().join(format!("kvkv-snapshot-aof-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let open = |aof: bool| {
+            let mut backend = Backend::new(HashMap::new());
+            let aof = aof.then(|| AofConfig {
+                path: dir.join("appendonly.aof"),
+                fsync: Fsync::Always,
+            });
+            let snapshots = Snapshots::new(dir.join("dump.snap"), Format::Kvkv, vec![]);
+            let persistence = restore(&mut backend, aof, Some(snapshots)).unwrap();
+            (backend, persistence)
+        };
+
+        let (mut backend, mut persistence) = open(false);
+        commit(&mut backend, &mut persistence, 1000, &["SET", "a", "1"]);
+        let snapshots = persistence.snapshots.as_mut().unwrap();
+        assert_eq!(
+            snapshots.save(&backend),
+            RespValue::SimpleString("OK".into())
+        );
+        drop((backend, persistence));
+
+        // Turning the append-only file on keeps what was in the snapshot
+        let (mut backend, mut persistence) = open(true);
+        commit(&mut backend, &mut persistence, 2000, &["SET", "b", "2"]);
+        drop((backend, persistence));
+        for _ in 0..2 {
+            let (mut backend, _) = open(true);
+            for (key, value) in [("a", "1"), ("b", "2")] {
+                assert_eq!(
+                    read(&mut backend, 3000, &["GET", key]),
+                    RespValue::Array(vec![RespValue::BulkBytes(value.into())])
+                );
+            }
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_restart_with_store() {
+        restart("lsm", |dir| Lsm::open(dir, Default::default()));
+        restart("bitcask", |dir| Bitcask::open(dir, Default::default()));
+    }
+}
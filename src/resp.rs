@@ -5,37 +5,115 @@ use tokio_util::codec::{Decoder, Encoder};
 
 pub struct RespCodec;
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// Commands that modify data, which the master commits to every replica.
+const WRITE_COMMANDS: &[&str] = &[
+    "SET",
+    "CAS",
+    "DEL",
+    "FLUSHALL",
+    "EVAL",
+    "EVALSHA",
+    "SETEX",
+    "PSETEX",
+    "EXPIRE",
+    "PEXPIRE",
+    "EXPIREAT",
+    "PEXPIREAT",
+    "PERSIST",
+    "SWEEP",
+    "EVICT",
+    "SETBIT",
+    "BITOP",
+    "BITFIELD",
+    "PFADD",
+    "PFMERGE",
+    "BF.RESERVE",
+    "BF.ADD",
+    "BF.MADD",
+    "CF.RESERVE",
+    "CF.ADD",
+    "CF.ADDNX",
+    "CF.DEL",
+    "ZREM",
+    "GEOADD",
+    "GEOSEARCHSTORE",
+    "JSON.SET",
+    "JSON.DEL",
+    "JSON.NUMINCRBY",
+    "JSON.ARRAPPEND",
+    "VADD",
+    "VREM",
+    "VSETATTR",
+    "HSET",
+    "HMSET",
+    "HDEL",
+    "HINCRBY",
+    "FT.CREATE",
+    "FT.DROPINDEX",
+    "CL.THROTTLE",
+    "LOCK.ACQUIRE",
+    "LOCK.RENEW",
+    "LOCK.RELEASE",
+    "Q.CREATE",
+    "Q.PUSH",
+    "Q.RESERVE",
+    "Q.ACK",
+    "Q.NACK",
+    "TAG.INVALIDATE",
+    "DELPREFIX",
+];
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum RespValue {
     SimpleString(String),
     Error(String),
     Integer(i64),
     BulkString(String),
+    /// A bulk string holding bytes that may not be UTF-8, such as bitmaps.
+    BulkBytes(Vec<u8>),
     Array(Vec<RespValue>),
+    /// The null bulk string, `$-1\r\n`
+    Null,
 }
 
 impl RespValue {
     pub(crate) fn is_write(&self) -> bool {
-        match self {
-            RespValue::Array(arr) => {
-                let mut arr = arr.into_iter();
-                if let RespValue::BulkString(verb) = arr.next().unwrap() {
-                    return match verb.as_str() {
-                        "SET" | "DEL" => true,
-                        _ => false,
-                    };
-                }
+        self.verb()
+            .is_some_and(|verb| WRITE_COMMANDS.contains(&verb))
+    }
+
+    /// The command name, if this value looks like a command,
+    /// i.e. an `Array` whose first element is a `BulkString`.
+    pub(crate) fn verb(&self) -> Option<&str> {
+        if let RespValue::Array(arr) = self {
+            if let Some(RespValue::BulkString(verb)) = arr.first() {
+                return Some(verb.as_str());
             }
-            _ => (),
-        };
-        false
+        }
+        None
+    }
+
+    /// Arguments following the verb of a command, skipping anything
+    /// that isn't a `BulkString`.
+    pub(crate) fn into_args(self) -> Vec<String> {
+        match self {
+            RespValue::Array(arr) => arr
+                .into_iter()
+                .skip(1)
+                .filter_map(|v| match v {
+                    RespValue::BulkString(s) => Some(s),
+                    _ => None,
+                })
+                .collect(),
+            _ => Vec::new(),
+        }
     }
 
     /// Convenient method to create an `Array` of `BulkString`s
     pub(crate) fn array(command: &[&str]) -> RespValue {
         RespValue::Array(
             command
-                .into_iter()
+                .iter()
                 .map(|s| RespValue::BulkString(s.to_string()))
                 .collect(),
         )
@@ -54,7 +132,7 @@ impl RespValue {
     pub(crate) fn from_bytes(resp_bytes: &[u8]) -> RespValue {
         let mut codec = RespCodec;
         let mut bytes = BytesMut::new();
-        bytes.extend_from_slice(&resp_bytes);
+        bytes.extend_from_slice(resp_bytes);
 
         codec.decode(&mut bytes).unwrap().unwrap()
     }
@@ -101,13 +179,8 @@ pub fn serialize_redis_value(dst: &mut BytesMut, value: &RespValue) {
             dst.extend_from_slice(format!("{}", i).as_bytes());
             dst.extend_from_slice(b"\r\n");
         }
-        RespValue::BulkString(s) => {
-            dst.extend_from_slice(b"$");
-            dst.extend_from_slice(format!("{}", s.len()).as_bytes());
-            dst.extend_from_slice(b"\r\n");
-            dst.extend_from_slice(s.as_bytes());
-            dst.extend_from_slice(b"\r\n");
-        }
+        RespValue::BulkString(s) => serialize_bulk(dst, s.as_bytes()),
+        RespValue::BulkBytes(b) => serialize_bulk(dst, b),
         RespValue::Array(arr) => {
             dst.extend_from_slice(b"*");
             dst.extend_from_slice(format!("{}", arr.len()).as_bytes());
@@ -116,20 +189,29 @@ pub fn serialize_redis_value(dst: &mut BytesMut, value: &RespValue) {
                 serialize_redis_value(dst, it);
             }
         }
+        RespValue::Null => dst.extend_from_slice(b"$-1\r\n"),
     }
 }
 
+fn serialize_bulk(dst: &mut BytesMut, b: &[u8]) {
+    dst.extend_from_slice(b"$");
+    dst.extend_from_slice(format!("{}", b.len()).as_bytes());
+    dst.extend_from_slice(b"\r\n");
+    dst.extend_from_slice(b);
+    dst.extend_from_slice(b"\r\n");
+}
+
 fn word(src: &[u8]) -> Option<(&[u8], usize)> {
-    let pos = memchr(b'\r', &src)?;
+    let pos = memchr(b'\r', src)?;
 
     // FIXME: pos + 2 can overrun the buffer
     Some((&src[..pos], pos + 2))
 }
 
 fn int(src: &[u8]) -> Option<(i64, usize)> {
-    word(src).and_then(|b| {
+    word(src).map(|b| {
         let s = std::str::from_utf8(b.0).unwrap(); // FIXME: Don't unwrap() here.
-        Some((s.parse().unwrap(), b.1))
+        (s.parse().unwrap(), b.1)
     })
 }
 
@@ -156,20 +238,32 @@ fn integer(src: &[u8]) -> Option<(RespValue, usize)> {
     int(src).map(|(i, pos)| (RespValue::Integer(i), pos))
 }
 
+/// Exactly the declared number of bytes, which may hold anything including
+/// `\r\n`, as a `BulkString` if they're UTF-8 or as `BulkBytes` otherwise.
 // TODO: More robost error handling
 fn bulk_string(src: &[u8]) -> Option<(RespValue, usize)> {
     // TODO: Use Result to indicate error.
-    let (_len, pos_len) = int(src)?; // @TODO: Check length
-    let (data, pos_data) = word(&src[pos_len..])?;
-
-    let s = String::from_utf8_lossy(&data).to_string(); // TODO: Eliminate copy
+    let (len, pos_len) = int(src)?;
+    if len < 0 {
+        return Some((RespValue::Null, pos_len));
+    }
+    let end = pos_len + len as usize;
+    // The data, then its trailing CRLF
+    let data = src.get(pos_len..end + 2)?[..len as usize].to_vec();
 
-    Some((RespValue::BulkString(s), pos_len + pos_data))
+    let value = match String::from_utf8(data) {
+        Ok(s) => RespValue::BulkString(s),
+        Err(e) => RespValue::BulkBytes(e.into_bytes()),
+    };
+    Some((value, end + 2))
 }
 
 fn array(src: &[u8]) -> Option<(RespValue, usize)> {
     let mut total_pos = 0;
     let (len, pos_len) = int(src)?;
+    if len < 0 {
+        return Some((RespValue::Null, pos_len));
+    }
 
     // TODO: It's becoming tedious to manually update total pos,
     // and advance buffer cursor.
@@ -177,8 +271,7 @@ fn array(src: &[u8]) -> Option<(RespValue, usize)> {
     total_pos += pos_len;
     let mut src = &src[pos_len..];
 
-    let mut array = Vec::new();
-    array.reserve(len as usize);
+    let mut array = Vec::with_capacity(len as usize);
 
     for _ in 0..len {
         let (value, data_len) = parse(src)?;
@@ -191,7 +284,7 @@ fn array(src: &[u8]) -> Option<(RespValue, usize)> {
 }
 
 fn parse(src: &[u8]) -> Option<(RespValue, usize)> {
-    if src.len() == 0 {
+    if src.is_empty() {
         return None;
     }
 
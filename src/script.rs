@@ -0,0 +1,279 @@
+use std::cell::{Cell, RefCell};
+
+use mlua::{HookTriggers, Lua, LuaOptions, StdLib, Table, Value, Variadic};
+
+use crate::{backend::Backend, command::Command, map::KvStore, resp::RespValue};
+
+/// How many Lua instructions a script may run before it's aborted. They're
+/// counted rather than timed, so that every replica aborts it at the same
+/// point, with the same writes done.
+const INSTRUCTION_BUDGET: u32 = 100_000_000;
+/// How many instructions run between checks of the budget.
+const INSTRUCTION_CHECK: u32 = 10_000;
+
+/// Makes `pairs` walk keys in order, as it would otherwise walk them in the
+/// order they hash to, which depends on a seed each Lua state picks at
+/// random, and so differs across replicas. Keys that have no order, such
+/// as tables, are refused. `next` is left as it is, for checking whether
+/// a table is empty.
+const PRELUDE: &str = r#"
+local next, sort, type, error = next, table.sort, type, error
+local rank = { boolean = 1, number = 2, string = 3 }
+function pairs(t)
+    local keys = {}
+    for k in next, t do
+        if not rank[type(k)] then
+            error("pairs can't order keys of type " .. type(k), 2)
+        end
+        keys[#keys + 1] = k
+    end
+    sort(keys, function(a, b)
+        if rank[type(a)] ~= rank[type(b)] then
+            return rank[type(a)] < rank[type(b)]
+        end
+        if type(a) == "boolean" then
+            return not a and b
+        end
+        return a < b
+    end)
+    local i = 0
+    return function()
+        i = i + 1
+        local k = keys[i]
+        if k ~= nil then
+            return k, t[k]
+        end
+    end, t, nil
+end
+"#;
+
+/// Hex encoded SHA1 digest of a script body, the name `EVALSHA` knows it by.
+pub(crate) fn sha1_hex(script: &str) -> String {
+    sha1_smol::Sha1::from(script).digest().to_string()
+}
+
+impl<T> Backend<T>
+where
+    T: KvStore,
+{
+    /// Runs a Lua script, which calls back into [process_command] through
+    /// `redis.call` and `redis.pcall`.
+    ///
+    /// Scripts are replicated by their body, so every replica runs the
+    /// same script against the same data. To keep them agreeing with each
+    /// other, the script only gets the `table`, `string` and `math`
+    /// libraries, `math.random` is reseeded before every run, `pairs`
+    /// walks keys in order, and scripts are aborted after as many
+    /// instructions everywhere. The writes an aborted script did stand.
+    ///
+    /// [process_command]: Backend::process_command
+    pub fn process_eval(
+        &mut self,
+        script: &str,
+        keys: Vec<String>,
+        args: Vec<String>,
+    ) -> RespValue {
+        match self.eval(script, keys, args) {
+            Ok(resp) => resp,
+            // Errors carry a stack traceback, which can't go in a RESP error
+            Err(e) => RespValue::Error(format!(
+                "ERR Error running script: {}",
+                e.to_string().lines().next().unwrap_or_default()
+            )),
+        }
+    }
+
+    fn eval(
+        &mut self,
+        script: &str,
+        keys: Vec<String>,
+        args: Vec<String>,
+    ) -> mlua::Result<RespValue> {
+        let lua = Lua::new_with(
+            StdLib::TABLE | StdLib::STRING | StdLib::MATH,
+            LuaOptions::default(),
+        )?;
+        lua.load("math.randomseed(0)").exec()?;
+        lua.load(PRELUDE).set_name("=prelude").exec()?;
+        let budget = Cell::new(INSTRUCTION_BUDGET / INSTRUCTION_CHECK);
+        let triggers = HookTriggers::new().every_nth_instruction(INSTRUCTION_CHECK);
+        lua.set_hook(triggers, move |lua, _| match budget.get() {
+            0 => {
+                // Every instruction fails from then on, so that catching
+                // the error with `pcall` doesn't let the script go on
+                let triggers = HookTriggers::new().every_nth_instruction(1);
+                lua.set_hook(triggers, |_, _| Err(out_of_instructions()));
+                Err(out_of_instructions())
+            }
+            left => {
+                budget.set(left - 1);
+                Ok(())
+            }
+        });
+
+        let globals = lua.globals();
+        globals.set("KEYS", keys)?;
+        globals.set("ARGV", args)?;
+
+        let backend = RefCell::new(self);
+        lua.scope(|scope| {
+            let call = scope.create_function(|lua, args: Variadic<String>| {
+                match call_backend(&mut backend.borrow_mut(), args) {
+                    RespValue::Error(e) => Err(mlua::Error::RuntimeError(e)),
+                    resp => resp_to_lua(lua, resp),
+                }
+            })?;
+            let pcall = scope.create_function(|lua, args: Variadic<String>| {
+                resp_to_lua(lua, call_backend(&mut backend.borrow_mut(), args))
+            })?;
+
+            let redis = lua.create_table()?;
+            redis.set("call", call)?;
+            redis.set("pcall", pcall)?;
+            globals.set("redis", redis)?;
+
+            lua_to_resp(lua.load(script).set_name("=user_script").eval()?)
+        })
+    }
+}
+
+fn out_of_instructions() -> mlua::Error {
+    mlua::Error::RuntimeError("script ran out of instructions".into())
+}
+
+fn call_backend<T>(backend: &mut Backend<T>, args: Variadic<String>) -> RespValue
+where
+    T: KvStore,
+{
+    let args: Vec<&str> = args.iter().map(|s| s.as_str()).collect();
+    match Command::try_from(RespValue::array(&args)) {
+        Ok(Command::Eval(..) | Command::EvalSha(..)) => {
+            RespValue::Error("ERR scripts can not call EVAL".into())
+        }
+        // Only the master sends these, to the replicas
+        Ok(Command::Sweep | Command::Evict(_)) => {
+            RespValue::Error("ERR unknown command or wrong arguments".into())
+        }
+        Ok(cmd) => backend.process_command(cmd),
+        Err(_) => RespValue::Error("ERR unknown command or wrong arguments".into()),
+    }
+}
+
+/// Converts a command reply the way Redis hands it to Lua scripts.
+fn resp_to_lua(lua: &Lua, resp: RespValue) -> mlua::Result<Value<'_>> {
+    Ok(match resp {
+        RespValue::Integer(i) => Value::Integer(i),
+        RespValue::BulkString(s) => Value::String(lua.create_string(&s)?),
+        RespValue::BulkBytes(b) => Value::String(lua.create_string(&b)?),
+        RespValue::SimpleString(s) => {
+            let t = lua.create_table()?;
+            t.set("ok", s)?;
+            Value::Table(t)
+        }
+        RespValue::Error(e) => {
+            let t = lua.create_table()?;
+            t.set("err", e)?;
+            Value::Table(t)
+        }
+        RespValue::Array(arr) => {
+            let t = lua.create_table_with_capacity(arr.len(), 0)?;
+            for v in arr {
+                t.raw_push(resp_to_lua(lua, v)?)?;
+            }
+            Value::Table(t)
+        }
+        RespValue::Null => Value::Boolean(false),
+    })
+}
+
+/// Converts a script's return value back to RESP, the inverse of [resp_to_lua].
+fn lua_to_resp(value: Value) -> mlua::Result<RespValue> {
+    Ok(match value {
+        Value::Integer(i) => RespValue::Integer(i),
+        Value::Number(n) => RespValue::Integer(n as i64),
+        Value::String(s) => match s.to_str() {
+            Ok(s) => RespValue::BulkString(s.to_string()),
+            Err(_) => RespValue::BulkBytes(s.as_bytes().to_vec()),
+        },
+        Value::Boolean(true) => RespValue::Integer(1),
+        Value::Table(t) => table_to_resp(t)?,
+        _ => RespValue::Null,
+    })
+}
+
+fn table_to_resp(t: Table) -> mlua::Result<RespValue> {
+    if let Some(e) = t.raw_get::<_, Option<String>>("err")? {
+        return Ok(RespValue::Error(e));
+    }
+    if let Some(s) = t.raw_get::<_, Option<String>>("ok")? {
+        return Ok(RespValue::SimpleString(s));
+    }
+
+    // Like Redis, the array stops at the first nil.
+    let arr = t
+        .sequence_values::<Value>()
+        .map(|v| lua_to_resp(v?))
+        .collect::<mlua::Result<_>>()?;
+    Ok(RespValue::Array(arr))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn eval(
+        backend: &mut Backend<HashMap<String, crate::value::Value>>,
+        script: &str,
+    ) -> RespValue {
+        backend.process_eval(script, vec![], vec![])
+    }
+
+    #[test]
+    fn test_instruction_budget() {
+        let mut backend = Backend::new(HashMap::new());
+        let error = RespValue::Error(
+            "ERR Error running script: runtime error: script ran out of instructions".into(),
+        );
+        assert_eq!(eval(&mut backend, "while true do end"), error);
+        // Catching the error doesn't keep it going
+        let script = "redis.call('SET', 'k', 'v') \
+            while true do pcall(function() while true do end end) end";
+        assert_eq!(eval(&mut backend, script), error);
+        assert_eq!(backend.store.kv_get("k").unwrap(), Some(&"v".into()));
+        assert_eq!(eval(&mut backend, "return 1"), RespValue::Integer(1));
+    }
+
+    #[test]
+    fn test_master_only_commands() {
+        let mut backend = Backend::new(HashMap::new());
+        backend.process_command(Command::try_from(RespValue::array(&["SET", "k", "v"])).unwrap());
+        assert!(matches!(
+            eval(&mut backend, "return redis.call('EVICT', 'k')"),
+            RespValue::Error(_)
+        ));
+        assert_eq!(backend.store.kv_get("k").unwrap(), Some(&"v".into()));
+    }
+
+    #[test]
+    fn test_pairs_in_order() {
+        let mut backend = Backend::new(HashMap::new());
+        let script = "local t = { c = 1, a = 2, [true] = 3, [2] = 4, b = 5, [1.5] = 6 } \
+            local keys = {} \
+            for k, v in pairs(t) do keys[#keys + 1] = tostring(k) .. '=' .. v end \
+            return keys";
+        assert_eq!(
+            eval(&mut backend, script),
+            RespValue::Array(
+                ["true=3", "1.5=6", "2=4", "a=2", "b=5", "c=1"]
+                    .iter()
+                    .map(|s| RespValue::BulkString(s.to_string()))
+                    .collect()
+            )
+        );
+        assert!(matches!(
+            eval(&mut backend, "for k in pairs({ [{}] = 1 }) do end"),
+            RespValue::Error(_)
+        ));
+    }
+}
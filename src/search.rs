@@ -0,0 +1,742 @@
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, BTreeSet, HashMap},
+    io,
+    iter::Peekable,
+    ops::Bound,
+    str::Chars,
+    vec::IntoIter,
+};
+
+use crate::{
+    backend::Backend,
+    command::{int, strings, CommandError},
+    hash::Hash,
+    map::KvStore,
+    resp::RespValue,
+    value::Value,
+    zset::SortedSet,
+};
+
+/// Results of `FT.SEARCH` unless asked for another `LIMIT`, as in RediSearch.
+const DEFAULT_LIMIT: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FieldType {
+    /// Comma separated tags, matched exactly but regardless of case.
+    Tag,
+    Numeric,
+    /// Full text, matched by words.
+    Text,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Field {
+    name: String,
+    kind: FieldType,
+}
+
+/// Splits text into lowercase words, the terms of full-text search.
+fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+}
+
+/// The tags or terms a tag or text field is found by.
+fn words(kind: FieldType, value: &str) -> Vec<String> {
+    match kind {
+        FieldType::Tag => value
+            .split(',')
+            .map(|tag| tag.trim().to_lowercase())
+            .filter(|tag| !tag.is_empty())
+            .collect(),
+        _ => tokenize(value).collect(),
+    }
+}
+
+/// A secondary index over the hashes whose key starts with one of its
+/// prefixes, kept up to date by [Backend::reindex] as they're written to.
+#[derive(Debug)]
+pub struct Index {
+    prefixes: Vec<String>,
+    fields: Vec<Field>,
+    /// The indexed fields of every document, to take it out of the index
+    /// when it changes, and to sort results by.
+    docs: BTreeMap<String, BTreeMap<String, String>>,
+    /// Documents by tag or term, for each tag and text field.
+    postings: HashMap<String, HashMap<String, BTreeSet<String>>>,
+    /// Documents scored by their value, for each numeric field.
+    numbers: HashMap<String, SortedSet>,
+}
+
+impl Index {
+    fn new(prefixes: Vec<String>, fields: Vec<Field>) -> Index {
+        let mut index = Index {
+            prefixes,
+            fields,
+            docs: BTreeMap::new(),
+            postings: HashMap::new(),
+            numbers: HashMap::new(),
+        };
+        for field in index.fields.iter() {
+            if field.kind == FieldType::Numeric {
+                index
+                    .numbers
+                    .insert(field.name.clone(), SortedSet::default());
+            } else {
+                index.postings.insert(field.name.clone(), HashMap::new());
+            }
+        }
+        index
+    }
+
+    /// The `FT.CREATE` that defines the index, for persisting it.
+    pub(crate) fn create_command(&self, name: &str) -> RespValue {
+        let mut args = vec!["FT.CREATE".to_string(), name.to_string(), "PREFIX".into()];
+        args.push(self.prefixes.len().to_string());
+        args.extend(self.prefixes.iter().cloned());
+        args.push("SCHEMA".into());
+        for field in self.fields.iter() {
+            args.push(field.name.clone());
+            args.push(format!("{:?}", field.kind).to_uppercase());
+        }
+        RespValue::Array(args.into_iter().map(RespValue::BulkString).collect())
+    }
+
+    fn covers(&self, key: &str) -> bool {
+        self.prefixes.is_empty() || self.prefixes.iter().any(|p| key.starts_with(p.as_str()))
+    }
+
+    fn field(&self, name: &str) -> Option<&Field> {
+        self.fields.iter().find(|f| f.name == name)
+    }
+
+    fn add(&mut self, key: &str, hash: &Hash) {
+        let mut doc = BTreeMap::new();
+        for field in self.fields.iter() {
+            let Some(value) = hash.get(&field.name) else {
+                continue;
+            };
+            if let Some(numbers) = self.numbers.get_mut(&field.name) {
+                match value.parse::<f64>() {
+                    Ok(n) if !n.is_nan() => numbers.insert(key, n),
+                    // Found by the other fields, but not this one
+                    _ => continue,
+                };
+            } else if let Some(postings) = self.postings.get_mut(&field.name) {
+                for word in words(field.kind, value) {
+                    postings.entry(word).or_default().insert(key.to_string());
+                }
+            }
+            doc.insert(field.name.clone(), value.to_string());
+        }
+        self.docs.insert(key.to_string(), doc);
+    }
+
+    fn remove(&mut self, key: &str) {
+        let Some(doc) = self.docs.remove(key) else {
+            return;
+        };
+        for (name, value) in doc {
+            if let Some(numbers) = self.numbers.get_mut(&name) {
+                numbers.remove(key);
+            } else if let (Some(field), Some(postings)) = (
+                self.fields.iter().find(|f| f.name == name),
+                self.postings.get_mut(&name),
+            ) {
+                for word in words(field.kind, &value) {
+                    if let Some(keys) = postings.get_mut(&word) {
+                        keys.remove(key);
+                        if keys.is_empty() {
+                            postings.remove(&word);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Checks that the query only looks for tags in tag fields, and so on.
+    fn check(&self, query: &Query) -> Result<(), String> {
+        let expect = |name: &str, kind: FieldType| match self.field(name) {
+            Some(field) if field.kind == kind => Ok(()),
+            Some(_) => Err(format!("ERR field `{}` is not of type {:?}", name, kind)),
+            None => Err(format!("ERR unknown field `{}`", name)),
+        };
+        match query {
+            Query::All | Query::Term(None, _) => Ok(()),
+            Query::Tags(name, _) => expect(name, FieldType::Tag),
+            Query::Range(name, ..) => expect(name, FieldType::Numeric),
+            Query::Term(Some(name), _) => expect(name, FieldType::Text),
+            Query::Not(query) => self.check(query),
+            Query::And(queries) => queries.iter().try_for_each(|q| self.check(q)),
+        }
+    }
+
+    /// Keys of the documents matching the query, in order.
+    fn search(&self, query: &Query) -> BTreeSet<&str> {
+        let postings = |name: &str, word: &str| {
+            self.postings
+                .get(name)
+                .and_then(|postings| postings.get(word))
+                .into_iter()
+                .flatten()
+                .map(String::as_str)
+        };
+        match query {
+            Query::All => self.docs.keys().map(String::as_str).collect(),
+            Query::Tags(name, tags) => tags.iter().flat_map(|tag| postings(name, tag)).collect(),
+            Query::Range(name, min, max) => self
+                .numbers
+                .get(name)
+                .into_iter()
+                .flat_map(|numbers| numbers.range_by_score(*min, *max))
+                .map(|(key, _)| key)
+                .collect(),
+            Query::Term(Some(name), term) => postings(name, term).collect(),
+            Query::Term(None, term) => self
+                .fields
+                .iter()
+                .filter(|f| f.kind == FieldType::Text)
+                .flat_map(|f| postings(&f.name, term))
+                .collect(),
+            Query::Not(query) => {
+                let excluded = self.search(query);
+                self.docs
+                    .keys()
+                    .map(String::as_str)
+                    .filter(|key| !excluded.contains(key))
+                    .collect()
+            }
+            Query::And(queries) => {
+                let mut results = queries.iter().map(|q| self.search(q));
+                let first = results.next().unwrap_or_default();
+                results.fold(first, |acc, keys| {
+                    acc.intersection(&keys).copied().collect()
+                })
+            }
+        }
+    }
+
+    /// Orders keys by the value of a field, numerically for numeric
+    /// fields, leaving the documents without it last.
+    fn sort(&self, keys: &mut [&str], field: &Field, descending: bool) {
+        let value = |key: &str| self.docs.get(key).and_then(|doc| doc.get(&field.name));
+        keys.sort_by(|a, b| {
+            let ordering = match (value(a), value(b)) {
+                (Some(a), Some(b)) if field.kind == FieldType::Numeric => {
+                    let number = |s: &String| s.parse::<f64>().unwrap_or(f64::NAN);
+                    number(a).total_cmp(&number(b))
+                }
+                (Some(a), Some(b)) => a.cmp(b),
+                (Some(_), None) => return Ordering::Less,
+                (None, Some(_)) => return Ordering::Greater,
+                (None, None) => Ordering::Equal,
+            };
+            if descending {
+                ordering.reverse()
+            } else {
+                ordering
+            }
+        });
+    }
+}
+
+/// What documents must match.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Query {
+    /// Every document, `*`.
+    All,
+    /// Documents with any of the tags, `@field:{a | b}`.
+    Tags(String, Vec<String>),
+    /// Documents with a number in the field within the bounds, `@field:[min max]`.
+    Range(String, Bound<f64>, Bound<f64>),
+    /// Documents with the term in the field, `@field:term`, or in any text
+    /// field, `term`.
+    Term(Option<String>, String),
+    /// `-query`
+    Not(Box<Query>),
+    /// Queries separated by spaces, which all must match.
+    And(Vec<Query>),
+}
+
+/// Reads up to the unescaped `end`, or to whitespace if `end` is `None`,
+/// taking backslashes as escapes, like in `{user\@example\.com}`.
+fn read_until(chars: &mut Peekable<Chars>, end: Option<char>) -> Option<String> {
+    let mut s = String::new();
+    loop {
+        match chars.peek().copied() {
+            None if end.is_none() => return Some(s),
+            None => return None,
+            Some(c) if end.is_none() && c.is_whitespace() => return Some(s),
+            Some(c) if Some(c) == end => {
+                chars.next();
+                return Some(s);
+            }
+            Some('\\') => {
+                chars.next();
+                s.push(chars.next()?);
+            }
+            Some(c) => {
+                chars.next();
+                s.push(c);
+            }
+        }
+    }
+}
+
+/// Terms of the text, all of which must be in the field.
+fn terms(field: Option<&str>, text: &str) -> Option<Query> {
+    let mut terms: Vec<_> = tokenize(text)
+        .map(|term| Query::Term(field.map(String::from), term))
+        .collect();
+    match terms.len() {
+        0 => None,
+        1 => terms.pop(),
+        _ => Some(Query::And(terms)),
+    }
+}
+
+fn bound(s: &str) -> Option<Bound<f64>> {
+    let (s, exclusive) = match s.strip_prefix('(') {
+        Some(s) => (s, true),
+        None => (s, false),
+    };
+    // Also takes `-inf` and `+inf`
+    let n: f64 = s.parse().ok().filter(|n: &f64| !n.is_nan())?;
+    Some(if exclusive {
+        Bound::Excluded(n)
+    } else {
+        Bound::Included(n)
+    })
+}
+
+fn parse_clause(chars: &mut Peekable<Chars>) -> Option<Query> {
+    if chars.next_if_eq(&'-').is_some() {
+        return Some(Query::Not(Box::new(parse_clause(chars)?)));
+    }
+    if chars.next_if_eq(&'*').is_some() {
+        return Some(Query::All);
+    }
+    if chars.next_if_eq(&'@').is_none() {
+        return terms(None, &read_until(chars, None)?);
+    }
+
+    let field = read_until(chars, Some(':'))?;
+    match chars.next()? {
+        '{' => {
+            let tags = read_until(chars, Some('}'))?
+                .split('|')
+                .map(|tag| tag.trim().to_lowercase())
+                .collect();
+            Some(Query::Tags(field, tags))
+        }
+        '[' => match read_until(chars, Some(']'))?
+            .split_whitespace()
+            .collect::<Vec<_>>()[..]
+        {
+            [min, max] => Some(Query::Range(field, bound(min)?, bound(max)?)),
+            _ => None,
+        },
+        '(' => terms(Some(&field), &read_until(chars, Some(')'))?),
+        c => {
+            let word = c.to_string() + &read_until(chars, None)?;
+            terms(Some(&field), &word)
+        }
+    }
+}
+
+/// Parses a query in a subset of the RediSearch syntax, e.g.
+/// `@role:{admin} @age:[(18 +inf] -@name:bob smith`.
+pub fn parse_query(s: &str) -> Option<Query> {
+    let mut chars = s.chars().peekable();
+    let mut clauses = Vec::new();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        if chars.peek().is_none() {
+            break;
+        }
+        clauses.push(parse_clause(&mut chars)?);
+    }
+    match clauses.len() {
+        0 => None,
+        1 => clauses.pop(),
+        _ => Some(Query::And(clauses)),
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SearchCommand {
+    Create {
+        index: String,
+        prefixes: Vec<String>,
+        fields: Vec<Field>,
+    },
+    DropIndex(String),
+    List,
+    Search {
+        index: String,
+        query: Query,
+        /// The field to sort by and whether in descending order,
+        /// otherwise results are ordered by key.
+        sort_by: Option<(String, bool)>,
+        offset: usize,
+        limit: usize,
+        no_content: bool,
+    },
+}
+
+impl SearchCommand {
+    /// Indexes aren't keys, and searches read whichever keys match.
+    pub fn keys(&self) -> Vec<&str> {
+        vec![]
+    }
+}
+
+/// Parses `FT.CREATE index [ON HASH] [PREFIX n prefix ...] SCHEMA field
+/// TAG|NUMERIC|TEXT [SORTABLE] ...`, where every field can be sorted by.
+fn parse_create(args: &[String]) -> Result<SearchCommand, CommandError> {
+    let [index, args @ ..] = args else {
+        return Err(CommandError::InvalidCommand);
+    };
+    let mut prefixes = Vec::new();
+    let mut args = args.iter();
+    loop {
+        let arg = args.next().ok_or(CommandError::InvalidCommand)?;
+        match arg.to_uppercase().as_str() {
+            "ON" if args
+                .next()
+                .is_some_and(|on| on.eq_ignore_ascii_case("HASH")) => {}
+            "PREFIX" => {
+                let n: usize = int(args.next().ok_or(CommandError::InvalidCommand)?)?;
+                for _ in 0..n {
+                    prefixes.push(args.next().ok_or(CommandError::InvalidCommand)?.clone());
+                }
+            }
+            "SCHEMA" => break,
+            _ => return Err(CommandError::InvalidCommand),
+        }
+    }
+
+    let mut fields: Vec<Field> = Vec::new();
+    let mut args = args.peekable();
+    while let Some(name) = args.next() {
+        let kind = match args.next().map(|kind| kind.to_uppercase()).as_deref() {
+            Some("TAG") => FieldType::Tag,
+            Some("NUMERIC") => FieldType::Numeric,
+            Some("TEXT") => FieldType::Text,
+            _ => return Err(CommandError::InvalidCommand),
+        };
+        args.next_if(|arg| arg.eq_ignore_ascii_case("SORTABLE"));
+        if fields.iter().any(|f| &f.name == name) {
+            return Err(CommandError::InvalidCommand);
+        }
+        fields.push(Field {
+            name: name.clone(),
+            kind,
+        });
+    }
+    if fields.is_empty() {
+        return Err(CommandError::InvalidCommand);
+    }
+
+    Ok(SearchCommand::Create {
+        index: index.clone(),
+        prefixes,
+        fields,
+    })
+}
+
+/// Parses `FT.SEARCH index query [SORTBY field [ASC|DESC]] [LIMIT offset num]
+/// [NOCONTENT]`.
+fn parse_search(args: &[String]) -> Result<SearchCommand, CommandError> {
+    let [index, query, args @ ..] = args else {
+        return Err(CommandError::InvalidCommand);
+    };
+    let query = parse_query(query).ok_or(CommandError::InvalidCommand)?;
+    let mut sort_by = None;
+    let (mut offset, mut limit) = (0, DEFAULT_LIMIT);
+    let mut no_content = false;
+
+    let mut args = args.iter().peekable();
+    while let Some(arg) = args.next() {
+        let mut next = || args.next().ok_or(CommandError::InvalidCommand);
+        match arg.to_uppercase().as_str() {
+            "SORTBY" => {
+                let field = next()?.clone();
+                let descending = match args.peek().map(|order| order.to_uppercase()).as_deref() {
+                    Some("ASC") => false,
+                    Some("DESC") => true,
+                    _ => {
+                        sort_by = Some((field, false));
+                        continue;
+                    }
+                };
+                args.next();
+                sort_by = Some((field, descending));
+            }
+            "LIMIT" => {
+                offset = int(next()?)?;
+                limit = int(next()?)?;
+            }
+            "NOCONTENT" => no_content = true,
+            _ => return Err(CommandError::InvalidCommand),
+        }
+    }
+
+    Ok(SearchCommand::Search {
+        index: index.clone(),
+        query,
+        sort_by,
+        offset,
+        limit,
+        no_content,
+    })
+}
+
+/// Parses the arguments of `FT.CREATE`, `FT.DROPINDEX`, `FT._LIST` and
+/// `FT.SEARCH`.
+pub fn parse(verb: &str, arr: IntoIter<RespValue>) -> Result<SearchCommand, CommandError> {
+    let args = strings(arr)?;
+    match (verb, args.as_slice()) {
+        ("FT.CREATE", args) => parse_create(args),
+        ("FT.DROPINDEX", [index]) => Ok(SearchCommand::DropIndex(index.clone())),
+        ("FT._LIST", []) => Ok(SearchCommand::List),
+        ("FT.SEARCH", args) => parse_search(args),
+        _ => Err(CommandError::InvalidCommand),
+    }
+}
+
+impl<T> Backend<T>
+where
+    T: KvStore,
+{
+    /// Brings the indexes up to date with the keys that changed since the
+    /// event at `from`, which every change to a key raises one of.
+    pub(crate) fn reindex(&mut self, from: usize) -> io::Result<()> {
+        if self.indexes.is_empty() {
+            return Ok(());
+        }
+        let keys: BTreeSet<&str> = self.events[from..].iter().map(|e| e.key.as_str()).collect();
+        for key in keys {
+            let hash = match self.store.kv_get(key)? {
+                Some(Value::Hash(hash)) if !self.is_expired(key) => Some(hash),
+                _ => None,
+            };
+            for index in self.indexes.values_mut().filter(|index| index.covers(key)) {
+                index.remove(key);
+                if let Some(hash) = hash {
+                    index.add(key, hash);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn process_search(&mut self, cmd: SearchCommand) -> RespValue {
+        use SearchCommand::*;
+        match cmd {
+            Create {
+                index,
+                prefixes,
+                fields,
+            } => self.process_ft_create(index, prefixes, fields),
+            DropIndex(index) => match self.indexes.remove(&index) {
+                Some(_) => Ok(RespValue::SimpleString("OK".into())),
+                None => Err(RespValue::Error("ERR Unknown Index name".into())),
+            },
+            List => Ok(RespValue::Array(
+                self.indexes
+                    .keys()
+                    .map(|name| RespValue::BulkString(name.clone()))
+                    .collect(),
+            )),
+            Search {
+                index,
+                query,
+                sort_by,
+                offset,
+                limit,
+                no_content,
+            } => self.process_ft_search(&index, &query, sort_by, offset, limit, no_content),
+        }
+        .unwrap_or_else(|e| e)
+    }
+
+    fn process_ft_create(
+        &mut self,
+        name: String,
+        prefixes: Vec<String>,
+        fields: Vec<Field>,
+    ) -> Result<RespValue, RespValue> {
+        if self.indexes.contains_key(&name) {
+            return Err(RespValue::Error("ERR Index already exists".into()));
+        }
+
+        // Unlike RediSearch, the keys already there are indexed right away
+        let mut index = Index::new(prefixes, fields);
+        let keys: Vec<String> = self
+            .store
+            .kv_keys()
+            .filter(|key| index.covers(key))
+            .map(String::from)
+            .collect();
+        for key in keys {
+            if let Some(Value::Hash(hash)) = self.value(&key)? {
+                index.add(&key, hash);
+            }
+            self.store.kv_trim();
+        }
+        self.indexes.insert(name, index);
+        Ok(RespValue::SimpleString("OK".into()))
+    }
+
+    /// Replies with the number of matching documents, then the keys and
+    /// fields of the requested page of them.
+    fn process_ft_search(
+        &self,
+        name: &str,
+        query: &Query,
+        sort_by: Option<(String, bool)>,
+        offset: usize,
+        limit: usize,
+        no_content: bool,
+    ) -> Result<RespValue, RespValue> {
+        let index = self
+            .indexes
+            .get(name)
+            .ok_or_else(|| RespValue::Error(format!("ERR {}: no such index", name)))?;
+        index.check(query).map_err(RespValue::Error)?;
+
+        // Documents that expired stay in the index until they're deleted
+        let mut keys: Vec<&str> = index
+            .search(query)
+            .into_iter()
+            .filter(|key| !self.is_expired(key))
+            .collect();
+        if let Some((name, descending)) = sort_by {
+            let field = index.field(&name).ok_or_else(|| {
+                RespValue::Error(format!("ERR Property `{}` not loaded nor in schema", name))
+            })?;
+            index.sort(&mut keys, field, descending);
+        }
+
+        let mut results = vec![RespValue::Integer(keys.len() as i64)];
+        for key in keys.into_iter().skip(offset).take(limit) {
+            results.push(RespValue::BulkString(key.to_string()));
+            if !no_content {
+                let fields = self.hash(key)?.into_iter().flatten();
+                let fields = fields.flat_map(|(field, value)| {
+                    [
+                        RespValue::BulkString(field.to_string()),
+                        RespValue::BulkString(value.to_string()),
+                    ]
+                });
+                results.push(RespValue::Array(fields.collect()));
+            }
+        }
+        Ok(RespValue::Array(results))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::Command;
+
+    fn hash(pairs: &[(&str, &str)]) -> Hash {
+        pairs
+            .iter()
+            .map(|(f, v)| (f.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_query() {
+        let query = parse_query(r"@email:{Bob\@example\.com | x} @age:[(18 +inf] -hello").unwrap();
+        assert_eq!(
+            query,
+            Query::And(vec![
+                Query::Tags("email".into(), vec!["bob@example.com".into(), "x".into()]),
+                Query::Range(
+                    "age".into(),
+                    Bound::Excluded(18.0),
+                    Bound::Included(f64::INFINITY)
+                ),
+                Query::Not(Box::new(Query::Term(None, "hello".into()))),
+            ])
+        );
+        assert_eq!(
+            parse_query("@name:(Ada Lovelace)"),
+            Some(Query::And(vec![
+                Query::Term(Some("name".into()), "ada".into()),
+                Query::Term(Some("name".into()), "lovelace".into()),
+            ]))
+        );
+        assert_eq!(parse_query("  "), None);
+        assert_eq!(parse_query("@age:[1]"), None);
+        assert_eq!(parse_query("@role:{admin"), None);
+    }
+
+    #[test]
+    fn test_index() {
+        let field = |name: &str, kind| Field {
+            name: name.into(),
+            kind,
+        };
+        let mut index = Index::new(
+            vec!["user:".into()],
+            vec![
+                field("name", FieldType::Text),
+                field("role", FieldType::Tag),
+                field("age", FieldType::Numeric),
+            ],
+        );
+        assert!(index.covers("user:1") && !index.covers("post:1"));
+        index.add(
+            "user:1",
+            &hash(&[
+                ("name", "Ada Lovelace"),
+                ("role", "admin,dev"),
+                ("age", "36"),
+            ]),
+        );
+        index.add("user:2", &hash(&[("name", "Alan Turing"), ("age", "41")]));
+        index.add("user:3", &hash(&[("name", "Ada Yonath"), ("age", "old")]));
+
+        let search = |index: &Index, query: &str| -> Vec<String> {
+            let query = parse_query(query).unwrap();
+            index.check(&query).unwrap();
+            index.search(&query).into_iter().map(String::from).collect()
+        };
+        assert_eq!(search(&index, "ada"), ["user:1", "user:3"]);
+        assert_eq!(search(&index, "@role:{DEV}"), ["user:1"]);
+        assert_eq!(search(&index, "@age:[36 (41]"), ["user:1"]);
+        assert_eq!(search(&index, "-ada"), ["user:2"]);
+        assert_eq!(search(&index, "*"), ["user:1", "user:2", "user:3"]);
+        assert!(index.check(&parse_query("@name:{ada}").unwrap()).is_err());
+
+        let cmd = index.create_command("users");
+        let Ok(Command::Search(SearchCommand::Create {
+            prefixes, fields, ..
+        })) = Command::try_from(cmd)
+        else {
+            panic!("not an FT.CREATE");
+        };
+        assert_eq!(
+            (prefixes, fields),
+            (index.prefixes.clone(), index.fields.clone())
+        );
+
+        let mut keys = vec!["user:3", "user:1", "user:2"];
+        index.sort(&mut keys, &field("age", FieldType::Numeric), true);
+        assert_eq!(keys, ["user:2", "user:1", "user:3"]);
+
+        index.remove("user:1");
+        index.add("user:1", &hash(&[("name", "Grace Hopper")]));
+        assert_eq!(search(&index, "ada"), ["user:3"]);
+        assert_eq!(search(&index, "@role:{admin}"), Vec::<String>::new());
+        assert!(index.postings["role"].is_empty());
+    }
+}
@@ -0,0 +1,368 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    thread::{self, JoinHandle},
+};
+
+use crc::{Crc, CRC_64_REDIS};
+use tracing::{info, warn};
+
+use crate::{aof::Record, backend::Backend, map::KvStore, rdb, resp::RespValue};
+
+const MAGIC: &[u8; 8] = b"KVKVSNAP";
+/// Bumped whenever the layout or the records change incompatibly.
+const VERSION: u32 = 1;
+const CHECKSUM: Crc<u64> = Crc::<u64>::new(&CRC_64_REDIS);
+
+/// `save <seconds> <changes>`: snapshot once at least `changes` writes
+/// happened over at least `seconds` since the last snapshot.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SaveRule {
+    pub seconds: u64,
+    pub changes: u64,
+}
+
+impl FromStr for SaveRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split_whitespace().collect();
+        match parts.as_slice() {
+            [seconds, changes] => Ok(SaveRule {
+                seconds: seconds.parse().map_err(|_| "invalid save seconds")?,
+                changes: changes.parse().map_err(|_| "invalid save changes")?,
+            }),
+            _ => Err(format!(
+                "invalid save rule {}, expected <seconds> <changes>",
+                s
+            )),
+        }
+    }
+}
+
+/// What snapshots are written as. Either is loaded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Kvkv,
+    /// An RDB file as Redis writes them, without what Redis has no
+    /// equivalent for, e.g. indexes and probabilistic data structures.
+    Rdb,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "kvkv" => Ok(Format::Kvkv),
+            "rdb" => Ok(Format::Rdb),
+            _ => Err(format!("invalid snapshot format {}", s)),
+        }
+    }
+}
+
+/// The snapshot file: the magic, the version, the number of records, each
+/// record as a length-prefixed blob, then a CRC-64 of all that.
+fn encode(records: &[Record]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(MAGIC);
+    buf.extend_from_slice(&VERSION.to_le_bytes());
+    buf.extend_from_slice(&(records.len() as u64).to_le_bytes());
+    for record in records {
+        let bytes = serde_json::to_vec(record).unwrap();
+        buf.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&bytes);
+    }
+    let checksum = CHECKSUM.checksum(&buf);
+    buf.extend_from_slice(&checksum.to_le_bytes());
+    buf
+}
+
+fn decode(buf: &[u8]) -> Result<Vec<Record>, String> {
+    let (body, checksum) = buf
+        .split_at_checked(buf.len().wrapping_sub(8))
+        .filter(|(body, _)| body.len() >= MAGIC.len() + 12)
+        .ok_or("snapshot is truncated")?;
+    if &body[..MAGIC.len()] != MAGIC {
+        return Err("not a snapshot file".into());
+    }
+    if u64::from_le_bytes(checksum.try_into().unwrap()) != CHECKSUM.checksum(body) {
+        return Err("snapshot checksum mismatch".into());
+    }
+
+    let mut rest = &body[MAGIC.len()..];
+    let mut take = |n: usize| -> Result<&[u8], String> {
+        let (head, tail) = rest.split_at_checked(n).ok_or("snapshot is truncated")?;
+        rest = tail;
+        Ok(head)
+    };
+    let version = u32::from_le_bytes(take(4)?.try_into().unwrap());
+    if version != VERSION {
+        return Err(format!("unsupported snapshot version {}", version));
+    }
+    let count = u64::from_le_bytes(take(8)?.try_into().unwrap());
+    let mut records = Vec::new();
+    for _ in 0..count {
+        let len = u32::from_le_bytes(take(4)?.try_into().unwrap());
+        let record = serde_json::from_slice(take(len as usize)?).map_err(|e| e.to_string())?;
+        records.push(record);
+    }
+    Ok(records)
+}
+
+/// Writes the snapshot next to `path`, then renames it over, so that a
+/// crash halfway leaves the previous snapshot be.
+pub fn write(path: &Path, records: &[Record], format: Format) -> io::Result<()> {
+    let buf = match format {
+        Format::Kvkv => encode(records),
+        Format::Rdb => rdb::encode(records),
+    };
+    let temp = path.with_extension("tmp");
+    let mut file = BufWriter::new(File::create(&temp)?);
+    file.write_all(&buf)?;
+    file.into_inner()?.sync_all()?;
+    fs::rename(&temp, path)
+}
+
+/// Reads the snapshot at `path`, in either format, if there's one.
+pub fn read(path: &Path) -> Result<Option<(Vec<Record>, Format)>, String> {
+    let buf = match fs::read(path) {
+        Ok(buf) => buf,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.to_string()),
+    };
+    let read = match rdb::is_rdb(&buf) {
+        true => rdb::decode(&buf, crate::backend::unix_millis()).map(|r| (r, Format::Rdb)),
+        false => decode(&buf).map(|r| (r, Format::Kvkv)),
+    };
+    read.map(Some)
+        .map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// Loads the snapshot at `path` into the backend, if there's one.
+pub fn load<T: KvStore>(path: &Path, backend: &mut Backend<T>) -> Result<(), String> {
+    let Some((records, format)) = read(path)? else {
+        return Ok(());
+    };
+    info!(
+        "loaded {} records from the {:?} snapshot",
+        records.len(),
+        format
+    );
+    for record in records {
+        backend.load(record).map_err(|e| e.to_string())?;
+    }
+    backend.now = crate::backend::unix_millis();
+    Ok(())
+}
+
+/// Rewrites the snapshot at `input` as `output`, in the other format
+/// unless told which.
+pub fn convert(
+    input: &Path,
+    output: &Path,
+    format: Option<Format>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (records, from) = read(input)?.ok_or(format!("{}: no such file", input.display()))?;
+    let to = format.unwrap_or(match from {
+        Format::Kvkv => Format::Rdb,
+        Format::Rdb => Format::Kvkv,
+    });
+    write(output, &records, to)?;
+    info!(
+        "converted {} records from {:?} to {:?}",
+        records.len(),
+        from,
+        to
+    );
+    Ok(())
+}
+
+/// A snapshot being written in the background, and how many changes it
+/// covers.
+struct Save {
+    handle: JoinHandle<io::Result<()>>,
+    started_at: u64,
+    changes: u64,
+}
+
+/// The snapshots of a replica, taken on demand or by the save rules.
+pub struct Snapshots {
+    path: PathBuf,
+    format: Format,
+    rules: Vec<SaveRule>,
+    /// Changes since the last successful snapshot.
+    changes: u64,
+    /// When the last successful snapshot was taken, in unix seconds.
+    last_save: u64,
+    save: Option<Save>,
+}
+
+impl Snapshots {
+    pub fn new(path: PathBuf, format: Format, rules: Vec<SaveRule>) -> Snapshots {
+        Snapshots {
+            path,
+            format,
+            rules,
+            changes: 0,
+            last_save: crate::backend::unix_millis() / 1000,
+            save: None,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Counts the changes of a committed write, snapshotting if that
+    /// satisfies a save rule.
+    pub fn changed<T: KvStore>(&mut self, changes: usize, backend: &Backend<T>) {
+        self.changes += changes as u64;
+        self.poll();
+        let elapsed = backend.now / 1000 - self.last_save.min(backend.now / 1000);
+        let due = self
+            .rules
+            .iter()
+            .any(|rule| self.changes >= rule.changes.max(1) && elapsed >= rule.seconds);
+        if due && self.save.is_none() {
+            info!("{} changes in {} seconds, saving", self.changes, elapsed);
+            self.bgsave(backend);
+        }
+    }
+
+    /// `SAVE`, writing the snapshot before replying.
+    pub fn save<T: KvStore>(&mut self, backend: &Backend<T>) -> RespValue {
+        if self.save.is_some() {
+            return RespValue::Error("ERR Background save already in progress".into());
+        }
+        match backend
+            .dump()
+            .and_then(|records| write(&self.path, &records, self.format))
+        {
+            Ok(()) => {
+                self.changes = 0;
+                self.last_save = backend.now / 1000;
+                RespValue::SimpleString("OK".into())
+            }
+            Err(e) => RespValue::Error(format!("ERR {}", e)),
+        }
+    }
+
+    /// `BGSAVE`, taking note of the dataset right away, then reading and
+    /// writing it out in the background.
+    pub fn bgsave<T: KvStore>(&mut self, backend: &Backend<T>) -> RespValue {
+        if self.save.is_some() {
+            return RespValue::Error("ERR Background save already in progress".into());
+        }
+        let dump = match backend.deferred_dump() {
+            Ok(dump) => dump,
+            Err(e) => return e.into(),
+        };
+        let (path, format) = (self.path.clone(), self.format);
+        self.save = Some(Save {
+            handle: thread::spawn(move || write(&path, &dump()?, format)),
+            started_at: backend.now / 1000,
+            changes: self.changes,
+        });
+        RespValue::SimpleString("Background saving started".into())
+    }
+
+    /// `LASTSAVE`
+    pub fn last_save(&mut self) -> RespValue {
+        self.poll();
+        RespValue::Integer(self.last_save as i64)
+    }
+
+    /// Takes note of a background snapshot that's done.
+    pub fn poll(&mut self) {
+        if !self
+            .save
+            .as_ref()
+            .is_some_and(|save| save.handle.is_finished())
+        {
+            return;
+        }
+        let save = self.save.take().unwrap();
+        match save.handle.join().unwrap() {
+            Ok(()) => {
+                info!("background saving terminated with success");
+                self.changes -= save.changes;
+                self.last_save = save.started_at;
+            }
+            Err(e) => warn!("background saving failed: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{command::Command, value::Value};
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_snapshot() {
+        let mut backend = Backend::new(HashMap::new());
+        backend.now = 1000;
+        for cmd in [
+            &["SET", "a", "1", "PX", "5000"][..],
+            &["HSET", "user:1", "name", "Ada"],
+            &["FT.CREATE", "users", "SCHEMA", "name", "TEXT"],
+        ] {
+            backend.process_command(Command::try_from(RespValue::array(cmd)).unwrap());
+        }
+
+        let buf = encode(&backend.dump().unwrap());
+        let mut loaded: Backend<HashMap<String, Value>> = Backend::new(HashMap::new());
+        for record in decode(&buf).unwrap() {
+            loaded.load(record).unwrap();
+        }
+        assert_eq!(loaded.store, backend.store);
+        assert_eq!(loaded.expires, backend.expires);
+        assert!(loaded.indexes.contains_key("users"));
+
+        let mut corrupt = buf.clone();
+        corrupt[20] ^= 1;
+        assert_eq!(decode(&corrupt), Err("snapshot checksum mismatch".into()));
+        assert_eq!(
+            decode(&buf[..buf.len() - 1]),
+            Err("snapshot checksum mismatch".into())
+        );
+        assert_eq!(decode(&buf[..10]), Err("snapshot is truncated".into()));
+    }
+
+    #[test]
+    fn test_save_rules() {
+        assert_eq!(
+            "900 1".parse(),
+            Ok(SaveRule {
+                seconds: 900,
+                changes: 1
+            })
+        );
+        assert!("900".parse::<SaveRule>().is_err());
+
+        let dir = std::env::temp_dir().join(format!("kvkv-snapshot-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let rules = vec!["60 2".parse().unwrap()];
+        let mut snapshots = Snapshots::new(dir.join("dump.snap"), Format::Kvkv, rules);
+        snapshots.last_save = 1000;
+
+        let mut backend = Backend::new(HashMap::new());
+        backend.now = 1_059_000;
+        snapshots.changed(5, &backend);
+        assert!(snapshots.save.is_none());
+        backend.now = 1_060_000;
+        snapshots.changed(0, &backend);
+        while snapshots.save.is_some() {
+            snapshots.poll();
+        }
+        assert_eq!(snapshots.changes, 0);
+        assert_eq!(snapshots.last_save, 1060);
+        assert!(dir.join("dump.snap").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
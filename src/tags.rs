@@ -0,0 +1,153 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    io,
+    vec::IntoIter,
+};
+
+use crate::{
+    backend::Backend,
+    command::{strings, CommandError},
+    map::KvStore,
+    resp::RespValue,
+};
+
+/// Tags attached to keys by `SET ... TAGS`, and the keys carrying each tag.
+#[derive(Debug, Default)]
+pub struct Tags {
+    keys: HashMap<String, BTreeSet<String>>,
+    tags: HashMap<String, BTreeSet<String>>,
+}
+
+impl Tags {
+    /// Replaces the tags of the key.
+    pub fn tag(&mut self, key: &str, tags: Vec<String>) {
+        self.untag(key);
+        if tags.is_empty() {
+            return;
+        }
+        for tag in tags.iter() {
+            self.keys
+                .entry(tag.clone())
+                .or_default()
+                .insert(key.to_string());
+        }
+        self.tags
+            .insert(key.to_string(), tags.into_iter().collect());
+    }
+
+    pub fn untag(&mut self, key: &str) {
+        for tag in self.tags.remove(key).into_iter().flatten() {
+            if let Some(keys) = self.keys.get_mut(&tag) {
+                keys.remove(key);
+                if keys.is_empty() {
+                    self.keys.remove(&tag);
+                }
+            }
+        }
+    }
+
+    fn keys(&self, tag: &str) -> impl Iterator<Item = &String> {
+        self.keys.get(tag).into_iter().flatten()
+    }
+
+    pub fn tags(&self, key: &str) -> impl Iterator<Item = &String> {
+        self.tags.get(key).into_iter().flatten()
+    }
+
+    /// Every key that has tags.
+    pub fn tagged(&self) -> impl Iterator<Item = &String> {
+        self.tags.keys()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum TagCommand {
+    /// `TAG.KEYS tag`
+    Keys(String),
+    /// `TAG.GET key`
+    Get(String),
+    /// `TAG.INVALIDATE tag [tag ...]`
+    Invalidate(Vec<String>),
+}
+
+impl TagCommand {
+    /// The keys behind tags aren't known until the command runs.
+    pub fn keys(&self) -> Vec<&str> {
+        match self {
+            TagCommand::Get(k) => vec![k.as_str()],
+            TagCommand::Keys(_) | TagCommand::Invalidate(_) => vec![],
+        }
+    }
+}
+
+/// Parses the arguments of `TAG.KEYS`, `TAG.GET` and `TAG.INVALIDATE`.
+pub fn parse(verb: &str, arr: IntoIter<RespValue>) -> Result<TagCommand, CommandError> {
+    let args = strings(arr)?;
+    match (verb, args.as_slice()) {
+        ("TAG.KEYS", [tag]) => Ok(TagCommand::Keys(tag.clone())),
+        ("TAG.GET", [k]) => Ok(TagCommand::Get(k.clone())),
+        ("TAG.INVALIDATE", tags) if !tags.is_empty() => Ok(TagCommand::Invalidate(tags.to_vec())),
+        _ => Err(CommandError::InvalidCommand),
+    }
+}
+
+fn bulk_strings<'a>(strings: impl Iterator<Item = &'a String>) -> RespValue {
+    RespValue::Array(strings.map(|s| RespValue::BulkString(s.clone())).collect())
+}
+
+impl<T> Backend<T>
+where
+    T: KvStore,
+{
+    /// Forgets the tags of the keys deleted since the event at `from`.
+    pub(crate) fn untag_deleted(&mut self, from: usize) -> io::Result<()> {
+        for event in self.events[from..].iter() {
+            if self.store.kv_get(&event.key)?.is_none() {
+                self.tags.untag(&event.key);
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn process_tag(&mut self, cmd: TagCommand) -> RespValue {
+        match cmd {
+            TagCommand::Keys(tag) => {
+                bulk_strings(self.tags.keys(&tag).filter(|k| !self.is_expired(k)))
+            }
+            TagCommand::Get(k) if self.is_expired(&k) => RespValue::Array(vec![]),
+            TagCommand::Get(k) => bulk_strings(self.tags.tags(&k)),
+            TagCommand::Invalidate(tags) => {
+                let keys: BTreeSet<String> = tags
+                    .iter()
+                    .flat_map(|tag| self.tags.keys(tag))
+                    .cloned()
+                    .collect();
+                self.delete_keys(keys, ('g', "del"))
+                    .map_or_else(RespValue::from, RespValue::Integer)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tags() {
+        let mut tags = Tags::default();
+        tags.tag("page:1", vec!["product:42".into(), "home".into()]);
+        tags.tag("page:2", vec!["product:42".into()]);
+        assert_eq!(
+            tags.keys("product:42").collect::<Vec<_>>(),
+            ["page:1", "page:2"]
+        );
+
+        tags.tag("page:1", vec!["home".into()]);
+        assert_eq!(tags.keys("product:42").collect::<Vec<_>>(), ["page:2"]);
+        tags.untag("page:2");
+        assert_eq!(tags.keys("product:42").count(), 0);
+        assert!(!tags.keys.contains_key("product:42"));
+        assert_eq!(tags.tags("page:1").collect::<Vec<_>>(), ["home"]);
+    }
+}
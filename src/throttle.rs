@@ -0,0 +1,226 @@
+use std::vec::IntoIter;
+
+use crate::{
+    backend::Backend,
+    command::{int, strings, CommandError},
+    map::KvStore,
+    resp::RespValue,
+    value::{Value, WRONGTYPE},
+};
+
+/// `CL.THROTTLE key max_burst count period [quantity]`: allows `count`
+/// actions every `period` seconds, plus bursts of up to `max_burst` more.
+#[derive(Debug, PartialEq)]
+pub struct ThrottleCommand {
+    pub key: String,
+    pub max_burst: u64,
+    pub count: u64,
+    pub period: u64,
+    pub quantity: u64,
+}
+
+impl ThrottleCommand {
+    pub fn keys(&self) -> Vec<&str> {
+        vec![self.key.as_str()]
+    }
+}
+
+/// Parses the arguments of `CL.THROTTLE`.
+pub fn parse(verb: &str, arr: IntoIter<RespValue>) -> Result<ThrottleCommand, CommandError> {
+    let args = strings(arr)?;
+    let (key, max_burst, count, period, quantity) = match (verb, args.as_slice()) {
+        ("CL.THROTTLE", [key, max_burst, count, period]) => (key, max_burst, count, period, "1"),
+        ("CL.THROTTLE", [key, max_burst, count, period, quantity]) => {
+            (key, max_burst, count, period, quantity.as_str())
+        }
+        _ => return Err(CommandError::InvalidCommand),
+    };
+    let cmd = ThrottleCommand {
+        key: key.clone(),
+        max_burst: int(max_burst)?,
+        count: int(count)?,
+        period: int(period)?,
+        quantity: int(quantity)?,
+    };
+    if cmd.count == 0 || cmd.period == 0 {
+        return Err(CommandError::InvalidCommand);
+    }
+    Ok(cmd)
+}
+
+/// What the generic cell rate algorithm decided, with times in microseconds.
+#[derive(Debug, PartialEq)]
+struct Outcome {
+    limited: bool,
+    /// How many more actions would be allowed right now.
+    remaining: u64,
+    /// When to retry, if limited and retrying can ever succeed.
+    retry_after: Option<u64>,
+    /// When the limit is back to its full burst.
+    reset_after: u64,
+    /// The theoretical arrival time to store, unless limited.
+    tat: Option<u64>,
+}
+
+/// GCRA as in redis-cell: every action pushes the theoretical arrival
+/// time (TAT) one emission interval further, and actions are limited
+/// once it gets further ahead of now than the burst allows. Arguments
+/// come from clients and the arithmetic runs on every replica, so it
+/// saturates rather than overflows.
+fn gcra(cmd: &ThrottleCommand, tat: Option<u64>, now: u64) -> Outcome {
+    let emission_interval = (cmd.period.saturating_mul(1_000_000) / cmd.count).max(1);
+    let tolerance = emission_interval.saturating_mul(cmd.max_burst.saturating_add(1));
+    let increment = emission_interval.saturating_mul(cmd.quantity);
+
+    let tat = tat.unwrap_or(now).max(now);
+    let new_tat = tat.saturating_add(increment);
+    let allowed = new_tat <= now.saturating_add(tolerance);
+
+    let ahead = if allowed { new_tat - now } else { tat - now };
+    let remaining = tolerance.saturating_sub(ahead) / emission_interval;
+    Outcome {
+        limited: !allowed,
+        remaining,
+        retry_after: match allowed {
+            false if increment <= tolerance => Some(new_tat - tolerance - now),
+            _ => None,
+        },
+        reset_after: ahead,
+        tat: allowed.then_some(new_tat),
+    }
+}
+
+/// Seconds in the reply, rounded up so that retrying after them succeeds.
+fn seconds(us: u64) -> RespValue {
+    integer(us.div_ceil(1_000_000))
+}
+
+/// A count in the reply, capped to what RESP integers hold.
+fn integer(n: u64) -> RespValue {
+    RespValue::Integer(n.min(i64::MAX as u64) as i64)
+}
+
+impl<T> Backend<T>
+where
+    T: KvStore,
+{
+    /// Replies with whether the action is limited, the limit, the remaining
+    /// actions, the seconds until retrying (or -1) and until the limit resets.
+    /// Runs on the master's clock like every write, so replicas agree.
+    pub(crate) fn process_throttle(&mut self, cmd: ThrottleCommand) -> RespValue {
+        self.throttle(cmd).unwrap_or_else(|e| e)
+    }
+
+    fn throttle(&mut self, cmd: ThrottleCommand) -> Result<RespValue, RespValue> {
+        let now = self.now * 1000;
+        let tat = match self.value_mut(&cmd.key)? {
+            Some(Value::Str(s)) => Some(
+                std::str::from_utf8(&s.as_bytes())
+                    .ok()
+                    .and_then(|s| s.parse().ok())
+                    .ok_or_else(|| RespValue::Error("ERR value is not a rate limit".into()))?,
+            ),
+            Some(_) => return Err(RespValue::Error(WRONGTYPE.into())),
+            None => None,
+        };
+
+        let outcome = gcra(&cmd, tat, now);
+        if let Some(tat) = outcome.tat {
+            // The key goes away once the limit is back to its full burst
+            self.store.kv_put(&cmd.key, Value::from(tat.to_string()))?;
+            let deadline = self.now.saturating_add(outcome.reset_after.div_ceil(1000));
+            self.expires.insert(cmd.key.clone(), deadline);
+            self.notify('g', "cl.throttle", &cmd.key);
+        }
+
+        Ok(RespValue::Array(vec![
+            RespValue::Integer(outcome.limited as i64),
+            integer(cmd.max_burst.saturating_add(1)),
+            integer(outcome.remaining),
+            outcome.retry_after.map_or(RespValue::Integer(-1), seconds),
+            seconds(outcome.reset_after),
+        ]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gcra() {
+        // 1 action per second, with bursts of 2 more
+        let cmd = ThrottleCommand {
+            key: "k".into(),
+            max_burst: 2,
+            count: 1,
+            period: 1,
+            quantity: 1,
+        };
+        let second = 1_000_000;
+        let mut now = 1000 * second;
+        let mut tat = None;
+        for remaining in [2, 1, 0] {
+            let outcome = gcra(&cmd, tat, now);
+            assert!(!outcome.limited);
+            assert_eq!(outcome.remaining, remaining);
+            tat = outcome.tat;
+        }
+
+        let outcome = gcra(&cmd, tat, now);
+        assert!(outcome.limited);
+        assert_eq!(outcome.remaining, 0);
+        assert_eq!(outcome.retry_after, Some(second));
+        assert_eq!(outcome.reset_after, 3 * second);
+        assert_eq!(outcome.tat, None);
+
+        now += second;
+        let outcome = gcra(&cmd, tat, now);
+        assert!(!outcome.limited);
+        assert_eq!(outcome.remaining, 0);
+
+        // More than the burst can never be allowed
+        let outcome = gcra(&ThrottleCommand { quantity: 4, ..cmd }, None, now);
+        assert!(outcome.limited);
+        assert_eq!(outcome.retry_after, None);
+    }
+
+    #[test]
+    fn test_huge_arguments() {
+        let cmd = ThrottleCommand {
+            key: "k".into(),
+            max_burst: u64::MAX,
+            count: 1,
+            period: u64::MAX,
+            quantity: u64::MAX,
+        };
+        let outcome = gcra(&cmd, Some(u64::MAX), 1000);
+        assert!(!outcome.limited);
+        assert_eq!(outcome.tat, Some(u64::MAX));
+
+        let mut backend = Backend::new(std::collections::HashMap::new());
+        backend.now = 1000;
+        let max = u64::MAX.to_string();
+        let reply = backend.process_command(
+            crate::command::Command::try_from(RespValue::array(&[
+                "CL.THROTTLE",
+                "k",
+                &max,
+                "1",
+                &max,
+                &max,
+            ]))
+            .unwrap(),
+        );
+        assert_eq!(
+            reply,
+            RespValue::Array(vec![
+                RespValue::Integer(0),
+                RespValue::Integer(i64::MAX),
+                RespValue::Integer(0),
+                RespValue::Integer(-1),
+                RespValue::Integer((u64::MAX - 1_000_000).div_ceil(1_000_000) as i64),
+            ])
+        );
+    }
+}
@@ -0,0 +1,76 @@
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{
+    bloom::BloomFilter,
+    cuckoo::CuckooFilter,
+    encoding::{List, Set, Str},
+    hash::Hash,
+    hll::HyperLogLog,
+    lock::Lock,
+    queue::Queue,
+    vector::VectorSet,
+    zset::SortedSet,
+};
+
+/// Error replied when a command doesn't apply to the type of a key's value.
+pub(crate) const WRONGTYPE: &str =
+    "WRONGTYPE Operation against a key holding the wrong kind of value";
+
+/// Values stored in a [KvStore].
+///
+/// [KvStore]: ../map/trait.KvStore.html
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Value {
+    /// A binary safe string, which is also what bitmaps are made of.
+    Str(Str),
+    Hll(HyperLogLog),
+    Bloom(BloomFilter),
+    Cuckoo(CuckooFilter),
+    /// A sorted set, which is also what geospatial indexes are made of.
+    ZSet(SortedSet),
+    Json(serde_json::Value),
+    Vector(VectorSet),
+    Hash(Hash),
+    Lock(Lock),
+    Queue(Queue),
+    /// Lists and sets only come from RDB files for now, and are kept so
+    /// that they make it back out to one.
+    List(List),
+    Set(Set),
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::Str(s.as_bytes().to_vec().into())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::Str(s.into_bytes().into())
+    }
+}
+
+impl From<Vec<u8>> for Value {
+    fn from(b: Vec<u8>) -> Self {
+        Value::Str(b.into())
+    }
+}
+
+impl Value {
+    /// How the value is kept, for `OBJECT ENCODING`, named after the
+    /// closest of Redis's encodings.
+    pub(crate) fn encoding(&self) -> &'static str {
+        match self {
+            Value::Str(s) => s.encoding(),
+            Value::Hash(hash) => hash.encoding(),
+            Value::Set(set) => set.encoding(),
+            Value::List(list) => list.encoding(),
+            Value::Hll(_) | Value::Bloom(_) | Value::Cuckoo(_) => "raw",
+            Value::ZSet(_) => "skiplist",
+            Value::Vector(_) => "hashtable",
+            Value::Json(_) => "json",
+            Value::Lock(_) | Value::Queue(_) => "struct",
+        }
+    }
+}
@@ -0,0 +1,472 @@
+use std::{collections::BTreeMap, vec::IntoIter};
+
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value as Json;
+
+use crate::{
+    backend::Backend,
+    command::{int, strings, CommandError},
+    json::{matches_filter, parse_filter_expression, Filter},
+    map::KvStore,
+    resp::RespValue,
+    value::{Value, WRONGTYPE},
+};
+
+/// Results of `VSIM` unless asked for another `COUNT`, as in Redis.
+const DEFAULT_COUNT: usize = 10;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct Element {
+    vector: Vec<f32>,
+    /// A JSON object that filters look into.
+    attributes: Option<Json>,
+}
+
+/// Vectors of the same dimension, named by element, searched by comparing
+/// the query to every one of them. Fine for the tens of thousands of
+/// vectors of small embedding sets, without an index to keep up to date.
+///
+/// `VSIM` is a read, so searches are spread over the replicas like `GET`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct VectorSet {
+    dim: usize,
+    elements: BTreeMap<String, Element>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Metric {
+    Cosine,
+    Dot,
+    L2,
+}
+
+impl Metric {
+    /// How close the vectors are, higher is closer, except for L2 which
+    /// is the distance between them.
+    fn score(self, a: &[f32], b: &[f32]) -> f32 {
+        let dot = || a.iter().zip(b).map(|(x, y)| x * y).sum::<f32>();
+        match self {
+            Metric::Dot => dot(),
+            Metric::Cosine => {
+                let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+                let norms = norm(a) * norm(b);
+                if norms == 0.0 {
+                    0.0
+                } else {
+                    dot() / norms
+                }
+            }
+            Metric::L2 => a
+                .iter()
+                .zip(b)
+                .map(|(x, y)| (x - y) * (x - y))
+                .sum::<f32>()
+                .sqrt(),
+        }
+    }
+}
+
+impl VectorSet {
+    /// The `k` elements closest to the query among those matching the
+    /// filter, closest first, with their scores.
+    fn search(
+        &self,
+        query: &[f32],
+        metric: Metric,
+        k: usize,
+        filter: Option<&Filter>,
+    ) -> Vec<(&str, &Element, f32)> {
+        let mut scored: Vec<_> = self
+            .elements
+            .iter()
+            .filter(|(_, e)| {
+                filter.is_none_or(|f| e.attributes.as_ref().is_some_and(|a| matches_filter(f, a)))
+            })
+            .map(|(name, e)| (name.as_str(), e, metric.score(query, &e.vector)))
+            .collect();
+
+        // Ties are broken by name, which the elements are already sorted by
+        match metric {
+            Metric::L2 => scored.sort_by(|a, b| a.2.total_cmp(&b.2)),
+            _ => scored.sort_by(|a, b| b.2.total_cmp(&a.2)),
+        }
+        scored.truncate(k);
+        scored
+    }
+}
+
+/// What to look for vectors similar to.
+#[derive(Debug, PartialEq)]
+pub enum Query {
+    Element(String),
+    Values(Vec<f32>),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum VectorCommand {
+    Add {
+        key: String,
+        vector: Vec<f32>,
+        element: String,
+        attributes: Option<Json>,
+    },
+    Rem(String, String),
+    Sim {
+        key: String,
+        query: Query,
+        count: usize,
+        metric: Metric,
+        filter: Option<Filter>,
+        with_scores: bool,
+        with_attribs: bool,
+    },
+    Card(String),
+    Dim(String),
+    Emb(String, String),
+    GetAttr(String, String),
+    SetAttr(String, String, Option<Json>),
+}
+
+impl VectorCommand {
+    pub fn keys(&self) -> Vec<&str> {
+        use VectorCommand::*;
+        match self {
+            Add { key, .. } | Rem(key, _) | Sim { key, .. } | Card(key) | Dim(key) => {
+                vec![key.as_str()]
+            }
+            Emb(key, _) | GetAttr(key, _) | SetAttr(key, ..) => vec![key.as_str()],
+        }
+    }
+}
+
+/// Parses `VALUES n v1 ... vn`, returning the vector and the arguments after it.
+fn values(args: &[String]) -> Result<(Vec<f32>, &[String]), CommandError> {
+    match args {
+        [values, n, rest @ ..] if values.eq_ignore_ascii_case("VALUES") => {
+            let n: usize = int(n)?;
+            if n == 0 || rest.len() < n {
+                return Err(CommandError::InvalidCommand);
+            }
+            let vector = rest[..n].iter().map(|v| int(v)).collect::<Result<_, _>>()?;
+            Ok((vector, &rest[n..]))
+        }
+        _ => Err(CommandError::InvalidCommand),
+    }
+}
+
+/// Attributes must be a JSON object, or empty to remove them.
+fn attributes(s: &str) -> Result<Option<Json>, CommandError> {
+    if s.is_empty() {
+        return Ok(None);
+    }
+    match serde_json::from_str(s) {
+        Ok(attributes @ Json::Object(_)) => Ok(Some(attributes)),
+        _ => Err(CommandError::InvalidCommand),
+    }
+}
+
+/// Parses the arguments of `VADD`, `VREM`, `VSIM`, `VCARD`, `VDIM`,
+/// `VEMB`, `VGETATTR` and `VSETATTR`.
+pub fn parse(verb: &str, arr: IntoIter<RespValue>) -> Result<VectorCommand, CommandError> {
+    let args = strings(arr)?;
+    let cmd = match (verb, args.as_slice()) {
+        ("VADD", [key, rest @ ..]) => {
+            let (vector, rest) = values(rest)?;
+            let (element, attrs) = match rest {
+                [element] => (element, None),
+                [element, flag, attrs] if flag.eq_ignore_ascii_case("SETATTR") => {
+                    (element, attributes(attrs)?)
+                }
+                _ => return Err(CommandError::InvalidCommand),
+            };
+            VectorCommand::Add {
+                key: key.clone(),
+                vector,
+                element: element.clone(),
+                attributes: attrs,
+            }
+        }
+        ("VREM", [key, element]) => VectorCommand::Rem(key.clone(), element.clone()),
+        ("VSIM", [key, rest @ ..]) => {
+            let (query, rest) = match rest {
+                [ele, element, rest @ ..] if ele.eq_ignore_ascii_case("ELE") => {
+                    (Query::Element(element.clone()), rest)
+                }
+                _ => {
+                    let (vector, rest) = values(rest)?;
+                    (Query::Values(vector), rest)
+                }
+            };
+
+            let mut count = DEFAULT_COUNT;
+            let mut metric = Metric::Cosine;
+            let mut filter = None;
+            let (mut with_scores, mut with_attribs) = (false, false);
+            let mut options = rest.iter();
+            while let Some(option) = options.next() {
+                let mut next = || options.next().ok_or(CommandError::InvalidCommand);
+                match option.to_uppercase().as_str() {
+                    "COUNT" => count = int(next()?)?,
+                    "METRIC" => {
+                        metric = match next()?.to_uppercase().as_str() {
+                            "COSINE" => Metric::Cosine,
+                            "DOT" => Metric::Dot,
+                            "L2" => Metric::L2,
+                            _ => return Err(CommandError::InvalidCommand),
+                        }
+                    }
+                    "FILTER" => {
+                        filter = Some(
+                            parse_filter_expression(next()?).ok_or(CommandError::InvalidCommand)?,
+                        )
+                    }
+                    "WITHSCORES" => with_scores = true,
+                    "WITHATTRIBS" => with_attribs = true,
+                    _ => return Err(CommandError::InvalidCommand),
+                }
+            }
+            VectorCommand::Sim {
+                key: key.clone(),
+                query,
+                count,
+                metric,
+                filter,
+                with_scores,
+                with_attribs,
+            }
+        }
+        ("VCARD", [key]) => VectorCommand::Card(key.clone()),
+        ("VDIM", [key]) => VectorCommand::Dim(key.clone()),
+        ("VEMB", [key, element]) => VectorCommand::Emb(key.clone(), element.clone()),
+        ("VGETATTR", [key, element]) => VectorCommand::GetAttr(key.clone(), element.clone()),
+        ("VSETATTR", [key, element, attrs]) => {
+            VectorCommand::SetAttr(key.clone(), element.clone(), attributes(attrs)?)
+        }
+        _ => return Err(CommandError::InvalidCommand),
+    };
+    Ok(cmd)
+}
+
+fn float(f: f32) -> RespValue {
+    RespValue::BulkString(f.to_string())
+}
+
+impl<T> Backend<T>
+where
+    T: KvStore,
+{
+    fn vset(&self, key: &str) -> Result<Option<&VectorSet>, RespValue> {
+        match self.value(key)? {
+            Some(Value::Vector(set)) => Ok(Some(set)),
+            Some(_) => Err(RespValue::Error(WRONGTYPE.into())),
+            None => Ok(None),
+        }
+    }
+
+    fn vset_mut(&mut self, key: &str) -> Result<Option<&mut VectorSet>, RespValue> {
+        match self.value_mut(key)? {
+            Some(Value::Vector(set)) => Ok(Some(set)),
+            Some(_) => Err(RespValue::Error(WRONGTYPE.into())),
+            None => Ok(None),
+        }
+    }
+
+    fn element(&self, key: &str, element: &str) -> Result<Option<&Element>, RespValue> {
+        Ok(self.vset(key)?.and_then(|set| set.elements.get(element)))
+    }
+
+    pub(crate) fn process_vector(&mut self, cmd: VectorCommand) -> RespValue {
+        use VectorCommand::*;
+        match cmd {
+            Add {
+                key,
+                vector,
+                element,
+                attributes,
+            } => self.process_vadd(&key, vector, element, attributes),
+            Rem(key, element) => self.process_vrem(&key, &element),
+            Sim {
+                key,
+                query,
+                count,
+                metric,
+                filter,
+                with_scores,
+                with_attribs,
+            } => self.vset(&key).and_then(|set| {
+                let Some(set) = set else {
+                    return Ok(RespValue::Array(Vec::new()));
+                };
+                let query = match &query {
+                    Query::Values(vector) if vector.len() != set.dim => {
+                        return Err(dimension_mismatch(vector.len(), set.dim))
+                    }
+                    Query::Values(vector) => vector,
+                    Query::Element(element) => match set.elements.get(element) {
+                        Some(e) => &e.vector,
+                        None => {
+                            return Err(RespValue::Error("ERR element not found in set".into()))
+                        }
+                    },
+                };
+
+                let mut results = Vec::new();
+                for (name, e, score) in set.search(query, metric, count, filter.as_ref()) {
+                    results.push(RespValue::BulkString(name.to_string()));
+                    if with_scores {
+                        results.push(float(score));
+                    }
+                    if with_attribs {
+                        results.push(match &e.attributes {
+                            Some(attributes) => RespValue::BulkString(attributes.to_string()),
+                            None => RespValue::Null,
+                        });
+                    }
+                }
+                Ok(RespValue::Array(results))
+            }),
+            Card(key) => self
+                .vset(&key)
+                .map(|set| RespValue::Integer(set.map_or(0, |s| s.elements.len()) as i64)),
+            Dim(key) => self.vset(&key).and_then(|set| match set {
+                Some(set) => Ok(RespValue::Integer(set.dim as i64)),
+                None => Err(RespValue::Error("ERR key does not exist".into())),
+            }),
+            Emb(key, element) => self.element(&key, &element).map(|e| match e {
+                Some(e) => RespValue::Array(e.vector.iter().map(|&f| float(f)).collect()),
+                None => RespValue::Null,
+            }),
+            GetAttr(key, element) => {
+                self.element(&key, &element)
+                    .map(|e| match e.and_then(|e| e.attributes.as_ref()) {
+                        Some(attributes) => RespValue::BulkString(attributes.to_string()),
+                        None => RespValue::Null,
+                    })
+            }
+            SetAttr(key, element, attributes) => self.process_vsetattr(&key, &element, attributes),
+        }
+        .unwrap_or_else(|e| e)
+    }
+
+    fn process_vadd(
+        &mut self,
+        key: &str,
+        vector: Vec<f32>,
+        element: String,
+        attributes: Option<Json>,
+    ) -> Result<RespValue, RespValue> {
+        let dim = vector.len();
+        if self.vset_mut(key)?.is_none() {
+            let set = VectorSet {
+                dim,
+                elements: BTreeMap::new(),
+            };
+            self.store.kv_put(key, Value::Vector(set))?;
+        }
+        let Some(Value::Vector(set)) = self.store.kv_get_mut(key)? else {
+            unreachable!()
+        };
+        if dim != set.dim {
+            return Err(dimension_mismatch(dim, set.dim));
+        }
+
+        let added = set
+            .elements
+            .insert(element, Element { vector, attributes })
+            .is_none();
+        self.notify('g', "vadd", key);
+        Ok(RespValue::Integer(added as i64))
+    }
+
+    fn process_vrem(&mut self, key: &str, element: &str) -> Result<RespValue, RespValue> {
+        let Some(set) = self.vset_mut(key)? else {
+            return Ok(RespValue::Integer(0));
+        };
+        if set.elements.remove(element).is_none() {
+            return Ok(RespValue::Integer(0));
+        }
+
+        let emptied = set.elements.is_empty();
+        self.notify('g', "vrem", key);
+        if emptied {
+            self.store.kv_del(key)?;
+            self.expires.remove(key);
+            self.notify('g', "del", key);
+        }
+        Ok(RespValue::Integer(1))
+    }
+
+    fn process_vsetattr(
+        &mut self,
+        key: &str,
+        element: &str,
+        attributes: Option<Json>,
+    ) -> Result<RespValue, RespValue> {
+        let Some(e) = self
+            .vset_mut(key)?
+            .and_then(|set| set.elements.get_mut(element))
+        else {
+            return Ok(RespValue::Integer(0));
+        };
+        e.attributes = attributes;
+        self.notify('g', "vsetattr", key);
+        Ok(RespValue::Integer(1))
+    }
+}
+
+fn dimension_mismatch(got: usize, dim: usize) -> RespValue {
+    RespValue::Error(format!(
+        "ERR Vector dimension mismatch - got {} but set has {}",
+        got, dim
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_vector_search() {
+        let mut set = VectorSet {
+            dim: 2,
+            elements: BTreeMap::new(),
+        };
+        let points = [
+            ("east", [1.0, 0.0], 2000),
+            ("north", [0.0, 1.0], 1990),
+            ("far-east", [10.0, 0.5], 1950),
+            ("west", [-1.0, 0.0], 2010),
+        ];
+        for (name, vector, year) in points {
+            let element = Element {
+                vector: vector.to_vec(),
+                attributes: Some(json!({ "year": year })),
+            };
+            set.elements.insert(name.into(), element);
+        }
+
+        let names = |results: Vec<(&str, &Element, f32)>| -> Vec<String> {
+            results.iter().map(|(name, ..)| name.to_string()).collect()
+        };
+        let query = [1.0, 0.0];
+        assert_eq!(
+            names(set.search(&query, Metric::Cosine, 2, None)),
+            ["east", "far-east"]
+        );
+        assert_eq!(
+            names(set.search(&query, Metric::Dot, 2, None)),
+            ["far-east", "east"]
+        );
+        assert_eq!(
+            names(set.search(&query, Metric::L2, 2, None)),
+            ["east", "north"]
+        );
+
+        let filter = parse_filter_expression(".year >= 1990 && .year < 2010").unwrap();
+        assert_eq!(
+            names(set.search(&query, Metric::Cosine, 10, Some(&filter))),
+            ["east", "north"]
+        );
+    }
+}
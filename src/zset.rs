@@ -0,0 +1,269 @@
+use std::{
+    cmp::Ordering,
+    collections::{BTreeSet, HashMap},
+    io,
+    ops::Bound,
+    vec::IntoIter,
+};
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{
+    backend::Backend,
+    command::{strings, CommandError},
+    map::KvStore,
+    resp::RespValue,
+    value::{Value, WRONGTYPE},
+};
+
+/// A score ordered by `f64::total_cmp`, so it can be a `BTreeSet` key.
+#[derive(Debug, Clone, Copy)]
+struct Score(f64);
+
+impl PartialEq for Score {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+/// Members ordered by score, then by member, like a Redis sorted set.
+///
+/// Only the scores get replicated, the order is rebuilt from them.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(from = "HashMap<String, f64>", into = "HashMap<String, f64>")]
+pub struct SortedSet {
+    scores: HashMap<String, f64>,
+    ordered: BTreeSet<(Score, String)>,
+}
+
+impl PartialEq for SortedSet {
+    fn eq(&self, other: &Self) -> bool {
+        self.scores == other.scores
+    }
+}
+
+impl From<HashMap<String, f64>> for SortedSet {
+    fn from(scores: HashMap<String, f64>) -> Self {
+        let ordered = scores.iter().map(|(m, &s)| (Score(s), m.clone())).collect();
+        SortedSet { scores, ordered }
+    }
+}
+
+impl From<SortedSet> for HashMap<String, f64> {
+    fn from(set: SortedSet) -> Self {
+        set.scores
+    }
+}
+
+impl SortedSet {
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    pub fn score(&self, member: &str) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    /// Sets the score of a member, returning its previous score.
+    pub fn insert(&mut self, member: &str, score: f64) -> Option<f64> {
+        let old = self.scores.insert(member.to_string(), score);
+        if let Some(old) = old {
+            self.ordered.remove(&(Score(old), member.to_string()));
+        }
+        self.ordered.insert((Score(score), member.to_string()));
+        old
+    }
+
+    pub fn remove(&mut self, member: &str) -> bool {
+        match self.scores.remove(member) {
+            Some(score) => self.ordered.remove(&(Score(score), member.to_string())),
+            None => false,
+        }
+    }
+
+    /// Members with a score in `min..max`, in order.
+    pub fn range(&self, min: f64, max: f64) -> impl Iterator<Item = (&str, f64)> {
+        self.range_by_score(Bound::Included(min), Bound::Excluded(max))
+    }
+
+    /// Members with a score within the bounds, in order.
+    pub fn range_by_score(
+        &self,
+        min: Bound<f64>,
+        max: Bound<f64>,
+    ) -> impl Iterator<Item = (&str, f64)> {
+        let start = match min {
+            Bound::Included(min) | Bound::Excluded(min) => {
+                Bound::Included((Score(min), String::new()))
+            }
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        self.ordered
+            .range((start, Bound::Unbounded))
+            .skip_while(move |(score, _)| matches!(min, Bound::Excluded(min) if score.0 == min))
+            .take_while(move |(score, _)| match max {
+                Bound::Included(max) => score.0 <= max,
+                Bound::Excluded(max) => score.0 < max,
+                Bound::Unbounded => true,
+            })
+            .map(|(score, member)| (member.as_str(), score.0))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ZSetCommand {
+    Rem(String, Vec<String>),
+    Card(String),
+    Score(String, String),
+}
+
+impl ZSetCommand {
+    pub fn keys(&self) -> Vec<&str> {
+        use ZSetCommand::*;
+        match self {
+            Rem(k, _) | Card(k) | Score(k, _) => vec![k.as_str()],
+        }
+    }
+}
+
+/// Parses the arguments of `ZREM`, `ZCARD` and `ZSCORE`.
+pub fn parse(verb: &str, arr: IntoIter<RespValue>) -> Result<ZSetCommand, CommandError> {
+    let args = strings(arr)?;
+    match (verb, args.as_slice()) {
+        ("ZREM", [k, members @ ..]) if !members.is_empty() => {
+            Ok(ZSetCommand::Rem(k.clone(), members.to_vec()))
+        }
+        ("ZCARD", [k]) => Ok(ZSetCommand::Card(k.clone())),
+        ("ZSCORE", [k, member]) => Ok(ZSetCommand::Score(k.clone(), member.clone())),
+        _ => Err(CommandError::InvalidCommand),
+    }
+}
+
+impl<T> Backend<T>
+where
+    T: KvStore,
+{
+    pub(crate) fn zset(&self, key: &str) -> Result<Option<&SortedSet>, RespValue> {
+        match self.value(key)? {
+            Some(Value::ZSet(set)) => Ok(Some(set)),
+            Some(_) => Err(RespValue::Error(WRONGTYPE.into())),
+            None => Ok(None),
+        }
+    }
+
+    /// The sorted set at `key` for adding to it, created if missing.
+    pub(crate) fn zset_mut(&mut self, key: &str) -> Result<&mut SortedSet, RespValue> {
+        if self.value_mut(key)?.is_none() {
+            self.store.kv_put(key, Value::ZSet(SortedSet::default()))?;
+        }
+        match self.store.kv_get_mut(key)? {
+            Some(Value::ZSet(set)) => Ok(set),
+            _ => Err(RespValue::Error(WRONGTYPE.into())),
+        }
+    }
+
+    /// Replaces the value at `key` with the sorted set, or deletes the key
+    /// if the set is empty, like Redis never keeps empty sets around.
+    pub(crate) fn store_zset(&mut self, key: &str, set: SortedSet, event: &str) -> io::Result<()> {
+        self.expire_if_needed(key)?;
+        self.expires.remove(key);
+        if set.is_empty() {
+            if self.store.kv_del(key)? {
+                self.notify('g', "del", key);
+            }
+        } else {
+            self.store.kv_put(key, Value::ZSet(set))?;
+            self.notify('z', event, key);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn process_zset(&mut self, cmd: ZSetCommand) -> RespValue {
+        use ZSetCommand::*;
+        match cmd {
+            Rem(k, members) => self.process_zrem(&k, &members),
+            Card(k) => self
+                .zset(&k)
+                .map(|set| RespValue::Integer(set.map_or(0, |s| s.len()) as i64)),
+            Score(k, member) => self
+                .zset(&k)
+                .map(|set| match set.and_then(|s| s.score(&member)) {
+                    Some(score) => RespValue::BulkString(score.to_string()),
+                    None => RespValue::Null,
+                }),
+        }
+        .unwrap_or_else(|e| e)
+    }
+
+    fn process_zrem(&mut self, key: &str, members: &[String]) -> Result<RespValue, RespValue> {
+        self.expire_if_needed(key)?;
+        let set = match self.store.kv_get_mut(key)? {
+            Some(Value::ZSet(set)) => set,
+            Some(_) => return Err(RespValue::Error(WRONGTYPE.into())),
+            None => return Ok(RespValue::Integer(0)),
+        };
+
+        let removed = members.iter().filter(|m| set.remove(m)).count();
+        let emptied = set.is_empty();
+        if removed > 0 {
+            self.notify('z', "zrem", key);
+        }
+        if emptied {
+            self.store.kv_del(key)?;
+            self.expires.remove(key);
+            self.notify('g', "del", key);
+        }
+        Ok(RespValue::Integer(removed as i64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sorted_set() {
+        let mut set = SortedSet::default();
+        assert_eq!(set.insert("b", 2.0), None);
+        set.insert("a", 2.0);
+        set.insert("c", 1.0);
+        set.insert("d", 5.0);
+        assert_eq!(set.insert("c", 3.0), Some(1.0));
+
+        let members: Vec<_> = set.range(2.0, 5.0).collect();
+        assert_eq!(members, vec![("a", 2.0), ("b", 2.0), ("c", 3.0)]);
+        let members: Vec<_> = set
+            .range_by_score(Bound::Excluded(2.0), Bound::Included(5.0))
+            .collect();
+        assert_eq!(members, vec![("c", 3.0), ("d", 5.0)]);
+
+        assert!(set.remove("a"));
+        assert!(!set.remove("a"));
+        assert_eq!(set.range(f64::MIN, f64::MAX).count(), 3);
+
+        let json = serde_json::to_string(&set).unwrap();
+        let copy: SortedSet = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            copy.range(f64::MIN, f64::MAX).collect::<Vec<_>>(),
+            vec![("b", 2.0), ("c", 3.0), ("d", 5.0)]
+        );
+    }
+}